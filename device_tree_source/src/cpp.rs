@@ -0,0 +1,1698 @@
+//! This module is a C preprocessor for Device Tree Source files, so that
+//! `gcc` is not needed to handle the `#include` and `#define` statements found
+//! in most real trees.
+//!
+//! It supports `#include`, object-like and function-like `#define` (including
+//! `#`, `##` and variadic macros), `#undef`, and the conditionals `#if`,
+//! `#ifdef`, `#ifndef`, `#elif`, `#else` and `#endif` with `defined()`. As
+//! with `gcc -x assembler-with-cpp`, lines such as `#address-cells = <1>;` that
+//! do not start with a known directive are left alone. Device Tree `/include/`
+//! statements are handled here as well, so the result of
+//! `Preprocessor::preprocess` takes the place of the result of
+//! `include::include_files`.
+//!
+//! Text that the preprocessor does not change is copied as is, so each
+//! returned `IncludeBounds` maps exactly back to the file. Macro expansions map
+//! back to the invocation they came from.
+//!
+//! After preprocessing, the `Preprocessor` can be asked which macros were
+//! defined, where, and what they replaced, which helps track a number in a
+//! property back to the header that defined it. It also keeps the regions
+//! that conditionals left out, so a node missing from the result can be found
+//! in the source along with the condition that removed it.
+
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use include::{IncludeBounds, IncludeMethod, find_include_file};
+use parser::eval_c_expr;
+
+// Guards against files that include themselves
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+const DIRECTIVES: &[&str] = &["define", "undef", "include", "if", "ifdef", "ifndef", "elif",
+                              "else", "endif", "error", "warning", "pragma", "line", "ident"];
+
+/// Defines errors from preprocessing a file.
+// TODO: impl Display and Error - issue 1.2
+#[derive(Debug)]
+pub enum CppError {
+    /// Some IO error, probably from trying to open a file. May include the
+    /// path of the file.
+    IOError(io::Error, Option<PathBuf>),
+    /// A problem with a directive, such as a malformed `#define`, an included
+    /// file that could not be found, an `#error` directive, or an `#if` without
+    /// an `#endif`. Holds the path of the file, the line, and a description.
+    Syntax(PathBuf, usize, String),
+}
+
+impl From<io::Error> for CppError {
+    fn from(err: io::Error) -> Self {
+        CppError::IOError(err, None)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TokenKind {
+    Ident,
+    Number,
+    Str,
+    Char,
+    Punct,
+    Other,
+    // Stands in for an empty argument next to `##`
+    Placemarker,
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    text: String,
+    // Whether whitespace came before the token
+    space: bool,
+    // Macros that may not be expanded again from this token
+    hide: Vec<String>,
+}
+
+impl Token {
+    fn new(kind: TokenKind, text: &[u8], space: bool) -> Self {
+        Token {
+            kind: kind,
+            text: String::from_utf8_lossy(text).into_owned(),
+            space: space,
+            hide: Vec::new(),
+        }
+    }
+
+    fn is_punct(&self, punct: &str) -> bool {
+        self.kind == TokenKind::Punct && self.text == punct
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Macro {
+    // `None` for object-like macros
+    params: Option<Vec<String>>,
+    variadic: bool,
+    body: Vec<Token>,
+}
+
+/// Where and how a macro was defined.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MacroDefinition {
+    name: String,
+    params: Option<Vec<String>>,
+    value: String,
+    path: PathBuf,
+    line: usize,
+    undefined: Option<(PathBuf, usize)>,
+}
+
+impl MacroDefinition {
+    /// Returns the name of the macro.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Returns the parameters of a function-like macro as they were
+    /// written, with variadic parameters ending in `...`. Object-like macros
+    /// have none.
+    pub fn params(&self) -> Option<&[String]> {
+        self.params.as_ref().map(|p| &p[..])
+    }
+
+    /// Returns the replacement text of the macro before any expansion.
+    pub fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Returns the path of the file that defined the macro. Macros defined
+    /// through `Preprocessor::define` have the path `<command-line>`.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the line of the `#define`, or 0 for macros defined through
+    /// `Preprocessor::define`.
+    pub fn line(&self) -> usize {
+        self.line
+    }
+
+    /// Returns the path and line of the `#undef` that removed this
+    /// definition, if it was removed rather than redefined or kept. Macros
+    /// removed through `Preprocessor::undefine` have the path
+    /// `<command-line>` and line 0.
+    pub fn undefined(&self) -> Option<(&Path, usize)> {
+        self.undefined.as_ref().map(|&(ref path, line)| (&**path, line))
+    }
+}
+
+impl fmt::Display for MacroDefinition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "#define {}", self.name)?;
+        if let Some(ref params) = self.params {
+            write!(f, "({})", params.join(", "))?;
+        }
+        if !self.value.is_empty() {
+            write!(f, " {}", self.value)?;
+        }
+        Ok(())
+    }
+}
+
+/// A part of a file left out by a conditional directive such as
+/// `#ifdef CONFIG_FOO`. Only the outermost region is kept when conditionals
+/// are nested.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExcludedRegion {
+    path: PathBuf,
+    lines: (usize, usize),
+    condition: String,
+    offset: usize,
+    text: String,
+}
+
+impl ExcludedRegion {
+    /// Returns the path of the file containing the region.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns the first and last lines of the region. The lines of the
+    /// directives around it are not included.
+    pub fn lines(&self) -> (usize, usize) {
+        self.lines
+    }
+
+    /// Returns the directive that left out the region, such as
+    /// `#ifdef CONFIG_FOO`, or `#else of #ifdef CONFIG_FOO` for an `#else`
+    /// branch.
+    pub fn condition(&self) -> &str {
+        &self.condition
+    }
+
+    /// Returns the offset in the preprocessed buffer where the region would
+    /// have been. This can be used to find the node the region is within.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Returns the text of the region as written.
+    pub fn text(&self) -> &str {
+        &self.text
+    }
+
+    /// Finds the nodes and properties defined in the region, returning the
+    /// path of each relative to whatever the region is within, along with its
+    /// line. Nodes inside the region add to the path, so
+    /// `uart { status = "okay"; };` gives `uart` and `uart/status`. Root nodes
+    /// and references keep their `/` or `&label` prefix.
+    ///
+    /// The region is only scanned for names rather than parsed, as it is often
+    /// not valid source on its own.
+    pub fn definitions(&self) -> Vec<(String, usize)> {
+        fn is_name_char(c: u8) -> bool {
+            is_ident_char(c) || b",.+*#?@-&".contains(&c)
+        }
+
+        let text = self.text.as_bytes();
+        // The text starts with the end of the line of the opening directive
+        let line_at = |pos: usize| {
+            self.lines.0 - 1 + text[..pos].iter().filter(|&&c| c == b'\n').count()
+        };
+        let join = |nodes: &[String], name: &str| {
+            let mut path = String::new();
+            for part in nodes.iter().map(|n| &**n).chain(Some(name)) {
+                if !path.is_empty() && !path.ends_with('/') {
+                    path.push('/');
+                }
+                path.push_str(part);
+            }
+            path
+        };
+        // Skips a statement up to and including its ';'
+        let statement_end = |pos: usize| {
+            let mut pos = pos;
+            while pos < text.len() && text[pos] != b';' {
+                pos += match text[pos] {
+                    b'"' | b'\'' => token_len(&text[pos..]).1,
+                    _ => 1,
+                };
+            }
+            pos + 1
+        };
+
+        let mut found = Vec::new();
+        let mut nodes: Vec<String> = Vec::new();
+        let mut line_start = false;
+        let mut pos = 0;
+        while pos < text.len() {
+            let rest = &text[pos..];
+            let newline = newline_len(rest);
+            if newline > 0 {
+                pos += newline;
+                line_start = true;
+                continue;
+            }
+            let space = space_len(rest, false);
+            if space > 0 {
+                pos += space;
+                continue;
+            }
+            if line_start && rest[0] == b'#' {
+                let end = logical_line_end(text, pos);
+                if is_directive(&line_tokens(text, pos + 1, end)) {
+                    pos = end;
+                    continue;
+                }
+            }
+            line_start = false;
+
+            let len = match rest[0] {
+                b'/' => {
+                    let keyword = rest[1..].iter()
+                        .position(|&c| c == b'/')
+                        .filter(|&end| rest[1..end + 1].iter().all(|&c| is_name_char(c)))
+                        .map(|end| &rest[..end + 2]);
+                    match keyword {
+                        Some(b"/omit-if-no-ref/") | Some(b"/include/") => {
+                            pos += keyword.unwrap().len();
+                            continue;
+                        }
+                        Some(_) => {
+                            pos = statement_end(pos);
+                            continue;
+                        }
+                        None => 1,
+                    }
+                }
+                c if is_name_char(c) => {
+                    rest.iter().position(|&c| !is_name_char(c)).unwrap_or(rest.len())
+                }
+                b'}' => {
+                    nodes.pop();
+                    pos += 1;
+                    continue;
+                }
+                b'"' | b'\'' => {
+                    pos += token_len(rest).1;
+                    continue;
+                }
+                _ => {
+                    pos += 1;
+                    continue;
+                }
+            };
+
+            let name = String::from_utf8_lossy(&rest[..len]).into_owned();
+            let after = pos + len + space_len(&rest[len..], true);
+            match text.get(after) {
+                Some(&b'{') => {
+                    found.push((join(&nodes, &name), line_at(pos)));
+                    nodes.push(name);
+                    pos = after + 1;
+                }
+                Some(&b'=') | Some(&b';') if name != "/" => {
+                    found.push((join(&nodes, &name), line_at(pos)));
+                    pos = statement_end(pos);
+                }
+                // Labels and anything else not understood
+                _ => pos += len,
+            }
+        }
+
+        found
+    }
+}
+
+// The state of one `#if` ... `#endif` group
+#[derive(Debug)]
+struct Cond {
+    // The directive that opened the group
+    opening: String,
+    line: usize,
+    parent_active: bool,
+    active: bool,
+    // Whether a branch of the group has been taken
+    taken: bool,
+    else_seen: bool,
+}
+
+#[derive(Debug, Default)]
+struct Output {
+    buffer: Vec<u8>,
+    bounds: Vec<IncludeBounds>,
+    depth: usize,
+}
+
+impl Output {
+    fn copy(&mut self, path: &Path, src: &[u8], start: usize, end: usize, method: &IncludeMethod) {
+        if end > start {
+            self.bounds.push(IncludeBounds::new_linear(path.to_owned(),
+                                                       self.buffer.len(),
+                                                       start,
+                                                       end - start,
+                                                       method.clone()));
+            self.buffer.extend_from_slice(&src[start..end]);
+        }
+    }
+
+    fn expansion(&mut self, path: &Path, start: usize, end: usize, text: &str) {
+        if !text.is_empty() {
+            self.bounds.push(IncludeBounds::new_expansion(path.to_owned(),
+                                                          self.buffer.len(),
+                                                          start,
+                                                          end - start,
+                                                          text.len()));
+            self.buffer.extend_from_slice(text.as_bytes());
+        }
+    }
+}
+
+/// A C preprocessor holding a set of include directories and defined macros.
+///
+/// # Examples
+///
+/// ```rust,no_run
+/// use device_tree_source::cpp::Preprocessor;
+///
+/// let mut cpp = Preprocessor::new(&["include"]);
+/// cpp.define("__DTS__", "1");
+/// let (buffer, bounds) = cpp.preprocess("board.dts").unwrap();
+/// ```
+#[derive(Debug, Clone)]
+pub struct Preprocessor {
+    include_dirs: Vec<PathBuf>,
+    macros: HashMap<String, Macro>,
+    // Every definition of each macro, in order
+    history: HashMap<String, Vec<MacroDefinition>>,
+    excluded: Vec<ExcludedRegion>,
+    forced_includes: Vec<PathBuf>,
+}
+
+impl Preprocessor {
+    /// Creates a preprocessor that searches `include_dirs`, in order, for
+    /// included files. No macros are defined to start with.
+    pub fn new<I: AsRef<Path>>(include_dirs: &[I]) -> Self {
+        Preprocessor {
+            include_dirs: include_dirs.iter().map(|d| d.as_ref().to_owned()).collect(),
+            macros: HashMap::new(),
+            history: HashMap::new(),
+            excluded: Vec::new(),
+            forced_includes: Vec::new(),
+        }
+    }
+
+    /// Defines an object-like macro, as `-D name=value` would. `value` may be
+    /// empty.
+    pub fn define(&mut self, name: &str, value: &str) {
+        let mac = Macro {
+            params: None,
+            variadic: false,
+            body: line_tokens(value.as_bytes(), 0, value.len()),
+        };
+        self.add_macro(name.to_owned(), mac, PathBuf::from("<command-line>"), 0);
+    }
+
+    /// Removes a macro, as `-U name` would.
+    pub fn undefine(&mut self, name: &str) {
+        self.remove_macro(name, PathBuf::from("<command-line>"), 0);
+    }
+
+    /// Adds a file to include before the first line of each preprocessed
+    /// file, as `-include file` would. Files that do not exist as given are
+    /// looked for in the include directories.
+    pub fn force_include<P: AsRef<Path>>(&mut self, file: P) {
+        self.forced_includes.push(file.as_ref().to_owned());
+    }
+
+    /// Checks whether a macro is currently defined.
+    pub fn is_defined(&self, name: &str) -> bool {
+        self.macros.contains_key(name)
+    }
+
+    /// Returns the current definition of a macro.
+    pub fn definition(&self, name: &str) -> Option<&MacroDefinition> {
+        if self.is_defined(name) {
+            self.history.get(name).and_then(|defs| defs.last())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the current definition of every defined macro, sorted by
+    /// name.
+    pub fn definitions(&self) -> Vec<&MacroDefinition> {
+        let mut defs: Vec<_> = self.macros
+            .keys()
+            .filter_map(|name| self.definition(name))
+            .collect();
+        defs.sort_by(|a, b| a.name.cmp(&b.name));
+        defs
+    }
+
+    /// Returns every definition a macro has had, oldest first, including ones
+    /// that were later redefined or removed with `#undef`. Removed ones say
+    /// where with `MacroDefinition::undefined`.
+    pub fn history(&self, name: &str) -> &[MacroDefinition] {
+        self.history.get(name).map_or(&[], |defs| &defs[..])
+    }
+
+    /// Macro expands `text` using the macros currently defined.
+    ///
+    /// # Examples
+    ///
+    /// ```rust
+    /// use device_tree_source::cpp::Preprocessor;
+    ///
+    /// let mut cpp = Preprocessor::new::<&str>(&[]);
+    /// cpp.define("IRQ_TYPE_LEVEL_HIGH", "4");
+    /// cpp.define("FLAGS", "(IRQ_TYPE_LEVEL_HIGH | 1)");
+    /// assert_eq!(cpp.expand_text("<FLAGS>"), "<(4 | 1)>");
+    /// ```
+    pub fn expand_text(&self, text: &str) -> String {
+        render(&self.expand(line_tokens(text.as_bytes(), 0, text.len())))
+    }
+
+    fn add_macro(&mut self, name: String, mac: Macro, path: PathBuf, line: usize) {
+        let params = mac.params.as_ref().map(|params| {
+            params.iter()
+                .enumerate()
+                .map(|(i, param)| match *param {
+                    _ if !mac.variadic || i + 1 < params.len() => param.clone(),
+                    ref p if p == "__VA_ARGS__" => "...".to_owned(),
+                    ref p => format!("{}...", p),
+                })
+                .collect()
+        });
+        let def = MacroDefinition {
+            name: name.clone(),
+            params: params,
+            value: render(&mac.body),
+            path: path,
+            line: line,
+            undefined: None,
+        };
+        self.history.entry(name.clone()).or_insert_with(Vec::new).push(def);
+        self.macros.insert(name, mac);
+    }
+
+    fn remove_macro(&mut self, name: &str, path: PathBuf, line: usize) {
+        if self.macros.remove(name).is_some() {
+            if let Some(def) = self.history.get_mut(name).and_then(|defs| defs.last_mut()) {
+                def.undefined = Some((path, line));
+            }
+        }
+    }
+
+    /// Preprocesses `file` and everything it includes. Returns the resulting
+    /// buffer and the bounds mapping it back to the files it came from, just
+    /// as `include::include_files` does.
+    ///
+    /// Macros defined by the file stay defined afterwards, and the regions
+    /// left out by conditionals can be found with `excluded`.
+    ///
+    /// # Errors
+    ///
+    /// Returns `CppError::IOError` if `file` could not be read and
+    /// `CppError::Syntax` for problems with directives, including files that
+    /// could not be found.
+    pub fn preprocess<P: AsRef<Path>>(&mut self, file: P)
+                                      -> Result<(Vec<u8>, Vec<IncludeBounds>), CppError> {
+        let mut out = Output::default();
+        self.excluded.clear();
+        self.process(file.as_ref(), IncludeMethod::CPP, &mut out)?;
+        Ok((out.buffer, out.bounds))
+    }
+
+    /// Returns the regions left out by conditionals during the last call to
+    /// `preprocess`, in the order they were found.
+    pub fn excluded(&self) -> &[ExcludedRegion] {
+        &self.excluded
+    }
+
+    // Runs a single file, appending the result to `out`. Files included with
+    // `/include/` are copied without being preprocessed, as dtc would do.
+    fn process(&mut self,
+               path: &Path,
+               method: IncludeMethod,
+               out: &mut Output)
+               -> Result<(), CppError> {
+        let mut src = Vec::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_end(&mut src))
+            .map_err(|e| CppError::IOError(e, Some(path.to_owned())))?;
+
+        // Marks where the file starts, even if it adds nothing to the buffer
+        out.bounds.push(IncludeBounds::new_linear(path.to_owned(),
+                                                  out.buffer.len(),
+                                                  0,
+                                                  0,
+                                                  method.clone()));
+        out.depth += 1;
+
+        if out.depth == 1 {
+            for file in self.forced_includes.clone() {
+                let found = if file.is_file() {
+                    Some(file.clone())
+                } else {
+                    self.find_system(&file.to_string_lossy())
+                };
+                match found {
+                    Some(found) => self.process(&found, IncludeMethod::CPP, out)?,
+                    None => {
+                        let err = io::Error::new(io::ErrorKind::NotFound,
+                                                 "could not find forced include");
+                        return Err(CppError::IOError(err, Some(file)));
+                    }
+                }
+            }
+        }
+
+        let cpp = method == IncludeMethod::CPP;
+        let mut conds: Vec<Cond> = Vec::new();
+        // The branch being left out: its depth in `conds`, where it starts, the
+        // line of its directive, its condition, and where it would have been
+        // in the buffer
+        let mut excluded: Option<(usize, usize, usize, String, usize)> = None;
+        let mut pos = 0;
+        let mut run = 0;
+        let mut line_start = true;
+
+        while pos < src.len() {
+            let rest = &src[pos..];
+            let newline = newline_len(rest);
+            if newline > 0 {
+                pos += newline;
+                line_start = true;
+                continue;
+            }
+            let space = space_len(rest, false);
+            if space > 0 {
+                pos += space;
+                continue;
+            }
+
+            let active = conds.last().map_or(true, |c| c.active);
+            if cpp && line_start && rest[0] == b'#' {
+                let end = logical_line_end(&src, pos);
+                let tokens = line_tokens(&src, pos + 1, end);
+                if is_directive(&tokens) {
+                    if active {
+                        out.copy(path, &src, run, pos, &method);
+                    }
+                    let line = line_of(&src, pos);
+                    let name = tokens.first().map_or(String::new(), |t| t.text.clone());
+                    let text = format!("#{}", render(&tokens));
+                    let depth = conds.len();
+                    self.directive(path, &src[pos + 1..end], tokens, line, &mut conds, out)?;
+
+                    let closed = match excluded {
+                        Some((d, ..)) => {
+                            d == depth && (name == "elif" || name == "else" || name == "endif")
+                        }
+                        None => false,
+                    };
+                    if closed {
+                        let (_, start, open_line, condition, offset) = excluded.take().unwrap();
+                        if line > open_line + 1 {
+                            self.excluded.push(ExcludedRegion {
+                                path: path.to_owned(),
+                                lines: (open_line + 1, line - 1),
+                                condition: condition,
+                                offset: offset,
+                                text: String::from_utf8_lossy(&src[start..pos]).into_owned(),
+                            });
+                        }
+                    }
+                    match conds.last() {
+                        Some(cond) if excluded.is_none() && cond.parent_active && !cond.active &&
+                                      name != "endif" => {
+                            let condition = if name == "else" {
+                                format!("#else of {}", cond.opening)
+                            } else {
+                                text
+                            };
+                            excluded = Some((conds.len(), end, line, condition, out.buffer.len()));
+                        }
+                        _ => {}
+                    }
+                    pos = end;
+                    run = end;
+                    line_start = false;
+                    continue;
+                }
+            }
+            line_start = false;
+
+            if !active {
+                pos += token_len(rest).1;
+                continue;
+            }
+
+            if rest.starts_with(b"/include/") {
+                if let Some((name, len)) = dts_include(rest) {
+                    out.copy(path, &src, run, pos, &method);
+                    let included = self.find(&name, path)
+                        .ok_or_else(|| {
+                            CppError::Syntax(path.to_owned(),
+                                             line_of(&src, pos),
+                                             format!("could not find included file {}", name))
+                        })?;
+                    self.include(&included, IncludeMethod::DTS, path, line_of(&src, pos), out)?;
+                    pos += len;
+                    run = pos;
+                    continue;
+                }
+            }
+
+            let (kind, len) = token_len(rest);
+            if cpp && kind == TokenKind::Ident {
+                if let Some((end, expansion)) = self.expand_invocation(&src, pos) {
+                    out.copy(path, &src, run, pos, &method);
+                    out.expansion(path, pos, end, &render(&expansion));
+                    pos = end;
+                    run = end;
+                    continue;
+                }
+            }
+            pos += len;
+        }
+
+        if let Some(cond) = conds.first() {
+            return Err(CppError::Syntax(path.to_owned(), cond.line, "unterminated #if".to_owned()));
+        }
+        out.copy(path, &src, run, src.len(), &method);
+        out.depth -= 1;
+
+        Ok(())
+    }
+
+    fn include(&mut self,
+               included: &Path,
+               method: IncludeMethod,
+               from: &Path,
+               line: usize,
+               out: &mut Output)
+               -> Result<(), CppError> {
+        if out.depth >= MAX_INCLUDE_DEPTH {
+            return Err(CppError::Syntax(from.to_owned(),
+                                        line,
+                                        "#include nested too deeply".to_owned()));
+        }
+        self.process(included, method, out)
+    }
+
+    // Quoted includes look next to the including file first
+    fn find(&self, name: &str, from: &Path) -> Option<PathBuf> {
+        from.parent()
+            .map(|dir| dir.join(name))
+            .into_iter()
+            .find(|path| path.is_file())
+            .or_else(|| self.find_system(name))
+    }
+
+    fn find_system(&self, name: &str) -> Option<PathBuf> {
+        find_include_file(name, &self.include_dirs).ok()
+    }
+
+    // `line` is the directive without its leading `#`
+    fn directive(&mut self,
+                 path: &Path,
+                 line: &[u8],
+                 tokens: Vec<Token>,
+                 line_num: usize,
+                 conds: &mut Vec<Cond>,
+                 out: &mut Output)
+                 -> Result<(), CppError> {
+        let error = |msg: String| CppError::Syntax(path.to_owned(), line_num, msg);
+        let active = conds.last().map_or(true, |c| c.active);
+        let mut tokens = tokens.into_iter();
+        let name = match tokens.next() {
+            Some(ref tok) if tok.kind == TokenKind::Ident => tok.text.clone(),
+            // Null directives and linemarkers
+            _ => return Ok(()),
+        };
+        let args: Vec<Token> = tokens.collect();
+
+        match &*name {
+            "if" | "ifdef" | "ifndef" => {
+                let opening = format!("#{} {}", name, render(&args));
+                let value = if !active {
+                    false
+                } else if name == "if" {
+                    self.eval_condition(args).map_err(&error)?
+                } else {
+                    let defined = match args.first() {
+                        Some(tok) if tok.kind == TokenKind::Ident => self.is_defined(&tok.text),
+                        _ => return Err(error(format!("expected macro name after #{}", name))),
+                    };
+                    defined == (name == "ifdef")
+                };
+                conds.push(Cond {
+                    opening: opening,
+                    line: line_num,
+                    parent_active: active,
+                    active: value,
+                    taken: value || !active,
+                    else_seen: false,
+                });
+            }
+            "elif" => {
+                let value = match conds.last() {
+                    None => return Err(error("#elif without #if".to_owned())),
+                    Some(cond) if cond.else_seen => {
+                        return Err(error("#elif after #else".to_owned()))
+                    }
+                    Some(cond) if cond.taken => false,
+                    Some(_) => self.eval_condition(args).map_err(&error)?,
+                };
+                let cond = conds.last_mut().unwrap();
+                cond.active = value;
+                cond.taken |= value;
+            }
+            "else" => {
+                let cond = match conds.last_mut() {
+                    None => return Err(error("#else without #if".to_owned())),
+                    Some(cond) => cond,
+                };
+                if cond.else_seen {
+                    return Err(error("#else after #else".to_owned()));
+                }
+                cond.active = cond.parent_active && !cond.taken;
+                cond.taken = true;
+                cond.else_seen = true;
+            }
+            "endif" => {
+                if conds.pop().is_none() {
+                    return Err(error("#endif without #if".to_owned()));
+                }
+            }
+            _ if !active => {}
+            "define" => {
+                let (name, mac) = parse_define(&args).map_err(&error)?;
+                self.add_macro(name, mac, path.to_owned(), line_num);
+            }
+            "undef" => {
+                match args.first() {
+                    Some(tok) if tok.kind == TokenKind::Ident => {
+                        self.remove_macro(&tok.text, path.to_owned(), line_num)
+                    }
+                    _ => return Err(error("expected macro name after #undef".to_owned())),
+                }
+            }
+            "include" => {
+                let (file, quoted) = include_name(line, &args, self).map_err(&error)?;
+                let found = if quoted { self.find(&file, path) } else { self.find_system(&file) };
+                let found = found.ok_or_else(|| {
+                        error(format!("could not find included file {}", file))
+                    })?;
+                self.include(&found, IncludeMethod::CPP, path, line_num, out)?;
+            }
+            "error" => {
+                let msg = String::from_utf8_lossy(line).trim().to_owned();
+                return Err(error(msg));
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    // Evaluates the expression of an `#if` or `#elif`
+    fn eval_condition(&self, tokens: Vec<Token>) -> Result<bool, String> {
+        let mut replaced = Vec::new();
+        let mut tokens = tokens.into_iter().peekable();
+        while let Some(tok) = tokens.next() {
+            if tok.kind != TokenKind::Ident || tok.text != "defined" {
+                replaced.push(tok);
+                continue;
+            }
+
+            let paren = tokens.peek().map_or(false, |t| t.is_punct("("));
+            if paren {
+                tokens.next();
+            }
+            let name = match tokens.next() {
+                Some(ref t) if t.kind == TokenKind::Ident => t.text.clone(),
+                _ => return Err("expected macro name after defined".to_owned()),
+            };
+            if paren && !tokens.next().map_or(false, |t| t.is_punct(")")) {
+                return Err("expected ')' after defined(name".to_owned());
+            }
+            let value = if self.is_defined(&name) { b"1" } else { b"0" };
+            replaced.push(Token::new(TokenKind::Number, value, tok.space));
+        }
+
+        // Identifiers left after expansion count as zero
+        let expr = self.expand(replaced)
+            .into_iter()
+            .map(|tok| if tok.kind == TokenKind::Ident { "0".to_owned() } else { tok.text })
+            .collect::<Vec<_>>()
+            .join(" ");
+        if expr.is_empty() {
+            return Err("#if with no expression".to_owned());
+        }
+        eval_c_expr(&expr).map(|v| v != 0).map_err(|e| e.to_owned())
+    }
+
+    // Expands the macro invocation at `pos` in the file, if there is one.
+    // Returns where the invocation ends and the expansion.
+    fn expand_invocation(&self, src: &[u8], pos: usize) -> Option<(usize, Vec<Token>)> {
+        let len = token_len(&src[pos..]).1;
+        let mut tokens = vec![Token::new(TokenKind::Ident, &src[pos..pos + len], false)];
+        let mut end = pos + len;
+
+        if self.macros.get(&tokens[0].text)?.params.is_some() {
+            let (args, args_end) = read_group(src, end)?;
+            tokens.extend(args);
+            end = args_end;
+        }
+
+        loop {
+            let expanded = self.expand(tokens);
+            // A function-like macro at the end may take its arguments from
+            // the text following the invocation
+            let more = match expanded.last() {
+                Some(tok) if tok.kind == TokenKind::Ident && !tok.hide.contains(&tok.text) => {
+                    self.macros.get(&tok.text).map_or(false, |m| m.params.is_some())
+                }
+                _ => false,
+            };
+            match if more { read_group(src, end) } else { None } {
+                Some((args, args_end)) => {
+                    tokens = expanded;
+                    tokens.extend(args);
+                    end = args_end;
+                }
+                None => return Some((end, expanded)),
+            }
+        }
+    }
+
+    // Fully macro expands a list of tokens, following the hide set algorithm
+    // described by Dave Prosser.
+    fn expand(&self, tokens: Vec<Token>) -> Vec<Token> {
+        let mut input: VecDeque<Token> = tokens.into_iter().collect();
+        let mut output = Vec::new();
+
+        while let Some(tok) = input.pop_front() {
+            let mac = match self.macros.get(&tok.text) {
+                Some(mac) if tok.kind == TokenKind::Ident && !tok.hide.contains(&tok.text) => mac,
+                _ => {
+                    output.push(tok);
+                    continue;
+                }
+            };
+
+            let mut hide = tok.hide.clone();
+            let args = if mac.params.is_some() {
+                match collect_args(&mut input) {
+                    Some((args, rparen)) => {
+                        hide.retain(|h| rparen.hide.contains(h));
+                        match_args(mac, args)
+                    }
+                    None => {
+                        output.push(tok);
+                        continue;
+                    }
+                }
+            } else {
+                Vec::new()
+            };
+            hide.push(tok.text.clone());
+
+            for replaced in self.substitute(mac, &args, &hide, tok.space).into_iter().rev() {
+                input.push_front(replaced);
+            }
+        }
+
+        output
+    }
+
+    // Replaces the parameters in the body of a macro with its arguments
+    fn substitute(&self,
+                  mac: &Macro,
+                  args: &[Vec<Token>],
+                  hide: &[String],
+                  space: bool)
+                  -> Vec<Token> {
+        let params: &[String] = mac.params.as_ref().map_or(&[], |p| p);
+        let param = |tok: &Token| if tok.kind == TokenKind::Ident {
+            params.iter().position(|p| *p == tok.text)
+        } else {
+            None
+        };
+
+        let body = &mac.body;
+        let mut out: Vec<Token> = Vec::new();
+        let mut i = 0;
+        while i < body.len() {
+            let tok = &body[i];
+            if tok.is_punct("#") && mac.params.is_some() {
+                if let Some(p) = body.get(i + 1).and_then(|t| param(t)) {
+                    out.push(stringize(&args[p], tok.space));
+                    i += 2;
+                    continue;
+                }
+            }
+
+            if tok.is_punct("##") {
+                if let Some(next) = body.get(i + 1) {
+                    let right = match param(next) {
+                        Some(p) => args[p].clone(),
+                        None => vec![next.clone()],
+                    };
+                    paste(&mut out, right);
+                    i += 2;
+                } else {
+                    i += 1;
+                }
+                continue;
+            }
+
+            match param(tok) {
+                Some(p) => {
+                    // Arguments next to `##` are not expanded first
+                    let mut arg = if body.get(i + 1).map_or(false, |t| t.is_punct("##")) {
+                        if args[p].is_empty() {
+                            vec![Token::new(TokenKind::Placemarker, b"", false)]
+                        } else {
+                            args[p].clone()
+                        }
+                    } else {
+                        self.expand(args[p].clone())
+                    };
+                    if let Some(first) = arg.first_mut() {
+                        first.space = tok.space;
+                    }
+                    out.extend(arg);
+                }
+                None => out.push(tok.clone()),
+            }
+            i += 1;
+        }
+
+        out.retain(|t| t.kind != TokenKind::Placemarker);
+        for tok in &mut out {
+            for h in hide {
+                if !tok.hide.contains(h) {
+                    tok.hide.push(h.clone());
+                }
+            }
+        }
+        if let Some(first) = out.first_mut() {
+            first.space = space;
+        }
+        out
+    }
+}
+
+fn parse_define(tokens: &[Token]) -> Result<(String, Macro), String> {
+    let name = match tokens.first() {
+        Some(tok) if tok.kind == TokenKind::Ident => tok.text.clone(),
+        _ => return Err("expected macro name after #define".to_owned()),
+    };
+
+    let mut params = None;
+    let mut variadic = false;
+    let mut rest = &tokens[1..];
+    // Only a parenthesis directly after the name makes a function-like macro
+    if rest.first().map_or(false, |t| t.is_punct("(") && !t.space) {
+        let mut list = Vec::new();
+        let mut i = 1;
+        loop {
+            match rest.get(i) {
+                Some(tok) if tok.is_punct(")") && list.is_empty() => break,
+                Some(tok) if tok.is_punct("...") && !variadic => {
+                    list.push("__VA_ARGS__".to_owned());
+                    variadic = true;
+                }
+                Some(tok) if tok.kind == TokenKind::Ident && !variadic => {
+                    list.push(tok.text.clone());
+                    // GNU named variadic parameter
+                    if rest.get(i + 1).map_or(false, |t| t.is_punct("...")) {
+                        variadic = true;
+                        i += 1;
+                    }
+                }
+                _ => return Err(format!("invalid parameter list for macro {}", name)),
+            }
+            i += 1;
+            match rest.get(i) {
+                Some(tok) if tok.is_punct(")") => break,
+                Some(tok) if tok.is_punct(",") && !variadic => i += 1,
+                _ => return Err(format!("invalid parameter list for macro {}", name)),
+            }
+        }
+        params = Some(list);
+        rest = &rest[i + 1..];
+    }
+
+    Ok((name,
+        Macro {
+            params: params,
+            variadic: variadic,
+            body: rest.to_vec(),
+        }))
+}
+
+// The file named by an `#include`, and whether it was quoted rather than in
+// angle brackets. Anything else is macro expanded first.
+fn include_name(line: &[u8], args: &[Token], cpp: &Preprocessor) -> Result<(String, bool), String> {
+    let start = space_len(line, false);
+    let rest = &line[start + token_len(&line[start..]).1..];
+    let rest = &rest[space_len(rest, false)..];
+
+    let delimited = |rest: &[u8], close: u8| {
+        rest[1..].iter()
+            .position(|&c| c == close)
+            .map(|end| String::from_utf8_lossy(&rest[1..end + 1]).into_owned())
+    };
+    match rest.first() {
+        Some(&b'"') => delimited(rest, b'"').map(|n| (n, true)),
+        Some(&b'<') => delimited(rest, b'>').map(|n| (n, false)),
+        _ => {
+            let expanded = render(&cpp.expand(args.to_vec()));
+            let bytes = expanded.as_bytes();
+            match bytes.first() {
+                Some(&b'"') => delimited(bytes, b'"').map(|n| (n, true)),
+                Some(&b'<') => {
+                    delimited(bytes, b'>').map(|n| (n.replace(' ', ""), false))
+                }
+                _ => None,
+            }
+        }
+    }
+    .ok_or_else(|| "expected \"file\" or <file> after #include".to_owned())
+}
+
+// Parses `/include/ "file"`, returning the file name and the length of the
+// statement
+fn dts_include(input: &[u8]) -> Option<(String, usize)> {
+    let start = 9 + space_len(&input[9..], true);
+    if input.get(start) != Some(&b'"') {
+        return None;
+    }
+    let len = quoted_len(&input[start..])?;
+    Some((String::from_utf8_lossy(&input[start + 1..start + len - 1]).into_owned(), start + len))
+}
+
+// Takes the arguments of a function-like macro from the front of `input`,
+// along with the closing parenthesis. Nothing is taken if the arguments are
+// missing or unterminated.
+fn collect_args(input: &mut VecDeque<Token>) -> Option<(Vec<Vec<Token>>, Token)> {
+    if !input.front().map_or(false, |t| t.is_punct("(")) {
+        return None;
+    }
+
+    let mut depth = 0;
+    let close = input.iter().position(|tok| {
+        if tok.is_punct("(") {
+            depth += 1;
+        } else if tok.is_punct(")") {
+            depth -= 1;
+        }
+        depth == 0
+    })?;
+
+    let mut group: Vec<Token> = input.drain(..close + 1).collect();
+    let rparen = group.pop().unwrap();
+    let mut args = vec![Vec::new()];
+    let mut depth = 0;
+    for tok in group.into_iter().skip(1) {
+        if tok.is_punct(",") && depth == 0 {
+            args.push(Vec::new());
+            continue;
+        }
+        if tok.is_punct("(") {
+            depth += 1;
+        } else if tok.is_punct(")") {
+            depth -= 1;
+        }
+        args.last_mut().unwrap().push(tok);
+    }
+
+    Some((args, rparen))
+}
+
+// Lines up arguments with the parameters of a macro, joining any extra
+// arguments of a variadic macro into the last
+fn match_args(mac: &Macro, mut args: Vec<Vec<Token>>) -> Vec<Vec<Token>> {
+    let count = mac.params.as_ref().map_or(0, |p| p.len());
+    if count == 0 {
+        return Vec::new();
+    }
+    if args.len() > count {
+        let extra: Vec<Vec<Token>> = args.drain(count..).collect();
+        if mac.variadic {
+            let last = args.last_mut().unwrap();
+            for arg in extra {
+                last.push(Token::new(TokenKind::Punct, b",", false));
+                last.extend(arg);
+            }
+        }
+    }
+    while args.len() < count {
+        args.push(Vec::new());
+    }
+    args
+}
+
+fn stringize(arg: &[Token], space: bool) -> Token {
+    let mut text = String::from("\"");
+    for (i, tok) in arg.iter().enumerate() {
+        if i > 0 && tok.space {
+            text.push(' ');
+        }
+        match tok.kind {
+            TokenKind::Str | TokenKind::Char => {
+                for c in tok.text.chars() {
+                    if c == '"' || c == '\\' {
+                        text.push('\\');
+                    }
+                    text.push(c);
+                }
+            }
+            _ => text.push_str(&tok.text),
+        }
+    }
+    text.push('"');
+    Token::new(TokenKind::Str, text.as_bytes(), space)
+}
+
+fn paste(out: &mut Vec<Token>, right: Vec<Token>) {
+    let mut right = right.into_iter();
+    let first = match right.next() {
+        Some(first) => first,
+        None => Token::new(TokenKind::Placemarker, b"", false),
+    };
+    match out.pop() {
+        Some(left) => {
+            let text = left.text + &first.text;
+            let kind = match token_len(text.as_bytes()) {
+                _ if text.is_empty() => TokenKind::Placemarker,
+                (kind, len) if len == text.len() => kind,
+                _ => TokenKind::Other,
+            };
+            out.push(Token {
+                kind: kind,
+                text: text,
+                space: left.space,
+                hide: left.hide,
+            });
+        }
+        None => out.push(first),
+    }
+    out.extend(right);
+}
+
+// Turns tokens back into text, keeping apart tokens that would otherwise run
+// together
+fn render(tokens: &[Token]) -> String {
+    let mut text = String::new();
+    let mut prev: Option<&Token> = None;
+    for tok in tokens {
+        if let Some(prev) = prev {
+            let joined = format!("{}{}", prev.text, tok.text);
+            if tok.space || token_len(joined.as_bytes()).1 > prev.text.len() {
+                text.push(' ');
+            }
+        }
+        text.push_str(&tok.text);
+        prev = Some(tok);
+    }
+    text
+}
+
+fn is_directive(tokens: &[Token]) -> bool {
+    match tokens.first() {
+        None => true,
+        Some(tok) if tok.kind == TokenKind::Number => true,
+        Some(tok) => tok.kind == TokenKind::Ident && DIRECTIVES.contains(&&*tok.text),
+    }
+}
+
+// Reads the parenthesized arguments of a function-like macro invocation from
+// the file, which may span lines
+fn read_group(src: &[u8], pos: usize) -> Option<(Vec<Token>, usize)> {
+    let mut pos = pos + space_len(&src[pos..], true);
+    if src.get(pos) != Some(&b'(') {
+        return None;
+    }
+
+    let mut tokens = Vec::new();
+    let mut depth = 0;
+    let mut space = false;
+    while pos < src.len() {
+        let skip = space_len(&src[pos..], true);
+        if skip > 0 {
+            pos += skip;
+            space = true;
+            continue;
+        }
+        let (kind, len) = token_len(&src[pos..]);
+        let tok = Token::new(kind, &src[pos..pos + len], space);
+        pos += len;
+        space = false;
+        if tok.is_punct("(") {
+            depth += 1;
+        } else if tok.is_punct(")") {
+            depth -= 1;
+        }
+        tokens.push(tok);
+        if depth == 0 {
+            return Some((tokens, pos));
+        }
+    }
+
+    None
+}
+
+// Splits a single line, from `start` to `end`, into tokens
+fn line_tokens(src: &[u8], start: usize, end: usize) -> Vec<Token> {
+    let mut tokens = Vec::new();
+    let mut pos = start;
+    while pos < end {
+        let space = space_len(&src[pos..end], false);
+        pos += space;
+        if pos >= end {
+            break;
+        }
+        let (kind, len) = token_len(&src[pos..end]);
+        tokens.push(Token::new(kind, &src[pos..pos + len], space > 0));
+        pos += len;
+    }
+    tokens
+}
+
+// Finds the newline ending the logical line starting at `pos`, following
+// line continuations and comments
+fn logical_line_end(src: &[u8], pos: usize) -> usize {
+    let mut pos = pos;
+    while pos < src.len() {
+        let rest = &src[pos..];
+        if newline_len(rest) > 0 {
+            return pos;
+        }
+        let skip = space_len(rest, false);
+        if skip > 0 {
+            pos += skip;
+        } else {
+            pos += token_len(rest).1;
+        }
+    }
+    src.len()
+}
+
+fn line_of(src: &[u8], pos: usize) -> usize {
+    src[..pos].iter().filter(|&&c| c == b'\n').count() + 1
+}
+
+fn newline_len(input: &[u8]) -> usize {
+    if input.starts_with(b"\r\n") {
+        2
+    } else if input.starts_with(b"\n") {
+        1
+    } else {
+        0
+    }
+}
+
+// Whitespace, comments and line continuations. Newlines are only included if
+// `newlines` is set.
+fn space_len(input: &[u8], newlines: bool) -> usize {
+    let mut len = 0;
+    loop {
+        let rest = &input[len..];
+        let skip = match rest.first() {
+            Some(&b' ') | Some(&b'\t') | Some(&b'\x0b') | Some(&b'\x0c') => 1,
+            Some(&b'\r') if !rest.starts_with(b"\r\n") => 1,
+            Some(&b'\r') | Some(&b'\n') if newlines => newline_len(rest),
+            Some(&b'\\') if newline_len(&rest[1..]) > 0 => 1 + newline_len(&rest[1..]),
+            Some(&b'/') if rest.starts_with(b"/*") => {
+                rest[2..].windows(2)
+                    .position(|w| w == b"*/")
+                    .map_or(rest.len(), |end| end + 4)
+            }
+            Some(&b'/') if rest.starts_with(b"//") => {
+                rest.iter().position(|&c| c == b'\n' || c == b'\r').unwrap_or(rest.len())
+            }
+            _ => 0,
+        };
+        if skip == 0 {
+            return len;
+        }
+        len += skip;
+    }
+}
+
+// The length of a string or character literal, if it is closed on the same
+// line
+fn quoted_len(input: &[u8]) -> Option<usize> {
+    let quote = input[0];
+    let mut i = 1;
+    while i < input.len() {
+        match input[i] {
+            b'\\' => i += 2,
+            b'\n' => return None,
+            c if c == quote => return Some(i + 1),
+            _ => i += 1,
+        }
+    }
+    None
+}
+
+const PUNCTUATORS: &[&[u8]] = &[b"...", b"<<=", b">>=", b"##", b"<<", b">>", b"<=", b">=",
+                                b"==", b"!=", b"&&", b"||", b"->", b"++", b"--"];
+
+fn is_ident_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+// The kind and length of the token at the start of `input`, which must not
+// start with whitespace or a comment
+fn token_len(input: &[u8]) -> (TokenKind, usize) {
+    fn run<F: Fn(usize) -> bool>(input: &[u8], pred: F) -> usize {
+        (1..input.len()).find(|&i| !pred(i)).unwrap_or(input.len())
+    }
+
+    let c = input[0];
+
+    if c.is_ascii_alphabetic() || c == b'_' {
+        (TokenKind::Ident, run(input, |i| is_ident_char(input[i])))
+    } else if c.is_ascii_digit() ||
+              (c == b'.' && input.get(1).map_or(false, |d| d.is_ascii_digit())) {
+        // A pp-number, which covers every kind of C number
+        let len = run(input, |i| {
+            is_ident_char(input[i]) || input[i] == b'.' ||
+            ((input[i] == b'+' || input[i] == b'-') &&
+             b"eEpP".contains(&input[i - 1]))
+        });
+        (TokenKind::Number, len)
+    } else if c == b'"' || c == b'\'' {
+        // Unterminated quotes are allowed in assembler-with-cpp mode
+        match quoted_len(input) {
+            Some(len) if c == b'"' => (TokenKind::Str, len),
+            Some(len) => (TokenKind::Char, len),
+            None => (TokenKind::Punct, 1),
+        }
+    } else if c >= 0x80 {
+        (TokenKind::Other, run(input, |i| input[i] & 0xC0 == 0x80))
+    } else {
+        let len = PUNCTUATORS.iter()
+            .find(|p| input.starts_with(p))
+            .map_or(1, |p| p.len());
+        (TokenKind::Punct, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use include::get_bounds_containing_offset;
+    use std::fs;
+    use std::io::Write;
+    use mktemp::Temp;
+
+    fn write(dir: &Path, name: &str, contents: &str) -> PathBuf {
+        let path = dir.join(name);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).unwrap();
+        }
+        File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+        path
+    }
+
+    fn run(cpp: &mut Preprocessor, file: &Path) -> String {
+        let (buffer, _) = cpp.preprocess(file).unwrap();
+        String::from_utf8(buffer).unwrap()
+    }
+
+    #[test]
+    fn macros() {
+        let dir = Temp::new_dir().unwrap();
+        let file = write(dir.as_ref(),
+                         "test.dts",
+                         "#define IRQ 37\n\
+                          #define PAIR(a, b) a b\n\
+                          #define STR(x) #x\n\
+                          #define CAT(a, b) a ## b\n\
+                          #define SELF SELF + 1\n\
+                          #define VA(fmt, ...) fmt: __VA_ARGS__\n\
+                          #address-cells = <1>;\n\
+                          a = <IRQ>;\n\
+                          b = <PAIR(IRQ, (2))>;\n\
+                          c = STR(x  \"y\");\n\
+                          d = <CAT(0x, 10)>;\n\
+                          e = <SELF>;\n\
+                          f = VA(x, 1, 2);\n\
+                          g = \"IRQ\"; // IRQ\n");
+        let mut cpp = Preprocessor::new::<&str>(&[]);
+        assert_eq!(run(&mut cpp, &file),
+                   "\n\n\n\n\n\n\
+                    #address-cells = <1>;\n\
+                    a = <37>;\n\
+                    b = <37 (2)>;\n\
+                    c = \"x \\\"y\\\"\";\n\
+                    d = <0x10>;\n\
+                    e = <SELF + 1>;\n\
+                    f = x: 1, 2;\n\
+                    g = \"IRQ\"; // IRQ\n");
+        assert!(cpp.is_defined("PAIR"));
+    }
+
+    #[test]
+    fn conditionals() {
+        let dir = Temp::new_dir().unwrap();
+        let file = write(dir.as_ref(),
+                         "test.dts",
+                         "#define A 2\n\
+                          #if defined(A) && A > 1\n\
+                          yes1\n\
+                          #else\n\
+                          no1\n\
+                          #endif\n\
+                          #ifdef B\n\
+                          no2\n\
+                          #elif !defined B && (A * 3 == 6)\n\
+                          yes2\n\
+                          #elif 1\n\
+                          no3\n\
+                          #endif\n\
+                          #if 0\n\
+                          #if 1\n\
+                          no4\n\
+                          #else\n\
+                          no5\n\
+                          #endif\n\
+                          #error not reached\n\
+                          #endif\n\
+                          #ifndef __DTS__\n\
+                          no6\n\
+                          #endif\n");
+        let mut cpp = Preprocessor::new::<&str>(&[]);
+        cpp.define("__DTS__", "1");
+        assert_eq!(run(&mut cpp, &file).split_whitespace().collect::<Vec<_>>(),
+                   vec!["yes1", "yes2"]);
+    }
+
+    #[test]
+    fn signed_conditions() {
+        let dir = Temp::new_dir().unwrap();
+        let file = write(dir.as_ref(),
+                         "test.dts",
+                         "#define X 0\n\
+                          #if -1 < 0\n\
+                          yes1\n\
+                          #endif\n\
+                          #if X - 1 >= 0\n\
+                          no1\n\
+                          #else\n\
+                          yes2\n\
+                          #endif\n\
+                          #if (X - 3) / 2 == -1 && -4 >> 1 == -2\n\
+                          yes3\n\
+                          #endif\n");
+        let mut cpp = Preprocessor::new::<&str>(&[]);
+        assert_eq!(run(&mut cpp, &file).split_whitespace().collect::<Vec<_>>(),
+                   vec!["yes1", "yes2", "yes3"]);
+    }
+
+    #[test]
+    fn includes() {
+        let dir = Temp::new_dir().unwrap();
+        let inc = dir.as_ref().join("include");
+        write(&inc, "dt-bindings/irq.h", "#define IRQ 5\n");
+        write(dir.as_ref(), "local.h", "#define LOCAL 6\n");
+        write(dir.as_ref(), "raw.dtsi", "#define NOT_A_MACRO\nraw = <IRQ>;\n");
+        let file = write(dir.as_ref(),
+                         "test.dts",
+                         "#include <dt-bindings/irq.h>\n\
+                          #include \"local.h\"\n\
+                          /include/ \"raw.dtsi\"\n\
+                          a = <IRQ LOCAL>;\n");
+
+        let mut cpp = Preprocessor::new(&[&inc]);
+        let (buffer, bounds) = cpp.preprocess(&file).unwrap();
+        assert_eq!(String::from_utf8(buffer.clone()).unwrap(),
+                   "\n\n\n\n#define NOT_A_MACRO\nraw = <IRQ>;\n\na = <5 6>;\n");
+
+        let mut files: Vec<_> = bounds.iter()
+            .map(|b| b.child_path().file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        files.dedup();
+        assert_eq!(files[..5], ["test.dts", "irq.h", "test.dts", "local.h", "test.dts"]);
+        let raw = bounds.iter().find(|b| b.include_method() == &IncludeMethod::DTS).unwrap();
+        assert_eq!(raw.child_path(), &*dir.as_ref().join("raw.dtsi"));
+
+        // Positions map back exactly, with expansions at their invocation
+        let offset = buffer.windows(1).rposition(|w| w == b"6").unwrap();
+        let bound = get_bounds_containing_offset(&bounds, offset).unwrap();
+        assert_eq!(bound.file_line_from_global(&buffer, offset).unwrap(), (4, 10));
+        assert_eq!(bound.expanded_from(), Some((78, 83)));
+
+        let missing = write(dir.as_ref(), "missing.dts", "\n#include <none.h>\n");
+        match cpp.preprocess(&missing) {
+            Err(CppError::Syntax(_, 2, _)) => {}
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn macro_definitions() {
+        let dir = Temp::new_dir().unwrap();
+        let header = write(dir.as_ref(),
+                           "irq.h",
+                           "#define IRQ_TYPE 4\n\
+                            #define IRQ(n, ...) 0 n IRQ_TYPE __VA_ARGS__\n");
+        let file = write(dir.as_ref(),
+                         "test.dts",
+                         "#include \"irq.h\"\n\
+                          #define IRQ_TYPE 8\n\
+                          #define GONE\n\
+                          #undef GONE\n");
+
+        let mut cpp = Preprocessor::new::<&str>(&[]);
+        cpp.define("__DTS__", "1");
+        cpp.preprocess(&file).unwrap();
+
+        let names: Vec<_> = cpp.definitions().iter().map(|d| d.name()).collect();
+        assert_eq!(names, vec!["IRQ", "IRQ_TYPE", "__DTS__"]);
+
+        let irq = cpp.definition("IRQ").unwrap();
+        assert_eq!(irq.to_string(), "#define IRQ(n, ...) 0 n IRQ_TYPE __VA_ARGS__");
+        assert_eq!((irq.path(), irq.line()), (&*header, 2));
+
+        let history = cpp.history("IRQ_TYPE");
+        assert_eq!(history.len(), 2);
+        assert_eq!((history[0].path(), history[0].line(), history[0].value()), (&*header, 1, "4"));
+        assert_eq!((history[1].path(), history[1].line(), history[1].value()), (&*file, 2, "8"));
+        assert_eq!(cpp.definition("IRQ_TYPE"), history.last());
+
+        assert_eq!(cpp.definition("GONE"), None);
+        assert_eq!(cpp.history("GONE").len(), 1);
+        assert_eq!(cpp.history("GONE")[0].undefined(), Some((&*file, 4)));
+        assert_eq!(history[0].undefined(), None);
+        assert_eq!(cpp.definition("__DTS__").unwrap().path(), Path::new("<command-line>"));
+        assert_eq!(cpp.expand_text("IRQ(5, 6)"), "0 5 8 6");
+    }
+
+    #[test]
+    fn excluded_regions() {
+        let dir = Temp::new_dir().unwrap();
+        let file = write(dir.as_ref(),
+                         "test.dts",
+                         "/ {\n\
+                          #ifdef CONFIG_A\n\
+                          \ta: uart@1 {\n\
+                          \t\tstatus = \"okay\";\n\
+                          \t};\n\
+                          #else\n\
+                          \tother;\n\
+                          #endif\n\
+                          #if 0\n\
+                          #if 1\n\
+                          \tnested { };\n\
+                          #endif\n\
+                          #endif\n\
+                          #ifdef __DTS__\n\
+                          \tkept;\n\
+                          #elif X\n\
+                          \tgone = <1>;\n\
+                          #endif\n\
+                          };\n\
+                          #ifndef __DTS__\n\
+                          /delete-node/ &a;\n\
+                          &a { x = \"a;b\"; };\n\
+                          #endif\n");
+
+        let mut cpp = Preprocessor::new::<&str>(&[]);
+        cpp.define("__DTS__", "1");
+        let (buffer, _) = cpp.preprocess(&file).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(),
+                   "/ {\n\n\tother;\n\n\n\n\tkept;\n\n};\n\n");
+
+        let excluded = cpp.excluded();
+        let summary: Vec<_> = excluded.iter()
+            .map(|r| (r.lines(), r.condition(), r.definitions()))
+            .collect();
+        let defs = |d: &[(&str, usize)]| -> Vec<(String, usize)> {
+            d.iter().map(|&(n, l)| (n.to_owned(), l)).collect()
+        };
+        assert_eq!(summary,
+                   vec![((3, 5), "#ifdef CONFIG_A", defs(&[("uart@1", 3), ("uart@1/status", 4)])),
+                        ((10, 12), "#if 0", defs(&[("nested", 11)])),
+                        ((17, 17), "#elif X", defs(&[("gone", 17)])),
+                        ((21, 22), "#ifndef __DTS__", defs(&[("&a", 22), ("&a/x", 22)]))]);
+        assert_eq!(excluded[0].offset(), 4);
+        assert_eq!(excluded[0].path(), &*file);
+
+        cpp.define("CONFIG_A", "");
+        cpp.preprocess(&file).unwrap();
+        assert_eq!(cpp.excluded()[0].condition(), "#else of #ifdef CONFIG_A");
+        assert_eq!(cpp.excluded()[0].lines(), (7, 7));
+    }
+
+    #[test]
+    fn forced_includes() {
+        let dir = Temp::new_dir().unwrap();
+        let inc = dir.as_ref().join("include");
+        write(&inc, "config.h", "#define CONFIG_A 1\n");
+        let forced = write(dir.as_ref(), "forced.h", "#define IRQ 5\n");
+        let file = write(dir.as_ref(), "test.dts", "#ifdef CONFIG_A\na = <IRQ>;\n#endif\n");
+
+        let mut cpp = Preprocessor::new(&[&inc]);
+        cpp.force_include(&forced);
+        cpp.force_include("config.h");
+        let (buffer, bounds) = cpp.preprocess(&file).unwrap();
+        assert_eq!(String::from_utf8(buffer).unwrap(), "\n\n\na = <5>;\n\n");
+        assert_eq!(bounds[0].child_path(), &*file);
+        assert_eq!(bounds[1].child_path(), &*forced);
+
+        cpp.force_include("missing.h");
+        match cpp.preprocess(&file) {
+            Err(CppError::IOError(_, Some(path))) => assert_eq!(path, Path::new("missing.h")),
+            e => panic!("{:?}", e),
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let dir = Temp::new_dir().unwrap();
+        let mut cpp = Preprocessor::new::<&str>(&[]);
+        let cases = [("#if 1\n", 1), ("\n#error stop\n", 2), ("#endif\n", 1),
+                     ("#if 1\n#else\n#else\n#endif\n", 3), ("#if 1 +\n#endif\n", 1),
+                     ("#define\n", 1)];
+        for &(source, line) in &cases {
+            let file = write(dir.as_ref(), "test.dts", source);
+            match cpp.preprocess(&file) {
+                Err(CppError::Syntax(_, l, _)) => assert_eq!(l, line, "{}", source),
+                e => panic!("{}: {:?}", source, e),
+            }
+        }
+    }
+}
@@ -0,0 +1,1127 @@
+//! A lossless concrete syntax tree for Device Tree Source files.
+//!
+//! `parser::parse_dt` skips comments, whitespace and linemarkers as junk, so
+//! the tree it returns cannot be used to reproduce or rewrite the source it
+//! came from. The concrete syntax tree returned by `parse` keeps every byte of
+//! the input. Each `Token` holds the text it was made from along with the
+//! `Trivia` (whitespace, comments, linemarkers and preprocessor directives)
+//! before and after it, so printing a `SourceFile` gives back the original
+//! source byte-for-byte.
+//!
+//! Trivia before a token on earlier lines is leading trivia of that token,
+//! while trivia after a token up to and including the end of its line is
+//! trailing trivia. A comment above a property is therefore attached to the
+//! property, and a comment after it on the same line stays with it as well.
+//!
+//! Parsing never fails. Anything that is not understood is wrapped in a
+//! `SyntaxKind::Error` node so that it is still printed. The semantic tree
+//! made of `tree::DTInfo` and `tree::Node` can be derived from a concrete
+//! syntax tree, edited or not, with `SourceFile::to_tree` or
+//! `SourceFile::to_overlay`.
+
+use std::fmt;
+use std::mem;
+
+use tree::{DTInfo, Overlay, Node, Span, Spanned};
+use parser::{SyntaxError, parse_dt_recover, parse_overlay_recover};
+
+/// The kinds of text that is kept between tokens.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TriviaKind {
+    /// Spaces, tabs and line endings.
+    Whitespace,
+    /// A `/* */` block comment or a `//` line comment.
+    Comment,
+    /// A linemarker left by the C preprocessor, `# 12 "file.dtsi"`.
+    Linemarker,
+    /// A C preprocessor directive such as `#include` or `#define`, including
+    /// any continuation lines.
+    Directive,
+}
+
+/// Text between tokens that has no meaning to the device tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trivia {
+    /// What kind of trivia this is.
+    pub kind: TriviaKind,
+    /// The exact text of the trivia.
+    pub text: String,
+    /// Where the trivia was found in the source. Trivia that was added after
+    /// parsing should use `Span::default()`.
+    pub span: Span,
+}
+
+impl fmt::Display for Trivia {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+impl Spanned for Trivia {
+    fn get_span(&self) -> Span {
+        self.span
+    }
+}
+
+/// The kinds of tokens that make up a concrete syntax tree.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    /// A keyword surrounded by slashes, such as `/dts-v1/` or `/delete-node/`.
+    Keyword,
+    /// The name of a node or property, or `/` for the root node.
+    Name,
+    /// A label definition including the trailing colon, `label:`.
+    Label,
+    /// A reference to a label, `&label`, or a path, `&{/path}`.
+    Reference,
+    /// A double quoted string including the quotes.
+    String,
+    /// A single quoted character literal including the quotes.
+    Char,
+    /// An integer literal, or a run of hex digits in a byte string.
+    Number,
+    /// An identifier within data or an expression, often a C preprocessor
+    /// macro that was never expanded.
+    Ident,
+    /// An operator within an expression.
+    Operator,
+    /// One of `{`, `}`, `;`, `=`, `,`, `<`, `>`, `[`, `]`, `(` or `)`.
+    Punct,
+    /// A character that is not part of the Device Tree Source syntax.
+    Unknown,
+}
+
+/// A single token with the trivia around it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    /// What kind of token this is.
+    pub kind: TokenKind,
+    /// The exact text of the token.
+    pub text: String,
+    /// Trivia found before the token.
+    pub leading: Vec<Trivia>,
+    /// Trivia found after the token up to and including the end of the line.
+    pub trailing: Vec<Trivia>,
+    /// Where the token, without its trivia, was found in the source. Tokens
+    /// that were added after parsing should use `Span::default()`.
+    pub span: Span,
+}
+
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for trivia in &self.leading {
+            write!(f, "{}", trivia)?;
+        }
+        write!(f, "{}", self.text)?;
+        for trivia in &self.trailing {
+            write!(f, "{}", trivia)?;
+        }
+        Ok(())
+    }
+}
+
+impl Spanned for Token {
+    fn get_span(&self) -> Span {
+        self.span
+    }
+}
+
+/// The kinds of syntax nodes that tokens are grouped into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum SyntaxKind {
+    /// The `/dts-v1/;` header.
+    Header,
+    /// The `/plugin/;` marker of an overlay.
+    Plugin,
+    /// A `/memreserve/ address length;` entry.
+    MemReserve,
+    /// A `/include/ "file"` statement.
+    Include,
+    /// A node with a body. Nodes after the root node that amend a label or
+    /// the root node are also of this kind.
+    Node,
+    /// A `/delete-node/ name;` statement.
+    DeleteNode,
+    /// A `/delete-property/ name;` statement.
+    DeleteProperty,
+    /// A `/omit-if-no-ref/ &label;` statement.
+    OmitIfNoRef,
+    /// A property with or without a value.
+    Property,
+    /// A list of cells, `<...>`, along with any `/bits/` before it.
+    Cells,
+    /// A byte string, `[...]`.
+    Bytes,
+    /// An `/incbin/(...)` statement.
+    Incbin,
+    /// A parenthesized expression or a macro call.
+    Expr,
+    /// Input that could not be parsed. Still holds all of its tokens.
+    Error,
+}
+
+/// Either a syntax node or a token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyntaxElement {
+    /// A nested syntax node.
+    Node(SyntaxNode),
+    /// A token.
+    Token(Token),
+}
+
+impl fmt::Display for SyntaxElement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SyntaxElement::Node(ref node) => write!(f, "{}", node),
+            SyntaxElement::Token(ref token) => write!(f, "{}", token),
+        }
+    }
+}
+
+/// A group of tokens and nested syntax nodes, in the order they appeared in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxNode {
+    /// What the group of tokens is.
+    pub kind: SyntaxKind,
+    /// The tokens and nested syntax nodes of this node.
+    pub children: Vec<SyntaxElement>,
+}
+
+impl SyntaxNode {
+    /// Returns all tokens within the node in source order.
+    pub fn tokens(&self) -> Vec<&Token> {
+        fn collect<'a>(node: &'a SyntaxNode, tokens: &mut Vec<&'a Token>) {
+            for child in &node.children {
+                match *child {
+                    SyntaxElement::Node(ref node) => collect(node, tokens),
+                    SyntaxElement::Token(ref token) => tokens.push(token),
+                }
+            }
+        }
+
+        let mut tokens = Vec::new();
+        collect(self, &mut tokens);
+        tokens
+    }
+
+    /// Returns the first token within the node.
+    pub fn first_token(&self) -> Option<&Token> {
+        self.children.iter().filter_map(|child| match *child {
+            SyntaxElement::Node(ref node) => node.first_token(),
+            SyntaxElement::Token(ref token) => Some(token),
+        }).next()
+    }
+
+    /// Returns the last token within the node.
+    pub fn last_token(&self) -> Option<&Token> {
+        self.children.iter().rev().filter_map(|child| match *child {
+            SyntaxElement::Node(ref node) => node.last_token(),
+            SyntaxElement::Token(ref token) => Some(token),
+        }).next()
+    }
+
+    /// Returns the trivia before the node, such as the comments above it.
+    pub fn leading_trivia(&self) -> &[Trivia] {
+        self.first_token().map_or(&[], |token| &token.leading)
+    }
+
+    /// Returns the trivia after the node up to the end of its last line.
+    pub fn trailing_trivia(&self) -> &[Trivia] {
+        self.last_token().map_or(&[], |token| &token.trailing)
+    }
+
+    /// Returns the token naming a node, property, or the target of a delete or
+    /// omit statement. The name of the root node is `/` and amendments are
+    /// named by a `TokenKind::Reference`.
+    pub fn name(&self) -> Option<&Token> {
+        self.direct_tokens()
+            .into_iter()
+            .find(|token| token.kind == TokenKind::Name || token.kind == TokenKind::Reference)
+    }
+
+    /// Returns the labels defined directly on this node.
+    pub fn labels(&self) -> Vec<&Token> {
+        self.direct_tokens()
+            .into_iter()
+            .filter(|token| token.kind == TokenKind::Label)
+            .collect()
+    }
+
+    /// Returns the syntax nodes directly within this node. For a
+    /// `SyntaxKind::Node` these are its properties, child nodes and delete
+    /// statements.
+    pub fn child_nodes(&self) -> Vec<&SyntaxNode> {
+        self.children.iter().filter_map(|child| match *child {
+            SyntaxElement::Node(ref node) => Some(node),
+            SyntaxElement::Token(_) => None,
+        }).collect()
+    }
+
+    fn direct_tokens(&self) -> Vec<&Token> {
+        self.children.iter().filter_map(|child| match *child {
+            SyntaxElement::Node(_) => None,
+            SyntaxElement::Token(ref token) => Some(token),
+        }).collect()
+    }
+}
+
+impl Spanned for SyntaxNode {
+    /// Returns the span from the start of the first token to the end of the
+    /// last, not including trivia.
+    fn get_span(&self) -> Span {
+        match (self.first_token(), self.last_token()) {
+            (Some(first), Some(last)) => Span::new(first.span.start, last.span.end),
+            _ => Span::default(),
+        }
+    }
+}
+
+impl fmt::Display for SyntaxNode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for child in &self.children {
+            write!(f, "{}", child)?;
+        }
+        Ok(())
+    }
+}
+
+/// A whole source file as a lossless concrete syntax tree. Printing it with
+/// `Display` gives back the exact text it was parsed from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceFile {
+    /// The top level statements in source order.
+    pub items: Vec<SyntaxNode>,
+    /// Trivia after the last token.
+    pub trailing: Vec<Trivia>,
+}
+
+impl SourceFile {
+    /// Returns `true` if the file is an overlay, marked with `/plugin/;`.
+    pub fn is_overlay(&self) -> bool {
+        self.items.iter().any(|item| item.kind == SyntaxKind::Plugin)
+    }
+
+    /// Returns `true` if any part of the file could not be parsed.
+    pub fn has_errors(&self) -> bool {
+        fn has_errors(node: &SyntaxNode) -> bool {
+            node.kind == SyntaxKind::Error || node.child_nodes().into_iter().any(has_errors)
+        }
+
+        self.items.iter().any(has_errors)
+    }
+
+    /// Derives the device tree and the amendments following it from the
+    /// current text of the file, see `parser::parse_dt_recover`. If the
+    /// concrete syntax tree has not been edited, the spans of the returned
+    /// tree line up with the spans of its tokens.
+    pub fn to_tree(&self) -> (DTInfo, Vec<Node>, Vec<SyntaxError>) {
+        parse_dt_recover(self.to_string().as_bytes())
+    }
+
+    /// Derives an overlay from the current text of the file, see
+    /// `parser::parse_overlay_recover`.
+    pub fn to_overlay(&self) -> (Overlay, Vec<SyntaxError>) {
+        parse_overlay_recover(self.to_string().as_bytes())
+    }
+}
+
+impl fmt::Display for SourceFile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for item in &self.items {
+            write!(f, "{}", item)?;
+        }
+        for trivia in &self.trailing {
+            write!(f, "{}", trivia)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses the source into a lossless concrete syntax tree.
+///
+/// Both plain and preprocessed sources are accepted. Linemarkers and any
+/// preprocessor directives at the start of a line are kept as trivia and
+/// macros that were not expanded are kept as `TokenKind::Ident` tokens.
+///
+/// # Example
+/// ```rust
+/// use device_tree_source::cst::parse;
+///
+/// let source = "/dts-v1/;\n/ {\n\t// The model\n\tmodel = \"foo\"; /* board */\n};\n";
+/// let file = parse(source);
+///
+/// assert_eq!(file.to_string(), source);
+/// assert!(!file.has_errors());
+/// ```
+pub fn parse(source: &str) -> SourceFile {
+    let mut parser = CstParser::new(source);
+    let mut items = Vec::new();
+
+    while parser.peek().is_some() {
+        items.push(parser.top_item());
+    }
+
+    SourceFile {
+        items: items,
+        trailing: parser.pending,
+    }
+}
+
+const DIRECTIVES: &[&str] = &["define", "undef", "include", "if", "ifdef", "ifndef", "elif",
+                              "else", "endif", "error", "warning", "pragma"];
+
+fn is_prop_node_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b',' || c == b'.' || c == b'_' || c == b'+' ||
+    c == b'*' || c == b'#' || c == b'?' || c == b'@' || c == b'-'
+}
+
+fn is_path_char(c: u8) -> bool {
+    is_prop_node_char(c) || c == b'/'
+}
+
+fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+fn is_ident_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+fn run_len<F: Fn(u8) -> bool>(input: &[u8], f: F) -> usize {
+    input.iter().take_while(|&&c| f(c)).count()
+}
+
+fn line_len(input: &[u8]) -> usize {
+    run_len(input, |c| c != b'\n' && c != b'\r')
+}
+
+fn newline_len(input: &[u8]) -> usize {
+    if input.starts_with(b"\r\n") {
+        2
+    } else if input.starts_with(b"\n") || input.starts_with(b"\r") {
+        1
+    } else {
+        0
+    }
+}
+
+fn block_comment_len(input: &[u8]) -> usize {
+    input[2..].windows(2)
+              .position(|w| w == b"*/")
+              .map_or(input.len(), |pos| pos + 4)
+}
+
+fn ident_len(input: &[u8]) -> usize {
+    match input.first() {
+        Some(&c) if is_ident_start(c) => run_len(input, is_ident_char),
+        _ => 0,
+    }
+}
+
+fn label_len(input: &[u8]) -> usize {
+    let len = ident_len(input);
+    if len > 0 && input.get(len) == Some(&b':') {
+        len + 1
+    } else {
+        0
+    }
+}
+
+fn keyword_len(input: &[u8]) -> usize {
+    if input.first() != Some(&b'/') {
+        return 0;
+    }
+    let len = run_len(&input[1..], |c| c.is_ascii_alphanumeric() || c == b'-');
+    if len > 0 && input.get(len + 1) == Some(&b'/') {
+        len + 2
+    } else {
+        0
+    }
+}
+
+fn reference_len(input: &[u8]) -> usize {
+    if input.first() != Some(&b'&') {
+        return 0;
+    }
+    if input.get(1) == Some(&b'{') {
+        let len = run_len(&input[2..], is_path_char);
+        if input.get(len + 2) == Some(&b'}') {
+            return len + 3;
+        }
+        return 0;
+    }
+    match run_len(&input[1..], is_ident_char) {
+        0 => 0,
+        len => len + 1,
+    }
+}
+
+// Unterminated strings and characters run to the end of the input
+fn quoted_len(input: &[u8], quote: u8) -> usize {
+    let mut escaped = false;
+    for (i, &c) in input.iter().enumerate().skip(1) {
+        if escaped {
+            escaped = false;
+        } else if c == b'\\' {
+            escaped = true;
+        } else if c == quote {
+            return i + 1;
+        }
+    }
+    input.len()
+}
+
+fn operator_len(input: &[u8]) -> usize {
+    const OPERATORS: &[&[u8]] = &[b"<<", b">>", b"<=", b">=", b"==", b"!=", b"&&", b"||"];
+    if OPERATORS.iter().any(|op| input.starts_with(op)) {
+        2
+    } else {
+        match input.first() {
+            Some(&c) if b"+-*/%<>&|^~!?:".contains(&c) => 1,
+            _ => 0,
+        }
+    }
+}
+
+// Linemarkers and directives take up the rest of the line. Directives may be
+// continued onto the next line with a backslash.
+fn directive_len(input: &[u8]) -> Option<(TriviaKind, usize)> {
+    let spaces = 1 + run_len(&input[1..], |c| c == b' ' || c == b'\t');
+    let word = &input[spaces..];
+    let kind = if word.first().map_or(false, u8::is_ascii_digit) {
+        TriviaKind::Linemarker
+    } else {
+        let len = ident_len(word);
+        if word.get(len).map_or(false, |&c| is_prop_node_char(c)) {
+            return None;
+        }
+        match &word[..len] {
+            b"line" => TriviaKind::Linemarker,
+            w if DIRECTIVES.iter().any(|d| d.as_bytes() == w) => TriviaKind::Directive,
+            _ => return None,
+        }
+    };
+
+    let mut len = 0;
+    loop {
+        len += line_len(&input[len..]);
+        if kind == TriviaKind::Directive && input[..len].ends_with(b"\\") &&
+           newline_len(&input[len..]) > 0 {
+            len += newline_len(&input[len..]);
+        } else {
+            return Some((kind, len));
+        }
+    }
+}
+
+struct CstParser<'a> {
+    source: &'a str,
+    pos: usize,
+    // Trivia already read before the token at `pos`
+    pending: Vec<Trivia>,
+}
+
+impl<'a> CstParser<'a> {
+    fn new(source: &'a str) -> Self {
+        let mut parser = CstParser {
+            source: source,
+            pos: 0,
+            pending: Vec::new(),
+        };
+        parser.pending = parser.leading_trivia();
+        parser
+    }
+
+    fn rest(&self) -> &'a [u8] {
+        &self.source.as_bytes()[self.pos..]
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.rest().first().cloned()
+    }
+
+    fn at_line_start(&self) -> bool {
+        self.source.as_bytes()[..self.pos]
+            .iter()
+            .rev()
+            .take_while(|&&c| c != b'\n')
+            .all(|&c| c == b' ' || c == b'\t')
+    }
+
+    fn trivia(&mut self, kind: TriviaKind, len: usize) -> Trivia {
+        let start = self.pos;
+        self.pos += len;
+        Trivia {
+            kind: kind,
+            text: self.source[start..self.pos].to_owned(),
+            span: Span::new(start, self.pos),
+        }
+    }
+
+    fn leading_trivia(&mut self) -> Vec<Trivia> {
+        let mut trivia = Vec::new();
+        loop {
+            let rest = self.rest();
+            let spaces = run_len(rest, |c| c.is_ascii_whitespace());
+            let t = if spaces > 0 {
+                self.trivia(TriviaKind::Whitespace, spaces)
+            } else if rest.starts_with(b"/*") {
+                self.trivia(TriviaKind::Comment, block_comment_len(rest))
+            } else if rest.starts_with(b"//") {
+                self.trivia(TriviaKind::Comment, line_len(rest))
+            } else if rest.starts_with(b"#") && self.at_line_start() {
+                match directive_len(rest) {
+                    Some((kind, len)) => self.trivia(kind, len),
+                    None => break,
+                }
+            } else {
+                break;
+            };
+            trivia.push(t);
+        }
+        trivia
+    }
+
+    fn trailing_trivia(&mut self) -> Vec<Trivia> {
+        let mut trivia = Vec::new();
+        loop {
+            let rest = self.rest();
+            let spaces = run_len(rest, |c| c == b' ' || c == b'\t');
+            let t = if spaces > 0 {
+                self.trivia(TriviaKind::Whitespace, spaces)
+            } else if rest.starts_with(b"/*") {
+                self.trivia(TriviaKind::Comment, block_comment_len(rest))
+            } else if rest.starts_with(b"//") {
+                self.trivia(TriviaKind::Comment, line_len(rest))
+            } else {
+                break;
+            };
+            trivia.push(t);
+        }
+        let newline = newline_len(self.rest());
+        if newline > 0 {
+            let t = self.trivia(TriviaKind::Whitespace, newline);
+            trivia.push(t);
+        }
+        trivia
+    }
+
+    fn bump(&mut self, kind: TokenKind, len: usize) -> SyntaxElement {
+        let start = self.pos;
+        self.pos += len;
+        let end = self.pos;
+        let leading = mem::replace(&mut self.pending, Vec::new());
+        let trailing = self.trailing_trivia();
+        self.pending = self.leading_trivia();
+        SyntaxElement::Token(Token {
+            kind: kind,
+            text: self.source[start..end].to_owned(),
+            leading: leading,
+            trailing: trailing,
+            span: Span::new(start, end),
+        })
+    }
+
+    fn keyword(&self) -> Option<&'a str> {
+        match keyword_len(self.rest()) {
+            0 => None,
+            len => Some(&self.source[self.pos..self.pos + len]),
+        }
+    }
+
+    fn eat_keyword(&mut self, children: &mut Vec<SyntaxElement>, keyword: &str) -> bool {
+        if self.keyword() == Some(keyword) {
+            let token = self.bump(TokenKind::Keyword, keyword.len());
+            children.push(token);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_punct(&mut self, children: &mut Vec<SyntaxElement>, c: u8) -> bool {
+        if self.peek() == Some(c) {
+            let token = self.bump(TokenKind::Punct, 1);
+            children.push(token);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn eat_reference(&mut self, children: &mut Vec<SyntaxElement>) -> bool {
+        match reference_len(self.rest()) {
+            0 => false,
+            len => {
+                let token = self.bump(TokenKind::Reference, len);
+                children.push(token);
+                true
+            }
+        }
+    }
+
+    fn labels(&mut self, children: &mut Vec<SyntaxElement>) {
+        loop {
+            match label_len(self.rest()) {
+                0 => return,
+                len => {
+                    let token = self.bump(TokenKind::Label, len);
+                    children.push(token);
+                }
+            }
+        }
+    }
+
+    fn any_token(&mut self) -> SyntaxElement {
+        let rest = self.rest();
+        let c = rest[0];
+        let (kind, len) = if c == b'"' {
+            (TokenKind::String, quoted_len(rest, b'"'))
+        } else if c == b'\'' {
+            (TokenKind::Char, quoted_len(rest, b'\''))
+        } else if keyword_len(rest) > 0 {
+            (TokenKind::Keyword, keyword_len(rest))
+        } else if label_len(rest) > 0 {
+            (TokenKind::Label, label_len(rest))
+        } else if reference_len(rest) > 0 {
+            (TokenKind::Reference, reference_len(rest))
+        } else if is_prop_node_char(c) {
+            (TokenKind::Name, run_len(rest, is_prop_node_char))
+        } else if b"{};=,<>[]()".contains(&c) {
+            (TokenKind::Punct, 1)
+        } else if operator_len(rest) > 0 {
+            (TokenKind::Operator, operator_len(rest))
+        } else {
+            let len = self.source[self.pos..].chars().next().map_or(1, char::len_utf8);
+            (TokenKind::Unknown, len)
+        };
+        self.bump(kind, len)
+    }
+
+    // Tokens within an expression or cell list. Returns `None` at the end of
+    // a statement so that an unterminated list does not swallow the rest of
+    // the file.
+    fn expr_token(&mut self, in_parens: bool) -> Option<SyntaxElement> {
+        let rest = self.rest();
+        let c = match rest.first() {
+            None | Some(&b';') | Some(&b'{') | Some(&b'}') => return None,
+            Some(&c) => c,
+        };
+        let (kind, len) = if c == b'"' {
+            (TokenKind::String, quoted_len(rest, b'"'))
+        } else if c == b'\'' {
+            (TokenKind::Char, quoted_len(rest, b'\''))
+        } else if c.is_ascii_digit() {
+            (TokenKind::Number, run_len(rest, is_ident_char))
+        } else if is_ident_start(c) {
+            return Some(self.ident());
+        } else if c == b'(' {
+            return Some(SyntaxElement::Node(self.group()));
+        } else if !in_parens && label_len(rest) > 0 {
+            (TokenKind::Label, label_len(rest))
+        } else if (!in_parens || rest.starts_with(b"&{")) && reference_len(rest) > 0 {
+            (TokenKind::Reference, reference_len(rest))
+        } else if c == b',' {
+            (TokenKind::Punct, 1)
+        } else if operator_len(rest) > 0 {
+            (TokenKind::Operator, operator_len(rest))
+        } else {
+            return Some(self.any_token());
+        };
+        Some(self.bump(kind, len))
+    }
+
+    // An identifier, grouped with its arguments if it is a macro call
+    fn ident(&mut self) -> SyntaxElement {
+        let len = ident_len(self.rest());
+        let ident = self.bump(TokenKind::Ident, len);
+        if self.peek() == Some(b'(') {
+            let args = self.group();
+            SyntaxElement::Node(SyntaxNode {
+                kind: SyntaxKind::Expr,
+                children: vec![ident, SyntaxElement::Node(args)],
+            })
+        } else {
+            ident
+        }
+    }
+
+    fn group(&mut self) -> SyntaxNode {
+        let mut children = Vec::new();
+        self.eat_punct(&mut children, b'(');
+        while !self.eat_punct(&mut children, b')') {
+            match self.expr_token(true) {
+                Some(token) => children.push(token),
+                None => break,
+            }
+        }
+        SyntaxNode {
+            kind: SyntaxKind::Expr,
+            children: children,
+        }
+    }
+
+    fn cells(&mut self) -> SyntaxNode {
+        let mut children = Vec::new();
+        if self.eat_keyword(&mut children, "/bits/") {
+            let len = run_len(self.rest(), is_ident_char);
+            if len > 0 {
+                let token = self.bump(TokenKind::Number, len);
+                children.push(token);
+            }
+        }
+        if self.eat_punct(&mut children, b'<') {
+            while !self.eat_punct(&mut children, b'>') {
+                match self.expr_token(false) {
+                    Some(token) => children.push(token),
+                    None => break,
+                }
+            }
+        }
+        SyntaxNode {
+            kind: SyntaxKind::Cells,
+            children: children,
+        }
+    }
+
+    fn bytes(&mut self) -> SyntaxNode {
+        let mut children = Vec::new();
+        self.eat_punct(&mut children, b'[');
+        while !self.eat_punct(&mut children, b']') {
+            let rest = self.rest();
+            let token = match rest.first() {
+                None | Some(&b';') | Some(&b'{') | Some(&b'}') => break,
+                _ if label_len(rest) > 0 => self.bump(TokenKind::Label, label_len(rest)),
+                Some(c) if c.is_ascii_hexdigit() => {
+                    self.bump(TokenKind::Number, run_len(rest, |c| c.is_ascii_hexdigit()))
+                }
+                _ => self.any_token(),
+            };
+            children.push(token);
+        }
+        SyntaxNode {
+            kind: SyntaxKind::Bytes,
+            children: children,
+        }
+    }
+
+    // Labels may come before and after each comma separated piece of data
+    fn value(&mut self, children: &mut Vec<SyntaxElement>) {
+        loop {
+            self.labels(children);
+            let rest = self.rest();
+            let data = match rest.first() {
+                Some(&b'"') => self.bump(TokenKind::String, quoted_len(rest, b'"')),
+                Some(&b'<') => SyntaxElement::Node(self.cells()),
+                Some(&b'[') => SyntaxElement::Node(self.bytes()),
+                Some(&b'&') if reference_len(rest) > 0 => {
+                    self.bump(TokenKind::Reference, reference_len(rest))
+                }
+                Some(&c) if is_ident_start(c) => self.ident(),
+                _ => match self.keyword() {
+                    Some("/bits/") => SyntaxElement::Node(self.cells()),
+                    Some("/incbin/") => {
+                        let mut incbin = Vec::new();
+                        self.eat_keyword(&mut incbin, "/incbin/");
+                        if self.peek() == Some(b'(') {
+                            incbin.push(SyntaxElement::Node(self.group()));
+                        }
+                        SyntaxElement::Node(SyntaxNode {
+                            kind: SyntaxKind::Incbin,
+                            children: incbin,
+                        })
+                    }
+                    _ => return,
+                },
+            };
+            children.push(data);
+            self.labels(children);
+            if !self.eat_punct(children, b',') {
+                return;
+            }
+        }
+    }
+
+    // Skips to the end of the statement, keeping everything that was skipped.
+    // At least one token is consumed if nothing has been read yet so that the
+    // parser always makes progress.
+    fn error(&mut self, mut children: Vec<SyntaxElement>) -> SyntaxNode {
+        let mut depth = 0usize;
+        loop {
+            match self.peek() {
+                None => break,
+                Some(b'}') if depth == 0 && !children.is_empty() => break,
+                Some(b';') if depth == 0 => {
+                    self.eat_punct(&mut children, b';');
+                    break;
+                }
+                Some(b'{') => depth += 1,
+                Some(b'}') => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            let token = self.any_token();
+            children.push(token);
+        }
+        SyntaxNode {
+            kind: SyntaxKind::Error,
+            children: children,
+        }
+    }
+
+    fn finish(&mut self,
+              kind: SyntaxKind,
+              mut children: Vec<SyntaxElement>,
+              end: u8)
+              -> SyntaxNode {
+        if self.eat_punct(&mut children, end) {
+            SyntaxNode {
+                kind: kind,
+                children: children,
+            }
+        } else {
+            self.error(children)
+        }
+    }
+
+    fn top_item(&mut self) -> SyntaxNode {
+        let mut children = Vec::new();
+        self.labels(&mut children);
+        match self.keyword() {
+            Some("/dts-v1/") => {
+                self.eat_keyword(&mut children, "/dts-v1/");
+                self.finish(SyntaxKind::Header, children, b';')
+            }
+            Some("/plugin/") => {
+                self.eat_keyword(&mut children, "/plugin/");
+                self.finish(SyntaxKind::Plugin, children, b';')
+            }
+            Some("/memreserve/") => {
+                self.eat_keyword(&mut children, "/memreserve/");
+                while self.peek() != Some(b';') {
+                    match self.expr_token(false) {
+                        Some(token) => children.push(token),
+                        None => break,
+                    }
+                }
+                self.finish(SyntaxKind::MemReserve, children, b';')
+            }
+            Some("/include/") => {
+                self.eat_keyword(&mut children, "/include/");
+                if self.peek() == Some(b'"') {
+                    let len = quoted_len(self.rest(), b'"');
+                    let token = self.bump(TokenKind::String, len);
+                    children.push(token);
+                }
+                SyntaxNode {
+                    kind: SyntaxKind::Include,
+                    children: children,
+                }
+            }
+            Some("/delete-node/") => self.delete(children, SyntaxKind::DeleteNode),
+            _ => self.definition(children, true),
+        }
+    }
+
+    fn body_item(&mut self) -> SyntaxNode {
+        let children = Vec::new();
+        match self.keyword() {
+            Some("/delete-node/") => self.delete(children, SyntaxKind::DeleteNode),
+            Some("/delete-property/") => self.delete(children, SyntaxKind::DeleteProperty),
+            _ => self.definition(children, false),
+        }
+    }
+
+    fn delete(&mut self, mut children: Vec<SyntaxElement>, kind: SyntaxKind) -> SyntaxNode {
+        self.any_token_into(&mut children);
+        if !self.eat_reference(&mut children) {
+            match run_len(self.rest(), is_prop_node_char) {
+                0 => return self.error(children),
+                len => {
+                    let token = self.bump(TokenKind::Name, len);
+                    children.push(token);
+                }
+            }
+        }
+        self.finish(kind, children, b';')
+    }
+
+    fn any_token_into(&mut self, children: &mut Vec<SyntaxElement>) {
+        let token = self.any_token();
+        children.push(token);
+    }
+
+    // A node or property. At the top level only the root node and amendments
+    // of it or of labels are allowed.
+    fn definition(&mut self, mut children: Vec<SyntaxElement>, top: bool) -> SyntaxNode {
+        self.labels(&mut children);
+        let omit = self.eat_keyword(&mut children, "/omit-if-no-ref/");
+        self.labels(&mut children);
+
+        let rest = self.rest();
+        if top && rest.first() == Some(&b'/') && keyword_len(rest) == 0 {
+            let token = self.bump(TokenKind::Name, 1);
+            children.push(token);
+        } else if top {
+            if !self.eat_reference(&mut children) {
+                return self.error(children);
+            }
+        } else {
+            match run_len(rest, is_prop_node_char) {
+                0 => return self.error(children),
+                len => {
+                    let token = self.bump(TokenKind::Name, len);
+                    children.push(token);
+                }
+            }
+        }
+
+        match self.peek() {
+            Some(b'{') => {
+                self.eat_punct(&mut children, b'{');
+                while self.peek().map_or(false, |c| c != b'}') {
+                    children.push(SyntaxElement::Node(self.body_item()));
+                }
+                if !self.eat_punct(&mut children, b'}') {
+                    return self.error(children);
+                }
+                self.finish(SyntaxKind::Node, children, b';')
+            }
+            Some(b'=') if !top => {
+                self.eat_punct(&mut children, b'=');
+                self.value(&mut children);
+                self.finish(SyntaxKind::Property, children, b';')
+            }
+            Some(b';') if omit => self.finish(SyntaxKind::OmitIfNoRef, children, b';'),
+            Some(b';') if !top => self.finish(SyntaxKind::Property, children, b';'),
+            _ => self.error(children),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(nodes: &[&SyntaxNode]) -> Vec<SyntaxKind> {
+        nodes.iter().map(|node| node.kind).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let source = "// SPDX-License-Identifier: GPL-2.0\n\
+                      /dts-v1/;\r\n\
+                      #include <dt-bindings/gpio/gpio.h>\n\
+                      #define FOO(x) \\\n\t(x + 1)\n\
+                      # 1 \"board.dts\"\n\
+                      /memreserve/ 0x10000000 0x4000;\n\
+                      /include/ \"common.dtsi\"\n\
+                      / {\n\
+                      \t#address-cells = <1>;\t/* cells */\n\
+                      \tl1: l2: n@1 {\n\
+                      \t\tp = lbl: \"a\", [de ad], <&x (1 << FOO(2)) 'c'>, /bits/ 8 <1>;\n\
+                      \t\tempty;\n\
+                      \t\t/delete-property/ q;\n\
+                      \t};\n\
+                      \t/delete-node/ gone;\n\
+                      };\n\
+                      /omit-if-no-ref/ &x;\n\
+                      &{/n@1} { blob = /incbin/(\"f.bin\", 0, 4); };\n\
+                      /* end */  ";
+        let file = parse(source);
+        assert_eq!(file.to_string(), source);
+        assert!(!file.has_errors());
+
+        let items: Vec<&SyntaxNode> = file.items.iter().collect();
+        assert_eq!(kinds(&items),
+                   vec![SyntaxKind::Header,
+                        SyntaxKind::MemReserve,
+                        SyntaxKind::Include,
+                        SyntaxKind::Node,
+                        SyntaxKind::OmitIfNoRef,
+                        SyntaxKind::Node]);
+        assert_eq!(kinds(&items[3].child_nodes()),
+                   vec![SyntaxKind::Property, SyntaxKind::Node, SyntaxKind::DeleteNode]);
+
+        let node = items[3].child_nodes()[1];
+        assert_eq!(node.name().unwrap().text, "n@1");
+        let labels: Vec<&str> = node.labels().iter().map(|l| l.text.as_str()).collect();
+        assert_eq!(labels, vec!["l1:", "l2:"]);
+        assert_eq!(kinds(&node.child_nodes()),
+                   vec![SyntaxKind::Property, SyntaxKind::Property, SyntaxKind::DeleteProperty]);
+        assert_eq!(kinds(&node.child_nodes()[0].child_nodes()),
+                   vec![SyntaxKind::Bytes, SyntaxKind::Cells, SyntaxKind::Cells]);
+        assert_eq!(items[5].name().unwrap().kind, TokenKind::Reference);
+    }
+
+    #[test]
+    fn trivia_attachment() {
+        let source = "/dts-v1/;\n/ {\n\t/* above */\n\tp = <1>; // after\n\tq;\n};\n";
+        let file = parse(source);
+        let props = file.items[1].child_nodes();
+
+        let leading: Vec<&str> = props[0].leading_trivia()
+                                         .iter()
+                                         .map(|t| t.text.as_str())
+                                         .collect();
+        assert_eq!(leading, vec!["\t", "/* above */", "\n\t"]);
+        let trailing: Vec<&str> = props[0].trailing_trivia()
+                                          .iter()
+                                          .map(|t| t.text.as_str())
+                                          .collect();
+        assert_eq!(trailing, vec![" ", "// after", "\n"]);
+        assert_eq!(props[1].leading_trivia()[0].text, "\t");
+        assert_eq!(props[0].get_span(), Span::new(28, 36));
+    }
+
+    #[test]
+    fn directives_and_linemarkers() {
+        let source = "#include \"a.h\"\n# 1 \"a.dts\"\n/dts-v1/;\n/ { #size-cells = <0>; };";
+        let file = parse(source);
+        let kinds: Vec<TriviaKind> = file.items[0].leading_trivia()
+                                                  .iter()
+                                                  .map(|t| t.kind)
+                                                  .collect();
+        assert_eq!(kinds,
+                   vec![TriviaKind::Directive,
+                        TriviaKind::Whitespace,
+                        TriviaKind::Linemarker,
+                        TriviaKind::Whitespace]);
+        assert_eq!(file.items[1].child_nodes()[0].name().unwrap().text, "#size-cells");
+    }
+
+    #[test]
+    fn errors_are_kept() {
+        let source = "/dts-v1/;\n/ {\n\tp = <1 2;\n\tfoo bar { x; };\n\tq = \"ok\";\n};\n\
+                      } /* stray";
+        let file = parse(source);
+        assert_eq!(file.to_string(), source);
+        assert!(file.has_errors());
+
+        let children = file.items[1].child_nodes();
+        assert_eq!(kinds(&children),
+                   vec![SyntaxKind::Property, SyntaxKind::Error, SyntaxKind::Property]);
+        assert_eq!(file.items[2].kind, SyntaxKind::Error);
+    }
+
+    #[test]
+    fn derive_tree() {
+        let source = "/dts-v1/;\n/ {\n\t/* comment */\n\tn: node { p = <1>; };\n};\n&n { q; };\n";
+        let mut file = parse(source);
+        let (tree, amends, errors) = file.to_tree();
+        assert!(errors.is_empty());
+        assert_eq!(amends.len(), 1);
+
+        let node = tree.get_node_by_path("/node").unwrap();
+        let cst_node = file.items[1].child_nodes()[0];
+        assert_eq!(node.get_span(), cst_node.get_span());
+
+        // Edits show up in the derived tree
+        if let SyntaxElement::Node(ref mut node) = file.items[1].children[2] {
+            if let SyntaxElement::Token(ref mut name) = node.children[1] {
+                name.text = "renamed".to_owned();
+            }
+        }
+        let (tree, _, _) = file.to_tree();
+        assert!(tree.get_node_by_path("/renamed").is_ok());
+    }
+}
@@ -5,8 +5,9 @@
 use std::str::{self, FromStr};
 use std::path::{PathBuf, Path};
 use std::fs::File;
-use std::io::{self, Read};
-use std::cmp::Ordering;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::cmp::{self, Ordering};
+use std::collections::HashMap;
 
 use nom::{IResult, ErrorKind, Needed, FindSubstring, digit, space, multispace, line_ending};
 
@@ -84,6 +85,20 @@ pub struct IncludeBounds {
     child_start: usize,
     len: usize,
     method: IncludeMethod,
+    mapping: Mapping,
+}
+
+// How offsets within a bound map back to the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Mapping {
+    // Each byte maps to the byte at the same distance from `child_start`
+    Linear,
+    // Lines were recovered from C preprocessor linemarkers, so lines are exact
+    // but columns are estimated
+    Linemarkers,
+    // The whole bound is the expansion of the macro invocation of the given
+    // length found at `child_start`
+    Expansion(usize),
 }
 
 /// Specifies the method used to include a file.
@@ -156,6 +171,51 @@ impl IncludeBounds {
         &self.method
     }
 
+    // A bound that maps byte for byte onto the file
+    pub(crate) fn new_linear(path: PathBuf,
+                             global_start: usize,
+                             child_start: usize,
+                             len: usize,
+                             method: IncludeMethod)
+                             -> Self {
+        IncludeBounds {
+            path: path,
+            global_start: global_start,
+            child_start: child_start,
+            len: len,
+            method: method,
+            mapping: Mapping::Linear,
+        }
+    }
+
+    // A bound holding `len` bytes expanded from the macro invocation of
+    // `invocation_len` bytes at `child_start` in the file
+    pub(crate) fn new_expansion(path: PathBuf,
+                                global_start: usize,
+                                child_start: usize,
+                                invocation_len: usize,
+                                len: usize)
+                                -> Self {
+        IncludeBounds {
+            path: path,
+            global_start: global_start,
+            child_start: child_start,
+            len: len,
+            method: IncludeMethod::CPP,
+            mapping: Mapping::Expansion(invocation_len),
+        }
+    }
+
+    /// Returns the span of the macro invocation, within the file this bound
+    /// maps to, that the bound was expanded from. Only bounds created by the
+    /// built-in preprocessor in the `cpp` module hold expansions.
+    pub fn expanded_from(&self) -> Option<(usize, usize)> {
+        match self.mapping {
+            Mapping::Expansion(len) => Some((self.child_start, self.child_start + len)),
+            _ => None,
+        }
+    }
+
     fn split_bounds(bounds: &mut Vec<IncludeBounds>, start: usize, end: usize, offset: usize) {
         let mut remainders: Vec<IncludeBounds> = Vec::new();
 
@@ -175,6 +235,7 @@ impl IncludeBounds {
                     child_start: start - b.start() + offset,
                     len: remainder,
                     method: b.include_method().clone(),
+                    mapping: b.mapping.clone(),
                 });
 
                 b.len = start - b.start();
@@ -199,17 +260,21 @@ impl IncludeBounds {
                                  offset: usize)
                                  -> Result<(usize, usize), BoundsError> {
         if offset >= self.global_start && offset < self.end() {
-            match self.method {
-                IncludeMethod::DTS => {
+            match self.mapping {
+                Mapping::Linear | Mapping::Expansion(_) => {
                     let b = match File::open(&self.path) {
                             Ok(f) => f,
                             Err(e) => return Err(BoundsError::IOError(e, Some(self.path.to_owned()))),
                         }
                         .bytes().filter_map(|e| e.ok());
-                    byte_offset_to_line_col(b, offset - self.global_start + self.child_start)
-                                            .map_err(|e| e.into())
+                    // An expansion has no position of its own within the file
+                    let child_offset = match self.mapping {
+                        Mapping::Expansion(_) => self.child_start,
+                        _ => offset - self.global_start + self.child_start,
+                    };
+                    byte_offset_to_line_col(b, child_offset).map_err(|e| e.into())
                 }
-                IncludeMethod::CPP => {
+                Mapping::Linemarkers => {
                     let (g_line, g_col) = byte_offset_to_line_col(global_buffer.iter(), offset)?;
                     let (s_line, s_col) = byte_offset_to_line_col(global_buffer.iter(),
                                                                   self.global_start)?;
@@ -362,6 +427,7 @@ fn parse_linemarkers(buf: &[u8],
             },
             len: rem.len() + post_len,
             method: IncludeMethod::CPP,
+            mapping: Mapping::Linemarkers,
         };
 
         bounds.push(new_bound);
@@ -429,16 +495,7 @@ pub fn include_files<P: AsRef<Path>, I: AsRef<Path>>(file: P, include_dirs: &[I]
                       include_dirs: &[&Path],
                       main_offset: usize)
                       -> Result<(Vec<u8>, Vec<IncludeBounds>), IncludeError> {
-        fn find_file(path: &Path, include_dirs: &[&Path]) -> Result<PathBuf, IncludeError> {
-            for dir in include_dirs.iter() {
-                let p = dir.join(path);
-                if p.is_file() {
-                    return Ok(p);
-                }
-            }
-            Err(IncludeError::IOError(io::Error::from(io::ErrorKind::NotFound), Some(path.to_owned())))
-        }
-        let path = find_file(path, include_dirs)?;
+        let path = find_include_file(path, include_dirs)?;
         let mut file = match File::open(&path) {
             Ok(f) => f,
             Err(e) => return Err(IncludeError::IOError(e, Some(path.to_owned()))),
@@ -480,6 +537,7 @@ pub fn include_files<P: AsRef<Path>, I: AsRef<Path>>(file: P, include_dirs: &[I]
                     }
                     .bytes().count(),
                 method: IncludeMethod::CPP,
+                mapping: Mapping::Linemarkers,
             };
 
             buffer.extend_from_slice(line);
@@ -498,6 +556,7 @@ pub fn include_files<P: AsRef<Path>, I: AsRef<Path>>(file: P, include_dirs: &[I]
                     }
                     .bytes().count(),
                 method: IncludeMethod::DTS,
+                mapping: Mapping::Linear,
             }
         };
         bounds.push(start_bound);
@@ -549,6 +608,257 @@ pub fn include_files<P: AsRef<Path>, I: AsRef<Path>>(file: P, include_dirs: &[I]
                    0)
 }
 
+/// Searches `include_dirs` in order for `file`, returning the path to the first
+/// match. This is the same search performed for `/include/` statements by
+/// `include_files`.
+///
+/// # Errors
+/// Returns `IOError` with a `NotFound` error if the file is not in any of the
+/// directories.
+pub fn find_include_file<P: AsRef<Path>, I: AsRef<Path>>(file: P,
+                                                         include_dirs: &[I])
+                                                         -> Result<PathBuf, IncludeError> {
+    let file = file.as_ref();
+    for dir in include_dirs.iter() {
+        let p = dir.as_ref().join(file);
+        if p.is_file() {
+            return Ok(p);
+        }
+    }
+    Err(IncludeError::IOError(io::Error::from(io::ErrorKind::NotFound), Some(file.to_owned())))
+}
+
+/// Reads the data included by a `/incbin/` statement, as stored in
+/// `tree::Data::Incbin`. The file is searched for in `include_dirs` in the same
+/// way as `include_files` does. If `length` is `None` the file is read from
+/// `offset` to its end.
+///
+/// # Errors
+/// Returns `IOError` if the file cannot be found or opened, or if it is too
+/// short to contain the requested range.
+pub fn read_incbin<P: AsRef<Path>, I: AsRef<Path>>(file: P,
+                                                   offset: u64,
+                                                   length: Option<u64>,
+                                                   include_dirs: &[I])
+                                                   -> Result<Vec<u8>, IncludeError> {
+    let path = find_include_file(file, include_dirs)?;
+    let read = |path: &Path| -> io::Result<Vec<u8>> {
+        let mut f = File::open(path)?;
+        f.seek(SeekFrom::Start(offset))?;
+        let mut data = Vec::new();
+        match length {
+            Some(len) => {
+                f.take(len).read_to_end(&mut data)?;
+                if (data.len() as u64) < len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof));
+                }
+            }
+            None => {
+                f.read_to_end(&mut data)?;
+            }
+        }
+        Ok(data)
+    };
+
+    read(&path).map_err(|e| IncludeError::IOError(e, Some(path.clone())))
+}
+
+/// Finds the C preprocessor macros that numbers in the global buffer returned
+/// by `include_files` were expanded from, such as `GIC_SPI` for the `0` in
+/// `interrupts = <0 37 4>;`.
+///
+/// The lines of a `CPP` bound are exact, so each line of the global buffer is
+/// compared to the same line of the original file. The top level tokens of both
+/// lines are paired up in order and a token that differs after ignoring
+/// whitespace is taken to be a macro. Lines where the count of tokens does not
+/// match, such as when a macro expands to more than one cell, are skipped.
+///
+/// Files are only read once and kept for the life of the `MacroLocator`.
+#[derive(Debug)]
+pub struct MacroLocator<'a> {
+    bounds: &'a [IncludeBounds],
+    global_buffer: &'a [u8],
+    global_lines: Vec<usize>,
+    // Each file read so far along with the start of each of its lines
+    files: HashMap<PathBuf, Option<(Vec<u8>, Vec<usize>)>>,
+}
+
+impl<'a> MacroLocator<'a> {
+    /// Creates a `MacroLocator` for a global buffer and its bounds, as
+    /// returned by `include_files`.
+    pub fn new(bounds: &'a [IncludeBounds], global_buffer: &'a [u8]) -> MacroLocator<'a> {
+        MacroLocator {
+            bounds: bounds,
+            global_buffer: global_buffer,
+            global_lines: line_starts(global_buffer),
+            files: HashMap::new(),
+        }
+    }
+
+    /// Returns the macro, as written in the original file, that expanded to the
+    /// token starting at `offset` in the global buffer. Function like macros
+    /// are returned along with their arguments. Returns `None` if the token was
+    /// not expanded from a macro or if it cannot be determined.
+    pub fn macro_at_offset(&mut self, offset: usize) -> Option<String> {
+        let bound = match get_bounds_containing_offset(self.bounds, offset) {
+            Ok(bound) if bound.mapping == Mapping::Linemarkers => bound,
+            Ok(bound) => return self.expansion_at(bound),
+            _ => return None,
+        };
+
+        let line_of = |lines: &[usize], off: usize| match lines.binary_search(&off) {
+            Ok(line) => line,
+            Err(line) => line - 1,
+        };
+        let g_line = line_of(&self.global_lines, offset);
+        let s_line = line_of(&self.global_lines, bound.start());
+        let line_start = cmp::max(self.global_lines[g_line], bound.start());
+        let line_end = self.global_lines.get(g_line + 1).map_or(self.global_buffer.len(), |&e| e);
+        let expanded = &self.global_buffer[line_start..line_end];
+
+        let &(ref file, ref file_lines) = self.file(bound.child_path())?;
+        let c_line = line_of(file_lines, bound.child_start());
+        let o_line = c_line + g_line - s_line;
+        let original = match file_lines.get(o_line) {
+            Some(&start) => {
+                let end = file_lines.get(o_line + 1).map_or(file.len(), |&e| e);
+                &file[start..end]
+            }
+            None => return None,
+        };
+
+        let expanded = split_tokens(expanded);
+        let original = split_tokens(original);
+        if expanded.len() != original.len() {
+            return None;
+        }
+
+        let index = expanded.iter().position(|&(start, _)| line_start + start == offset)?;
+        let strip = |t: &[u8]| t.iter()
+                                .filter(|c| !c.is_ascii_whitespace())
+                                .cloned()
+                                .collect::<Vec<u8>>();
+        let (_, exp) = expanded[index];
+        let (_, orig) = original[index];
+        if strip(exp) != strip(orig) {
+            Some(String::from_utf8_lossy(orig).into_owned())
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> MacroLocator<'a> {
+    // Bounds from the built-in preprocessor know the invocation they were
+    // expanded from, so no comparison is needed
+    fn expansion_at(&mut self, bound: &IncludeBounds) -> Option<String> {
+        let (start, end) = bound.expanded_from()?;
+        let &(ref file, _) = self.file(bound.child_path())?;
+        file.get(start..end).map(|inv| String::from_utf8_lossy(inv).into_owned())
+    }
+
+    fn file(&mut self, path: &Path) -> Option<&(Vec<u8>, Vec<usize>)> {
+        self.files
+            .entry(path.to_owned())
+            .or_insert_with(|| {
+                let mut buf = Vec::new();
+                File::open(path)
+                    .and_then(|mut f| f.read_to_end(&mut buf))
+                    .ok()
+                    .map(|_| {
+                        let lines = line_starts(&buf);
+                        (buf, lines)
+                    })
+            })
+            .as_ref()
+    }
+}
+
+fn line_starts(buf: &[u8]) -> Vec<usize> {
+    let mut starts = vec![0];
+    starts.extend(buf.iter().enumerate().filter(|&(_, &c)| c == b'\n').map(|(i, _)| i + 1));
+    starts
+}
+
+// Splits a line into top level tokens, each with its start within the line.
+// Parenthesized groups, strings, and identifiers along with any call arguments
+// are kept as one token. Comments are dropped.
+fn split_tokens(line: &[u8]) -> Vec<(usize, &[u8])> {
+    fn is_word_char(c: u8) -> bool {
+        c.is_ascii_alphanumeric() || c == b'_'
+    }
+
+    fn group_end(line: &[u8], start: usize) -> usize {
+        let mut depth = 0;
+        for (i, &c) in line.iter().enumerate().skip(start) {
+            match c {
+                b'(' => depth += 1,
+                b')' => {
+                    depth -= 1;
+                    if depth == 0 {
+                        return i + 1;
+                    }
+                }
+                _ => {}
+            }
+        }
+        line.len()
+    }
+
+    fn quoted_end(line: &[u8], start: usize) -> usize {
+        let quote = line[start];
+        let mut i = start + 1;
+        while i < line.len() {
+            match line[i] {
+                b'\\' => i += 1,
+                c if c == quote => return i + 1,
+                _ => {}
+            }
+            i += 1;
+        }
+        line.len()
+    }
+
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < line.len() {
+        let c = line[i];
+        let end = if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        } else if line[i..].starts_with(b"//") {
+            break;
+        } else if line[i..].starts_with(b"/*") {
+            i = line[i..].windows(2)
+                         .position(|w| w == b"*/")
+                         .map_or(line.len(), |p| i + p + 2);
+            continue;
+        } else if c == b'"' || c == b'\'' {
+            quoted_end(line, i)
+        } else if c == b'(' {
+            group_end(line, i)
+        } else if is_word_char(c) {
+            let word_end = line[i..].iter()
+                                    .position(|&c| !is_word_char(c))
+                                    .map_or(line.len(), |p| i + p);
+            let next = line[word_end..].iter()
+                                       .position(|c| !c.is_ascii_whitespace())
+                                       .map(|p| word_end + p);
+            match next {
+                Some(next) if !c.is_ascii_digit() && line[next] == b'(' => group_end(line, next),
+                _ => word_end,
+            }
+        } else {
+            i + 1
+        };
+
+        tokens.push((i, &line[i..end]));
+        i = end;
+    }
+
+    tokens
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -585,4 +895,14 @@ mod tests {
             )
         );
     }
+
+    #[test]
+    fn tokens_in_line() {
+        let line = b"\tinterrupts = <GIC_SPI IRQ(3, 4) (1 << 2) 'a'>; /* x */ // y\n";
+        let tokens: Vec<(usize, &[u8])> = vec![
+            (1, b"interrupts"), (12, b"="), (14, b"<"), (15, b"GIC_SPI"), (23, b"IRQ(3, 4)"),
+            (33, b"(1 << 2)"), (42, b"'a'"), (45, b">"), (46, b";"),
+        ];
+        assert_eq!(split_tokens(line), tokens);
+    }
 }
@@ -0,0 +1,193 @@
+//! An index over a device tree for fast repeated lookups.
+//!
+//! The lookups on `tree::DTInfo` walk the tree on every call, which is fine for
+//! a few of them but adds up when a tool looks up every reference in a large
+//! tree. A `TreeIndex` walks the tree once and answers each lookup from hash
+//! maps instead.
+//!
+//! The index borrows the tree, so it can not go stale: the tree can not be
+//! changed, such as by `DTInfo::merge_amends`, while the index is alive. Build
+//! a new index once the changes are done.
+//!
+//! ```
+//! use device_tree_source::index::TreeIndex;
+//! use device_tree_source::parser::{parse_dt, ParseResult};
+//!
+//! let source = b"/dts-v1/;\n/ { soc { uart0: uart@1000 { compatible = \"ns16550a\"; }; }; };";
+//! let tree = match parse_dt(source) {
+//!     Ok(ParseResult::Complete(tree, _)) => tree,
+//!     _ => unreachable!(),
+//! };
+//!
+//! let index = TreeIndex::new(&tree);
+//! assert_eq!(index.path_of_label("uart0"), Some("/soc/uart@1000"));
+//! assert_eq!(index.nodes_by_compatible("ns16550a").len(), 1);
+//! ```
+
+use std::collections::HashMap;
+
+use tree::{DTInfo, Node, Property, Data};
+use visit;
+
+/// Lookup tables for the nodes of a `DTInfo`, see the module documentation.
+#[derive(Debug)]
+pub struct TreeIndex<'a> {
+    tree: &'a DTInfo,
+    // Every node with its full path, in the order of a depth first walk
+    nodes: Vec<(String, &'a Node)>,
+    paths: HashMap<String, usize>,
+    labels: HashMap<&'a str, usize>,
+    phandles: HashMap<u32, usize>,
+    compatibles: HashMap<&'a str, Vec<usize>>,
+}
+
+impl<'a> TreeIndex<'a> {
+    /// Builds the index by walking the whole tree once.
+    pub fn new(tree: &'a DTInfo) -> Self {
+        let mut index = TreeIndex {
+            tree: tree,
+            nodes: Vec::new(),
+            paths: HashMap::new(),
+            labels: HashMap::new(),
+            phandles: HashMap::new(),
+            compatibles: HashMap::new(),
+        };
+        for (path, node) in visit::depth_first(&tree.root) {
+            index.add(node, path);
+        }
+        index
+    }
+
+    fn add(&mut self, node: &'a Node, path: String) {
+        let (proplist, labels) = match *node {
+            Node::Existing { ref proplist, ref labels, .. } => (proplist, labels),
+            Node::Deleted { .. } => return,
+        };
+        let i = self.nodes.len();
+
+        // Like `DTInfo::get_node_by_label`, the first node found wins
+        for label in labels {
+            self.labels.entry(label).or_insert(i);
+        }
+        let phandle = proplist.get("phandle")
+            .or_else(|| proplist.get("linux,phandle"))
+            .and_then(Property::cells);
+        if let Some(ref cells) = phandle {
+            if cells.len() == 1 && cells[0] <= u64::from(u32::max_value()) {
+                self.phandles.entry(cells[0] as u32).or_insert(i);
+            }
+        }
+        if let Some(&Property::Existing { val: Some(ref data), .. }) = proplist.get("compatible") {
+            for d in data {
+                if let Data::String(ref compatible, _) = *d {
+                    self.compatibles.entry(compatible).or_insert_with(Vec::new).push(i);
+                }
+            }
+        }
+
+        self.paths.insert(path.clone(), i);
+        self.nodes.push((path, node));
+    }
+
+    /// The number of nodes in the tree.
+    pub fn len(&self) -> usize {
+        self.nodes.len()
+    }
+
+    /// Returns true if the tree has no nodes, which only happens if the root
+    /// node is deleted.
+    pub fn is_empty(&self) -> bool {
+        self.nodes.is_empty()
+    }
+
+    /// Get a reference to a `Node` by its path. Unit addresses may be left
+    /// out as with `DTInfo::get_node_by_path`, although such a path is looked
+    /// up in the tree rather than the index.
+    pub fn node_by_path(&self, path: &str) -> Option<&'a Node> {
+        match self.paths.get(path) {
+            Some(&i) => Some(self.nodes[i].1),
+            None => {
+                self.tree
+                    .full_path(path)
+                    .ok()
+                    .and_then(|full| self.paths.get(&full))
+                    .map(|&i| self.nodes[i].1)
+            }
+        }
+    }
+
+    /// Get a reference to the first `Node` with the label, in the same order
+    /// as `DTInfo::get_node_by_label`.
+    pub fn node_by_label(&self, label: &str) -> Option<&'a Node> {
+        self.labels.get(label).map(|&i| self.nodes[i].1)
+    }
+
+    /// Get the full path of the first `Node` with the label. Useful for
+    /// changing the node with `DTInfo::get_node_by_path_mut` once the index
+    /// is dropped.
+    pub fn path_of_label(&self, label: &str) -> Option<&str> {
+        self.labels.get(label).map(|&i| self.nodes[i].0.as_str())
+    }
+
+    /// Get a reference to the `Node` with the phandle, as given by its
+    /// `phandle` or `linux,phandle` property. Phandles are only known for
+    /// every referenced node once `DTInfo::resolve_references` has been run.
+    pub fn node_by_phandle(&self, phandle: u32) -> Option<&'a Node> {
+        self.phandles.get(&phandle).map(|&i| self.nodes[i].1)
+    }
+
+    /// Get the full path of the `Node` with the phandle, see
+    /// `node_by_phandle`.
+    pub fn path_of_phandle(&self, phandle: u32) -> Option<&str> {
+        self.phandles.get(&phandle).map(|&i| self.nodes[i].0.as_str())
+    }
+
+    /// Get the full path of every `Node` that lists `compatible` in its
+    /// `compatible` property, along with the node, in the order of a depth
+    /// first walk of the tree.
+    pub fn nodes_by_compatible(&self, compatible: &str) -> Vec<(&str, &'a Node)> {
+        self.compatibles
+            .get(compatible)
+            .map(|found| {
+                found.iter().map(|&i| (self.nodes[i].0.as_str(), self.nodes[i].1)).collect()
+            })
+            .unwrap_or_else(Vec::new)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_base;
+
+    #[test]
+    fn lookups_match_tree() {
+        let (mut tree, _) = parse_base(b"/dts-v1/;\n/ {\n\
+                                    intc: intc { compatible = \"arm,gic-400\"; };\n\
+                                    soc {\n\
+                                        a: uart@1000 { compatible = \"acme,uart\", \"ns16550a\"; \
+                                                       interrupt-parent = <&intc>; };\n\
+                                        a: uart@2000 { compatible = \"ns16550a\"; };\n\
+                                    };\n\
+                                };");
+        assert!(tree.resolve_references().is_ok());
+
+        let index = TreeIndex::new(&tree);
+        assert_eq!(index.len(), 5);
+        for label in &["intc", "a", "missing"] {
+            assert_eq!(index.node_by_label(label), tree.get_node_by_label(label).ok());
+        }
+        for path in &["/", "/soc", "/soc/uart@2000", "/soc/uart", "/soc/uart@3000"] {
+            assert_eq!(index.node_by_path(path), tree.get_node_by_path(path).ok());
+        }
+        assert_eq!(index.path_of_label("a"), Some("/soc/uart@1000"));
+        assert_eq!(index.path_of_phandle(1), Some("/intc"));
+        assert_eq!(index.node_by_phandle(2), None);
+        assert_eq!(index.nodes_by_compatible("ns16550a")
+                        .iter()
+                        .map(|&(path, _)| path)
+                        .collect::<Vec<_>>(),
+                   vec!["/soc/uart@1000", "/soc/uart@2000"]);
+        assert!(index.nodes_by_compatible("acme,spi").is_empty());
+    }
+}
@@ -4,17 +4,31 @@
 //! The main parsing function is `parser::parse_dt`. The structures that make
 //! up the returned tree are found in the `tree` module. Finally, functions to
 //! parse includes and manipulate the information gleaned from include
-//! statements can be found in the `include` module.
+//! statements can be found in the `include` module. Trees that are searched
+//! often can be indexed with `index::TreeIndex`, and the `visit` module has
+//! iterators and visitors for walking every node and property. Nodes and
+//! properties can also be found with the selectors of `query::Query`.
+//!
+//! Tools that need to reproduce or rewrite source text, such as formatters,
+//! can use the lossless concrete syntax tree from `cst::parse` instead, which
+//! keeps comments and formatting.
 
 #![deny(missing_debug_implementations, trivial_casts, trivial_numeric_casts)]
 #![warn(missing_docs)]
 
 #[macro_use]
 extern crate nom;
+#[cfg(test)]
+extern crate mktemp;
 
 pub mod tree;
+pub mod index;
+pub mod visit;
+pub mod query;
 pub mod parser;
 pub mod include;
+pub mod cpp;
+pub mod cst;
 
 use std::borrow::Borrow;
 use std::iter::once;
@@ -25,10 +39,9 @@ use std::iter::once;
 pub enum ParseError {
     /// Some value could not be found by parsing the input.
     NotFound,
-    /// No more input was available where more was expected for parsing.
-    IncompleteInput,
-    /// Some `nom` error occurred. Helpful, I know. This is only temporary.
-    NomError,
+    /// The input contained a syntax error. See `parser::SyntaxError` for the
+    /// location and description of the error.
+    Syntax(parser::SyntaxError),
 }
 
 /// Returns the byte offset of the starting character of line within the iterator.
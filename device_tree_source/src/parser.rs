@@ -8,11 +8,13 @@
 
 use std::str::{self, FromStr};
 use std::num::ParseIntError;
+use std::cell::RefCell;
 
-use nom::{IResult, ErrorKind, hex_digit, oct_digit, digit, is_alphanumeric, alpha, line_ending,
-          not_line_ending, multispace, space, rest};
+use nom::{IResult, Err, ErrorKind, Needed, hex_digit, oct_digit, digit, is_alphanumeric, alpha,
+          line_ending, not_line_ending, multispace, space, rest};
 
-use tree::{DTInfo, ReserveInfo, Node, NodeName, Property, Data, Cell};
+use tree::{DTInfo, Overlay, ReserveInfo, Node, NodeName, Property, Data, Cell, Span,
+           OrderedMap, Duplicate};
 use ::ParseError;
 
 // Copied and modified from rust-lang/rust/src/libcore/num/mod.rs
@@ -59,6 +61,253 @@ macro_rules! comments_ws (
     } )
 );
 
+// Runs the parser and returns its output along with the span of the input it
+// consumed from a buffer of `input_len` bytes. `comments_ws!` does not look
+// inside this macro, so no junk is skipped and the span is exact even when
+// used within `comments_ws!`.
+macro_rules! spanned (
+    ($i:expr, $input_len:expr, $submac:ident!( $($args:tt)* )) => ( {
+        let input = $i;
+        let input_len: usize = $input_len;
+        match $submac!(input, $($args)*) {
+            IResult::Done(rem, o) => {
+                let span = Span::new(input_len - input.len(), input_len - rem.len());
+                IResult::Done(rem, (o, span))
+            }
+            IResult::Error(e) => IResult::Error(e),
+            IResult::Incomplete(n) => IResult::Incomplete(n),
+        }
+    } );
+    ($i:expr, $input_len:expr, $f:expr) => (
+        spanned!($i, $input_len, call!($f))
+    );
+);
+
+// Custom error codes attached to `nom` errors with `return_error!` so that a
+// failed parse can be turned into a `SyntaxError`. See `error_message`.
+const ERR_EXPR: u32 = 1;
+const ERR_HEADER: u32 = 2;
+const ERR_ROOT_NODE: u32 = 3;
+const ERR_NODE_ITEM: u32 = 4;
+const ERR_NODE_CLOSE: u32 = 5;
+const ERR_NODE_END: u32 = 6;
+const ERR_PROP_ORDER: u32 = 7;
+const ERR_PROP_DATA: u32 = 8;
+const ERR_PROP_END: u32 = 9;
+const ERR_CELLS_END: u32 = 10;
+const ERR_BYTES_END: u32 = 11;
+const ERR_STRING_END: u32 = 12;
+const ERR_BITS: u32 = 13;
+const ERR_DELETE_NAME: u32 = 14;
+const ERR_EOF: u32 = 15;
+const ERR_AMEND: u32 = 16;
+const ERR_INCBIN: u32 = 17;
+const ERR_DIV_ZERO: u32 = 18;
+const ERR_SHIFT: u32 = 19;
+const ERR_PLUGIN: u32 = 20;
+const ERR_OVERLAY: u32 = 21;
+const ERR_OMIT_REF: u32 = 22;
+const ERR_HEADER_FLAGS: u32 = 23;
+const ERR_MEMRESERVE_ORDER: u32 = 24;
+const ERR_OVERFLOW: u32 = 25;
+
+fn error_message(code: Option<u32>) -> &'static str {
+    match code {
+        Some(ERR_EXPR) => "invalid expression",
+        Some(ERR_HEADER) => "expected '/dts-v1/;' at start of file",
+        Some(ERR_ROOT_NODE) => "expected root node '/ { ... };'",
+        Some(ERR_NODE_ITEM) => "expected property, child node or '}'",
+        Some(ERR_NODE_CLOSE) => "expected '}' to close node",
+        Some(ERR_NODE_END) => "expected ';' after node",
+        Some(ERR_PROP_ORDER) => "property defined after child nodes",
+        Some(ERR_PROP_DATA) => "expected property data after '='",
+        Some(ERR_PROP_END) => "expected ';' after property",
+        Some(ERR_CELLS_END) => "unterminated cell list",
+        Some(ERR_BYTES_END) => "unterminated byte string",
+        Some(ERR_STRING_END) => "unterminated string",
+        Some(ERR_BITS) => "expected 8, 16, 32, or 64 after /bits/",
+        Some(ERR_DELETE_NAME) => "expected name and ';' after delete statement",
+        Some(ERR_EOF) => "unexpected end of input",
+        Some(ERR_AMEND) => "expected '&label { ... };' or '/ { ... };'",
+        Some(ERR_INCBIN) => "expected '(\"file\")' or '(\"file\", offset, length)' after /incbin/",
+        Some(ERR_DIV_ZERO) => "division by zero in expression",
+        Some(ERR_SHIFT) => "shift by 64 or more bits in expression",
+        Some(ERR_PLUGIN) => "expected '/plugin/;' at start of overlay",
+        Some(ERR_OVERLAY) => "overlay found where a base tree was expected",
+        Some(ERR_OMIT_REF) => "expected '&label;' after /omit-if-no-ref/",
+        Some(ERR_HEADER_FLAGS) => "header does not match earlier ones, '/plugin/;' must be \
+                                   on every header or none",
+        Some(ERR_MEMRESERVE_ORDER) => "/memreserve/ must come before the root node",
+        Some(ERR_OVERFLOW) => "overflow in expression, the result wraps around",
+        _ => "invalid syntax",
+    }
+}
+
+/// A syntax error found by `parse_dt`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SyntaxError {
+    /// The offset in bytes of the error within the buffer that was parsed.
+    /// This can be mapped back to the original file with
+    /// `include::IncludeBounds::file_line_from_global`.
+    pub offset: usize,
+    /// A description of the construct that was expected at `offset`.
+    pub message: String,
+    /// The offending input, starting at `offset` and continuing up to the end
+    /// of that line.
+    pub snippet: String,
+}
+
+impl SyntaxError {
+    /// Creates an error located at the start of `rem`, the input remaining
+    /// from a buffer of `input_len` bytes.
+    fn at(input_len: usize, rem: &[u8], code: Option<u32>) -> Self {
+        let snippet = rem.split(|&c| c == b'\n')
+                         .next()
+                         .unwrap_or(&[]);
+        let snippet = &snippet[..snippet.len().min(60)];
+
+        SyntaxError {
+            offset: input_len - rem.len(),
+            message: error_message(code).to_owned(),
+            snippet: String::from_utf8_lossy(snippet).trim_end().to_owned(),
+        }
+    }
+
+    /// Creates an error from a `nom` error returned by a parser that was
+    /// started on `input`. The error is located at `input` if the `nom` error
+    /// holds no position.
+    fn from_nom(input_len: usize, input: &[u8], err: &Err<&[u8]>) -> Self {
+        let (pos, code) = deepest_error(err);
+        SyntaxError::at(input_len, pos.unwrap_or(input), code)
+    }
+}
+
+// When recovering from syntax errors, errors are collected here instead of
+// being returned. `None` means parsing stops at the first error.
+type Recovery<'a> = Option<&'a RefCell<Vec<SyntaxError>>>;
+
+fn record_error(errors: &RefCell<Vec<SyntaxError>>, err: SyntaxError) {
+    // Several constructs may fail at the same point, such as nested nodes that
+    // are all unterminated at the end of input. Only the first is reported.
+    let mut errors = errors.borrow_mut();
+    if errors.last().map(|last| last.offset) != Some(err.offset) {
+        errors.push(err);
+    }
+}
+
+/// Skips past the statement starting at `input` so parsing can resume after a
+/// syntax error. The statement ends after a `;` or the `};` of a node at the
+/// same brace depth, or before a `}` that closes the enclosing node. Strings
+/// and comments are skipped over so braces or semicolons in them are ignored.
+fn skip_statement(input: &[u8]) -> &[u8] {
+    let mut depth = 0usize;
+    let mut i = 0;
+
+    while i < input.len() {
+        match input[i] {
+            b'"' => {
+                i += 1;
+                while i < input.len() && input[i] != b'"' {
+                    if input[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            b'/' if input[i..].starts_with(b"/*") => {
+                i = input[i + 2..].windows(2)
+                                  .position(|w| w == b"*/")
+                                  .map_or(input.len(), |pos| i + 2 + pos + 1);
+            }
+            b'/' if input[i..].starts_with(b"//") => {
+                i = input[i..].iter()
+                              .position(|&c| c == b'\n')
+                              .map_or(input.len(), |pos| i + pos);
+            }
+            b'{' => depth += 1,
+            b'}' if depth == 0 && i > 0 => return &input[i..],
+            b'}' if depth <= 1 => {
+                let mut rem = &input[i + 1..];
+                if let IResult::Done(cleaned, _) = eat_junk(rem) {
+                    if cleaned.first() == Some(&b';') {
+                        rem = &cleaned[1..];
+                    }
+                }
+                return rem;
+            }
+            b'}' => depth -= 1,
+            b';' if depth == 0 => return &input[i + 1..],
+            _ => {}
+        }
+        i += 1;
+    }
+
+    &input[input.len()..]
+}
+
+/// Expects `c` at the start of `input`. When recovering from errors a missing
+/// character is recorded and parsing continues as if it were there. Returns the
+/// offset just past the character, which is the end of the statement it
+/// closes.
+fn expect_char<'a>(input: &'a [u8], c: u8, code: u32, input_len: usize, errors: Recovery)
+                   -> IResult<&'a [u8], usize> {
+    match input.first() {
+        Some(&x) if x == c => IResult::Done(&input[1..], input_len - input.len() + 1),
+        _ => match errors {
+            Some(errors) => {
+                record_error(errors, SyntaxError::at(input_len, input, Some(code)));
+                IResult::Done(input, input_len - input.len())
+            }
+            None if input.is_empty() => IResult::Incomplete(Needed::Size(1)),
+            None => IResult::Error(error_position!(ErrorKind::Custom(code), input)),
+        }
+    }
+}
+
+/// Walks a `nom` error chain looking for the position that parsing got
+/// furthest into the input, along with the innermost custom error code in the
+/// chain. The innermost code is the one closest to the failure and so is the
+/// most specific.
+fn deepest_error<'a>(err: &Err<&'a [u8]>) -> (Option<&'a [u8]>, Option<u32>) {
+    // Nested errors are stored innermost first, followed by the outer error.
+    fn flatten<'a, 'b>(err: &'b Err<&'a [u8]>,
+                       out: &mut Vec<(&'b ErrorKind, Option<&'a [u8]>)>) {
+        match *err {
+            Err::Code(ref kind) => out.push((kind, None)),
+            Err::Position(ref kind, pos) => out.push((kind, Some(pos))),
+            Err::Node(ref kind, ref children) => {
+                for child in children {
+                    flatten(child, out);
+                }
+                out.push((kind, None));
+            }
+            Err::NodePosition(ref kind, pos, ref children) => {
+                for child in children {
+                    flatten(child, out);
+                }
+                out.push((kind, Some(pos)));
+            }
+        }
+    }
+
+    let mut chain = Vec::new();
+    flatten(err, &mut chain);
+
+    let pos = chain.iter().filter_map(|&(_, pos)| pos).min_by_key(|pos| pos.len());
+    let code = chain.iter()
+                    .filter_map(|&(kind, _)| match *kind {
+                        ErrorKind::Custom(code) => Some(code),
+                        _ => None,
+                    })
+                    .next();
+
+    (pos, code)
+}
+
+fn remaining_len(err: &Err<&[u8]>) -> usize {
+    deepest_error(err).0.map_or(usize::MAX, |p| p.len())
+}
+
 named!(opr_infix<OprInfix>, alt_complete!(
     tag!("*") => { |_| OprInfix::Multiply } |
     tag!("/") => { |_| OprInfix::Divide } |
@@ -113,33 +362,76 @@ enum OprInfix {
 }
 
 impl OprInfix {
-    fn apply(&self, a: u64, b: u64) -> u64 {
+    fn precedence(&self) -> u8 {
         match *self {
-            OprInfix::Multiply => a * b,
-            OprInfix::Divide => a / b,
-            OprInfix::Modulus => a % b,
-
-            OprInfix::Add => a + b,
-            OprInfix::Subtract => a - b,
-
-            OprInfix::LeftShift => a << b,
-            OprInfix::RightShift => a >> b,
-
-            OprInfix::Lesser => if a < b { 1 } else { 0 },
-            OprInfix::Greater => if a > b { 1 } else { 0 },
-            OprInfix::LesserEqual => if a <= b { 1 } else { 0 },
-            OprInfix::GreaterEqual => if a >= b { 1 } else { 0 },
-            OprInfix::Equal => if a == b { 1 } else { 0 },
-            OprInfix::NotEqual => if a != b { 1 } else { 0 },
-
-            OprInfix::BitAnd => a & b,
-            OprInfix::BitXor => a ^ b,
-            OprInfix::BitOr => a | b,
+            OprInfix::Multiply | OprInfix::Divide | OprInfix::Modulus => 10,
+            OprInfix::Add | OprInfix::Subtract => 9,
+            OprInfix::LeftShift | OprInfix::RightShift => 8,
+            OprInfix::Lesser | OprInfix::Greater |
+            OprInfix::LesserEqual | OprInfix::GreaterEqual => 7,
+            OprInfix::Equal | OprInfix::NotEqual => 6,
+            OprInfix::BitAnd => 5,
+            OprInfix::BitXor => 4,
+            OprInfix::BitOr => 3,
+            OprInfix::And => 2,
+            OprInfix::Or => 1,
+        }
+    }
 
-            OprInfix::And => if a != 0 && b != 0 { 1 } else { 0 },
-            OprInfix::Or => if a != 0 || b != 0 { 1 } else { 0 },
+    // Returns the error code to report if the operation cannot be performed.
+    // Signed values are kept in the same bits as unsigned ones, so only the
+    // operators that depend on the sign need to tell them apart.
+    fn apply(&self, a: u64, b: u64, sign: Sign) -> Result<u64, u32> {
+        let shift = |b: u64| if b < 64 { Ok(b as u32) } else { Err(ERR_SHIFT) };
+        let (sa, sb) = (a as i64, b as i64);
+        let signed = sign == Sign::Signed;
+        let compare = |unsigned: bool, signed_result: bool| {
+            Ok(if signed { u64::from(signed_result) } else { u64::from(unsigned) })
+        };
+        match *self {
+            OprInfix::Multiply => Ok(a.wrapping_mul(b)),
+            OprInfix::Divide | OprInfix::Modulus if b == 0 => Err(ERR_DIV_ZERO),
+            OprInfix::Divide if signed => Ok(sa.wrapping_div(sb) as u64),
+            OprInfix::Divide => Ok(a / b),
+            OprInfix::Modulus if signed => Ok(sa.wrapping_rem(sb) as u64),
+            OprInfix::Modulus => Ok(a % b),
+
+            OprInfix::Add => Ok(a.wrapping_add(b)),
+            OprInfix::Subtract => Ok(a.wrapping_sub(b)),
+
+            OprInfix::LeftShift => shift(b).map(|b| a << b),
+            OprInfix::RightShift if signed => shift(b).map(|b| (sa >> b) as u64),
+            OprInfix::RightShift => shift(b).map(|b| a >> b),
+
+            OprInfix::Lesser => compare(a < b, sa < sb),
+            OprInfix::Greater => compare(a > b, sa > sb),
+            OprInfix::LesserEqual => compare(a <= b, sa <= sb),
+            OprInfix::GreaterEqual => compare(a >= b, sa >= sb),
+            OprInfix::Equal => Ok(if a == b { 1 } else { 0 }),
+            OprInfix::NotEqual => Ok(if a != b { 1 } else { 0 }),
+
+            OprInfix::BitAnd => Ok(a & b),
+            OprInfix::BitXor => Ok(a ^ b),
+            OprInfix::BitOr => Ok(a | b),
+
+            OprInfix::And => Ok(if a != 0 && b != 0 { 1 } else { 0 }),
+            OprInfix::Or => Ok(if a != 0 || b != 0 { 1 } else { 0 }),
         }
     }
+
+    // Whether `apply` wrapped around. Unsigned values may also be read as
+    // signed, so `(0 - 1)` and `(-4 + 8)` are fine and only results that are
+    // wrong either way count.
+    fn overflows(&self, a: u64, b: u64, sign: Sign) -> bool {
+        let (sa, sb) = (a as i64, b as i64);
+        let (unsigned, signed) = match *self {
+            OprInfix::Multiply => (a.overflowing_mul(b).1, sa.overflowing_mul(sb).1),
+            OprInfix::Add => (a.overflowing_add(b).1, sa.overflowing_add(sb).1),
+            OprInfix::Subtract => (a.overflowing_sub(b).1, sa.overflowing_sub(sb).1),
+            _ => return false,
+        };
+        signed && (unsigned || sign == Sign::Signed)
+    }
 }
 
 named!(opr_prefix<OprPrefix>, alt_complete!(
@@ -165,209 +457,146 @@ impl OprPrefix {
     }
 }
 
-#[derive(Debug)]
-enum Token {
-    Number(u64),
-    Prefix(OprPrefix),
-    Infix(OprInfix),
-    Paren,
+// How the operators of an expression treat their operands.
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Sign {
+    // As unsigned 64 bit integers, as dtc does
+    Unsigned,
+    // As signed 64 bit integers, as cpp does for `#if`
+    Signed,
 }
 
+// Parses the expressions in cells as dtc evaluates them
+#[cfg(test)]
 fn parse_c_expr(input: &[u8]) -> IResult<&[u8], u64> {
-    let mut stack = Vec::new();
-    // println!("Input: {:?}", String::from_utf8_lossy(input));
-    let mut buf = input;
-    loop {
-        // println!("Stack: {:?}", stack);
-        buf = if let IResult::Done(cleaned, _) = eat_junk(buf) {
-            // println!("cleaned: {}", String::from_utf8_lossy(cleaned));
-            if let IResult::Done(matched, num) = integer(cleaned) {
-                match stack.pop() {
-                    None => {
-                        // println!("Pop Nothing");
-                        stack.push(Token::Number(num));
-                    }
-                    Some(Token::Paren) => {
-                        // println!("Pop Paren");
-                        stack.push(Token::Paren);
-                        stack.push(Token::Number(num));
-                    }
-                    Some(Token::Prefix(ref x)) => {
-                        // println!("Pop Prefix");
-                        let num = x.apply(num);
-                        stack.push(Token::Number(num));
-                    }
-                    Some(Token::Infix(ref x)) => {
-                        // println!("Pop Infix");
-                        if let Some(Token::Number(a)) = stack.pop() {
-                            let num = x.apply(a, num);
-                            stack.push(Token::Number(num));
-                        } else {
-                            return IResult::Error(error_position!(ErrorKind::Custom(1), buf));
-                        }
-                    }
-                    Some(Token::Number(a)) if stack.is_empty() => return IResult::Done(buf, a),
-                    _ => return IResult::Error(error_position!(ErrorKind::Custom(1), buf)),
-                };
-
-                matched
-            } else if let IResult::Done(matched, tok) = opr_infix(cleaned) {
-                // println!("Infix: {:?}", tok);
-                if let Some(&Token::Number(a)) = stack.last() {
-                    if (tok == OprInfix::Greater || tok == OprInfix::BitAnd) && stack.len() == 1 {
-                        if let IResult::Done(cleaned, _) = eat_junk(matched) {
-                            named!(test, preceded!(
-                                eat_junk,
-                                alt!(
-                                    tag!("(") |
-                                    recognize!(opr_prefix) |
-                                    recognize!(integer)
-                            )));
-                            match test(cleaned) {
-                                IResult::Done(_, _) => {}
-                                _ => return IResult::Done(buf, a),
-                            }
-                        }
-                    }
-                    stack.push(Token::Infix(tok));
-                } else if tok == OprInfix::Subtract {
-                    stack.push(Token::Prefix(OprPrefix::Negate));
-                } else {
-                    return IResult::Error(error_position!(ErrorKind::Custom(1), buf));
-                };
+    parse_expr(input, Sign::Unsigned, input.len(), None)
+}
 
-                matched
-            } else if let IResult::Done(matched, tok) = opr_prefix(cleaned) {
-                // println!("Prefix: {:?}", tok);
-                if let Some(&Token::Number(a)) = stack.last() {
-                    if stack.len() == 1 {
-                        return IResult::Done(buf, a);
-                    }
-                    return IResult::Error(error_position!(ErrorKind::Custom(1), buf));
-                } else {
-                    stack.push(Token::Prefix(tok));
-                };
+// Parses a full C expression, including the ternary operator. The numbers in
+// the expression are C integer literals, character literals, or parenthesized
+// expressions. All arithmetic is done on 64 bit integers, treated as `sign`
+// says, and wraps around, so `(-4 + 8)` is 4. A divide by zero or a shift by
+// 64 or more bits is an error located at its operator. When recovering from
+// errors, an operator that overflows is also recorded.
+fn parse_expr<'a>(input: &'a [u8], sign: Sign, input_len: usize, errors: Recovery)
+                  -> IResult<&'a [u8], u64> {
+    let (rem, cond) = try_parse!(input, call!(parse_binary_expr, 0, sign, input_len, errors));
+    let (cleaned, _) = try_parse!(rem, eat_junk);
+    if cleaned.first() != Some(&b'?') {
+        return IResult::Done(rem, cond);
+    }
 
-                matched
-            } else if let Some(&c) = cleaned.first() {
-                match c {
-                    b'(' => {
-                        // println!("Paren");
-                        match stack.pop() {
-                            None => {
-                                stack.push(Token::Paren);
-                            }
-                            Some(x @ Token::Paren) |
-                            Some(x @ Token::Prefix(_)) |
-                            Some(x @ Token::Infix(_)) => {
-                                stack.push(x);
-                                stack.push(Token::Paren);
-                            }
-                            Some(Token::Number(a)) => {
-                                if stack.is_empty() {
-                                    return IResult::Done(buf, a);
-                                } else {
-                                    return IResult::Error(
-                                        error_position!(ErrorKind::Custom(1), buf)
-                                    );
-                                }
-                            }
-                        };
-                    }
+    let (rem, a) = try_parse!(&cleaned[1..], call!(parse_expr, sign, input_len, errors));
+    let (cleaned, _) = try_parse!(rem, eat_junk);
+    if cleaned.first() != Some(&b':') {
+        return IResult::Error(error_position!(ErrorKind::Custom(ERR_EXPR), cleaned));
+    }
+    let (rem, b) = try_parse!(&cleaned[1..], call!(parse_expr, sign, input_len, errors));
 
-                    b')' => {
-                        // println!("Close");
-                        if let Some(Token::Number(num)) = stack.pop() {
-                            if let Some(Token::Paren) = stack.pop() {
-                                match stack.pop() {
-                                    None => {
-                                        stack.push(Token::Number(num));
-                                    }
-                                    Some(Token::Paren) => {
-                                        stack.push(Token::Paren);
-                                        stack.push(Token::Number(num));
-                                    }
-                                    Some(Token::Prefix(ref x)) => {
-                                        let num = x.apply(num);
-                                        stack.push(Token::Number(num));
-                                    }
-                                    Some(Token::Infix(ref x)) => {
-                                        if let Some(Token::Number(a)) = stack.pop() {
-                                            let num = x.apply(a, num);
-                                            stack.push(Token::Number(num));
-                                        } else {
-                                            return IResult::Error(
-                                                error_position!(ErrorKind::Custom(1), buf)
-                                            );
-                                        }
-                                    }
-                                    _ => return IResult::Error(
-                                            error_position!(ErrorKind::Custom(1), buf)
-                                        ),
-                                };
-                            } else {
-                                return IResult::Error(error_position!(ErrorKind::Custom(1), buf));
-                            }
-                        } else {
-                            return IResult::Error(error_position!(ErrorKind::Custom(1), buf));
-                        }
-                    }
+    IResult::Done(rem, if cond != 0 { a } else { b })
+}
 
-                    b';' => {
-                        match stack.pop() {
-                            Some(Token::Number(a)) => {
-                                if stack.is_empty() {
-                                    return IResult::Done(buf, a);
-                                } else {
-                                    return IResult::Error(
-                                        error_position!(ErrorKind::Custom(1), buf)
-                                    );
-                                }
-                            }
-                            _ => return IResult::Error(error_position!(ErrorKind::Custom(1), buf)),
-                        }
-                    }
+// Evaluates a complete C expression for the preprocessor's `#if` directives,
+// returning a description of the problem if it is not valid. Like cpp, every
+// value is a signed 64 bit integer. Suffixes such as `U` are ignored, so
+// values never become unsigned.
+pub(crate) fn eval_c_expr(input: &str) -> Result<i64, &'static str> {
+    // Numbers at the very end of the input would be incomplete
+    let input = format!("{}\n", input);
+    match parse_expr(input.as_bytes(), Sign::Signed, input.len(), None) {
+        IResult::Done(rem, num) => {
+            match eat_junk(rem) {
+                IResult::Done(rem, _) if rem.is_empty() => Ok(num as i64),
+                _ => Err(error_message(Some(ERR_EXPR))),
+            }
+        }
+        IResult::Error(err) => Err(error_message(deepest_error(&err).1.or(Some(ERR_EXPR)))),
+        IResult::Incomplete(_) => Err(error_message(Some(ERR_EXPR))),
+    }
+}
 
-                    x => {
-                        println!("Unimplemented char: {}", x as char);
-                        return IResult::Error(error_position!(ErrorKind::Custom(1), buf));
+// Precedence climbing over the binary operators. Only operators binding at
+// least as tightly as `min_prec` are consumed, which makes every operator left
+// associative.
+fn parse_binary_expr<'a>(input: &'a [u8],
+                         min_prec: u8,
+                         sign: Sign,
+                         input_len: usize,
+                         errors: Recovery)
+                         -> IResult<&'a [u8], u64> {
+    let (mut rem, mut a) = try_parse!(input, call!(parse_unary_expr, sign, input_len, errors));
+    loop {
+        let (cleaned, _) = try_parse!(rem, eat_junk);
+        match opr_infix(cleaned) {
+            IResult::Done(matched, opr) => {
+                if opr.precedence() < min_prec {
+                    return IResult::Done(rem, a);
+                }
+                let (after, b) = try_parse!(matched,
+                                            call!(parse_binary_expr,
+                                                  opr.precedence() + 1,
+                                                  sign,
+                                                  input_len,
+                                                  errors));
+                if let Some(errors) = errors {
+                    if opr.overflows(a, b, sign) {
+                        let err = SyntaxError::at(input_len, cleaned, Some(ERR_OVERFLOW));
+                        record_error(errors, err);
                     }
                 }
-
-                &cleaned[1..]
-            } else {
-                // Incomplete
-                println!("Incomplete: {}", String::from_utf8_lossy(buf));
-                unreachable!()
+                a = match opr.apply(a, b, sign) {
+                    Ok(num) => num,
+                    Err(code) => {
+                        return IResult::Error(error_position!(ErrorKind::Custom(code), cleaned))
+                    }
+                };
+                rem = after;
             }
-        } else {
-            // println!("Junk eating failed!?");
-            unreachable!()
-        };
+            _ => return IResult::Done(rem, a),
+        }
+    }
+}
 
-        if buf.is_empty() {
-            if stack.len() == 1 {
-                if let Some(&Token::Number(num)) = stack.last() {
-                    return IResult::Done(buf, num);
-                } else {
-                    return IResult::Error(error_position!(ErrorKind::Custom(1), buf));
-                }
-            } else {
-                return IResult::Error(error_position!(ErrorKind::Custom(1), buf));
-            }
+fn parse_unary_expr<'a>(input: &'a [u8], sign: Sign, input_len: usize, errors: Recovery)
+                        -> IResult<&'a [u8], u64> {
+    let (cleaned, _) = try_parse!(input, eat_junk);
+    match opr_prefix(cleaned) {
+        IResult::Done(matched, opr) => {
+            let (rem, a) = try_parse!(matched, call!(parse_unary_expr, sign, input_len, errors));
+            IResult::Done(rem, opr.apply(a))
         }
+        _ => parse_prim(cleaned, sign, input_len, errors),
     }
 }
 
-// TODO: - issue 5
-/*
-comments_ws!(do_parse!( // trinary
-    a: flat_map!(take_until_and_consume!("?"), integer) >>
-    b: flat_map!(take_until_and_consume!(":"), integer) >>
-    c: integer >>
-    (if a != 0 { b } else { c })
-)) |
-*/
+// The form a number takes outside of an expression, such as within a list of
+// cells. Anything more than a single integer must be wrapped in parentheses.
+fn parse_int_prim<'a>(input: &'a [u8], input_len: usize, errors: Recovery)
+                      -> IResult<&'a [u8], u64> {
+    parse_prim(input, Sign::Unsigned, input_len, errors)
+}
+
+fn parse_prim<'a>(input: &'a [u8], sign: Sign, input_len: usize, errors: Recovery)
+                  -> IResult<&'a [u8], u64> {
+    let (cleaned, _) = try_parse!(input, eat_junk);
+    if cleaned.first() == Some(&b'(') {
+        let (rem, num) = try_parse!(&cleaned[1..], call!(parse_expr, sign, input_len, errors));
+        let (cleaned, _) = try_parse!(rem, eat_junk);
+        if cleaned.first() == Some(&b')') {
+            IResult::Done(&cleaned[1..], num)
+        } else {
+            IResult::Error(error_position!(ErrorKind::Custom(ERR_EXPR), cleaned))
+        }
+    } else {
+        match alt_complete!(cleaned,
+            integer |
+            map!(delimited!(char!('\''), escape_c_char, char!('\'')), u64::from)
+        ) {
+            IResult::Done(rem, num) => IResult::Done(rem, num),
+            _ => IResult::Error(error_position!(ErrorKind::Custom(ERR_EXPR), cleaned)),
+        }
+    }
+}
 
 // ([0-9]+|0[xX][0-9a-fA-F]+)(U|L|UL|LL|ULL)
 named!(integer<u64>, terminated!(
@@ -469,173 +698,480 @@ as a C-style escape code. All hexadecimal and octal escape codes work up to
     take!(1) => { |c: &[u8]| c[0] }
 ));
 
-named_args!(parse_cell(bits: usize)<Cell>, 
+named_args!(parse_data_label(input_len: usize)<(String, Span)>,
+    spanned!(input_len, terminated!(parse_label, char!(':')))
+);
+
+named_args!(parse_name(input_len: usize)<(String, Span)>, spanned!(input_len,
+    map!(map_res!(take_while1!(is_prop_node_char), str::from_utf8), String::from)
+));
+
+fn parse_cell_num<'a>(input: &'a [u8], bits: usize, input_len: usize, errors: Recovery)
+                      -> IResult<&'a [u8], Cell> {
+    let (cleaned, _) = try_parse!(input, eat_junk);
+    let (rem, value) = try_parse!(cleaned, call!(parse_int_prim, input_len, errors));
+
+    if bits != 64 {
+        let mask = (1 << bits) - 1;
+        if (value > mask) && ((value | mask) != u64::max_value()) {
+            return IResult::Error(error_position!(ErrorKind::Verify, cleaned));
+        }
+    }
+
+    IResult::Done(rem, Cell::Expr {
+        value: value,
+        text: String::from_utf8_lossy(&cleaned[..cleaned.len() - rem.len()]).into_owned(),
+        macro_name: None,
+        span: Span::new(input_len - cleaned.len(), input_len - rem.len()),
+    })
+}
+
+named_args!(parse_cell<'a>(bits: usize, input_len: usize, errors: Recovery<'a>)<Cell>,
     alt!(
-        map!(verify!(
-            alt!(
-                map!(delimited!(
-                    char!('\''),
-                    escape_c_char,
-                    char!('\'')
-                ), u64::from) |
-                parse_c_expr
-            ),
-            |num| {
-                if bits != 64 {
-                    let mask = (1 << bits) - 1;
-                    !((num > mask) && ((num | mask) != u64::max_value()))
-                } else {
-                    true
-                }
-            }
-        ), |num| Cell::Num(num)) |
-        map!(cond_reduce!(bits == 32, parse_ref), |s| Cell::Ref(s, None))
+        map!(call!(parse_data_label, input_len), |(label, span)| Cell::Label(label, span)) |
+        call!(parse_cell_num, bits, input_len, errors) |
+        map!(cond_reduce!(bits == 32, spanned!(input_len, parse_ref)),
+             |(s, span)| Cell::Ref(s, None, span))
     )
 );
 
-named!(parse_mem_reserve<ReserveInfo>, comments_ws!(do_parse!(
-    labels: many0!(terminated!(parse_label, char!(':'))) >>
-    tag!("/memreserve/") >>
-    addr: parse_c_expr >>
-    size: parse_c_expr >>
-    char!(';') >>
-    (ReserveInfo { address: addr, size: size, labels: labels })
-)));
+named_args!(parse_mem_reserve<'a>(input_len: usize, errors: Recovery<'a>)<ReserveInfo>,
+    comments_ws!(do_parse!(
+        offset: map!(peek!(rest), |x: &[u8]| x.len()) >>
+        labels: many0!(call!(parse_data_label, input_len)) >>
+        tag!("/memreserve/") >>
+        addr: call!(parse_int_prim, input_len, errors) >>
+        size: call!(parse_int_prim, input_len, errors) >>
+        end: spanned!(input_len, char!(';')) >>
+        ({
+            let (labels, label_spans) = labels.into_iter().unzip();
+            ReserveInfo {
+                address: addr,
+                size: size,
+                labels: labels,
+                label_spans: label_spans,
+                span: Span::new(input_len - offset, (end.1).end),
+            }
+        })
+    ))
+);
 
-named!(parse_data_cells<Data>, do_parse!(
-    bits: verify!(
+named_args!(parse_data_cells<'a>(input_len: usize, errors: Recovery<'a>)<Data>, do_parse!(
+    bits: return_error!(ErrorKind::Custom(ERR_BITS), verify!(
         map!(opt!(complete!(comments_ws!(preceded!(
             tag!("/bits/"),
             flat_map!(take_until!("<"), integer)
         )))), |b: Option<u64>| b.unwrap_or(32)),
-    |b| b == 8 || b == 16 || b == 32 || b == 64 ) >>
+    |b| b == 8 || b == 16 || b == 32 || b == 64 )) >>
     val: delimited!(
         comments_ws!(char!('<')),
-            separated_list!(eat_junk, call!(parse_cell, bits as usize)),
-        comments_ws!(char!('>'))
+            separated_list!(eat_junk, alt!(
+                // Once a number has started it must be valid
+                preceded!(
+                    peek!(one_of!("0123456789'(")),
+                    return_error!(ErrorKind::Custom(ERR_EXPR),
+                                  call!(parse_cell_num, bits as usize, input_len, errors))
+                ) |
+                call!(parse_cell, bits as usize, input_len, errors)
+            )),
+        return_error!(ErrorKind::Custom(ERR_CELLS_END), preceded!(eat_junk, char!('>')))
     ) >>
-    ( Data::Cells(bits as usize, val) )
+    ( Data::Cells(bits as usize, val, Span::default()) )
 ));
 
-// TODO: labels in data - issue 6
-// TODO: include binary - issue 7
-named!(parse_data<Data>, comments_ws!(alt!(
-    delimited!(
-        char!('"'),
-        map!(escape_c_string, |s| Data::String(s)),
-        char!('"')
-    ) |
-    call!(parse_data_cells) |
-    delimited!(
-        char!('['),
+// The closing ')' is left outside of `comments_ws!` so that nothing after it is
+// taken as part of the data.
+named_args!(parse_incbin<'a>(input_len: usize, errors: Recovery<'a>)<Data>, terminated!(
+    comments_ws!(preceded!(
+        char!('('),
         do_parse!(
-            val: many1!(map_res!(map_res!(
-                    comments_ws!(take!(2)), str::from_utf8), from_str_hex::<u8>)) >>
-            (Data::ByteArray(val))
-        ),
-        char!(']')
-    ) |
-    map!(parse_ref, |x| (Data::Reference(x, None)))
-)));
+            file: comments_ws!(delimited!(char!('"'), escape_c_string, char!('"'))) >>
+            range: opt!(complete!(do_parse!(
+                char!(',') >>
+                offset: call!(parse_int_prim, input_len, errors) >>
+                comments_ws!(char!(',')) >>
+                len: call!(parse_int_prim, input_len, errors) >>
+                (offset, len)
+            ))) >>
+            (match range {
+                Some((offset, len)) => Data::Incbin(file, offset, Some(len), Span::default()),
+                None => Data::Incbin(file, 0, None, Span::default()),
+            })
+        )
+    )),
+    char!(')')
+));
+
+// Sets the span of data parsed by `parse_data`.
+fn set_data_span(data: Data, span: Span) -> Data {
+    match data {
+        Data::Reference(r, phandle, _) => Data::Reference(r, phandle, span),
+        Data::String(s, _) => Data::String(s, span),
+        Data::Cells(bits, cells, _) => Data::Cells(bits, cells, span),
+        Data::ByteArray(arr, _) => Data::ByteArray(arr, span),
+        Data::Label(label, _) => Data::Label(label, span),
+        Data::Incbin(file, offset, len, _) => Data::Incbin(file, offset, len, span),
+    }
+}
+
+named_args!(parse_data<'a>(input_len: usize, errors: Recovery<'a>)<Data>,
+    comments_ws!(map!(spanned!(input_len, alt!(
+        delimited!(
+            char!('"'),
+            map!(escape_c_string, |s| Data::String(s, Span::default())),
+            return_error!(ErrorKind::Custom(ERR_STRING_END), char!('"'))
+        ) |
+        preceded!(
+            peek!(alt!(tag!("<") | tag!("/bits/"))),
+            return_error!(ErrorKind::Custom(ERR_PROP_DATA),
+                          call!(parse_data_cells, input_len, errors))
+        ) |
+        delimited!(
+            char!('['),
+            do_parse!(
+                val: many1!(map_res!(map_res!(
+                        comments_ws!(take!(2)), str::from_utf8), from_str_hex::<u8>)) >>
+                (Data::ByteArray(val, Span::default()))
+            ),
+            return_error!(ErrorKind::Custom(ERR_BYTES_END), char!(']'))
+        ) |
+        preceded!(
+            tag!("/incbin/"),
+            return_error!(ErrorKind::Custom(ERR_INCBIN), call!(parse_incbin, input_len, errors))
+        ) |
+        map!(parse_ref, |x| Data::Reference(x, None, Span::default()))
+    )), |(data, span)| set_data_span(data, span)))
+);
+
+named_args!(parse_labeled_data<'a>(input_len: usize, errors: Recovery<'a>)<Vec<Data>>,
+    comments_ws!(do_parse!(
+        labels: many0!(comments_ws!(call!(parse_data_label, input_len))) >>
+        data: call!(parse_data, input_len, errors) >>
+        ({
+            let mut labeled: Vec<Data> = labels.into_iter()
+                                               .map(|(label, span)| Data::Label(label, span))
+                                               .collect();
+            labeled.push(data);
+            labeled
+        })
+    ))
+);
+
+// Labels may come before any piece of data and after the last one.
+// Once a ',' has been seen the following data is required, so errors are
+// returned instead of leaving the ',' to be reported as a missing ';'.
+named_args!(parse_prop_data<'a>(input_len: usize, errors: Recovery<'a>)<Vec<Data>>,
+    comments_ws!(do_parse!(
+        first: call!(parse_labeled_data, input_len, errors) >>
+        rest: many0!(preceded!(
+            char!(','),
+            return_error!(ErrorKind::Custom(ERR_PROP_DATA),
+                          call!(parse_labeled_data, input_len, errors))
+        )) >>
+        end_labels: many0!(comments_ws!(call!(parse_data_label, input_len))) >>
+        ({
+            let mut data = first;
+            for labeled in rest {
+                data.extend(labeled);
+            }
+            data.extend(end_labels.into_iter().map(|(label, span)| Data::Label(label, span)));
+            data
+        })
+    ))
+);
 
-named_args!(parse_prop(input_len: usize)<Property>, comments_ws!(alt!(
+named_args!(parse_prop<'a>(input_len: usize, errors: Recovery<'a>)<Property>, comments_ws!(alt!(
     do_parse!(
         offset: map!(peek!(rest), |x: &[u8]| x.len()) >>
         tag!("/delete-property/") >>
-        name: map!(map_res!(take_while1!(is_prop_node_char), str::from_utf8), String::from) >>
-        char!(';') >>
-        ( Property::Deleted { name: name, offset: input_len - offset } )
+        name: return_error!(ErrorKind::Custom(ERR_DELETE_NAME), call!(parse_name, input_len)) >>
+        end: return_error!(ErrorKind::Custom(ERR_DELETE_NAME),
+                           call!(expect_char, b';', ERR_DELETE_NAME, input_len, None)) >>
+        ( Property::Deleted { name: name.0,
+                              name_span: name.1,
+                              span: Span::new(input_len - offset, end) } )
     ) |
     do_parse!(
         offset: map!(peek!(rest), |x: &[u8]| x.len()) >>
-        labels: many0!(terminated!(parse_label, char!(':'))) >>
-        name: map!(map_res!(take_while1!(is_prop_node_char), str::from_utf8), String::from) >>
+        labels: many0!(call!(parse_data_label, input_len)) >>
+        name: call!(parse_name, input_len) >>
         data: opt!(preceded!(
             char!('='),
-            separated_nonempty_list!(comments_ws!(char!(',')), parse_data))) >>
-        char!(';') >>
-        ( Property::Existing { name: name,
-                               val: data,
-                               labels: labels,
-                               offset: input_len - offset } )
+            return_error!(ErrorKind::Custom(ERR_PROP_DATA),
+                          call!(parse_prop_data, input_len, errors)))) >>
+        end: return_error!(ErrorKind::Custom(ERR_PROP_END),
+                           call!(expect_char, b';', ERR_PROP_END, input_len, None)) >>
+        ({
+            let (labels, label_spans) = labels.into_iter().unzip();
+            Property::Existing { name: name.0,
+                                 name_span: name.1,
+                                 val: data,
+                                 labels: labels,
+                                 label_spans: label_spans,
+                                 span: Span::new(input_len - offset, end) }
+        })
     )
 )));
 
-named_args!(parse_node(input_len: usize)<Node>, comments_ws!(alt!(
+named_args!(parse_node<'a>(input_len: usize, errors: Recovery<'a>)<Node>, comments_ws!(alt!(
     do_parse!(
         offset: map!(peek!(rest), |x: &[u8]| x.len()) >>
         tag!("/delete-node/") >>
-        name: map!(map_res!(take_while1!(is_prop_node_char), str::from_utf8), String::from) >>
-        char!(';') >>
-        ( Node::Deleted { name: NodeName::Full(name), offset: input_len - offset } )
+        name: return_error!(ErrorKind::Custom(ERR_DELETE_NAME), call!(parse_name, input_len)) >>
+        end: return_error!(ErrorKind::Custom(ERR_DELETE_NAME),
+                           call!(expect_char, b';', ERR_DELETE_NAME, input_len, None)) >>
+        ( Node::Deleted { name: NodeName::Full(name.0),
+                          name_span: name.1,
+                          span: Span::new(input_len - offset, end) } )
     ) |
     do_parse!(
         offset: map!(peek!(rest), |x: &[u8]| x.len()) >>
-        labels: many0!(terminated!(parse_label, char!(':'))) >>
-        name: map!(map_res!(alt!(
+        labels: many0!(call!(parse_data_label, input_len)) >>
+        omit: opt!(complete!(tag!("/omit-if-no-ref/"))) >>
+        more_labels: many0!(call!(parse_data_label, input_len)) >>
+        name: spanned!(input_len, map!(map_res!(alt!(
             take_while1!(is_prop_node_char) |
             tag!("/")
-        ), str::from_utf8), String::from) >>
+        ), str::from_utf8), String::from)) >>
         char!('{') >>
-        props: many0!(call!(parse_prop, input_len)) >>
-        subnodes: many0!(call!(parse_node, input_len)) >>
-        char!('}') >>
-        char!(';') >>
-        ( Node::Existing { name: NodeName::Full(name),
-                           proplist: props.into_iter()
-                                          .map(|p| (p.name().to_owned(), p))
-                                          .collect(),
-                           children: subnodes.into_iter()
-                                             .map(|n| (n.name().as_str().to_owned(), n))
-                                             .collect(),
-                           labels: labels,
-                           offset: input_len - offset } )
+        body: return_error!(ErrorKind::Custom(ERR_NODE_ITEM),
+                            call!(parse_node_body, input_len, errors)) >>
+        return_error!(ErrorKind::Custom(ERR_NODE_CLOSE),
+                      call!(expect_char, b'}', ERR_NODE_CLOSE, input_len, errors)) >>
+        end: return_error!(ErrorKind::Custom(ERR_NODE_END),
+                           call!(expect_char, b';', ERR_NODE_END, input_len, errors)) >>
+        ({
+            let (labels, label_spans) = labels.into_iter().chain(more_labels).unzip();
+            let (proplist, children, duplicates) = collect_body(body);
+            Node::Existing { name: NodeName::Full(name.0),
+                             name_span: name.1,
+                             proplist: proplist,
+                             children: children,
+                             duplicates: duplicates,
+                             labels: labels,
+                             label_spans: label_spans,
+                             omit_if_no_ref: omit.is_some(),
+                             span: Span::new(input_len - offset, end) }
+        })
     )
 )));
 
-named_args!(parse_amend(input_len: usize)<Node>, comments_ws!(alt!(
+/// Parses the properties and child nodes between the braces of a node. All
+/// properties must come before any child nodes.
+///
+/// Both a property and a node are attempted for each item. If neither parse,
+/// the error that got furthest into the input is returned as it is most
+/// likely to describe what the author intended. When recovering from errors,
+/// the error is recorded instead and the item is skipped.
+fn parse_node_body<'a>(input: &'a [u8], input_len: usize, errors: Recovery)
+                       -> IResult<&'a [u8], (Vec<Property>, Vec<Node>)> {
+    let mut props = Vec::new();
+    let mut nodes = Vec::new();
+    let mut buf = input;
+
+    loop {
+        if let IResult::Done(rem, _) = eat_junk(buf) {
+            buf = rem;
+        }
+
+        if buf.is_empty() || buf[0] == b'}' {
+            return IResult::Done(buf, (props, nodes));
+        }
+
+        let end = &buf[buf.len()..];
+
+        let prop_err = match parse_prop(buf, input_len, errors) {
+            IResult::Done(rem, prop) => {
+                if !nodes.is_empty() {
+                    match errors {
+                        Some(errors) => record_error(errors,
+                            SyntaxError::at(input_len, buf, Some(ERR_PROP_ORDER))),
+                        None => return IResult::Error(
+                            error_position!(ErrorKind::Custom(ERR_PROP_ORDER), buf)),
+                    }
+                }
+                props.push(prop);
+                buf = rem;
+                continue;
+            }
+            IResult::Error(err) => err,
+            IResult::Incomplete(needed) => match errors {
+                Some(errors) => {
+                    record_error(errors, SyntaxError::at(input_len, end, Some(ERR_EOF)));
+                    return IResult::Done(end, (props, nodes));
+                }
+                None => return IResult::Incomplete(needed),
+            },
+        };
+
+        let node_err = match parse_node(buf, input_len, errors) {
+            IResult::Done(rem, node) => {
+                nodes.push(node);
+                buf = rem;
+                continue;
+            }
+            IResult::Error(err) => err,
+            IResult::Incomplete(needed) => match errors {
+                Some(errors) => {
+                    record_error(errors, SyntaxError::at(input_len, end, Some(ERR_EOF)));
+                    return IResult::Done(end, (props, nodes));
+                }
+                None => return IResult::Incomplete(needed),
+            },
+        };
+
+        let err = if remaining_len(&prop_err) <= remaining_len(&node_err) {
+            prop_err
+        } else {
+            node_err
+        };
+        let err = if remaining_len(&err) >= buf.len() {
+            error_position!(ErrorKind::Custom(ERR_NODE_ITEM), buf)
+        } else {
+            err
+        };
+
+        match errors {
+            Some(errors) => {
+                record_error(errors, SyntaxError::from_nom(input_len, buf, &err));
+                buf = skip_statement(buf);
+            }
+            None => return IResult::Error(err),
+        }
+    }
+}
+
+// Collects the items of a node body by name. A later definition of a name
+// replaces the earlier one, which is kept as a duplicate so that it is not
+// silently lost.
+fn collect_body((props, nodes): (Vec<Property>, Vec<Node>))
+                -> (OrderedMap<Property>, OrderedMap<Node>, Vec<Duplicate>) {
+    let mut duplicates = Vec::new();
+
+    let mut proplist = OrderedMap::new();
+    for prop in props {
+        if let Some(old) = proplist.insert(prop.name().to_owned(), prop) {
+            duplicates.push(Duplicate::Property(old));
+        }
+    }
+
+    let mut children = OrderedMap::new();
+    for node in nodes {
+        if let Some(old) = children.insert(node.name().as_str().to_owned(), node) {
+            duplicates.push(Duplicate::Node(old));
+        }
+    }
+
+    (proplist, children, duplicates)
+}
+
+named_args!(parse_amend<'a>(input_len: usize, errors: Recovery<'a>)<Node>, comments_ws!(alt!(
     do_parse!(
         offset: map!(peek!(rest), |x: &[u8]| x.len()) >>
         tag!("/delete-node/") >>
-        name: parse_ref >>
-        char!(';') >>
-        ( Node::Deleted { name: NodeName::Ref(name), offset: input_len - offset } )
+        name: return_error!(ErrorKind::Custom(ERR_DELETE_NAME), spanned!(input_len, parse_ref)) >>
+        end: return_error!(ErrorKind::Custom(ERR_DELETE_NAME),
+                           call!(expect_char, b';', ERR_DELETE_NAME, input_len, None)) >>
+        ( Node::Deleted { name: NodeName::Ref(name.0),
+                          name_span: name.1,
+                          span: Span::new(input_len - offset, end) } )
     ) |
+    // Only marks the referenced node, so is kept as an amendment with no body
     do_parse!(
         offset: map!(peek!(rest), |x: &[u8]| x.len()) >>
-        labels: many0!(terminated!(parse_label, char!(':'))) >>
-        name: alt!(
+        tag!("/omit-if-no-ref/") >>
+        name: return_error!(ErrorKind::Custom(ERR_OMIT_REF), spanned!(input_len, parse_ref)) >>
+        end: return_error!(ErrorKind::Custom(ERR_OMIT_REF),
+                           call!(expect_char, b';', ERR_OMIT_REF, input_len, None)) >>
+        ( Node::Existing { name: NodeName::Ref(name.0),
+                           name_span: name.1,
+                           proplist: OrderedMap::new(),
+                           children: OrderedMap::new(),
+                           duplicates: Vec::new(),
+                           labels: Vec::new(),
+                           label_spans: Vec::new(),
+                           omit_if_no_ref: true,
+                           span: Span::new(input_len - offset, end) } )
+    ) |
+    do_parse!(
+        offset: map!(peek!(rest), |x: &[u8]| x.len()) >>
+        labels: many0!(call!(parse_data_label, input_len)) >>
+        name: spanned!(input_len, alt!(
             map!(map!(map_res!(tag!("/"), str::from_utf8), String::from), |x| NodeName::Full(x)) |
             map!(parse_ref, |x| NodeName::Ref(x))
-        ) >>
+        )) >>
         char!('{') >>
-        props: many0!(call!(parse_prop, input_len)) >>
-        subnodes: many0!(call!(parse_node, input_len)) >>
-        char!('}') >>
-        char!(';') >>
-        ( Node::Existing { name: name,
-                           proplist: props.into_iter()
-                                          .map(|p| (p.name().to_owned(), p))
-                                          .collect(),
-                           children: subnodes.into_iter()
-                                             .map(|n| (n.name().as_str().to_owned(), n))
-                                             .collect(),
-                           labels: labels,
-                           offset: input_len - offset } )
+        body: return_error!(ErrorKind::Custom(ERR_NODE_ITEM),
+                            call!(parse_node_body, input_len, errors)) >>
+        return_error!(ErrorKind::Custom(ERR_NODE_CLOSE),
+                      call!(expect_char, b'}', ERR_NODE_CLOSE, input_len, errors)) >>
+        end: return_error!(ErrorKind::Custom(ERR_NODE_END),
+                           call!(expect_char, b';', ERR_NODE_END, input_len, errors)) >>
+        ({
+            let (labels, label_spans) = labels.into_iter().unzip();
+            let (proplist, children, duplicates) = collect_body(body);
+            Node::Existing { name: name.0,
+                             name_span: name.1,
+                             proplist: proplist,
+                             children: children,
+                             duplicates: duplicates,
+                             labels: labels,
+                             label_spans: label_spans,
+                             omit_if_no_ref: false,
+                             span: Span::new(input_len - offset, end) }
+        })
     )
 )));
 
-named_args!(parse_device_tree(input_len: usize)<Node>,
-            comments_ws!(preceded!(peek!(char!('/')), call!(parse_node, input_len))));
+named_args!(parse_device_tree<'a>(input_len: usize, errors: Recovery<'a>)<Node>,
+            comments_ws!(preceded!(peek!(char!('/')), call!(parse_node, input_len, errors))));
 
-named_args!(parse_dts(input_len: usize)<(DTInfo, Vec<Node>)>, comments_ws!(do_parse!(
-    tag!("/dts-v1/;") >>
-    mem_reserves: many0!(parse_mem_reserve) >>
-    device_tree: call!(parse_device_tree, input_len) >>
-    amendments: many0!(call!(parse_amend, input_len)) >>
-    // TODO: set boot cpu id - issue 8
-    (DTInfo { reserve_info: mem_reserves, root: device_tree, boot_cpuid: 0 }, amendments)
-)));
+named!(parse_plugin<()>, comments_ws!(do_parse!(tag!("/plugin/") >> char!(';') >> ())));
+
+// An included file may have a header of its own, so like dtc `/dts-v1/;` may
+// be repeated as long as every header agrees on whether the source is an
+// overlay.
+fn parse_extra_headers<'a>(input: &'a [u8], input_len: usize, plugin: bool, errors: Recovery)
+                           -> IResult<&'a [u8], ()> {
+    let mut buf = input;
+    loop {
+        let (cleaned, _) = try_parse!(buf, eat_junk);
+        let rem = match tag!(cleaned, "/dts-v1/;") {
+            IResult::Done(rem, _) => rem,
+            _ => return IResult::Done(buf, ()),
+        };
+        let (rem, found) = try_parse!(rem, opt!(complete!(parse_plugin)));
+        if found.is_some() != plugin {
+            match errors {
+                Some(errors) => {
+                    record_error(errors,
+                                 SyntaxError::at(input_len, cleaned, Some(ERR_HEADER_FLAGS)))
+                }
+                None => {
+                    return IResult::Error(error_position!(ErrorKind::Custom(ERR_HEADER_FLAGS),
+                                                          cleaned))
+                }
+            }
+        }
+        buf = rem;
+    }
+}
+
+// An overlay has no root node, only amendments. The root node is `None` if and
+// only if the source is an overlay.
+named_args!(parse_dts(input_len: usize)<(Vec<ReserveInfo>, Option<Node>, Vec<Node>)>,
+    comments_ws!(do_parse!(
+        return_error!(ErrorKind::Custom(ERR_HEADER), tag!("/dts-v1/;")) >>
+        plugin: opt!(complete!(parse_plugin)) >>
+        call!(parse_extra_headers, input_len, plugin.is_some(), None) >>
+        mem_reserves: many0!(call!(parse_mem_reserve, input_len, None)) >>
+        device_tree: cond_with_error!(plugin.is_none(),
+            return_error!(ErrorKind::Custom(ERR_ROOT_NODE),
+                          call!(parse_device_tree, input_len, None))) >>
+        amendments: many0!(call!(parse_amend, input_len, None)) >>
+        (mem_reserves, device_tree, amendments)
+    ))
+);
 
 /// Returned on a successful completion of `parse_dt`.
 #[derive(Debug)]
@@ -649,7 +1185,14 @@ pub enum ParseResult<'a> {
     /// following nodes, and a slice containing the remainder of the buffer.
     /// Having left over output after parsing is generally not expected and in
     /// most cases should be considered an error.
-    RemainingInput(DTInfo, Vec<Node>, &'a [u8])
+    RemainingInput(DTInfo, Vec<Node>, &'a [u8]),
+    /// Indicates that the source was an overlay, starting with `/plugin/;`, and
+    /// the entirety of the buffer was used while parsing.
+    Overlay(Overlay),
+    /// Indicates that the source was an overlay and only part of the buffer
+    /// was used while parsing. Holds the overlay and a slice containing the
+    /// remainder of the buffer, see `RemainingInput`.
+    OverlayRemainingInput(Overlay, &'a [u8]),
 }
 
 /// Parses the slice of `u8`s as ASCII characters and returns a device tree made
@@ -664,47 +1207,228 @@ pub enum ParseResult<'a> {
 /// `ParseResult::RemainingInput` is returned with the tree, following nodes,
 /// and a slice of the remaining input.
 ///
+/// If the `/dts-v1/;` header is followed by `/plugin/;` the source is parsed as
+/// an overlay and `ParseResult::Overlay` or
+/// `ParseResult::OverlayRemainingInput` is returned instead. An overlay does
+/// not need a root node and may consist solely of amendments to labels that
+/// are defined in the base tree the overlay will be applied to.
+///
 /// # Errors
-/// Returns `ParseError::IncompleteInput` if the end of the input was reached
-/// where more was expected.
-/// Returns `ParseError::NomError` if a `nom` parsing error was returned. This
-/// doesn't help much right now, but will be improved soon.
+/// Returns `ParseError::Syntax` if the tree could not be parsed. The contained
+/// `SyntaxError` holds the offset into `source` where the error was found, a
+/// description of what was expected, and the offending input. The end of the
+/// input being reached where more was expected is also reported this way.
 pub fn parse_dt(source: &[u8]) -> Result<ParseResult, ParseError> {
     match parse_dts(source, source.len()) {
-        IResult::Done(remaining, (tree, amends)) => {
+        IResult::Done(remaining, (reserve_info, Some(root), amends)) => {
+            let tree = DTInfo::new(reserve_info, root);
             if remaining.is_empty() {
                 Ok(ParseResult::Complete(tree, amends))
             } else {
                 Ok(ParseResult::RemainingInput(tree, amends, remaining))
             }
         }
-        IResult::Incomplete(_) => Err(ParseError::IncompleteInput),
-        IResult::Error(_err) => {
-            // TODO: specific error messages - issue 4
-            // println!("{:?}", _err);
-            Err(ParseError::NomError)
+        IResult::Done(remaining, (reserve_info, None, amends)) => {
+            let overlay = Overlay { reserve_info: reserve_info, amends: amends };
+            if remaining.is_empty() {
+                Ok(ParseResult::Overlay(overlay))
+            } else {
+                Ok(ParseResult::OverlayRemainingInput(overlay, remaining))
+            }
+        }
+        IResult::Incomplete(_) => {
+            Err(ParseError::Syntax(SyntaxError::at(source.len(), &[], Some(ERR_EOF))))
+        }
+        IResult::Error(err) => {
+            Err(ParseError::Syntax(SyntaxError::from_nom(source.len(), source, &err)))
+        }
+    }
+}
+
+// Parses a base tree that is expected to be complete, for the tests of the
+// modules that work on parsed trees
+#[cfg(test)]
+pub(crate) fn parse_base(source: &[u8]) -> (DTInfo, Vec<Node>) {
+    match parse_dt(source) {
+        Ok(ParseResult::Complete(tree, amends)) => (tree, amends),
+        x => panic!("unexpected result: {:?}", x),
+    }
+}
+
+/// Returns true if the source starts with the `/dts-v1/;` and `/plugin/;`
+/// headers of an overlay. Useful for choosing between `parse_dt_recover` and
+/// `parse_overlay_recover`.
+pub fn is_overlay(source: &[u8]) -> bool {
+    named!(header, comments_ws!(preceded!(tag!("/dts-v1/;"), recognize!(parse_plugin))));
+    header(source).is_done()
+}
+
+/// Parses the slice of `u8`s like `parse_dt`, but instead of stopping at the
+/// first syntax error, records the error and continues parsing. Returns the
+/// device tree, the nodes defined after the root node, and every syntax error
+/// found, in the order they appear in `source`.
+///
+/// After an error, parsing resumes at the end of the broken statement, either
+/// after the next `;` or the `};` closing the broken node. Everything that was
+/// successfully parsed is kept, so a single broken node does not prevent the
+/// rest of the tree from being used. A missing `}` or `;` at the end of a node
+/// is reported, but the node is still kept. An expression that overflows is
+/// also reported at its operator, and the wrapped value is kept as dtc does.
+///
+/// If no root node could be parsed, the returned tree will have an empty root
+/// node and the error will be included in the returned errors. Overlays are
+/// reported as an error, see `parse_overlay_recover` for parsing them.
+pub fn parse_dt_recover(source: &[u8]) -> (DTInfo, Vec<Node>, Vec<SyntaxError>) {
+    let (mem_reserves, root, amends, errors) = recover(source, false);
+
+    let root = root.unwrap_or_else(|| Node::Existing {
+        name: NodeName::Full("/".to_owned()),
+        name_span: Span::default(),
+        proplist: OrderedMap::new(),
+        children: OrderedMap::new(),
+        duplicates: Vec::new(),
+        labels: Vec::new(),
+        label_spans: Vec::new(),
+        omit_if_no_ref: false,
+        span: Span::default(),
+    });
+
+    (DTInfo::new(mem_reserves, root), amends, errors)
+}
+
+/// Parses an overlay like `parse_dt_recover`, recording every syntax error
+/// instead of stopping at the first one. A missing `/plugin/;` header is
+/// reported, but the source is still parsed as an overlay.
+pub fn parse_overlay_recover(source: &[u8]) -> (Overlay, Vec<SyntaxError>) {
+    let (mem_reserves, _, amends, errors) = recover(source, true);
+    (Overlay { reserve_info: mem_reserves, amends: amends }, errors)
+}
+
+fn recover(source: &[u8], plugin: bool)
+           -> (Vec<ReserveInfo>, Option<Node>, Vec<Node>, Vec<SyntaxError>) {
+    let input_len = source.len();
+    let errors = RefCell::new(Vec::new());
+    let mut buf = source;
+
+    if let IResult::Done(rem, _) = eat_junk(buf) {
+        buf = rem;
+    }
+    match tag!(buf, "/dts-v1/;") {
+        IResult::Done(rem, _) => buf = rem,
+        _ => record_error(&errors, SyntaxError::at(input_len, buf, Some(ERR_HEADER))),
+    }
+    match (parse_plugin(buf), plugin) {
+        (IResult::Done(rem, _), true) => buf = rem,
+        (IResult::Done(rem, _), false) => {
+            record_error(&errors, SyntaxError::at(input_len, buf, Some(ERR_OVERLAY)));
+            buf = rem;
+        }
+        (_, true) => record_error(&errors, SyntaxError::at(input_len, buf, Some(ERR_PLUGIN))),
+        (_, false) => {}
+    }
+    if let IResult::Done(rem, _) = parse_extra_headers(buf, input_len, plugin, Some(&errors)) {
+        buf = rem;
+    }
+
+    let mut mem_reserves = Vec::new();
+    let mut root = None;
+    let mut amends = Vec::new();
+
+    loop {
+        if let IResult::Done(rem, _) = eat_junk(buf) {
+            buf = rem;
+        }
+        if buf.is_empty() {
+            break;
+        }
+
+        // Reserved memory must come before the root node. Any found later are
+        // reported but still kept.
+        if let IResult::Done(rem, reserve) = parse_mem_reserve(buf, input_len, Some(&errors)) {
+            if root.is_some() || !amends.is_empty() {
+                record_error(&errors, SyntaxError::at(input_len, buf, Some(ERR_MEMRESERVE_ORDER)));
+            }
+            mem_reserves.push(reserve);
+            buf = rem;
+            continue;
+        }
+
+        // The root node must come first, followed by amendments to it. An
+        // overlay only has amendments.
+        let (res, code) = if root.is_none() && amends.is_empty() {
+            if plugin {
+                (parse_amend(buf, input_len, Some(&errors)), ERR_AMEND)
+            } else {
+                (parse_device_tree(buf, input_len, Some(&errors)), ERR_ROOT_NODE)
+            }
+        } else {
+            (parse_amend(buf, input_len, Some(&errors)), ERR_AMEND)
+        };
+
+        match res {
+            IResult::Done(rem, node) => {
+                if root.is_none() && !plugin {
+                    root = Some(node);
+                } else {
+                    amends.push(node);
+                }
+                buf = rem;
+            }
+            IResult::Error(err) => {
+                let err = if remaining_len(&err) >= buf.len() {
+                    SyntaxError::at(input_len, buf, Some(code))
+                } else {
+                    SyntaxError::from_nom(input_len, buf, &err)
+                };
+                record_error(&errors, err);
+                buf = skip_statement(buf);
+            }
+            IResult::Incomplete(_) => {
+                record_error(&errors, SyntaxError::at(input_len, &[], Some(ERR_EOF)));
+                break;
+            }
         }
     }
+
+    (mem_reserves, root, amends, errors.into_inner())
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
     use nom::IResult;
+    use tree::{Labeled, Spanned};
+
+    fn expr(value: u64, text: &str, start: usize) -> Cell {
+        Cell::Expr {
+            value: value,
+            text: text.to_owned(),
+            macro_name: None,
+            span: Span::new(start, start + text.len()),
+        }
+    }
+
+    // Builds an existing property with no labels that spans all of `input`.
+    fn prop(input: &[u8], name: &str, val: Option<Vec<Data>>) -> Property {
+        let name_start = input.windows(name.len()).position(|w| w == name.as_bytes()).unwrap();
+        Property::Existing {
+            name: name.to_owned(),
+            name_span: Span::new(name_start, name_start + name.len()),
+            val: val,
+            labels: Vec::new(),
+            label_spans: Vec::new(),
+            span: Span::new(0, input.len()),
+        }
+    }
 
     #[test]
     fn prop_empty() {
         let input = b"empty_prop;";
         assert_eq!(
-            parse_prop(input, input.len()),
+            parse_prop(input, input.len(), None),
             IResult::Done(
                 &b""[..],
-                Property::Existing {
-                    name: "empty_prop".to_owned(),
-                    val: None,
-                    labels: Vec::new(),
-                    offset: 0,
-                }
+                prop(input, "empty_prop", None)
             )
         );
     }
@@ -713,15 +1437,14 @@ mod tests {
     fn prop_cells() {
         let input = b"cell_prop = < 1 2 10 >;";
         assert_eq!(
-            parse_prop(input, input.len()),
+            parse_prop(input, input.len(), None),
             IResult::Done(
                 &b""[..],
-                Property::Existing {
-                    name: "cell_prop".to_owned(),
-                    val: Some(vec![Data::Cells(32, vec![Cell::Num(1), Cell::Num(2), Cell::Num(10)])]),
-                    labels: Vec::new(),
-                    offset: 0,
-                }
+                prop(input, "cell_prop", Some(vec![Data::Cells(32, vec![
+                        expr(1, "1", 14),
+                        expr(2, "2", 16),
+                        expr(10, "10", 18)
+                    ], Span::new(12, 22))]))
             )
         );
     }
@@ -730,18 +1453,13 @@ mod tests {
     fn prop_strings() {
         let input = b"string_prop = \"string\", \"string2\";";
         assert_eq!(
-            parse_prop(input, input.len()),
+            parse_prop(input, input.len(), None),
             IResult::Done(
                 &b""[..],
-                Property::Existing {
-                    name: "string_prop".to_owned(),
-                    val: Some(vec![
-                            Data::String("string".to_owned()),
-                            Data::String("string2".to_owned())
-                         ]),
-                    labels: Vec::new(),
-                    offset: 0,
-                }
+                prop(input, "string_prop", Some(vec![
+                            Data::String("string".to_owned(), Span::new(14, 22)),
+                            Data::String("string2".to_owned(), Span::new(24, 33))
+                         ]))
             )
         );
     }
@@ -750,15 +1468,11 @@ mod tests {
     fn prop_bytes() {
         let input = b"bytes_prop = [1234 56 78];";
         assert_eq!(
-            parse_prop(input, input.len()),
+            parse_prop(input, input.len(), None),
             IResult::Done(
                 &b""[..],
-                Property::Existing {
-                    name: "bytes_prop".to_owned(),
-                    val: Some(vec![Data::ByteArray(vec![0x12, 0x34, 0x56, 0x78])]),
-                    labels: Vec::new(),
-                    offset: 0,
-                }
+                prop(input, "bytes_prop", Some(vec![Data::ByteArray(vec![0x12, 0x34, 0x56, 0x78],
+                                                                    Span::new(13, 25))]))
             )
         );
     }
@@ -767,19 +1481,18 @@ mod tests {
     fn prop_mixed() {
         let input = b"mixed_prop = \"abc\", [1234], <0xa 0xb 0xc>;";
         assert_eq!(
-            parse_prop(input, input.len()),
+            parse_prop(input, input.len(), None),
             IResult::Done(
                 &b""[..],
-                Property::Existing {
-                    name: "mixed_prop".to_owned(),
-                    val: Some(vec![
-                        Data::String("abc".to_owned()),
-                        Data::ByteArray(vec![0x12, 0x34]),
-                        Data::Cells(32, vec![Cell::Num(0xa), Cell::Num(0xb), Cell::Num(0xc)])
-                    ]),
-                    labels: Vec::new(),
-                    offset: 0,
-                }
+                prop(input, "mixed_prop", Some(vec![
+                        Data::String("abc".to_owned(), Span::new(13, 18)),
+                        Data::ByteArray(vec![0x12, 0x34], Span::new(20, 26)),
+                        Data::Cells(32, vec![
+                            expr(0xa, "0xa", 29),
+                            expr(0xb, "0xb", 33),
+                            expr(0xc, "0xc", 37)
+                        ], Span::new(28, 41))
+                    ]))
             )
         );
     }
@@ -788,15 +1501,14 @@ mod tests {
     fn block_comment() {
         let input = b"test_prop /**/ = < 1 2 10 >;";
         assert_eq!(
-            parse_prop(input, input.len()),
+            parse_prop(input, input.len(), None),
             IResult::Done(
                 &b""[..],
-                Property::Existing {
-                    name: "test_prop".to_owned(),
-                    val: Some(vec![Data::Cells(32, vec![Cell::Num(1), Cell::Num(2), Cell::Num(10)])]),
-                    labels: Vec::new(),
-                    offset: 0,
-                }
+                prop(input, "test_prop", Some(vec![Data::Cells(32, vec![
+                        expr(1, "1", 19),
+                        expr(2, "2", 21),
+                        expr(10, "10", 23)
+                    ], Span::new(17, 27))]))
             )
         );
     }
@@ -805,106 +1517,127 @@ mod tests {
     fn line_comment() {
         let input = b"test_prop // stuff\n\t= < 1 2 10 >;";
         assert_eq!(
-            parse_prop(input, input.len()),
+            parse_prop(input, input.len(), None),
             IResult::Done(
                 &b""[..],
-                Property::Existing {
-                    name: "test_prop".to_owned(),
-                    val: Some(vec![Data::Cells(32, vec![Cell::Num(1), Cell::Num(2), Cell::Num(10)])]),
-                    labels: Vec::new(),
-                    offset: 0,
-                }
+                prop(input, "test_prop", Some(vec![Data::Cells(32, vec![
+                        expr(1, "1", 24),
+                        expr(2, "2", 26),
+                        expr(10, "10", 28)
+                    ], Span::new(22, 32))]))
             )
         );
     }
 
     #[test]
     fn data_string_pain() {
+        let input = b"\"\\x7f\\0stuffstuff\\t\\t\\t\\n\\n\\n\"";
         assert_eq!(
-            parse_data(b"\"\\x7f\\0stuffstuff\\t\\t\\t\\n\\n\\n\""),
+            parse_data(input, input.len(), None),
             IResult::Done(
                 &b""[..],
-                Data::String("\x7f\0stuffstuff\t\t\t\n\n\n".to_owned())
+                Data::String("\x7f\0stuffstuff\t\t\t\n\n\n".to_owned(),
+                             Span::new(0, input.len()))
             )
         );
     }
 
     #[test]
     fn data_string_empty() {
+        let input = b"\"\"";
         assert_eq!(
-            parse_data(b"\"\""),
+            parse_data(input, input.len(), None),
             IResult::Done(
                 &b""[..],
-                Data::String("".to_owned())
+                Data::String("".to_owned(), Span::new(0, input.len()))
             )
         );
     }
 
     #[test]
     fn data_cell_sized_8_escapes() {
+        let input = b"/bits/ 8 <'\\r' 'b' '\\0' '\\'' '\\xff' 0xde>";
         assert_eq!(
-            parse_data(b"/bits/ 8 <'\\r' 'b' '\\0' '\\'' '\\xff' 0xde>"),
+            parse_data(input, input.len(), None),
             IResult::Done(&b""[..], Data::Cells(8, vec![
-                Cell::Num(b'\r' as u64), Cell::Num(b'b' as u64), Cell::Num(0),
-                Cell::Num(b'\'' as u64), Cell::Num(0xFF), Cell::Num(0xDE)
-            ]))
+                expr(b'\r' as u64, "'\\r'", 10), expr(b'b' as u64, "'b'", 15), expr(0, "'\\0'", 19),
+                expr(b'\'' as u64, "'\\''", 24), expr(0xFF, "'\\xff'", 29), expr(0xDE, "0xde", 36)
+            ], Span::new(0, input.len())))
         );
     }
 
     #[test]
     fn data_cell_sized_16_escapes() {
+        let input = b"/bits/ 16 <'\\r' 'b' '\\0' '\\'' '\\xff' 0xdead>";
         assert_eq!(
-            parse_data(b"/bits/ 16 <'\\r' 'b' '\\0' '\\'' '\\xff' 0xdead>"),
+            parse_data(input, input.len(), None),
             IResult::Done(&b""[..], Data::Cells(16, vec![
-                Cell::Num(b'\r' as u64), Cell::Num(b'b' as u64), Cell::Num(0),
-                Cell::Num(b'\'' as u64), Cell::Num(0xFF), Cell::Num(0xDEAD)
-            ]))
+                expr(b'\r' as u64, "'\\r'", 11), expr(b'b' as u64, "'b'", 16), expr(0, "'\\0'", 20),
+                expr(b'\'' as u64, "'\\''", 25), expr(0xFF, "'\\xff'", 30),
+                expr(0xDEAD, "0xdead", 37)
+            ], Span::new(0, input.len())))
         );
     }
 
     #[test]
     fn data_cell_sized_32_escapes() {
+        let input = b"/bits/ 32 <'\\r' 'b' '\\0' '\\'' '\\xff' 0xdeadbeef>";
         assert_eq!(
-            parse_data(b"/bits/ 32 <'\\r' 'b' '\\0' '\\'' '\\xff' 0xdeadbeef>"),
+            parse_data(input, input.len(), None),
             IResult::Done(&b""[..], Data::Cells(32, vec![
-                Cell::Num(b'\r' as u64), Cell::Num(b'b' as u64), Cell::Num(0),
-                Cell::Num(b'\'' as u64), Cell::Num(0xFF), Cell::Num(0xDEADBEEF)
-            ]))
+                expr(b'\r' as u64, "'\\r'", 11), expr(b'b' as u64, "'b'", 16), expr(0, "'\\0'", 20),
+                expr(b'\'' as u64, "'\\''", 25), expr(0xFF, "'\\xff'", 30),
+                expr(0xDEADBEEF, "0xdeadbeef", 37)
+            ], Span::new(0, input.len())))
         );
     }
 
     #[test]
     fn data_cell_sized_64_escapes() {
+        let input = b"/bits/ 64 <'\\r' 'b' '\\0' '\\'' '\\xff' 0xdeadbeef00000000>";
         assert_eq!(
-            parse_data(b"/bits/ 64 <'\\r' 'b' '\\0' '\\'' '\\xff' 0xdeadbeef00000000>"),
+            parse_data(input, input.len(), None),
             IResult::Done(&b""[..], Data::Cells(64, vec![
-                Cell::Num(b'\r' as u64), Cell::Num(b'b' as u64), Cell::Num(0),
-                Cell::Num(b'\'' as u64), Cell::Num(0xFF), Cell::Num(0xDEADBEEF00000000)
-            ]))
+                expr(b'\r' as u64, "'\\r'", 11), expr(b'b' as u64, "'b'", 16), expr(0, "'\\0'", 20),
+                expr(b'\'' as u64, "'\\''", 25), expr(0xFF, "'\\xff'", 30),
+                expr(0xDEADBEEF00000000, "0xdeadbeef00000000", 37)
+            ], Span::new(0, input.len())))
         );
     }
 
     #[test]
     fn data_cell_sized_default() {
+        let input = b"<0x12345678 0x0000ffff>";
         assert_eq!(
-            parse_data(b"<0x12345678 0x0000ffff>"),
-            IResult::Done(&b""[..], Data::Cells(32, vec![Cell::Num(0x12345678), Cell::Num(0x0000FFFF)]))
+            parse_data(input, input.len(), None),
+            IResult::Done(&b""[..], Data::Cells(32, vec![
+                expr(0x12345678, "0x12345678", 1),
+                expr(0x0000FFFF, "0x0000ffff", 12)
+            ], Span::new(0, input.len())))
         );
     }
 
     #[test]
     fn data_cell_sized_16() {
+        let input = b"/bits/ 16 <0x1234 0x5678 0x0 0xffff>";
         assert_eq!(
-            parse_data(b"/bits/ 16 <0x1234 0x5678 0x0 0xffff>"),
+            parse_data(input, input.len(), None),
             IResult::Done(&b""[..], Data::Cells(16,
-                vec![Cell::Num(0x1234), Cell::Num(0x5678), Cell::Num(0), Cell::Num(0xFFFF)]
+                vec![
+                    expr(0x1234, "0x1234", 11),
+                    expr(0x5678, "0x5678", 18),
+                    expr(0, "0x0", 25),
+                    expr(0xFFFF, "0xffff", 29)
+                ],
+                Span::new(0, input.len())
             ))
         );
     }
 
     #[test]
     fn data_cell_sized_incorrect() {
-        match parse_data(b"/bits/ 16 <0x12345678 0x0000ffff>") {
+        let input = b"/bits/ 16 <0x12345678 0x0000ffff>";
+        match parse_data(input, input.len(), None) {
             IResult::Error(_) => {},
             x => panic!(format!("parse_data did not return error: {:?}", x)),
         }
@@ -912,7 +1645,8 @@ mod tests {
 
     #[test]
     fn data_cell_sized_incorrect_ref() {
-        match parse_data(b"/bits/ 16 <&ref>") {
+        let input = b"/bits/ 16 <&ref>";
+        match parse_data(input, input.len(), None) {
             IResult::Error(_) => {},
             x => panic!(format!("parse_data did not return error: {:?}", x)),
         }
@@ -920,17 +1654,21 @@ mod tests {
 
     #[test]
     fn data_cell_ref() {
+        let input = b"<&ref>";
         assert_eq!(
-            parse_data(b"<&ref>"),
-            IResult::Done(&b""[..], Data::Cells(32, vec![Cell::Ref("ref".to_owned(), None)]))
+            parse_data(input, input.len(), None),
+            IResult::Done(&b""[..], Data::Cells(32, vec![
+                Cell::Ref("ref".to_owned(), None, Span::new(1, 5))
+            ], Span::new(0, input.len())))
         );
     }
 
     #[test]
     fn data_cell_empty() {
+        let input = b"<>";
         assert_eq!(
-            parse_data(b"<>"),
-            IResult::Done(&b""[..], Data::Cells(32, Vec::new()))
+            parse_data(input, input.len(), None),
+            IResult::Done(&b""[..], Data::Cells(32, Vec::new(), Span::new(0, input.len())))
         );
     }
 
@@ -984,17 +1722,396 @@ mod tests {
 
     #[test]
     fn math_cell_1() {
+        let input = b"< ((((0x910)) & 0xffff) - (0x800)) >";
         assert_eq!(
-            parse_data(b"< ((((0x910)) & 0xffff) - (0x800)) >"),
-            IResult::Done(&b""[..], Data::Cells(32, vec![Cell::Num(272)]))
+            parse_data(input, input.len(), None),
+            IResult::Done(&b""[..], Data::Cells(32, vec![
+                expr(272, "((((0x910)) & 0xffff) - (0x800))", 2)
+            ], Span::new(0, input.len())))
         );
     }
 
     #[test]
     fn math_cell_2() {
+        let input = b"< ((((0x910)) & 0xffff) - (0x800)) (0 | 3) >";
+        assert_eq!(
+            parse_data(input, input.len(), None),
+            IResult::Done(&b""[..], Data::Cells(32, vec![
+                expr(272, "((((0x910)) & 0xffff) - (0x800))", 2),
+                expr(3, "(0 | 3)", 35)
+            ], Span::new(0, input.len())))
+        );
+    }
+
+    #[test]
+    fn error_unterminated_cells() {
+        let input = b"/dts-v1/;\n/ {\n\tfoo = <1 2;\n};\n";
+        assert_eq!(
+            parse_dt(input).err(),
+            Some(ParseError::Syntax(SyntaxError {
+                offset: 25,
+                message: "unterminated cell list".to_owned(),
+                snippet: ";".to_owned(),
+            }))
+        );
+    }
+
+    #[test]
+    fn error_prop_semicolon() {
+        let input = b"/dts-v1/;\n/ {\n\tnode {\n\t\tfoo = \"bar\"\n\t};\n};\n";
+        assert_eq!(
+            parse_dt(input).err(),
+            Some(ParseError::Syntax(SyntaxError {
+                offset: 37,
+                message: "expected ';' after property".to_owned(),
+                snippet: "};".to_owned(),
+            }))
+        );
+    }
+
+    #[test]
+    fn error_prop_after_node() {
+        let input = b"/dts-v1/;\n/ {\n\tnode { };\n\tfoo;\n};\n";
+        assert_eq!(
+            parse_dt(input).err(),
+            Some(ParseError::Syntax(SyntaxError {
+                offset: 26,
+                message: "property defined after child nodes".to_owned(),
+                snippet: "foo;".to_owned(),
+            }))
+        );
+    }
+
+    #[test]
+    fn recover_broken_prop() {
+        let input = b"/dts-v1/;\n/ {\n\tfoo = <1 2;\n\tbar = <3>;\n\tnode { };\n};\n";
+        let (tree, amends, errors) = parse_dt_recover(input);
+        assert_eq!(
+            errors,
+            vec![SyntaxError {
+                offset: 25,
+                message: "unterminated cell list".to_owned(),
+                snippet: ";".to_owned(),
+            }]
+        );
+        assert!(amends.is_empty());
+        match tree.root {
+            Node::Existing { ref proplist, ref children, .. } => {
+                assert!(!proplist.contains_key("foo"));
+                assert!(proplist.contains_key("bar"));
+                assert!(children.contains_key("node"));
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn recover_multiple_errors() {
+        let input = b"/dts-v1/;\n/ {\n\tn1 { a = ; }\n\tn2 { };\n};\n&x { b = <1; };\n&y { };\n";
+        let (tree, amends, errors) = parse_dt_recover(input);
         assert_eq!(
-            parse_data(b"< ((((0x910)) & 0xffff) - (0x800)) (0 | 3) >"),
-            IResult::Done(&b""[..], Data::Cells(32, vec![Cell::Num(272), Cell::Num(3)]))
+            errors.iter().map(|e| (e.offset, e.message.as_str())).collect::<Vec<_>>(),
+            vec![(24, "expected property data after '='"),
+                 (29, "expected ';' after node"),
+                 (51, "unterminated cell list")]
         );
+        match tree.root {
+            Node::Existing { ref children, .. } => {
+                assert!(children.contains_key("n1"));
+                assert!(children.contains_key("n2"));
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(amends.len(), 2);
+        match amends[0] {
+            Node::Existing { ref name, ref proplist, .. } => {
+                assert_eq!(name, &NodeName::Ref("x".to_owned()));
+                assert!(proplist.is_empty());
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(amends[1].name(), &NodeName::Ref("y".to_owned()));
+    }
+
+    #[test]
+    fn data_cell_labels() {
+        let input = b"<0x0 start: 0x1000 end:>";
+        assert_eq!(
+            parse_data(input, input.len(), None),
+            IResult::Done(&b""[..], Data::Cells(32, vec![
+                expr(0, "0x0", 1),
+                Cell::Label("start".to_owned(), Span::new(5, 11)),
+                expr(0x1000, "0x1000", 12),
+                Cell::Label("end".to_owned(), Span::new(19, 23)),
+            ], Span::new(0, input.len())))
+        );
+    }
+
+    #[test]
+    fn prop_data_labels() {
+        let input = b"compatible = lbl: \"foo\", mid: \"bar\" end:;";
+        let prop = prop(input, "compatible", Some(vec![
+                Data::Label("lbl".to_owned(), Span::new(13, 17)),
+                Data::String("foo".to_owned(), Span::new(18, 23)),
+                Data::Label("mid".to_owned(), Span::new(25, 29)),
+                Data::String("bar".to_owned(), Span::new(30, 35)),
+                Data::Label("end".to_owned(), Span::new(36, 40)),
+            ]));
+        assert_eq!(parse_prop(input, input.len(), None), IResult::Done(&b""[..], prop.clone()));
+        assert_eq!(prop.to_string(), "compatible = lbl: foo, mid: bar end:;");
+    }
+
+    #[test]
+    fn data_incbin() {
+        let input = b"/incbin/(\"calib.bin\", 0x10, (2 * 8))";
+        assert_eq!(
+            parse_data(input, input.len(), None),
+            IResult::Done(&b""[..], Data::Incbin("calib.bin".to_owned(), 0x10, Some(16),
+                                                 Span::new(0, input.len())))
+        );
+        let input = b"/incbin/ ( \"fw.bin\" )";
+        assert_eq!(
+            parse_data(input, input.len(), None),
+            IResult::Done(&b""[..], Data::Incbin("fw.bin".to_owned(), 0, None,
+                                                 Span::new(0, input.len())))
+        );
+    }
+
+    #[test]
+    fn error_incbin_args() {
+        let input = b"/dts-v1/;\n/ {\n\tfw = /incbin/(\"fw.bin\", 0x10);\n};";
+        match parse_dt(input) {
+            Err(ParseError::Syntax(err)) => {
+                assert_eq!(err.offset, 37);
+                assert_eq!(err.message, error_message(Some(ERR_INCBIN)));
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn expr_precedence() {
+        assert_eq!(parse_c_expr(b"(1 + 2 * 3)"), IResult::Done(&b""[..], 7));
+        assert_eq!(parse_c_expr(b"(1 << 2 + 1)"), IResult::Done(&b""[..], 8));
+        assert_eq!(parse_c_expr(b"(10 - 4 - 3)"), IResult::Done(&b""[..], 3));
+        assert_eq!(parse_c_expr(b"(1 | 6 & 3 == 3)"), IResult::Done(&b""[..], 1));
+        assert_eq!(parse_c_expr(b"(~0 >> 60 ^ 1)"), IResult::Done(&b""[..], 14));
+        assert_eq!(parse_c_expr(b"(!0 + 'b')"), IResult::Done(&b""[..], 99));
+    }
+
+    #[test]
+    fn expr_ternary() {
+        assert_eq!(parse_c_expr(b"(2 > 1 ? 5 : 6)"), IResult::Done(&b""[..], 5));
+        assert_eq!(parse_c_expr(b"(0 ? 1 : 0 ? 2 : 3)"), IResult::Done(&b""[..], 3));
+        assert_eq!(parse_c_expr(b"(1 ? 2 ? 3 : 4 : 5)"), IResult::Done(&b""[..], 3));
+    }
+
+    #[test]
+    fn data_cell_exprs() {
+        let input = b"<(1 + 2 * 3) (1 ? 4 : 5) 0x10>";
+        assert_eq!(
+            parse_data(input, input.len(), None),
+            IResult::Done(&b""[..], Data::Cells(32, vec![
+                expr(7, "(1 + 2 * 3)", 1),
+                expr(4, "(1 ? 4 : 5)", 13),
+                expr(16, "0x10", 25)
+            ], Span::new(0, input.len())))
+        );
+    }
+
+    #[test]
+    fn error_expr_div_zero() {
+        let input = b"/dts-v1/;\n/ {\n\tval = <(4 / (1 - 1))>;\n};";
+        match parse_dt(input) {
+            Err(ParseError::Syntax(err)) => {
+                assert_eq!(err.offset, 25);
+                assert_eq!(err.message, error_message(Some(ERR_DIV_ZERO)));
+                assert_eq!(err.snippet, "/ (1 - 1))>;");
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn expr_wrapping() {
+        assert_eq!(parse_c_expr(b"(-4 + 8)"), IResult::Done(&b""[..], 4));
+        assert_eq!(parse_c_expr(b"(0 - 1)"), IResult::Done(&b""[..], u64::max_value()));
+        assert_eq!(parse_c_expr(b"(0xffffffffffffffff + 1)"), IResult::Done(&b""[..], 0));
+        assert_eq!(parse_c_expr(b"(-2 * 3)"), IResult::Done(&b""[..], 6u64.wrapping_neg()));
+    }
+
+    #[test]
+    fn expr_overflow_recorded() {
+        let input = b"/dts-v1/;\n/memreserve/ (0x8000000000000000 + 0x8000000000000000) 0x10;\n\
+                      / { a = <(0x100000000 * 0x100000000) (0 - 1) (-4 + 8)>; };\n";
+        let (tree, _, errors) = parse_dt_recover(input);
+        let at = |s: &str| String::from_utf8_lossy(input).find(s).unwrap();
+        assert_eq!(errors.iter().map(|e| (e.offset, e.message.as_str())).collect::<Vec<_>>(),
+                   vec![(at("+ 0x8"), error_message(Some(ERR_OVERFLOW))),
+                        (at("* 0x1"), error_message(Some(ERR_OVERFLOW)))]);
+        assert_eq!(tree.reserve_info[0].address, 0);
+
+        // Without recovery the wrapped values are kept silently
+        assert!(parse_dt(input).is_ok());
+    }
+
+    #[test]
+    fn expr_signed() {
+        assert_eq!(eval_c_expr("-1 < 0"), Ok(1));
+        assert_eq!(eval_c_expr("0 - 1 >= 0"), Ok(0));
+        assert_eq!(eval_c_expr("-7 / 2"), Ok(-3));
+        assert_eq!(eval_c_expr("-7 % 2"), Ok(-1));
+        assert_eq!(eval_c_expr("-8 >> 1"), Ok(-4));
+        assert_eq!(eval_c_expr("0xffffffffffffffff < 0"), Ok(1));
+        assert_eq!(parse_c_expr(b"(-1 < 0)"), IResult::Done(&b""[..], 0));
+    }
+
+    #[test]
+    fn error_expr_shift() {
+        let input = b"< (1 << 64) >";
+        match parse_data(input, input.len(), None) {
+            IResult::Error(ref err) => {
+                assert_eq!(deepest_error(err), (Some(&b"<< 64) >"[..]), Some(ERR_SHIFT)));
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+        assert!(parse_c_expr(b"(1 >> 64)").is_err());
+    }
+
+    #[test]
+    fn overlay_amends_only() {
+        let input = b"/dts-v1/;\n/plugin/;\n&uart0 {\n\tstatus = \"okay\";\n};\n\
+                      / { fragment@0 { }; };\n";
+        match parse_dt(input) {
+            Ok(ParseResult::Overlay(overlay)) => {
+                assert!(overlay.reserve_info.is_empty());
+                assert_eq!(overlay.amends.len(), 2);
+                assert_eq!(overlay.amends[0].name(), &NodeName::Ref("uart0".to_owned()));
+                assert_eq!(overlay.amends[1].name(), &NodeName::Full("/".to_owned()));
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+        assert!(is_overlay(input));
+        assert!(!is_overlay(b"/dts-v1/;\n/ { };"));
+    }
+
+    #[test]
+    fn overlay_recover() {
+        let input = b"/dts-v1/;\n/plugin/;\n&a { x = ; };\n&b { };\n";
+        let (overlay, errors) = parse_overlay_recover(input);
+        assert_eq!(overlay.amends.len(), 2);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0].offset, 29);
+
+        let (_, _, errors) = parse_dt_recover(input);
+        assert_eq!(errors[0].message, error_message(Some(ERR_OVERLAY)));
+        let (_, errors) = parse_overlay_recover(b"/dts-v1/;\n&b { };\n");
+        assert_eq!(errors[0].message, error_message(Some(ERR_PLUGIN)));
+    }
+
+    #[test]
+    fn repeated_headers() {
+        let input = b"/dts-v1/;\n/dts-v1/;\n/memreserve/ 0x1000 0x100;\n/ { };\n";
+        match parse_dt(input) {
+            Ok(ParseResult::Complete(tree, _)) => assert_eq!(tree.reserve_info.len(), 1),
+            x => panic!("unexpected result: {:?}", x),
+        }
+
+        let input = b"/dts-v1/;\n/plugin/;\n/dts-v1/;\n&a { };\n";
+        match parse_dt(input) {
+            Err(ParseError::Syntax(err)) => {
+                assert_eq!(err.offset, 20);
+                assert_eq!(err.message, error_message(Some(ERR_HEADER_FLAGS)));
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+        let (overlay, errors) = parse_overlay_recover(input);
+        assert_eq!(overlay.amends.len(), 1);
+        assert_eq!(errors.iter().map(|e| e.offset).collect::<Vec<_>>(), vec![20]);
+    }
+
+    #[test]
+    fn recover_late_memreserve() {
+        let input = b"/dts-v1/;\n/memreserve/ 0x0 0x10;\n/ { };\nr: /memreserve/ 0x20 0x10;\n";
+        let (tree, _, errors) = parse_dt_recover(input);
+        assert_eq!(tree.reserve_info.len(), 2);
+        assert_eq!(tree.reserve_info[1].labels, vec!["r".to_owned()]);
+        assert_eq!(
+            errors.iter().map(|e| (e.offset, e.message.as_str())).collect::<Vec<_>>(),
+            vec![(40, error_message(Some(ERR_MEMRESERVE_ORDER)))]
+        );
+    }
+
+    #[test]
+    fn boot_cpuid() {
+        let input = b"/dts-v1/;\n/ {\n\
+                      \tcpus {\n\
+                      \t\tcpu-map { };\n\
+                      \t\tcpu@100 { device_type = \"cpu\"; reg = <0x0 0x100>; };\n\
+                      \t\tcpu@0 { device_type = \"cpu\"; reg = <0x0 0x0>; };\n\
+                      \t};\n};\n";
+        match parse_dt(input) {
+            Ok(ParseResult::Complete(tree, _)) => {
+                assert_eq!(tree.boot_cpuid, 0x100);
+                assert!(!tree.boot_cpuid_fixed);
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+
+        let (tree, _, _) = parse_dt_recover(b"/dts-v1/;\n/ { cpus { cpu@3 { reg = <3>; }; }; };");
+        assert_eq!(tree.boot_cpuid, 3);
+        let (tree, _, _) = parse_dt_recover(b"/dts-v1/;\n/ { cpus { cpu@1 { reg = <1 0>; }; }; };");
+        assert_eq!(tree.boot_cpuid, 0);
+    }
+
+    #[test]
+    fn node_omit_if_no_ref() {
+        let input = b"/dts-v1/;\n/ {\n\ta: /omit-if-no-ref/ b: pins { };\n};\n\
+                      /omit-if-no-ref/ &c;\n/delete-node/ &{/soc/uart};";
+        match parse_dt(input) {
+            Ok(ParseResult::Complete(tree, amends)) => {
+                match tree.get_node_by_path("/pins") {
+                    Ok(&Node::Existing { ref labels, omit_if_no_ref, .. }) => {
+                        assert_eq!(labels, &["a".to_owned(), "b".to_owned()]);
+                        assert!(omit_if_no_ref);
+                    }
+                    x => panic!("unexpected node: {:?}", x),
+                }
+                match amends[0] {
+                    Node::Existing { ref name, omit_if_no_ref: true, .. } =>
+                        assert_eq!(name, &NodeName::Ref("c".to_owned())),
+                    ref x => panic!("unexpected amend: {:?}", x),
+                }
+                assert_eq!(amends[1], Node::Deleted {
+                    name: NodeName::Ref("/soc/uart".to_owned()),
+                    name_span: Span::new(86, 98),
+                    span: Span::new(72, 99),
+                });
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn node_spans() {
+        let input = b"/dts-v1/;\n/ {\n\tl1: n@1 {\n\t\tp = <1>; /* c */\n\t};\n};";
+        let text = |span: Span| &input[span.start..span.end];
+        match parse_dt(input) {
+            Ok(ParseResult::Complete(tree, _)) => {
+                let node = tree.get_node_by_path("/n@1").unwrap();
+                assert_eq!(text(node.get_span()), &b"l1: n@1 {\n\t\tp = <1>; /* c */\n\t};"[..]);
+                assert_eq!(text(node.name_span()), b"n@1");
+                assert_eq!(text(node.get_label_spans()[0]), b"l1:");
+                match *node {
+                    Node::Existing { ref proplist, .. } => {
+                        let prop = &proplist["p"];
+                        assert_eq!(text(prop.get_span()), b"p = <1>;");
+                        assert_eq!(text(prop.name_span()), b"p");
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
     }
 }
@@ -0,0 +1,522 @@
+//! A small query language for finding nodes and properties in a tree.
+//!
+//! A query is made of up to three parts, each of which may be left out:
+//!
+//! - Which nodes to look at, either as a path such as `/soc/**/serial*` or as
+//!   a label such as `&uart*`. In a path `*` and `?` match any run of
+//!   characters or any one character within a node name, and `**` matches any
+//!   number of nodes. A name without a unit address also matches any node with
+//!   that base name, so `/soc/uart` matches `/soc/uart@1000`. Leaving this
+//!   part out looks at every node.
+//! - Any number of conditions on the properties of each node, in brackets:
+//!   `[name]` if the property exists, `[!name]` if it does not, and
+//!   `[name=value]` or `[name!=value]` if it does or does not have the value.
+//!   The value may be put in double quotes if it holds a `]`.
+//! - A `:name` to select the properties with that name from each node found,
+//!   rather than the nodes themselves. `:*` selects every property.
+//!
+//! Names in each part may use `*` and `?`. A value is compared with each
+//! string of a property such as `compatible`, and may use `*` and `?` as well,
+//! or with a list of cells given as numbers and `&label` references, with or
+//! without the angle brackets. Nodes without a `status` property count as
+//! `status = "okay"`, as they do for the kernel, and `ok` is the same as
+//! `okay`.
+//!
+//! ```
+//! use device_tree_source::parser::{parse_dt, ParseResult};
+//! use device_tree_source::query::Query;
+//!
+//! let source = b"/dts-v1/;\n/ { soc {\n\
+//!                uart@1000 { compatible = \"snps,dw-apb-uart\"; };\n\
+//!                uart@2000 { compatible = \"snps,dw-apb-uart\"; status = \"disabled\"; };\n\
+//!                }; };";
+//! let tree = match parse_dt(source) {
+//!     Ok(ParseResult::Complete(tree, _)) => tree,
+//!     _ => unreachable!(),
+//! };
+//!
+//! let query = Query::parse("[compatible=snps,dw-apb-uart][status=okay]").unwrap();
+//! let paths: Vec<_> = query.nodes(&tree).into_iter().map(|(path, _)| path).collect();
+//! assert_eq!(paths, vec!["/soc/uart@1000"]);
+//! ```
+
+use std::str::FromStr;
+
+use tree::{DTInfo, Node, Property, Data, Cell, Labeled};
+use visit;
+
+/// An error from parsing a query.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryError {
+    /// The offset in bytes of the error within the query.
+    pub offset: usize,
+    /// A description of what was expected at `offset`.
+    pub message: String,
+}
+
+/// A parsed query, see the module documentation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Query {
+    head: Head,
+    conditions: Vec<Condition>,
+    property: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Head {
+    Any,
+    // Each part of the path, with `**` kept as its own part
+    Path(Vec<String>),
+    Label(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Condition {
+    Exists(String),
+    Missing(String),
+    Equals(String, String),
+    NotEquals(String, String),
+}
+
+impl Query {
+    /// Parses a query.
+    ///
+    /// # Errors
+    /// Returns a `QueryError` pointing at the first part of the query that
+    /// could not be understood.
+    pub fn parse(query: &str) -> Result<Self, QueryError> {
+        let error = |offset: usize, message: &str| {
+            Err(QueryError {
+                offset: offset,
+                message: message.to_owned(),
+            })
+        };
+        let end_of_name = |start: usize| {
+            query[start..]
+                .find(|c: char| c == '[' || c == ':' || c.is_whitespace())
+                .map_or(query.len(), |i| start + i)
+        };
+
+        let mut pos = skip_whitespace(query, 0);
+        let head = if query[pos..].starts_with('/') {
+            let end = end_of_name(pos);
+            let parts = query[pos..end]
+                .split('/')
+                .filter(|part| !part.is_empty())
+                .map(|part| part.to_owned())
+                .collect();
+            pos = end;
+            Head::Path(parts)
+        } else if query[pos..].starts_with('&') {
+            let end = end_of_name(pos + 1);
+            if end == pos + 1 {
+                return error(pos + 1, "expected a label");
+            }
+            let label = query[pos + 1..end].to_owned();
+            pos = end;
+            Head::Label(label)
+        } else {
+            Head::Any
+        };
+
+        let mut conditions = Vec::new();
+        let mut property = None;
+        loop {
+            pos = skip_whitespace(query, pos);
+            if pos == query.len() {
+                break;
+            }
+            if property.is_some() {
+                return error(pos, "expected the end of the query after the property name");
+            }
+
+            if query[pos..].starts_with(':') {
+                let start = skip_whitespace(query, pos + 1);
+                let end = end_of_name(start);
+                if end == start {
+                    return error(start, "expected a property name");
+                }
+                property = Some(query[start..end].to_owned());
+                pos = end;
+            } else if query[pos..].starts_with('[') {
+                let (condition, end) = parse_condition(query, pos + 1)?;
+                conditions.push(condition);
+                pos = end;
+            } else {
+                return error(pos, "expected '[' or ':'");
+            }
+        }
+
+        Ok(Query {
+            head: head,
+            conditions: conditions,
+            property: property,
+        })
+    }
+
+    /// Returns true if the query ends with a `:name` to select properties.
+    pub fn selects_properties(&self) -> bool {
+        self.property.is_some()
+    }
+
+    /// Returns true if the node at `path` matches the path or label and all of
+    /// the conditions of the query. Deleted nodes never match.
+    pub fn matches(&self, path: &str, node: &Node) -> bool {
+        if let Node::Deleted { .. } = *node {
+            return false;
+        }
+
+        let head = match self.head {
+            Head::Any => true,
+            Head::Path(ref pattern) => {
+                let names: Vec<&str> = path.split('/').filter(|name| !name.is_empty()).collect();
+                path_matches(pattern, &names)
+            }
+            Head::Label(ref pattern) => node.get_labels().iter().any(|l| glob(pattern, l)),
+        };
+
+        head && self.conditions.iter().all(|condition| condition.matches(node))
+    }
+
+    /// Finds every node that matches the query, with its full path, in the
+    /// order of a depth first walk of the tree. The `:name` part of the query,
+    /// if any, is not used.
+    pub fn nodes<'a>(&self, tree: &'a DTInfo) -> Vec<(String, &'a Node)> {
+        visit::depth_first(&tree.root)
+            .filter(|&(ref path, node)| self.matches(path, node))
+            .collect()
+    }
+
+    /// Finds the properties selected by the `:name` part of the query in every
+    /// node that matches, with the full path of the node holding each. Every
+    /// property of the nodes is found if there is no `:name` part.
+    pub fn properties<'a>(&self, tree: &'a DTInfo) -> Vec<(String, &'a Property)> {
+        let mut found = Vec::new();
+        for (path, node) in self.nodes(tree) {
+            if let Node::Existing { ref proplist, .. } = *node {
+                for (name, prop) in proplist {
+                    if self.property.as_ref().map_or(true, |pattern| glob(pattern, name)) {
+                        found.push((path.clone(), prop));
+                    }
+                }
+            }
+        }
+        found
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, QueryError> {
+        Query::parse(s)
+    }
+}
+
+impl Condition {
+    fn matches(&self, node: &Node) -> bool {
+        let proplist = match *node {
+            Node::Existing { ref proplist, .. } => proplist,
+            Node::Deleted { .. } => return false,
+        };
+        let named = |pattern: &str| {
+            proplist.iter()
+                .filter(move |&(name, _)| glob(pattern, name))
+                .map(|(_, prop)| prop)
+                .collect::<Vec<_>>()
+        };
+
+        match *self {
+            Condition::Exists(ref name) => !named(name).is_empty(),
+            Condition::Missing(ref name) => named(name).is_empty(),
+            Condition::Equals(ref name, ref value) |
+            Condition::NotEquals(ref name, ref value) => {
+                let props = named(name);
+                let equal = if name == "status" && props.is_empty() {
+                    // No status is the same as okay
+                    status_matches("okay", value)
+                } else {
+                    props.iter().any(|prop| value_matches(name, prop, value))
+                };
+                match *self {
+                    Condition::Equals(..) => equal,
+                    _ => !equal,
+                }
+            }
+        }
+    }
+}
+
+// Parses the condition after a '[', returning it along with the offset after
+// the closing ']'
+fn parse_condition(query: &str, start: usize) -> Result<(Condition, usize), QueryError> {
+    let error = |offset: usize, message: &str| {
+        Err(QueryError {
+            offset: offset,
+            message: message.to_owned(),
+        })
+    };
+
+    // Find the closing bracket, skipping any within quotes
+    let mut in_quotes = false;
+    let mut close = None;
+    for (i, c) in query[start..].char_indices() {
+        match c {
+            '"' => in_quotes = !in_quotes,
+            ']' if !in_quotes => {
+                close = Some(start + i);
+                break;
+            }
+            _ => {}
+        }
+    }
+    let close = match close {
+        Some(close) => close,
+        None => return error(query.len(), "expected ']'"),
+    };
+
+    let body = &query[start..close];
+    let condition = match body.find('=') {
+        Some(eq) => {
+            let negated = body[..eq].ends_with('!');
+            let name = body[..if negated { eq - 1 } else { eq }].trim();
+            if name.is_empty() {
+                return error(start, "expected a property name");
+            }
+            let value = body[eq + 1..].trim();
+            let value = if value.len() >= 2 && value.starts_with('"') && value.ends_with('"') {
+                &value[1..value.len() - 1]
+            } else {
+                value
+            };
+            if negated {
+                Condition::NotEquals(name.to_owned(), value.to_owned())
+            } else {
+                Condition::Equals(name.to_owned(), value.to_owned())
+            }
+        }
+        None => {
+            let name = body.trim();
+            let (missing, name) = if name.starts_with('!') {
+                (true, name[1..].trim_start())
+            } else {
+                (false, name)
+            };
+            if name.is_empty() {
+                return error(start, "expected a property name");
+            }
+            if missing {
+                Condition::Missing(name.to_owned())
+            } else {
+                Condition::Exists(name.to_owned())
+            }
+        }
+    };
+
+    Ok((condition, close + 1))
+}
+
+fn skip_whitespace(query: &str, pos: usize) -> usize {
+    query[pos..].find(|c: char| !c.is_whitespace()).map_or(query.len(), |i| pos + i)
+}
+
+// Matches the names of each node along a path against the parts of a path
+// pattern
+fn path_matches(pattern: &[String], names: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => names.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..names.len() + 1).any(|skip| path_matches(rest, &names[skip..]))
+        }
+        Some((first, rest)) => {
+            match names.split_first() {
+                Some((name, names)) => name_matches(first, name) && path_matches(rest, names),
+                None => false,
+            }
+        }
+    }
+}
+
+// A pattern without a unit address also matches the base name
+fn name_matches(pattern: &str, name: &str) -> bool {
+    glob(pattern, name) ||
+    (!pattern.contains('@') && glob(pattern, name.split('@').next().unwrap_or(name)))
+}
+
+// Matches text against a pattern where `*` matches any run of characters and
+// `?` matches any one character
+fn glob(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    // Backtracks to just after the last `*` on a mismatch
+    let (mut p, mut t) = (0, 0);
+    let mut star = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((star_p, star_t)) = star {
+            p = star_p + 1;
+            t = star_t + 1;
+            star = Some((star_p, star_t + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+fn status_matches(status: &str, value: &str) -> bool {
+    let normalize = |s: &str| if s == "ok" { "okay".to_owned() } else { s.to_owned() };
+    glob(&normalize(value), &normalize(status))
+}
+
+fn value_matches(name: &str, prop: &Property, value: &str) -> bool {
+    let data = match *prop {
+        Property::Existing { val: Some(ref data), .. } => data,
+        _ => return false,
+    };
+
+    let strings: Vec<&str> = data.iter()
+        .filter_map(|d| match *d {
+            Data::String(ref s, _) => Some(s.as_str()),
+            _ => None,
+        })
+        .collect();
+    if !strings.is_empty() {
+        return if name == "status" {
+            strings.iter().any(|s| status_matches(s, value))
+        } else {
+            strings.iter().any(|s| glob(value, s))
+        };
+    }
+
+    let cells: Vec<&Cell> = data.iter()
+        .filter_map(|d| match *d {
+            Data::Cells(_, ref cells, _) => Some(cells),
+            _ => None,
+        })
+        .flat_map(|cells| cells.iter())
+        .filter(|cell| match **cell {
+            Cell::Label(..) => false,
+            _ => true,
+        })
+        .collect();
+    let value = value.trim_start_matches('<').trim_end_matches('>');
+    let wanted: Vec<&str> = value.split_whitespace().collect();
+    !cells.is_empty() && cells.len() == wanted.len() &&
+    cells.iter().zip(&wanted).all(|(cell, wanted)| cell_matches(cell, wanted))
+}
+
+fn cell_matches(cell: &Cell, wanted: &str) -> bool {
+    if wanted.starts_with('&') {
+        return match *cell {
+            Cell::Ref(ref refr, ..) => *refr == wanted[1..],
+            _ => false,
+        };
+    }
+
+    let number = if wanted.starts_with("0x") || wanted.starts_with("0X") {
+        u64::from_str_radix(&wanted[2..], 16)
+    } else {
+        wanted.parse()
+    };
+    match number {
+        Ok(number) => cell.value() == Some(number),
+        Err(_) => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_base;
+
+    const SOURCE: &'static [u8] = b"/dts-v1/;\n/ {\n\
+                                    intc: interrupt-controller@100 { \
+                                        compatible = \"arm,gic-400\"; \
+                                        interrupt-controller; };\n\
+                                    soc {\n\
+                                        uart0: serial@1000 { \
+                                            compatible = \"snps,dw-apb-uart\", \"ns16550a\"; \
+                                            reg = <0x1000 0x100>; \
+                                            interrupt-parent = <&intc>; };\n\
+                                        uart1: serial@2000 { \
+                                            compatible = \"snps,dw-apb-uart\"; \
+                                            reg = <0x2000 0x100>; status = \"disabled\"; };\n\
+                                        i2c@3000 { status = \"ok\"; };\n\
+                                    };\n\
+                                };";
+
+    fn paths(tree: &DTInfo, query: &str) -> Vec<String> {
+        Query::parse(query).unwrap().nodes(tree).into_iter().map(|(path, _)| path).collect()
+    }
+
+    #[test]
+    fn path_patterns() {
+        let (tree, _) = parse_base(SOURCE);
+        assert_eq!(paths(&tree, "/"), vec!["/"]);
+        assert_eq!(paths(&tree, "/soc/*"),
+                   vec!["/soc/serial@1000", "/soc/serial@2000", "/soc/i2c@3000"]);
+        assert_eq!(paths(&tree, "/soc/serial"), vec!["/soc/serial@1000", "/soc/serial@2000"]);
+        assert_eq!(paths(&tree, "/soc/serial@2000"), vec!["/soc/serial@2000"]);
+        assert_eq!(paths(&tree, "/**/*@?000"),
+                   vec!["/soc/serial@1000", "/soc/serial@2000", "/soc/i2c@3000"]);
+        assert_eq!(paths(&tree, "/**/soc/**").len(), 4);
+        assert_eq!(paths(&tree, "&uart*"), vec!["/soc/serial@1000", "/soc/serial@2000"]);
+        assert_eq!(paths(&tree, "").len(), 6);
+    }
+
+    #[test]
+    fn conditions() {
+        let (tree, _) = parse_base(SOURCE);
+        assert_eq!(paths(&tree, "[compatible=snps,dw-apb-uart][status=okay]"),
+                   vec!["/soc/serial@1000"]);
+        assert_eq!(paths(&tree, "/soc/*[status = ok]"),
+                   vec!["/soc/serial@1000", "/soc/i2c@3000"]);
+        assert_eq!(paths(&tree, "[status!=disabled][compatible]"),
+                   vec!["/interrupt-controller@100", "/soc/serial@1000"]);
+        assert_eq!(paths(&tree, "[interrupt-controller]"), vec!["/interrupt-controller@100"]);
+        assert_eq!(paths(&tree, "/soc/*[!reg]"), vec!["/soc/i2c@3000"]);
+        assert_eq!(paths(&tree, "[compatible=\"arm,*\"]"), vec!["/interrupt-controller@100"]);
+        assert_eq!(paths(&tree, "[reg=<0x2000 256>]"), vec!["/soc/serial@2000"]);
+        assert_eq!(paths(&tree, "[reg=0x2000]"), Vec::<String>::new());
+        assert_eq!(paths(&tree, "[interrupt-parent=&intc]"), vec!["/soc/serial@1000"]);
+        assert_eq!(paths(&tree, "&uart0[#*-cells]"), Vec::<String>::new());
+    }
+
+    #[test]
+    fn select_properties() {
+        let (tree, _) = parse_base(SOURCE);
+        let query = Query::parse("/soc/serial@1000 :reg").unwrap();
+        assert!(query.selects_properties());
+        let found: Vec<_> = query.properties(&tree)
+            .into_iter()
+            .map(|(path, prop)| format!("{} {}", path, prop.name()))
+            .collect();
+        assert_eq!(found, vec!["/soc/serial@1000 reg"]);
+
+        let query = Query::parse("&uart1:*").unwrap();
+        assert_eq!(query.properties(&tree).len(), 3);
+    }
+
+    #[test]
+    fn syntax_errors() {
+        let error = |query: &str| Query::parse(query).unwrap_err();
+        assert_eq!(error("/soc [status"),
+                   QueryError {
+                       offset: 12,
+                       message: "expected ']'".to_owned(),
+                   });
+        assert_eq!(error("&").offset, 1);
+        assert_eq!(error("/soc x").offset, 5);
+        assert_eq!(error("[=okay]").offset, 1);
+        assert_eq!(error(":reg [status]").offset, 5);
+        assert_eq!(error("/soc:").offset, 5);
+    }
+}
@@ -1,8 +1,16 @@
 //! Contains the structures that represent the device tree.
 
 use std::fmt;
-use std::collections::HashMap;
-use std::collections::hash_map::Entry;
+use std::iter;
+use std::mem;
+use std::slice;
+use std::vec;
+use std::ops::Index;
+use std::iter::FromIterator;
+use std::collections::{HashMap, HashSet};
+
+use index::TreeIndex;
+use visit::{self, VisitorMut};
 
 /// Trait applied to all data structures in a device tree that can have a
 /// label/alias.
@@ -18,6 +26,11 @@ pub trait Labeled {
     /// return an empty slice if there are no labels or the object does not
     /// allow labels in its current form, such as will a deleted Node.
     fn get_labels(&self) -> &[String];
+
+    /// Gets a slice containing the span of each label, including the trailing
+    /// ':', in the same order as `get_labels`. Labels added with `add_label`
+    /// were not parsed from a buffer and so have an empty span.
+    fn get_label_spans(&self) -> &[Span];
 }
 
 /// Trait for objects that track their starting offset from within the global
@@ -27,6 +40,41 @@ pub trait Offset {
     fn get_offset(&self) -> usize;
 }
 
+/// Trait for objects that track the span of text they were parsed from within
+/// the global buffer.
+pub trait Spanned {
+    /// Get the span of the object.
+    fn get_span(&self) -> Span;
+}
+
+/// A range of bytes within the buffer that a tree was parsed from. The start is
+/// inclusive and the end is exclusive, so the text of an object is
+/// `&buffer[span.start..span.end]`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default, Hash)]
+pub struct Span {
+    /// The offset of the first byte.
+    pub start: usize,
+    /// The offset one past the last byte.
+    pub end: usize,
+}
+
+impl Span {
+    /// Creates a new `Span` from `start` up to, but not including, `end`.
+    pub fn new(start: usize, end: usize) -> Self {
+        Span { start: start, end: end }
+    }
+
+    /// The length of the span in bytes.
+    pub fn len(&self) -> usize {
+        self.end - self.start
+    }
+
+    /// Returns true if the span holds no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.start == self.end
+    }
+}
+
 /// The device tree info as specified by the Device Tree Specification. Includes
 /// the reserved memory info, the boot CPU ID, and the root node of the tree.
 /// The root node may not include any changes made by node specifications listed
@@ -36,14 +84,160 @@ pub trait Offset {
 pub struct DTInfo {
     /// The reserved memory information.
     pub reserve_info: Vec<ReserveInfo>,
-    /// The boot CPU ID as specified by the device tree. Defaults to the first
-    /// CPU ID if not specified.
+    /// The boot CPU ID. Unless set with `set_boot_cpuid`, this is the ID of
+    /// the first CPU under `/cpus`, see `DTInfo::new`, and is worked out again
+    /// whenever amendments or overlays are merged.
     pub boot_cpuid: u32,
+    /// Whether `boot_cpuid` was set with `set_boot_cpuid` rather than worked
+    /// out from the tree.
+    pub boot_cpuid_fixed: bool,
     /// The root node of the device tree. Will always be named '/'.
     pub root: Node,
 }
 
+/// A device tree overlay, parsed from a source with the `/plugin/;` header. An
+/// overlay has no tree of its own, only amendments to be applied to a base
+/// tree. The labels referenced by the amendments are not resolved until the
+/// overlay is applied. See `parser::parse_dt`.
+#[derive(Debug, Clone)]
+pub struct Overlay {
+    /// The reserved memory information.
+    pub reserve_info: Vec<ReserveInfo>,
+    /// The amendments to the base tree in the order they were defined. Either
+    /// in the form `&label { ... };` or `/ { ... };`, the latter of which may
+    /// hold `fragment@N` nodes.
+    pub amends: Vec<Node>,
+}
+
+/// Errors that can occur while applying amendments or overlays to a tree.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AmendError {
+    /// The label or path an amendment or overlay fragment refers to does not
+    /// exist in the tree. Holds the reference, a path if it starts with '/'
+    /// and a label otherwise, and the offset of the amending `Node`.
+    UnresolvedTarget(String, usize),
+    /// An overlay fragment has an `__overlay__` node but no `target` or
+    /// `target-path` property that refers to a node. Holds the offset of the
+    /// fragment.
+    MissingTarget(usize),
+    /// A `/delete-node/` statement names a child node that does not exist.
+    /// Holds the name of the node and the offset of the statement.
+    NoSuchNode(String, usize),
+}
+
+/// A `/memreserve/` entry that overlaps a region reserved by a child of the
+/// `/reserved-memory` node, as found by `DTInfo::reserve_overlaps`. The same
+/// memory is then reserved twice, which usually means one of the two is stale.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReserveOverlap {
+    /// The index of the entry within `DTInfo::reserve_info`.
+    pub reserve: usize,
+    /// The path of the `/reserved-memory` child.
+    pub path: String,
+    /// The starting address of the child's region.
+    pub address: u64,
+    /// The length in bytes of the child's region.
+    pub size: u64,
+}
+
+/// A property or child node that is defined more than once within a single
+/// node body, as found by `Node::redefinitions`. The later definition replaces
+/// the earlier one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Redefinition {
+    /// A property was defined again. Holds the path of the property and the
+    /// spans of the earlier and later definitions.
+    Property(String, Span, Span),
+    /// A child node was defined again. Holds the path of the node and the
+    /// spans of the earlier and later definitions.
+    Node(String, Span, Span),
+}
+
+/// A unit address that does not agree with the `reg` property of its node, as
+/// found by `DTInfo::check_unit_addresses`. Each holds the path of the node and
+/// the span of its name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum UnitAddressError {
+    /// The node has a unit address but neither a `reg` nor a `ranges`
+    /// property.
+    NoReg(String, Span),
+    /// The node has a `reg` property but no unit address. Also holds the unit
+    /// address the first `reg` entry calls for.
+    Missing(String, String, Span),
+    /// The unit address is not the address of the first `reg` entry. Also
+    /// holds the unit address the entry calls for.
+    Mismatch(String, String, Span),
+}
+
+/// A problem found by `DTInfo::resolve_references`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ReferenceError {
+    /// A label or path that does not refer to a node. Holds the reference, the
+    /// path of the property it is in and the span of the reference.
+    Dangling(String, String, Span),
+    /// A node has the same explicit phandle as an earlier node. Holds the
+    /// phandle, the path of the later node and the span of its `phandle` or
+    /// `linux,phandle` property.
+    DuplicatePhandle(u32, String, Span),
+    /// A node has an explicit phandle of 0 or 0xffffffff, which dtc rejects.
+    /// Holds the path of the node and the span of the property.
+    BadPhandle(String, Span),
+}
+
+/// An earlier definition of a property or child node that was replaced by a
+/// later definition of the same name within the same node body.
+#[derive(PartialEq, Eq, Debug, Clone)]
+pub enum Duplicate {
+    /// A replaced property.
+    Property(Property),
+    /// A replaced child node.
+    Node(Node),
+}
+
+/// A fragment of an overlay in the form
+/// ```ignore
+/// fragment@0 {
+///     target = <&label>;
+///     __overlay__ { ... };
+/// };
+/// ```
+/// See `Node::fragment`.
+#[derive(Debug, Clone, Copy)]
+pub struct Fragment<'a> {
+    /// The node the fragment applies to, as given by the `target` or
+    /// `target-path` property. A path if it starts with '/' and a label
+    /// otherwise. `None` if neither property refers to a node.
+    pub target: Option<&'a str>,
+    /// The `__overlay__` node holding the contents to merge into the target.
+    pub overlay: &'a Node,
+}
+
 impl DTInfo {
+    /// Create a new `DTInfo` from the reserved memory info and root node, with
+    /// the boot CPU ID worked out from the tree.
+    ///
+    /// The boot CPU is the first child of `/cpus` with `device_type = "cpu"`,
+    /// or failing that the first child named `cpu@...`, and its ID is the
+    /// value of its `reg` property. A `reg` of two cells is accepted if it
+    /// fits in 32 bits. The ID is 0 if there is no such CPU. dtc instead only
+    /// looks at the first child, which is often the `cpu-map` node.
+    pub fn new(reserve_info: Vec<ReserveInfo>, root: Node) -> Self {
+        let boot_cpuid = boot_cpuid(&root);
+        DTInfo {
+            reserve_info: reserve_info,
+            boot_cpuid: boot_cpuid,
+            boot_cpuid_fixed: false,
+            root: root,
+        }
+    }
+
+    /// Sets the boot CPU ID, such as with dtc's `-b` option. The ID is kept as
+    /// is when amendments or overlays are merged.
+    pub fn set_boot_cpuid(&mut self, boot_cpuid: u32) {
+        self.boot_cpuid = boot_cpuid;
+        self.boot_cpuid_fixed = true;
+    }
+
     /// Create a new `DTInfo` where the tree is a merging of the original
     /// `DTInfo`'s tree and the list of `Node`s. The original tree is left
     /// unmodified.
@@ -51,10 +245,13 @@ impl DTInfo {
     /// The 'Nodes' will be applied to the tree by fist finding the 'Node' they
     /// reference, either by path or label, and then merging the existing 'Node'
     /// and the 'Node' from the list. This merge is done via 'Node::merge`.
-    pub fn apply_amends(&self, amends: &[Node]) -> Self {
+    ///
+    /// # Errors
+    /// Returns every `AmendError` found while merging, see `merge_amends`.
+    pub fn apply_amends(&self, amends: &[Node]) -> Result<Self, Vec<AmendError>> {
         let mut new_tree: DTInfo = self.clone();
-        new_tree.merge_amends(amends);
-        new_tree
+        new_tree.merge_amends(amends)?;
+        Ok(new_tree)
     }
 
     /// Merge a list of 'Nodes' into the `DTInfo`'s tree, modifying it.
@@ -62,32 +259,555 @@ impl DTInfo {
     /// The 'Nodes' will be applied to the tree by fist finding the 'Node' they
     /// reference, either by path or label, and then merging the existing 'Node'
     /// and the 'Node' from the list. This merge is done via 'Node::merge`.
-    pub fn merge_amends(&mut self, amends: &[Node]) {
+    ///
+    /// Labels are looked up in a map of every label in the tree, which is only
+    /// built again after an amendment that adds labels or deletes nodes, so
+    /// the many `&label { ... };` amendments of a large tree stay cheap.
+    ///
+    /// # Errors
+    /// Returns an `AmendError` for each reference that could not be resolved
+    /// and each deleted node that did not exist. Everything that could be
+    /// resolved is still merged, so the tree is left in a usable state.
+    pub fn merge_amends(&mut self, amends: &[Node]) -> Result<(), Vec<AmendError>> {
+        let mut errors = Vec::new();
+        let mut labels = None;
+
         for a in amends {
-            match a.name() {
-                &NodeName::Ref(ref refr) => {
-                    // could be either label ref or path ref
-                    if refr.starts_with('/') {
-                        // path ref
-                        self.get_node_by_path_mut(refr).unwrap().merge(a)
-                    } else {
-                        // label ref, time to get searching
-                        self.get_node_by_label_mut(refr).unwrap().merge(a)
-                    };
-                }
-                &NodeName::Full(_) => {
+            match *a.name() {
+                NodeName::Ref(ref refr) => self.merge_into(refr, a, &mut labels, &mut errors),
+                NodeName::Full(_) => {
                     // has to be modifying from root node
                     // TODO: double check
-                    self.root.merge(a)
+                    self.root.merge(a, &mut errors);
+                    if moves_labels(a) {
+                        labels = None;
+                    }
+                }
+            }
+        }
+
+        self.update_boot_cpuid();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Apply an `Overlay` to the `DTInfo`'s tree, modifying it.
+    ///
+    /// Amendments in the form `&label { ... };` are merged as by
+    /// `merge_amends`. Amendments to the root node have each fragment, see
+    /// `Node::fragment`, merged into the node it targets. Anything else in the
+    /// root amendment is merged into the root node.
+    ///
+    /// # Errors
+    /// Returns an `AmendError` for each target that could not be resolved, see
+    /// `merge_amends`. The reserved memory info of the overlay is always added.
+    pub fn apply_overlay(&mut self, overlay: &Overlay) -> Result<(), Vec<AmendError>> {
+        let mut errors = Vec::new();
+        let mut label_paths = None;
+
+        self.reserve_info.extend(overlay.reserve_info.iter().cloned());
+
+        for a in &overlay.amends {
+            match *a {
+                Node::Existing { name: NodeName::Full(_),
+                                 ref proplist,
+                                 ref children,
+                                 ref labels,
+                                 ref label_spans,
+                                 span,
+                                 .. } => {
+                    let mut rest = OrderedMap::new();
+                    for (name, child) in children {
+                        match child.fragment() {
+                            Some(Fragment { target: Some(target), overlay }) => {
+                                self.merge_into(target, overlay, &mut label_paths, &mut errors)
+                            }
+                            Some(Fragment { target: None, .. }) =>
+                                errors.push(AmendError::MissingTarget(child.get_offset())),
+                            None => { rest.insert(name.to_owned(), child.clone()); }
+                        }
+                    }
+
+                    let rest = Node::Existing {
+                        name: NodeName::Full("/".to_owned()),
+                        name_span: span,
+                        proplist: proplist.clone(),
+                        children: rest,
+                        duplicates: Vec::new(),
+                        labels: labels.clone(),
+                        label_spans: label_spans.clone(),
+                        omit_if_no_ref: false,
+                        span: span,
+                    };
+                    self.root.merge(&rest, &mut errors);
+                    if moves_labels(&rest) {
+                        label_paths = None;
+                    }
+                }
+                _ => self.merge_into(a.name().as_str(), a, &mut label_paths, &mut errors),
+            }
+        }
+
+        self.update_boot_cpuid();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Merges or deletes the node referred to by `refr`, either a path or a
+    /// label, as given by `amend`. `labels` maps each label to the path of its
+    /// node. It is built if it is `None` and a label is needed, and reset to
+    /// `None` if `amend` may have moved a label.
+    fn merge_into(&mut self,
+                  refr: &str,
+                  amend: &Node,
+                  labels: &mut Option<HashMap<String, String>>,
+                  errors: &mut Vec<AmendError>) {
+        let unresolved = || AmendError::UnresolvedTarget(refr.to_owned(), amend.get_offset());
+
+        let path = if refr.starts_with('/') {
+            self.full_path(refr).ok()
+        } else {
+            if labels.is_none() {
+                *labels = Some(self.label_paths());
+            }
+            labels.as_ref().and_then(|labels| labels.get(refr).cloned())
+        };
+        if moves_labels(amend) {
+            *labels = None;
+        }
+
+        if let Node::Deleted { .. } = *amend {
+            let removed = path.as_ref()
+                              .and_then(|p| p.rfind('/').map(|pos| p.split_at(pos)))
+                              .and_then(|(parent, name)| {
+                                  let parent = if parent.is_empty() { "/" } else { parent };
+                                  match self.get_node_by_path_mut(parent) {
+                                      Ok(&mut Node::Existing { ref mut children, .. }) =>
+                                          children.remove(&name[1..]),
+                                      _ => None,
+                                  }
+                              });
+            if removed.is_none() {
+                errors.push(unresolved());
+            }
+            return;
+        }
+
+        match path.ok_or(()).and_then(|path| self.get_node_by_path_mut(&path)) {
+            Ok(node) => node.merge(amend, errors),
+            Err(_) => errors.push(unresolved()),
+        }
+    }
+
+    /// Works out the boot CPU ID again unless it was set explicitly.
+    fn update_boot_cpuid(&mut self) {
+        if !self.boot_cpuid_fixed {
+            self.boot_cpuid = boot_cpuid(&self.root);
+        }
+    }
+
+    /// Maps each label to the path of the first node with it, searching depth
+    /// first like `get_node_by_label`.
+    fn label_paths(&self) -> HashMap<String, String> {
+        let mut paths = HashMap::new();
+        for (path, node) in visit::depth_first(&self.root) {
+            for label in node.get_labels() {
+                paths.entry(label.clone()).or_insert_with(|| path.clone());
+            }
+        }
+        paths
+    }
+
+    /// Removes every node marked with `/omit-if-no-ref/` that is not referenced
+    /// by a label or path anywhere in the tree, such as `<&label>` or
+    /// `&{/path}`. Returns the paths of the removed nodes. This should be done
+    /// once all amendments and overlays have been applied.
+    pub fn omit_unreferenced(&mut self) -> Vec<String> {
+        fn omit(node: &mut Node,
+                path: &str,
+                referenced: &HashSet<String>,
+                omitted: &mut Vec<String>) {
+            if let Node::Existing { ref mut children, .. } = *node {
+                let mut unreferenced = Vec::new();
+                for (name, child) in children.iter_mut() {
+                    let path = child_path(path, name);
+                    match *child {
+                        Node::Existing { omit_if_no_ref: true, .. }
+                            if !referenced.contains(&path) => unreferenced.push(name.to_owned()),
+                        _ => omit(child, &path, referenced, omitted),
+                    }
+                }
+                for name in unreferenced {
+                    children.remove(&name);
+                    omitted.push(child_path(path, &name));
+                }
+            }
+        }
+
+        let referenced: HashSet<String> = {
+            let index = TreeIndex::new(self);
+            let mut refs = Vec::new();
+            for (_, prop) in visit::depth_first(&self.root).properties() {
+                if let Property::Existing { val: Some(ref data), .. } = *prop {
+                    for d in data {
+                        match *d {
+                            Data::Reference(ref refr, ..) => refs.push(refr),
+                            Data::Cells(_, ref cells, _) => {
+                                for cell in cells {
+                                    if let Cell::Ref(ref refr, ..) = *cell {
+                                        refs.push(refr);
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+            refs.into_iter()
+                .filter_map(|refr| if refr.starts_with('/') {
+                    self.full_path(refr).ok()
+                } else {
+                    index.path_of_label(refr).map(|path| path.to_owned())
+                })
+                .collect()
+        };
+
+        let mut omitted = Vec::new();
+        omit(&mut self.root, "/", &referenced, &mut omitted);
+        omitted.sort();
+        omitted
+    }
+
+    /// Finds every `/memreserve/` entry that overlaps a region in the `reg`
+    /// property of a child of `/reserved-memory`, in the order of the entries.
+    /// The cell sizes of `reg` are the `#address-cells` and `#size-cells` of
+    /// `/reserved-memory`, which default to 2 and 1. Children without a `reg`,
+    /// which are allocated at boot, are never reported.
+    pub fn reserve_overlaps(&self) -> Vec<ReserveOverlap> {
+        let (proplist, children) = match self.get_node_by_path("/reserved-memory") {
+            Ok(&Node::Existing { ref proplist, ref children, .. }) => (proplist, children),
+            _ => return Vec::new(),
+        };
+        let address_cells = cell_count(proplist, "#address-cells", 2);
+        let size_cells = cell_count(proplist, "#size-cells", 1);
+        // Anything wider will not fit the 64 bits of a `/memreserve/` entry
+        if address_cells == 0 || address_cells > 2 || size_cells > 2 {
+            return Vec::new();
+        }
+
+        let mut overlaps = Vec::new();
+        for (name, child) in children {
+            let reg = match *child {
+                Node::Existing { ref proplist, .. } => {
+                    proplist.get("reg").and_then(Property::cells)
+                }
+                Node::Deleted { .. } => None,
+            };
+            let reg = match reg {
+                Some(reg) => reg,
+                None => continue,
+            };
+
+            for region in reg.chunks(address_cells + size_cells) {
+                if region.len() != address_cells + size_cells {
+                    break;
+                }
+                let (address, size) = region.split_at(address_cells);
+                let address = address.iter().fold(0, |acc, cell| acc << 32 | cell);
+                let size = size.iter().fold(0, |acc, cell| acc << 32 | cell);
+
+                for (i, reserve) in self.reserve_info.iter().enumerate() {
+                    if reserve.size != 0 && size != 0 &&
+                       reserve.address < address.saturating_add(size) &&
+                       address < reserve.address.saturating_add(reserve.size) {
+                        overlaps.push(ReserveOverlap {
+                            reserve: i,
+                            path: child_path("/reserved-memory", name),
+                            address: address,
+                            size: size,
+                        });
+                    }
+                }
+            }
+        }
+
+        overlaps.sort_by_key(|overlap| overlap.reserve);
+        overlaps
+    }
+
+    /// Checks the unit address of every node against the first entry of its
+    /// `reg` property, read with the `#address-cells` of the parent node. Only
+    /// addresses of one or two cells are checked, as wider ones such as PCI's
+    /// are not written as a single number. Unit addresses are compared by
+    /// value, so leading zeros are allowed.
+    pub fn check_unit_addresses(&self) -> Vec<UnitAddressError> {
+        fn check(node: &Node, path: &str, errors: &mut Vec<UnitAddressError>) {
+            let (proplist, children) = match *node {
+                Node::Existing { ref proplist, ref children, .. } => (proplist, children),
+                Node::Deleted { .. } => return,
+            };
+            let address_cells = cell_count(proplist, "#address-cells", 2);
+
+            for (name, child) in children {
+                let path = child_path(path, name);
+                if let Node::Existing { ref name, name_span, ref proplist, .. } = *child {
+                    let expected = proplist.get("reg")
+                        .and_then(Property::cells)
+                        .and_then(|reg| if address_cells == 0 || address_cells > 2 ||
+                                           reg.len() < address_cells {
+                            None
+                        } else {
+                            Some(reg[..address_cells].iter().fold(0, |acc, cell| acc << 32 | cell))
+                        });
+
+                    match (name.unit_address(), expected) {
+                        (Some(_), None) if !proplist.contains_key("reg") &&
+                                           !proplist.contains_key("ranges") => {
+                            errors.push(UnitAddressError::NoReg(path.clone(), name_span))
+                        }
+                        (None, Some(expected)) => {
+                            errors.push(UnitAddressError::Missing(path.clone(),
+                                                                  format!("{:x}", expected),
+                                                                  name_span))
+                        }
+                        (Some(unit), Some(expected)) => {
+                            if u64::from_str_radix(unit, 16) != Ok(expected) {
+                                errors.push(UnitAddressError::Mismatch(path.clone(),
+                                                                       format!("{:x}", expected),
+                                                                       name_span))
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                check(child, &path, errors);
+            }
+        }
+
+        let mut errors = Vec::new();
+        check(&self.root, "/", &mut errors);
+        errors
+    }
+
+    /// Resolves every reference to a node in the tree, the same way dtc does.
+    ///
+    /// References in a list of cells, such as `<&gic>`, are given the phandle
+    /// of the node. A node keeps an explicit phandle from a `phandle` or
+    /// `linux,phandle` property. Other referenced nodes are given the lowest
+    /// phandle not yet in use, in the order they are first referenced with
+    /// each node's properties before its children, and a `phandle` property
+    /// holding it is added. References outside of cells, such as
+    /// `serial0 = &uart0;`, are given the full path of the node.
+    ///
+    /// Resolving again, such as after applying an overlay, keeps every phandle
+    /// that was given out before.
+    ///
+    /// # Errors
+    /// Returns a `ReferenceError` for each reference to a node that does not
+    /// exist, each explicit phandle that is already in use and each explicit
+    /// phandle of 0 or 0xffffffff. Everything else is still resolved, but a
+    /// node with an invalid phandle is not given a new one.
+    pub fn resolve_references(&mut self) -> Result<(), Vec<ReferenceError>> {
+        // Fills in every reference once the paths and phandles are known
+        struct Fill<'b> {
+            paths: &'b HashMap<String, Option<String>>,
+            phandles: &'b HashMap<String, u32>,
+        }
+
+        impl<'b> VisitorMut for Fill<'b> {
+            fn visit_property(&mut self, _: &str, prop: &mut Property) {
+                if let Property::Existing { val: Some(ref mut data), .. } = *prop {
+                    for d in data {
+                        match *d {
+                            Data::Reference(ref refr, ref mut full, _) => {
+                                *full = self.paths[refr].clone();
+                            }
+                            Data::Cells(_, ref mut cells, _) => {
+                                for cell in cells {
+                                    if let Cell::Ref(ref refr, ref mut phandle, _) = *cell {
+                                        *phandle = self.paths[refr]
+                                            .as_ref()
+                                            .and_then(|path| self.phandles.get(path))
+                                            .map(|&phandle| u64::from(phandle));
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut errors = Vec::new();
+        let mut paths = HashMap::new();
+        let mut phandles = HashMap::new();
+        let mut used = HashSet::new();
+        let mut bad = HashSet::new();
+        let mut allocated = Vec::new();
+        {
+            let index = TreeIndex::new(self);
+            // Every reference as the label or path, the path of the property it
+            // is in, its span and whether it is in a list of cells
+            let mut refs = Vec::new();
+            let mut explicit = Vec::new();
+            for (path, node) in visit::depth_first(&self.root) {
+                let proplist = match *node {
+                    Node::Existing { ref proplist, .. } => proplist,
+                    Node::Deleted { .. } => continue,
+                };
+
+                // Either property may be a reference to the node itself, in
+                // which case it is given a phandle like any other referenced node
+                let mut phandle = None;
+                for prop in ["phandle", "linux,phandle"].iter().filter_map(|n| proplist.get(n)) {
+                    match prop.cells() {
+                        Some(ref cells) if cells.len() == 1 => {
+                            if cells[0] == 0 || cells[0] == u64::from(u32::max_value()) {
+                                errors.push(ReferenceError::BadPhandle(path.clone(),
+                                                                       prop.get_span()));
+                                bad.insert(path.clone());
+                            } else if phandle.is_none() {
+                                phandle = Some((cells[0] as u32, prop.get_span()));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                if let Some((phandle, span)) = phandle {
+                    explicit.push((path.clone(), phandle, span));
+                }
+
+                for prop in proplist.values() {
+                    let data = match *prop {
+                        Property::Existing { val: Some(ref data), .. } => data,
+                        _ => continue,
+                    };
+                    let prop_path = child_path(&path, prop.name());
+                    for d in data {
+                        match *d {
+                            Data::Reference(ref refr, _, span) => {
+                                refs.push((refr, prop_path.clone(), span, false))
+                            }
+                            Data::Cells(_, ref cells, _) => {
+                                for cell in cells {
+                                    if let Cell::Ref(ref refr, _, span) = *cell {
+                                        refs.push((refr, prop_path.clone(), span, true));
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+            }
+
+            for (path, phandle, span) in explicit {
+                if used.insert(phandle) {
+                    phandles.insert(path, phandle);
+                } else {
+                    errors.push(ReferenceError::DuplicatePhandle(phandle, path, span));
+                }
+            }
+
+            let mut next = 1;
+            for (refr, prop_path, span, in_cells) in refs {
+                let path = paths.entry(refr.to_owned())
+                    .or_insert_with(|| if refr.starts_with('/') {
+                        self.full_path(refr).ok()
+                    } else {
+                        index.path_of_label(refr).map(|path| path.to_owned())
+                    })
+                    .clone();
+                match path {
+                    Some(path) => {
+                        if in_cells && !phandles.contains_key(&path) && !bad.contains(&path) {
+                            while used.contains(&next) {
+                                next += 1;
+                            }
+                            used.insert(next);
+                            phandles.insert(path.clone(), next);
+                            allocated.push(path);
+                        }
+                    }
+                    None => errors.push(ReferenceError::Dangling(refr.to_owned(), prop_path, span)),
                 }
             }
         }
 
-        // TODO: recalculate boot CPU ID
+        visit::walk_mut(&mut self.root,
+                        &mut Fill {
+                            paths: &paths,
+                            phandles: &phandles,
+                        });
+        for path in allocated {
+            let phandle = Data::Cells(32,
+                                      vec![Cell::Num(u64::from(phandles[&path]))],
+                                      Span::default());
+            if let Ok(&mut Node::Existing { ref mut proplist, .. }) =
+                   self.get_node_by_path_mut(&path) {
+                if !proplist.contains_key("phandle") {
+                    proplist.insert("phandle".to_owned(), Property::Existing {
+                        name: "phandle".to_owned(),
+                        name_span: Span::default(),
+                        val: Some(vec![phandle]),
+                        labels: Vec::new(),
+                        label_spans: Vec::new(),
+                        span: Span::default(),
+                    });
+                }
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Returns the full path of the node at `path`, filling in the unit
+    /// address of each node that was named without one, see
+    /// `get_node_by_path`.
+    ///
+    /// # Errors
+    /// Returns an error if no node exists at the specified path or if the
+    /// string passed is not a valid path.
+    pub fn full_path(&self, path: &str) -> Result<String, ()> {
+        if !path.starts_with('/') {
+            return Err(());
+        }
+
+        let mut node = &self.root;
+        let mut full = String::from("/");
+        for name in path[1..].split('/').filter(|name| !name.is_empty()) {
+            let children = match *node {
+                Node::Existing { ref children, .. } => children,
+                Node::Deleted { .. } => return Err(()),
+            };
+            let name = child_name(children, name).ok_or(())?;
+            full = child_path(&full, name);
+            node = &children[name];
+        }
+
+        Ok(full)
     }
 
     /// Get a reference to a `Node` in the tree by it's path.
     ///
+    /// Like dtc and the Linux kernel, a node may be named without its unit
+    /// address, such as `/soc/uart` for `/soc/uart@fe001000`, as long as no
+    /// other child of the parent has the same name.
+    ///
     /// # Errors
     /// Returns an error if no node exists at the specified path exists or if
     /// string passed is not a valid path.
@@ -99,12 +819,14 @@ impl DTInfo {
 
             let (name, rem) = path.find('/')
                                   .and_then(|pos| Some(path.split_at(pos)))
-                                  .and_then(|(a,b)| Some((a, Some(b))))
+                                  .and_then(|(a,b)| Some((a, Some(&b[1..]))))
                                   .unwrap_or((path, None));
 
             let subnode = match *node {
                 Node::Deleted{..} => return Err(()),
-                Node::Existing{ref children, ..} => children.get(name)
+                Node::Existing{ref children, ..} => {
+                    child_name(children, name).and_then(|name| children.get(name))
+                }
             };
 
             match subnode {
@@ -126,7 +848,8 @@ impl DTInfo {
         }
     }
 
-    /// Get a mutable reference to a `Node` in the tree by it's path.
+    /// Get a mutable reference to a `Node` in the tree by it's path. Unit
+    /// addresses may be left out as with `get_node_by_path`.
     ///
     /// # Errors
     /// Returns an error if no node exists at the specified path exists or if
@@ -139,12 +862,15 @@ impl DTInfo {
 
             let (name, rem) = path.find('/')
                                   .and_then(|pos| Some(path.split_at(pos)))
-                                  .and_then(|(a,b)| Some((a, Some(b))))
+                                  .and_then(|(a,b)| Some((a, Some(&b[1..]))))
                                   .unwrap_or((path, None));
 
             let subnode = match *node {
                 Node::Deleted{..} => return Err(()),
-                Node::Existing{ref mut children, ..} => children.get_mut(name)
+                Node::Existing{ref mut children, ..} => {
+                    let name = child_name(children, name).map(|name| name.to_owned());
+                    name.and_then(move |name| children.get_mut(&name))
+                }
             };
 
             match subnode {
@@ -168,10 +894,9 @@ impl DTInfo {
 
     /// Get a reference to a Node pointed to by a label.
     ///
-    /// This does a naive depth first search at the moment and has no cache
-    /// between runs. This should be fine for a few random accesses, but doing
-    /// some sort of manual caching is suggested if searching my labels is
-    /// needed often.
+    /// This does a naive depth first search and has no cache between runs.
+    /// This should be fine for a few random accesses, but `index::TreeIndex`
+    /// should be used if searching by labels is needed often.
     ///
     /// # Errors
     /// Returns an error if the label is empty or if no 'Node' with the label is
@@ -205,10 +930,10 @@ impl DTInfo {
 
     /// Get a mutable reference to a Node pointed to by a label.
     ///
-    /// This does a naive depth first search at the moment and has no cache
-    /// between runs. This should be fine for a few random accesses, but doing
-    /// some sort of manual caching is suggested if searching my labels is
-    /// needed often.
+    /// This does a naive depth first search and has no cache between runs.
+    /// This should be fine for a few random accesses, but the path of the
+    /// node can be found with `index::TreeIndex::path_of_label` if searching by
+    /// labels is needed often.
     ///
     /// # Errors
     /// Returns an error if the label is empty or if no 'Node' with the label is
@@ -241,6 +966,84 @@ impl DTInfo {
     }
 }
 
+// Whether merging `amend` may change which node a label is on, by adding
+// labels or deleting nodes.
+fn moves_labels(amend: &Node) -> bool {
+    visit::depth_first(amend).any(|(_, node)| match *node {
+        Node::Existing { ref labels, .. } => !labels.is_empty(),
+        Node::Deleted { .. } => true,
+    })
+}
+
+// Finds the name of the child that `name` refers to, which is either the full
+// name of the child or the only child with `name` as its base name. Only the
+// second needs to look through every child.
+fn child_name<'a>(children: &'a OrderedMap<Node>, name: &str) -> Option<&'a str> {
+    if let Some(i) = children.position(name) {
+        return Some(&children.entries[i].0);
+    }
+    if name.contains('@') {
+        return None;
+    }
+
+    let mut found = children.keys().filter(|full| split_unit_address(full).0 == name);
+    match (found.next(), found.next()) {
+        (Some(full), None) => Some(full),
+        _ => None,
+    }
+}
+
+// Reads a property such as `#address-cells` that holds a single cell.
+fn cell_count(proplist: &OrderedMap<Property>, name: &str, default: u64) -> usize {
+    match proplist.get(name).and_then(Property::cells) {
+        Some(ref cells) if cells.len() == 1 => cells[0] as usize,
+        _ => default as usize,
+    }
+}
+
+// Finds the ID of the boot CPU, see `DTInfo::new`.
+fn boot_cpuid(root: &Node) -> u32 {
+    let cpus = match *root {
+        Node::Existing { ref children, .. } => match children.get("cpus") {
+            Some(&Node::Existing { ref children, .. }) => children,
+            _ => return 0,
+        },
+        Node::Deleted { .. } => return 0,
+    };
+
+    let is_cpu = |node: &&Node| match *node {
+        &Node::Existing { ref proplist, .. } => match proplist.get("device_type") {
+            Some(&Property::Existing { val: Some(ref data), .. }) => match data.as_slice() {
+                [Data::String(ref device_type, _)] => device_type == "cpu",
+                _ => false,
+            },
+            _ => false,
+        },
+        &Node::Deleted { .. } => false,
+    };
+    let cpu = cpus.values().find(is_cpu).or_else(|| {
+        cpus.iter().find(|&(name, _)| name.starts_with("cpu@")).map(|(_, cpu)| cpu)
+    });
+
+    let reg = match cpu {
+        Some(&Node::Existing { ref proplist, .. }) => proplist.get("reg").and_then(Property::cells),
+        _ => None,
+    };
+    match reg.as_ref().map(|reg| reg.as_slice()) {
+        Some(&[id]) | Some(&[0, id]) if id <= u64::from(u32::max_value()) => id as u32,
+        _ => 0,
+    }
+}
+
+// Appends the name of a child node to the path of its parent.
+pub(crate) fn child_path(parent: &str, name: &str) -> String {
+    if parent == "/" {
+        format!("/{}", name)
+    } else {
+        format!("{}/{}", parent, name)
+    }
+}
+
 /// Stores the information from a `/memreserve/` statement.
 #[derive(Debug, Clone)]
 pub struct ReserveInfo {
@@ -250,6 +1053,10 @@ pub struct ReserveInfo {
     pub size: u64,
     /// All labels to the `/memreserve/` statement.
     pub labels: Vec<String>,
+    /// The spans of the labels, in the same order as `labels`.
+    pub label_spans: Vec<Span>,
+    /// The span of the whole statement, from the first label to the ';'.
+    pub span: Span,
 }
 
 impl Labeled for ReserveInfo {
@@ -257,6 +1064,7 @@ impl Labeled for ReserveInfo {
         let label = label.to_owned();
         if !self.labels.contains(&label) {
             self.labels.push(label);
+            self.label_spans.push(Span::default());
         }
 
         Ok(())
@@ -265,12 +1073,238 @@ impl Labeled for ReserveInfo {
     fn get_labels(&self) -> &[String] {
         &self.labels
     }
+
+    fn get_label_spans(&self) -> &[Span] {
+        &self.label_spans
+    }
+}
+
+impl Offset for ReserveInfo {
+    fn get_offset(&self) -> usize {
+        self.span.start
+    }
+}
+
+impl Spanned for ReserveInfo {
+    fn get_span(&self) -> Span {
+        self.span
+    }
+}
+
+/// A map from names to properties or nodes that keeps the order in which names
+/// were first inserted, so that trees are printed and walked in source order.
+///
+/// Inserting a name that is already present replaces its value in place, while
+/// new names are appended. This matches how dtc merges amendments into a node.
+#[derive(Debug, Clone)]
+pub struct OrderedMap<V> {
+    entries: Vec<(String, V)>,
+    index: HashMap<String, usize>,
+}
+
+impl<V> OrderedMap<V> {
+    /// Creates an empty `OrderedMap`.
+    pub fn new() -> Self {
+        OrderedMap {
+            entries: Vec::new(),
+            index: HashMap::new(),
+        }
+    }
+
+    /// Returns the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if the map has no entries.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Returns `true` if the map has an entry for the name.
+    pub fn contains_key(&self, name: &str) -> bool {
+        self.index.contains_key(name)
+    }
+
+    /// Returns a reference to the value for the name.
+    pub fn get(&self, name: &str) -> Option<&V> {
+        self.index.get(name).map(|&i| &self.entries[i].1)
+    }
+
+    /// Returns a mutable reference to the value for the name.
+    pub fn get_mut(&mut self, name: &str) -> Option<&mut V> {
+        match self.index.get(name) {
+            Some(&i) => Some(&mut self.entries[i].1),
+            None => None,
+        }
+    }
+
+    /// Returns the position of the name in insertion order.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.index.get(name).cloned()
+    }
+
+    /// Inserts a value for the name. If the name was already present its value
+    /// is replaced without changing its position and the old value is
+    /// returned, otherwise the entry is appended.
+    pub fn insert(&mut self, name: String, value: V) -> Option<V> {
+        if let Some(&i) = self.index.get(&name) {
+            return Some(mem::replace(&mut self.entries[i].1, value));
+        }
+        self.index.insert(name.clone(), self.entries.len());
+        self.entries.push((name, value));
+        None
+    }
+
+    /// Removes the entry for the name and returns its value. The remaining
+    /// entries keep their order.
+    pub fn remove(&mut self, name: &str) -> Option<V> {
+        let pos = self.index.remove(name)?;
+        let (_, value) = self.entries.remove(pos);
+        for &mut (ref name, _) in &mut self.entries[pos..] {
+            *self.index.get_mut(name).expect("name missing from index") -= 1;
+        }
+        Some(value)
+    }
+
+    /// Returns an iterator over the names and values in insertion order.
+    pub fn iter(&self) -> Iter<'_, V> {
+        Iter { inner: self.entries.iter() }
+    }
+
+    /// Returns an iterator over the names and mutable values in insertion
+    /// order.
+    pub fn iter_mut(&mut self) -> IterMut<'_, V> {
+        IterMut { inner: self.entries.iter_mut() }
+    }
+
+    /// Returns an iterator over the names in insertion order.
+    pub fn keys(&self) -> Keys<'_, V> {
+        Keys { inner: self.entries.iter() }
+    }
+
+    /// Returns an iterator over the values in insertion order.
+    pub fn values(&self) -> Values<'_, V> {
+        Values { inner: self.entries.iter() }
+    }
+
+    /// Returns an iterator over mutable values in insertion order.
+    pub fn values_mut(&mut self) -> ValuesMut<'_, V> {
+        ValuesMut { inner: self.entries.iter_mut() }
+    }
+}
+
+impl<V> Default for OrderedMap<V> {
+    fn default() -> Self {
+        OrderedMap::new()
+    }
+}
+
+// Two maps are only equal if their entries are in the same order
+impl<V: PartialEq> PartialEq for OrderedMap<V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
 }
 
+impl<V: Eq> Eq for OrderedMap<V> {}
+
+impl<'a, V> Index<&'a str> for OrderedMap<V> {
+    type Output = V;
+
+    fn index(&self, name: &str) -> &V {
+        self.get(name).expect("no entry found for name")
+    }
+}
+
+impl<V> FromIterator<(String, V)> for OrderedMap<V> {
+    fn from_iter<I: IntoIterator<Item = (String, V)>>(iter: I) -> Self {
+        let mut map = OrderedMap::new();
+        map.extend(iter);
+        map
+    }
+}
+
+impl<V> Extend<(String, V)> for OrderedMap<V> {
+    fn extend<I: IntoIterator<Item = (String, V)>>(&mut self, iter: I) {
+        for (name, value) in iter {
+            self.insert(name, value);
+        }
+    }
+}
+
+impl<'a, V> IntoIterator for &'a OrderedMap<V> {
+    type Item = (&'a String, &'a V);
+    type IntoIter = Iter<'a, V>;
+
+    fn into_iter(self) -> Iter<'a, V> {
+        self.iter()
+    }
+}
+
+impl<'a, V> IntoIterator for &'a mut OrderedMap<V> {
+    type Item = (&'a String, &'a mut V);
+    type IntoIter = IterMut<'a, V>;
+
+    fn into_iter(self) -> IterMut<'a, V> {
+        self.iter_mut()
+    }
+}
+
+impl<V> IntoIterator for OrderedMap<V> {
+    type Item = (String, V);
+    type IntoIter = vec::IntoIter<(String, V)>;
+
+    fn into_iter(self) -> vec::IntoIter<(String, V)> {
+        self.entries.into_iter()
+    }
+}
+
+macro_rules! ordered_map_iter {
+    ($(#[$attr:meta])* $name:ident, $iter:ident, $item:ty, $pat:pat => $map:expr) => {
+        $(#[$attr])*
+        #[derive(Debug)]
+        pub struct $name<'a, V: 'a> {
+            inner: slice::$iter<'a, (String, V)>,
+        }
+
+        impl<'a, V> Iterator for $name<'a, V> {
+            type Item = $item;
+
+            fn next(&mut self) -> Option<$item> {
+                self.inner.next().map(|$pat| $map)
+            }
+
+            fn size_hint(&self) -> (usize, Option<usize>) {
+                self.inner.size_hint()
+            }
+        }
+
+        impl<'a, V> DoubleEndedIterator for $name<'a, V> {
+            fn next_back(&mut self) -> Option<$item> {
+                self.inner.next_back().map(|$pat| $map)
+            }
+        }
+
+        impl<'a, V> ExactSizeIterator for $name<'a, V> {}
+    }
+}
+
+ordered_map_iter!(/// Iterator over the names and values of an `OrderedMap`.
+                  Iter, Iter, (&'a String, &'a V), &(ref k, ref v) => (k, v));
+ordered_map_iter!(/// Iterator over the names and mutable values of an `OrderedMap`.
+                  IterMut, IterMut, (&'a String, &'a mut V), &mut (ref k, ref mut v) => (k, v));
+ordered_map_iter!(/// Iterator over the names of an `OrderedMap`.
+                  Keys, Iter, &'a String, &(ref k, _) => k);
+ordered_map_iter!(/// Iterator over the values of an `OrderedMap`.
+                  Values, Iter, &'a V, &(_, ref v) => v);
+ordered_map_iter!(/// Iterator over the mutable values of an `OrderedMap`.
+                  ValuesMut, IterMut, &'a mut V, &mut (_, ref mut v) => v);
+
 /// A node in the device tree.
 ///
 /// The node can have labels, contain properties, and contain other nodes. The
-/// node also tracks it's own offset in the buffer that it was parsed from.
+/// node also tracks it's own span in the buffer that it was parsed from.
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Node {
     /// A deleted node, made from a `/delete-node/` statement.
@@ -279,9 +1313,12 @@ pub enum Node {
         /// of the full path
         name: NodeName,
 
-        /// The offset in bytes that this `Node` was found at within the buffer
-        /// that the containing tree was parsed from.
-        offset: usize
+        /// The span of the name, including the '&' of a ref.
+        name_span: Span,
+
+        /// The span of the statement within the buffer that the containing
+        /// tree was parsed from, from `/delete-node/` to the ';'.
+        span: Span,
     },
     /// A regular node.
     Existing {
@@ -289,15 +1326,23 @@ pub enum Node {
         /// of the full path
         name: NodeName,
 
-        /// The properties contained within this node. Stored in a hashmap with
-        /// the key being the name of the `Property` and the value the Property
-        /// its self.
-        proplist: HashMap<String, Property>,
+        /// The span of the name, including the '&' of a ref.
+        name_span: Span,
 
-        /// The child nodes contained within this node. Stored in a hashmap with
-        /// the key being the name of the child `Node` and the value the child
-        /// `Node` its self.
-        children: HashMap<String, Node>,
+        /// The properties contained within this node. Stored in source order
+        /// with the key being the name of the `Property` and the value the
+        /// Property its self.
+        proplist: OrderedMap<Property>,
+
+        /// The child nodes contained within this node. Stored in source order
+        /// with the key being the name of the child `Node` and the value the
+        /// child `Node` its self.
+        children: OrderedMap<Node>,
+
+        /// Earlier definitions of properties and child nodes that were defined
+        /// again later within the same body, in source order. Only the last
+        /// definition of a name is kept in `proplist` or `children`.
+        duplicates: Vec<Duplicate>,
 
         // fullpath: Option<PathBuf>,
         // length to the # part of node_name@#
@@ -310,9 +1355,19 @@ pub enum Node {
         /// The labels that refer to this node.
         labels: Vec<String>,
 
-        /// The offset in bytes that this `Node` was found at within the buffer
-        /// that the containing tree was parsed from.
-        offset: usize,
+        /// The spans of the labels, in the same order as `labels`.
+        label_spans: Vec<Span>,
+
+        /// Whether the node was marked with `/omit-if-no-ref/`, either before
+        /// its name or with a `/omit-if-no-ref/ &label;` statement. Such nodes
+        /// are dropped from the tree if nothing references them, see
+        /// `DTInfo::omit_unreferenced`.
+        omit_if_no_ref: bool,
+
+        /// The span of the node within the buffer that the containing tree was
+        /// parsed from, from the first label to the ';' after the closing
+        /// brace.
+        span: Span,
     },
 }
 
@@ -326,53 +1381,185 @@ impl Node {
         }
     }
 
+    /// Convenience function to get the span of the name no matter what form
+    /// the `Node` is in.
+    pub fn name_span(&self) -> Span {
+        match *self {
+            Node::Deleted { name_span, .. } |
+            Node::Existing { name_span, .. } => name_span,
+        }
+    }
+
     /// Merge one `Node` into another. If a property exists in both `Node`s the
     /// value in the `other` `Node` will be kept. This merge is also applied to
-    /// all child nodes, recursively.
-    fn merge(&mut self, other: &Node) {
+    /// all child nodes, recursively. Child nodes deleted by `other` that do not
+    /// exist are reported in `errors`.
+    fn merge(&mut self, other: &Node, errors: &mut Vec<AmendError>) {
         match (self, other) {
             (&mut Node::Existing { proplist: ref mut s_props,
                                    children: ref mut s_childs,
                                    labels: ref mut s_labels,
+                                   label_spans: ref mut s_label_spans,
+                                   omit_if_no_ref: ref mut s_omit,
                                    .. },
              &Node::Existing { proplist: ref o_props,
                                children: ref o_childs,
                                labels: ref o_labels,
+                               label_spans: ref o_label_spans,
+                               omit_if_no_ref: o_omit,
                                .. }) => {
+                *s_omit |= o_omit;
                 // merge props
                 s_props.extend(o_props.iter().map(|(k, v)| (k.to_owned(), v.to_owned())));
                 // merge nodes
                 for (name, node) in o_childs {
-                    match node {
-                        &Node::Deleted { .. } => { s_childs.remove(name).expect(&format!("Deleted non-existent node {}", name)); } // FIXME: should there always be a deletable node when deleting?
-                        &Node::Existing { .. } => {
-                            let entry = s_childs.entry(name.to_owned());
-                            if let Entry::Occupied(mut e) = entry {
-                                e.get_mut().merge(node);
-                            } else {
-                                entry.or_insert_with(|| node.clone());
+                    match *node {
+                        Node::Deleted { span, .. } => {
+                            if s_childs.remove(name).is_none() {
+                                errors.push(AmendError::NoSuchNode(name.to_owned(), span.start));
+                            }
+                        }
+                        Node::Existing { .. } => {
+                            match s_childs.get_mut(name) {
+                                Some(child @ &mut Node::Existing { .. }) => {
+                                    child.merge(node, errors);
+                                    continue;
+                                }
+                                Some(_) | None => {}
                             }
+                            s_childs.insert(name.to_owned(), node.clone());
                         }
                     }
                 }
                 // merge labels
-                s_labels.extend(o_labels.iter().map(|s| s.to_owned()));
-                s_labels.sort();
-                s_labels.dedup();
+                for (label, span) in o_labels.iter().zip(o_label_spans) {
+                    if !s_labels.contains(label) {
+                        s_labels.push(label.to_owned());
+                        s_label_spans.push(*span);
+                    }
+                }
             }
             _ => unreachable!()
         }
     }
+
+    /// Returns every property and child node defined more than once within the
+    /// body of this node or any node below it. `path` is the path of this node
+    /// and is used to build the paths in the returned `Redefinition`s. A name
+    /// that is deleted between two definitions is not counted as redefined.
+    pub fn redefinitions(&self, path: &str) -> Vec<Redefinition> {
+        fn internal(node: &Node, path: &str, found: &mut Vec<Redefinition>) {
+            if let Node::Existing { ref proplist, ref children, ref duplicates, .. } = *node {
+                // Each name is walked from its earliest definition to the one
+                // that was kept
+                let props = duplicates.iter()
+                                      .filter_map(|d| match *d {
+                                          Duplicate::Property(ref prop) => Some(prop),
+                                          Duplicate::Node(_) => None,
+                                      })
+                                      .chain(proplist.values());
+                let mut last: HashMap<&str, Span> = HashMap::new();
+                for prop in props {
+                    match *prop {
+                        Property::Existing { span, .. } => {
+                            if let Some(first) = last.insert(prop.name(), span) {
+                                found.push(Redefinition::Property(child_path(path, prop.name()),
+                                                                  first,
+                                                                  span));
+                            }
+                        }
+                        Property::Deleted { .. } => { last.remove(prop.name()); }
+                    }
+                }
+
+                let nodes = duplicates.iter()
+                                      .filter_map(|d| match *d {
+                                          Duplicate::Node(ref node) => Some(node),
+                                          Duplicate::Property(_) => None,
+                                      })
+                                      .chain(children.values());
+                let mut last: HashMap<&str, Span> = HashMap::new();
+                for child in nodes {
+                    let name = child.name().as_str();
+                    match *child {
+                        Node::Existing { span, .. } => {
+                            if let Some(first) = last.insert(name, span) {
+                                found.push(Redefinition::Node(child_path(path, name),
+                                                              first,
+                                                              span));
+                            }
+                        }
+                        Node::Deleted { .. } => { last.remove(name); }
+                    }
+                }
+
+                for dup in duplicates {
+                    if let Duplicate::Node(ref child) = *dup {
+                        internal(child, &child_path(path, child.name().as_str()), found);
+                    }
+                }
+                for (name, child) in children {
+                    internal(child, &child_path(path, name), found);
+                }
+            }
+        }
+
+        let mut found = Vec::new();
+        internal(self, path, &mut found);
+        found
+    }
+
+    /// Returns the `Fragment` held by this `Node` if it is a fragment of an
+    /// overlay, that is if it has an `__overlay__` child node. The target is
+    /// taken from a `target = <&label>;` or `target = <&{/path}>;` property,
+    /// or failing that a `target-path = "/path";` property.
+    pub fn fragment(&self) -> Option<Fragment<'_>> {
+        match *self {
+            Node::Deleted { .. } => None,
+            Node::Existing { ref proplist, ref children, .. } => {
+                let overlay = match children.get("__overlay__") {
+                    Some(overlay @ &Node::Existing { .. }) => overlay,
+                    _ => return None,
+                };
+
+                let target = match proplist.get("target") {
+                    Some(&Property::Existing { val: Some(ref data), .. }) => {
+                        match data.as_slice() {
+                            [Data::Cells(_, ref cells, _)] => match cells.as_slice() {
+                                [Cell::Ref(ref refr, ..)] => Some(refr.as_str()),
+                                _ => None,
+                            },
+                            [Data::Reference(ref refr, ..)] => Some(refr.as_str()),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                };
+                let target = target.or_else(|| match proplist.get("target-path") {
+                    Some(&Property::Existing { val: Some(ref data), .. }) => {
+                        match data.as_slice() {
+                            [Data::String(ref path, _)] => Some(path.as_str()),
+                            _ => None,
+                        }
+                    }
+                    _ => None,
+                });
+
+                Some(Fragment { target: target, overlay: overlay })
+            }
+        }
+    }
 }
 
 impl Labeled for Node {
     fn add_label(&mut self, label: &str) -> Result<(), ()> {
         match *self {
             Node::Deleted { .. } => Err(()),
-            Node::Existing { ref mut labels, .. } => {
+            Node::Existing { ref mut labels, ref mut label_spans, .. } => {
                 let label = label.to_owned();
-                if labels.contains(&label) {
+                if !labels.contains(&label) {
                     labels.push(label);
+                    label_spans.push(Span::default());
                 }
 
                 Ok(())
@@ -386,13 +1573,26 @@ impl Labeled for Node {
             Node::Existing { ref labels, .. } => labels,
         }
     }
+
+    fn get_label_spans(&self) -> &[Span] {
+        match *self {
+            Node::Deleted { .. } => &[],
+            Node::Existing { ref label_spans, .. } => label_spans,
+        }
+    }
 }
 
 impl Offset for Node {
     fn get_offset(&self) -> usize {
+        self.get_span().start
+    }
+}
+
+impl Spanned for Node {
+    fn get_span(&self) -> Span {
         match *self {
-            Node::Deleted { offset, .. } |
-            Node::Existing { offset, .. } => offset,
+            Node::Deleted { span, .. } |
+            Node::Existing { span, .. } => span,
         }
     }
 }
@@ -402,7 +1602,10 @@ impl fmt::Display for Node {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Node::Deleted { ref name, .. } => write!(f, "// Node {} deleted", name)?,
-            Node::Existing { ref name, ref proplist, ref children, .. } => {
+            Node::Existing { ref name, ref proplist, ref children, omit_if_no_ref, .. } => {
+                if omit_if_no_ref {
+                    write!(f, "/omit-if-no-ref/ ")?;
+                }
                 writeln!(f, "{} {{", name)?;
                 for prop in proplist.values() {
                     writeln!(f, "    {}", prop)?;
@@ -453,20 +1656,48 @@ impl NodeName {
             NodeName::Full(ref name) => name,
         }
     }
+
+    /// The name without its unit address, such as `uart` for
+    /// `uart@fe001000`. A reference is returned whole.
+    pub fn base_name(&self) -> &str {
+        match *self {
+            NodeName::Ref(ref name) => name,
+            NodeName::Full(ref name) => split_unit_address(name).0,
+        }
+    }
+
+    /// The unit address of the name, such as `fe001000` for `uart@fe001000`,
+    /// if it has one. A reference never has one.
+    pub fn unit_address(&self) -> Option<&str> {
+        match *self {
+            NodeName::Ref(_) => None,
+            NodeName::Full(ref name) => split_unit_address(name).1,
+        }
+    }
+}
+
+// Splits a node name into its base name and unit address.
+fn split_unit_address(name: &str) -> (&str, Option<&str>) {
+    match name.find('@') {
+        Some(pos) => (&name[..pos], Some(&name[pos + 1..])),
+        None => (name, None),
+    }
 }
 
 /// A property of a node.
 ///
-/// The property tracks it's own offset in the buffer that it was parsed from.
+/// The property tracks it's own span in the buffer that it was parsed from.
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Property {
     /// A deleted node, made from a `/delete-property/` statement.
     Deleted {
         /// The full name of the property.
         name: String,
-        /// The offset in bytes that this `Property` was found at within the
-        /// buffer that the containing tree was parsed from.
-        offset: usize
+        /// The span of the name.
+        name_span: Span,
+        /// The span of the statement within the buffer that the containing
+        /// tree was parsed from, from `/delete-property/` to the ';'.
+        span: Span,
     },
     /// A normal property. Unlike `Node`s, a `Property`'s name will never be a
     /// reference. If `val` is `None` then the property was in the form
@@ -480,14 +1711,18 @@ pub enum Property {
     Existing {
         /// The full name of the property.
         name: String,
+        /// The span of the name.
+        name_span: Span,
         /// The values assigned to the property. `None` if the property is in a
         /// marker form with no values.
         val: Option<Vec<Data>>,
         /// The labels that refer to this property.
         labels: Vec<String>,
-        /// The offset in bytes that this `Property` was found at within the
-        /// buffer that the containing tree was parsed from.
-        offset: usize,
+        /// The spans of the labels, in the same order as `labels`.
+        label_spans: Vec<Span>,
+        /// The span of the property within the buffer that the containing
+        /// tree was parsed from, from the first label to the ';'.
+        span: Span,
     },
 }
 
@@ -500,16 +1735,55 @@ impl Property {
            Property::Existing{ref name, ..} => name
         }
     }
+
+    /// Convenience function to get the span of the name no matter what form
+    /// the `Property` is in.
+    pub fn name_span(&self) -> Span {
+        match *self {
+           Property::Deleted { name_span, .. } |
+           Property::Existing { name_span, .. } => name_span,
+        }
+    }
+
+    /// Returns the numbers in the property if it only holds lists of 32-bit
+    /// cells, such as `reg = <0x0 0x1000>, <0x2000 0x100>;`, with the lists
+    /// joined together. Labels within the data are skipped. Returns `None` for
+    /// any other data, including references.
+    pub fn cells(&self) -> Option<Vec<u64>> {
+        let data = match *self {
+            Property::Existing { val: Some(ref data), .. } => data,
+            _ => return None,
+        };
+
+        let mut values = Vec::new();
+        for d in data {
+            match *d {
+                Data::Cells(32, ref cells, _) => {
+                    for cell in cells {
+                        match *cell {
+                            Cell::Label(..) => {}
+                            _ => values.push(cell.value()?),
+                        }
+                    }
+                }
+                Data::Label(..) => {}
+                _ => return None,
+            }
+        }
+
+        Some(values)
+    }
 }
 
 impl Labeled for Property {
     fn add_label(&mut self, label: &str) -> Result<(), ()> {
         match *self {
             Property::Deleted { .. } => Err(()),
-            Property::Existing { ref mut labels, .. } => {
+            Property::Existing { ref mut labels, ref mut label_spans, .. } => {
                 let label = label.to_owned();
-                if labels.contains(&label) {
+                if !labels.contains(&label) {
                     labels.push(label);
+                    label_spans.push(Span::default());
                 }
                 Ok(())
             }
@@ -522,13 +1796,26 @@ impl Labeled for Property {
             Property::Existing { ref labels, .. } => labels,
         }
     }
+
+    fn get_label_spans(&self) -> &[Span] {
+        match *self {
+            Property::Deleted { .. } => &[],
+            Property::Existing { ref label_spans, .. } => label_spans,
+        }
+    }
 }
 
 impl Offset for Property {
     fn get_offset(&self) -> usize {
+        self.get_span().start
+    }
+}
+
+impl Spanned for Property {
+    fn get_span(&self) -> Span {
         match *self {
-            Property::Deleted { offset, .. } |
-            Property::Existing { offset, .. } => offset,
+            Property::Deleted { span, .. } |
+            Property::Existing { span, .. } => span,
         }
     }
 }
@@ -542,10 +1829,30 @@ impl fmt::Display for Property {
                 write!(f, "{}", name)?;
                 if let Some(ref data) = *val {
                     if !data.is_empty() {
-                        let mut iter = data.iter();
-                        write!(f, " = {}", iter.next().unwrap())?;
-                        for d in iter {
-                            write!(f, ", {}", d)?;
+                        write!(f, " =")?;
+                        // Labels are written before the data they point to,
+                        // or after all data if none follows them.
+                        let mut need_comma = false;
+                        for (i, d) in data.iter().enumerate() {
+                            match *d {
+                                Data::Label(..) => {
+                                    let trailing = data[i..].iter().all(|d| match *d {
+                                        Data::Label(..) => true,
+                                        _ => false,
+                                    });
+                                    if need_comma && !trailing {
+                                        write!(f, ",")?;
+                                        need_comma = false;
+                                    }
+                                }
+                                _ => {
+                                    if need_comma {
+                                        write!(f, ",")?;
+                                    }
+                                    need_comma = true;
+                                }
+                            }
+                            write!(f, " {}", d)?;
                         }
                     }
                 }
@@ -557,29 +1864,62 @@ impl fmt::Display for Property {
     }
 }
 
-/// Data that properties might contain.
+/// Data that properties might contain. Each piece of data holds its span
+/// within the buffer that the containing tree was parsed from as its final
+/// field.
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Data {
-    /// A reference to a labeled object. Could be a node, a property, or data.
-    /// May contain the phandle number of the referenced object.
-    Reference(String, Option<u64>),
+    /// A reference to a node outside of a list of cells, such as `&uart0` in
+    /// `serial0 = &uart0;`, which stands for the full path of the node. Holds
+    /// the label or path as written and the full path once resolved, see
+    /// `DTInfo::resolve_references`.
+    Reference(String, Option<String>, Span),
     /// An ASCII string. See `parser::escape_c_string` to see the few
-    /// limitations placed on the string.
-    String(String),
+    /// limitations placed on the string. The span includes the quotes.
+    String(String, Span),
     /// A list of cells. The preceding `usize` to the `Vec` of cells is the number of bits used to
     /// represent each cell. If not set with a preceding `/bits/` statement, it defaults to 32 bits.
-    Cells(usize, Vec<Cell>),
+    /// The span includes the `/bits/` statement and the angle brackets.
+    Cells(usize, Vec<Cell>, Span),
     /// An array of bytes.
-    ByteArray(Vec<u8>),
+    ByteArray(Vec<u8>, Span),
+    /// A label pointing to a position within the data of a property, such as
+    /// `lbl` in `compatible = lbl: "foo";`. Holds the name of the label and
+    /// the span of the label, including the ':'.
+    Label(String, Span),
+    /// A binary include, made from a `/incbin/` statement. Holds the file name
+    /// as written, the offset in bytes to start reading from, and the number of
+    /// bytes to read. If no length was given the rest of the file is included.
+    /// The contents are not read while parsing, see `include::read_incbin`.
+    Incbin(String, u64, Option<u64>, Span),
+}
+
+impl Offset for Data {
+    fn get_offset(&self) -> usize {
+        self.get_span().start
+    }
+}
+
+impl Spanned for Data {
+    fn get_span(&self) -> Span {
+        match *self {
+            Data::Reference(_, _, span) |
+            Data::String(_, span) |
+            Data::Cells(_, _, span) |
+            Data::ByteArray(_, span) |
+            Data::Label(_, span) |
+            Data::Incbin(_, _, _, span) => span,
+        }
+    }
 }
 
 impl fmt::Display for Data {
-    // TODO: labels - issue 3 - issue 6
+    // TODO: labels - issue 3
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Data::Reference(ref r, _) => write!(f, "&{}", r)?,
-            Data::String(ref s) => write!(f, "{}", s)?,
-            Data::Cells(bits, ref cells) => {
+            Data::Reference(ref r, ..) => write!(f, "&{}", r)?,
+            Data::String(ref s, _) => write!(f, "{}", s)?,
+            Data::Cells(bits, ref cells, _) => {
                 if bits != 32 {
                     write!(f, "/bits/ {}", bits)?;
                 }
@@ -588,12 +1928,12 @@ impl fmt::Display for Data {
                     let mut iter = cells.iter();
                     write!(f, "{}", iter.next().unwrap())?;
                     for c in iter {
-                        write!(f, "{}", c)?;
+                        write!(f, " {}", c)?;
                     }
                 }
                 write!(f, ">")?;
             }
-            Data::ByteArray(ref arr) => {
+            Data::ByteArray(ref arr, _) => {
                 write!(f, "[ ")?;
                 if !arr.is_empty() {
                     let mut iter = arr.iter();
@@ -604,6 +1944,10 @@ impl fmt::Display for Data {
                 }
                 write!(f, " ]")?;
             }
+            Data::Label(ref label, _) => write!(f, "{}:", label)?,
+            Data::Incbin(ref file, offset, Some(len), _) =>
+                write!(f, "/incbin/(\"{}\", {:#X}, {:#X})", file, offset, len)?,
+            Data::Incbin(ref file, _, None, _) => write!(f, "/incbin/(\"{}\")", file)?,
         }
 
         Ok(())
@@ -614,48 +1958,434 @@ impl fmt::Display for Data {
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub enum Cell {
     /// A number represented in a certain number of bits. The number of bits is defined in the
-    /// `Data::Cells` holding this `Cell`.
+    /// `Data::Cells` holding this `Cell`. Never made by the parser, and so has no span.
     Num(u64),
-    /// A reference to a labeled object. Could be a node, a property, or data.
-    /// May contain the phandle number of the referenced object.
-    Ref(String, Option<u64>),
+    /// A reference to a node, either a label or a path in the form `&{/path}`,
+    /// that stands for the phandle of the node. Holds the label or path as
+    /// written and the phandle once resolved, see
+    /// `DTInfo::resolve_references`. The span includes the '&'.
+    Ref(String, Option<u64>, Span),
+    /// A label pointing to a position within a list of cells, such as `start`
+    /// in `reg = <0x0 start: 0x1000>;`. Holds the name of the label and the
+    /// span of the label, including the ':'.
+    Label(String, Span),
+    /// A number as written in the source, either as a literal or as an
+    /// expression. Parsed numbers are always stored in this form so that the
+    /// original text is not lost, such as `0x1000` being shown as `4096`.
+    Expr {
+        /// The value the expression evaluates to.
+        value: u64,
+        /// The expression as written in the buffer that the containing tree was
+        /// parsed from. If the buffer was run through the C preprocessor this is
+        /// the text after macro expansion.
+        text: String,
+        /// The macro the expression was expanded from, such as `GIC_SPI`, if it
+        /// is known. Never set by the parser, see `include::MacroLocator` for
+        /// one way to find it.
+        macro_name: Option<String>,
+        /// The span of the expression within the buffer that the containing
+        /// tree was parsed from.
+        span: Span,
+    },
+}
+
+impl Cell {
+    /// Returns the numeric value of the cell, if it has one. A reference only
+    /// has one once it has been resolved.
+    pub fn value(&self) -> Option<u64> {
+        match *self {
+            Cell::Num(value) |
+            Cell::Expr { value, .. } |
+            Cell::Ref(_, Some(value), _) => Some(value),
+            Cell::Ref(_, None, _) | Cell::Label(..) => None,
+        }
+    }
+}
+
+impl Offset for Cell {
+    fn get_offset(&self) -> usize {
+        self.get_span().start
+    }
+}
+
+impl Spanned for Cell {
+    fn get_span(&self) -> Span {
+        match *self {
+            Cell::Num(_) => Span::default(),
+            Cell::Ref(_, _, span) | Cell::Label(_, span) | Cell::Expr { span, .. } => span,
+        }
+    }
 }
 
 impl fmt::Display for Cell {
-    // TODO: labels - issue 3 - issue 6
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Cell::Num(i) => write!(f, "{}", i)?,
-            Cell::Ref(ref s, _) => write!(f, "&{}", s)?,
+            Cell::Expr { ref text, macro_name: None, .. } => write!(f, "{}", text)?,
+            Cell::Expr { value, macro_name: Some(ref name), .. } =>
+                write!(f, "{} /* {} */", name, value)?,
+            Cell::Ref(ref s, ..) => write!(f, "&{}", s)?,
+            Cell::Label(ref label, _) => write!(f, "{}:", label)?,
         }
 
         Ok(())
     }
 }
 
+/// The ID of a node within an `ArenaTree`. An ID stays valid until its node is
+/// removed, and is never given to another node after that.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct NodeId(usize);
+
+#[derive(Debug, Clone)]
+struct ArenaNode {
+    // The node itself, with its children moved out to `children`
+    node: Node,
+    parent: Option<NodeId>,
+    children: Vec<NodeId>,
+}
+
+/// A device tree stored as a flat list of nodes, each with a link to its
+/// parent and its children, instead of nodes owning their children as in
+/// `DTInfo`. This makes walking up the tree cheap, such as to find the
+/// `#address-cells` that applies to a node or its full path.
+///
+/// Convert to and from a `DTInfo` with `From`. Nothing is lost either way.
+///
+/// ```
+/// use device_tree_source::tree::{ArenaTree, DTInfo};
+/// use device_tree_source::parser::{parse_dt, ParseResult};
+///
+/// let source = b"/dts-v1/;\n/ { soc { #address-cells = <1>; uart@1000 { }; }; };";
+/// let tree = match parse_dt(source) {
+///     Ok(ParseResult::Complete(tree, _)) => tree,
+///     _ => unreachable!(),
+/// };
+///
+/// let arena = ArenaTree::from(tree);
+/// let uart = arena.find("/soc/uart").unwrap();
+/// assert_eq!(arena.path(uart), "/soc/uart@1000");
+/// assert_eq!(arena.address_cells(uart), 1);
+///
+/// let tree = DTInfo::from(arena);
+/// assert!(tree.get_node_by_path("/soc/uart@1000").is_ok());
+/// ```
+#[derive(Debug, Clone)]
+pub struct ArenaTree {
+    /// The reserved memory information.
+    pub reserve_info: Vec<ReserveInfo>,
+    /// The boot CPU ID, see `DTInfo::boot_cpuid`.
+    pub boot_cpuid: u32,
+    /// Whether `boot_cpuid` was set explicitly, see `DTInfo::boot_cpuid_fixed`.
+    pub boot_cpuid_fixed: bool,
+    // Removed nodes leave a `None` behind so that IDs stay stable
+    nodes: Vec<Option<ArenaNode>>,
+}
+
+impl ArenaTree {
+    /// The ID of the root node.
+    pub fn root(&self) -> NodeId {
+        NodeId(0)
+    }
+
+    /// Returns true if the ID is of a node that has not been removed.
+    pub fn contains(&self, id: NodeId) -> bool {
+        self.nodes.get(id.0).map_or(false, |entry| entry.is_some())
+    }
+
+    fn entry(&self, id: NodeId) -> &ArenaNode {
+        self.nodes[id.0].as_ref().expect("node was removed")
+    }
+
+    /// Get a reference to the node. Its `children` are always empty, see
+    /// `children` for them.
+    ///
+    /// # Panics
+    /// Panics if the node was removed.
+    pub fn node(&self, id: NodeId) -> &Node {
+        &self.entry(id).node
+    }
+
+    /// Get a mutable reference to the node. Any children added to it are
+    /// ignored, see `add_child` to add them.
+    ///
+    /// # Panics
+    /// Panics if the node was removed.
+    pub fn node_mut(&mut self, id: NodeId) -> &mut Node {
+        &mut self.nodes[id.0].as_mut().expect("node was removed").node
+    }
+
+    /// The parent of the node, or `None` for the root node.
+    pub fn parent(&self, id: NodeId) -> Option<NodeId> {
+        self.entry(id).parent
+    }
+
+    /// The children of the node, in order.
+    pub fn children(&self, id: NodeId) -> &[NodeId] {
+        &self.entry(id).children
+    }
+
+    /// Iterates over the parent of the node, its parent and so on up to the
+    /// root node.
+    pub fn ancestors(&self, id: NodeId) -> Ancestors<'_> {
+        Ancestors {
+            tree: self,
+            next: self.parent(id),
+        }
+    }
+
+    /// Finds the child of the node with the name. Like
+    /// `DTInfo::get_node_by_path`, the unit address may be left out if no
+    /// other child has the same name.
+    pub fn child(&self, id: NodeId, name: &str) -> Option<NodeId> {
+        let children = self.children(id);
+        if let Some(&child) = children.iter().find(|&&c| self.node(c).name().as_str() == name) {
+            return Some(child);
+        }
+        if name.contains('@') {
+            return None;
+        }
+
+        let mut found = children.iter().filter(|&&c| self.node(c).name().base_name() == name);
+        match (found.next(), found.next()) {
+            (Some(&child), None) => Some(child),
+            _ => None,
+        }
+    }
+
+    /// Finds the node at the path, see `child`.
+    pub fn find(&self, path: &str) -> Option<NodeId> {
+        if !path.starts_with('/') {
+            return None;
+        }
+        path[1..].split('/')
+                 .filter(|name| !name.is_empty())
+                 .fold(Some(self.root()), |id, name| id.and_then(|id| self.child(id, name)))
+    }
+
+    /// The full path of the node.
+    pub fn path(&self, id: NodeId) -> String {
+        let mut names: Vec<&str> = iter::once(id)
+            .chain(self.ancestors(id))
+            .filter(|&id| id != self.root())
+            .map(|id| self.node(id).name().as_str())
+            .collect();
+        names.reverse();
+        format!("/{}", names.join("/"))
+    }
+
+    /// The `#address-cells` of the node's parent, which gives the number of
+    /// cells in each address of the node's `reg` property. Defaults to 2 as
+    /// in the Devicetree Specification.
+    pub fn address_cells(&self, id: NodeId) -> usize {
+        self.parent_cells(id, "#address-cells", 2)
+    }
+
+    /// The `#size-cells` of the node's parent, which gives the number of cells
+    /// in each size of the node's `reg` property. Defaults to 1 as in the
+    /// Devicetree Specification.
+    pub fn size_cells(&self, id: NodeId) -> usize {
+        self.parent_cells(id, "#size-cells", 1)
+    }
+
+    fn parent_cells(&self, id: NodeId, name: &str, default: u64) -> usize {
+        match self.parent(id).map(|parent| self.node(parent)) {
+            Some(&Node::Existing { ref proplist, .. }) => cell_count(proplist, name, default),
+            _ => default as usize,
+        }
+    }
+
+    /// Finds the node with the phandle, as given by its `phandle` or
+    /// `linux,phandle` property. This looks at every node, so build a map of
+    /// phandles to IDs instead if many are needed.
+    pub fn node_by_phandle(&self, phandle: u32) -> Option<NodeId> {
+        (0..self.nodes.len()).map(NodeId)
+                             .filter(|&id| self.contains(id))
+                             .find(|&id| match *self.node(id) {
+                                 Node::Existing { ref proplist, .. } => {
+                                     proplist.get("phandle")
+                                             .or_else(|| proplist.get("linux,phandle"))
+                                             .and_then(Property::cells) ==
+                                     Some(vec![u64::from(phandle)])
+                                 }
+                                 Node::Deleted { .. } => false,
+                             })
+    }
+
+    /// The interrupt parent of the node, found the way Linux's
+    /// `of_irq_find_parent` does. The `interrupt-parent` property of the node
+    /// is followed if it has one, otherwise its parent in the tree is taken,
+    /// and this repeats until a node with `#interrupt-cells` is reached. So a
+    /// node inherits the `interrupt-parent` of the bus it is on or of the root
+    /// node. Returns `None` if the root node is passed without finding one or
+    /// an `interrupt-parent` does not refer to a node. References must have
+    /// been resolved, see `DTInfo::resolve_references`.
+    pub fn interrupt_parent(&self, id: NodeId) -> Option<NodeId> {
+        let mut id = id;
+        // Only a loop of `interrupt-parent` properties can take more steps
+        for _ in 0..self.nodes.len() {
+            let proplist = match *self.node(id) {
+                Node::Existing { ref proplist, .. } => proplist,
+                Node::Deleted { .. } => return None,
+            };
+            let next = match proplist.get("interrupt-parent").map(Property::cells) {
+                Some(Some(ref cells)) if cells.len() == 1 &&
+                                         cells[0] <= u64::from(u32::max_value()) => {
+                    self.node_by_phandle(cells[0] as u32)?
+                }
+                Some(_) => return None,
+                None => self.parent(id)?,
+            };
+            let found = match *self.node(next) {
+                Node::Existing { ref proplist, .. } => proplist.contains_key("#interrupt-cells"),
+                Node::Deleted { .. } => false,
+            };
+            if found {
+                return Some(next);
+            }
+            id = next;
+        }
+        None
+    }
+
+    /// Adds the node, along with all of its children, as the last child of
+    /// `parent`. Returns the ID of the added node.
+    ///
+    /// # Panics
+    /// Panics if the parent was removed.
+    pub fn add_child(&mut self, parent: NodeId, node: Node) -> NodeId {
+        assert!(self.contains(parent), "node was removed");
+        let id = self.insert(Some(parent), node);
+        self.nodes[parent.0].as_mut().unwrap().children.push(id);
+        id
+    }
+
+    /// Removes the node and all of its children. Their IDs are not reused.
+    ///
+    /// # Panics
+    /// Panics if the node was already removed or is the root node.
+    pub fn remove(&mut self, id: NodeId) {
+        let parent = self.parent(id).expect("the root node can not be removed");
+        self.nodes[parent.0].as_mut().unwrap().children.retain(|&child| child != id);
+
+        let mut removed = vec![id];
+        while let Some(id) = removed.pop() {
+            if let Some(entry) = self.nodes[id.0].take() {
+                removed.extend(entry.children);
+            }
+        }
+    }
+
+    // Moves the node and its children into the arena, depth first
+    fn insert(&mut self, parent: Option<NodeId>, mut node: Node) -> NodeId {
+        let children = match node {
+            Node::Existing { ref mut children, .. } => mem::replace(children, OrderedMap::new()),
+            Node::Deleted { .. } => OrderedMap::new(),
+        };
+        let id = NodeId(self.nodes.len());
+        self.nodes.push(Some(ArenaNode {
+            node: node,
+            parent: parent,
+            children: Vec::new(),
+        }));
+
+        let children = children.into_iter()
+                               .map(|(_, child)| self.insert(Some(id), child))
+                               .collect();
+        self.nodes[id.0].as_mut().unwrap().children = children;
+        id
+    }
+
+    // Rebuilds the node with its children back in place
+    fn extract(&mut self, id: NodeId) -> Node {
+        let entry = self.nodes[id.0].take().expect("node was removed");
+        let mut node = entry.node;
+        if let Node::Existing { ref mut children, .. } = node {
+            for child in entry.children {
+                let child = self.extract(child);
+                children.insert(child.name().as_str().to_owned(), child);
+            }
+        }
+        node
+    }
+}
+
+impl From<DTInfo> for ArenaTree {
+    fn from(tree: DTInfo) -> Self {
+        let mut arena = ArenaTree {
+            reserve_info: tree.reserve_info,
+            boot_cpuid: tree.boot_cpuid,
+            boot_cpuid_fixed: tree.boot_cpuid_fixed,
+            nodes: Vec::new(),
+        };
+        arena.insert(None, tree.root);
+        arena
+    }
+}
+
+impl From<ArenaTree> for DTInfo {
+    fn from(mut arena: ArenaTree) -> Self {
+        let root = arena.extract(NodeId(0));
+        DTInfo {
+            reserve_info: arena.reserve_info,
+            boot_cpuid: arena.boot_cpuid,
+            boot_cpuid_fixed: arena.boot_cpuid_fixed,
+            root: root,
+        }
+    }
+}
+
+/// Iterator over the ancestors of a node in an `ArenaTree`, see
+/// `ArenaTree::ancestors`.
+#[derive(Debug, Clone)]
+pub struct Ancestors<'a> {
+    tree: &'a ArenaTree,
+    next: Option<NodeId>,
+}
+
+impl<'a> Iterator for Ancestors<'a> {
+    type Item = NodeId;
+
+    fn next(&mut self) -> Option<NodeId> {
+        let id = self.next?;
+        self.next = self.tree.parent(id);
+        Some(id)
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use parser::parse_base;
 
     #[test]
     fn node_by_path() {
         let mut tree = DTInfo {
             reserve_info: Vec::new(),
             boot_cpuid: 0,
+            boot_cpuid_fixed: false,
             root: Node::Existing {
                 name: NodeName::Full("/".to_owned()),
-                proplist: HashMap::new(),
-                children: HashMap::new(),
+                name_span: Span::default(),
+                proplist: OrderedMap::new(),
+                children: OrderedMap::new(),
+                duplicates: Vec::new(),
                 labels: Vec::new(),
-                offset: 0,
+                label_spans: Vec::new(),
+                omit_if_no_ref: false,
+                span: Span::default(),
             }
         };
         let node = Node::Existing {
             name: NodeName::Full("node1".to_owned()),
-            proplist: HashMap::new(),
-            children: HashMap::new(),
+            name_span: Span::default(),
+            proplist: OrderedMap::new(),
+            children: OrderedMap::new(),
+            duplicates: Vec::new(),
             labels: vec!["meh".to_owned()],
-            offset: 0,
+            label_spans: vec![Span::default()],
+            omit_if_no_ref: false,
+            span: Span::default(),
         };
         match tree.root {
             Node::Existing { ref mut children, .. } => {
@@ -675,20 +2405,29 @@ mod tests {
         let mut tree = DTInfo {
             reserve_info: Vec::new(),
             boot_cpuid: 0,
+            boot_cpuid_fixed: false,
             root: Node::Existing {
                 name: NodeName::Full("/".to_owned()),
-                proplist: HashMap::new(),
-                children: HashMap::new(),
+                name_span: Span::default(),
+                proplist: OrderedMap::new(),
+                children: OrderedMap::new(),
+                duplicates: Vec::new(),
                 labels: Vec::new(),
-                offset: 0,
+                label_spans: Vec::new(),
+                omit_if_no_ref: false,
+                span: Span::default(),
             }
         };
         let node = Node::Existing {
             name: NodeName::Full("node1".to_owned()),
-            proplist: HashMap::new(),
-            children: HashMap::new(),
+            name_span: Span::default(),
+            proplist: OrderedMap::new(),
+            children: OrderedMap::new(),
+            duplicates: Vec::new(),
             labels: vec!["meh".to_owned()],
-            offset: 0,
+            label_spans: vec![Span::default()],
+            omit_if_no_ref: false,
+            span: Span::default(),
         };
         match tree.root {
             Node::Existing { ref mut children, .. } => {
@@ -702,4 +2441,432 @@ mod tests {
             Ok(&node)
         );
     }
+
+    fn parse_overlay(source: &[u8]) -> Overlay {
+        match ::parser::parse_dt(source) {
+            Ok(::parser::ParseResult::Overlay(overlay)) => overlay,
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn apply_overlay_fragments() {
+        let (mut tree, _) = parse_base(b"/dts-v1/;\n/ { soc { uart: serial { }; i2c { }; }; };");
+        let overlay = parse_overlay(b"/dts-v1/;\n/plugin/;\n&uart { status = \"okay\"; };\n\
+                                      / {\n\
+                                          fragment@0 { target = <&uart>; \
+                                              __overlay__ { baud = <115200>; }; };\n\
+                                          fragment@1 { target-path = \"/soc/i2c\"; \
+                                              __overlay__ { dev@50 { }; }; };\n\
+                                      };");
+        assert_eq!(tree.apply_overlay(&overlay), Ok(()));
+
+        match *tree.get_node_by_path("/soc/serial").unwrap() {
+            Node::Existing { ref proplist, .. } => {
+                assert!(proplist.contains_key("status"));
+                assert!(proplist.contains_key("baud"));
+            }
+            _ => unreachable!(),
+        }
+        assert!(tree.get_node_by_path("/soc/i2c/dev@50").is_ok());
+        assert!(tree.get_node_by_path("/fragment@0").is_err());
+    }
+
+    #[test]
+    fn apply_overlay_unresolved() {
+        let (mut tree, _) = parse_base(b"/dts-v1/;\n/ { a: node { }; };");
+        let overlay = parse_overlay(b"/dts-v1/;\n/plugin/;\n&missing { };\n\
+                                      / { fragment@0 { __overlay__ { }; }; };\n\
+                                      &a { /delete-node/ child; };");
+        assert_eq!(tree.apply_overlay(&overlay), Err(vec![
+            AmendError::UnresolvedTarget("missing".to_owned(), 20),
+            AmendError::MissingTarget(38),
+            AmendError::NoSuchNode("child".to_owned(), 79),
+        ]));
+    }
+
+    #[test]
+    fn merge_amends_delete() {
+        let (mut tree, amends) = parse_base(b"/dts-v1/;\n/ { a: node { b: sub { }; }; };\n\
+                                               /delete-node/ &b;\n&a { x; };");
+        assert_eq!(tree.merge_amends(&amends), Ok(()));
+        assert!(tree.get_node_by_path("/node/sub").is_err());
+        assert!(tree.get_node_by_label("a").is_ok());
+
+        // Paths may leave out unit addresses as amendments do
+        let (mut tree, amends) = parse_base(b"/dts-v1/;\n\
+                                               / { soc { uart@1000 { }; i2c@2000 { }; }; };\n\
+                                               &{/soc/i2c} { x; };\n\
+                                               /delete-node/ &{/soc/uart};");
+        assert_eq!(tree.merge_amends(&amends), Ok(()));
+        assert!(tree.get_node_by_path("/soc/uart@1000").is_err());
+        assert!(tree.get_node_by_path("/soc/i2c@2000").is_ok());
+    }
+
+    #[test]
+    fn merge_amends_new_labels() {
+        let (mut tree, amends) = parse_base(b"/dts-v1/;\n/ { a: node { }; };\n\
+                                               &a { b: sub { }; };\n\
+                                               &b { x; };\n\
+                                               /delete-node/ &b;\n\
+                                               &b { y; };\n\
+                                               &a { b: other { }; };\n\
+                                               &b { z; };");
+        assert_eq!(tree.merge_amends(&amends),
+                   Err(vec![AmendError::UnresolvedTarget("b".to_owned(), amends[3].get_offset())]));
+        assert!(tree.get_node_by_path("/node/sub").is_err());
+        match tree.get_node_by_path("/node/other") {
+            Ok(&Node::Existing { ref proplist, .. }) => {
+                assert_eq!(proplist.keys().collect::<Vec<_>>(), vec!["z"])
+            }
+            x => panic!("unexpected node: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn source_order() {
+        let (mut tree, amends) = parse_base(b"/dts-v1/;\n/ { z; a = <1>; m; c { }; b { }; };\n\
+                                               / { a = <2>; y; d { }; c { x; }; };");
+        assert_eq!(tree.merge_amends(&amends), Ok(()));
+
+        match tree.root {
+            Node::Existing { ref proplist, ref children, .. } => {
+                assert_eq!(proplist.keys().collect::<Vec<_>>(), vec!["z", "a", "m", "y"]);
+                assert_eq!(children.keys().collect::<Vec<_>>(), vec!["c", "b", "d"]);
+                assert_eq!(proplist["a"].to_string(), "a = <2>;");
+            }
+            _ => unreachable!(),
+        }
+        assert_eq!(tree.root.to_string(),
+                   "/ {\n    z;\n    a = <2>;\n    m;\n    y;\n\
+                    \x20   c { ... }\n    b { ... }\n    d { ... }\n}");
+    }
+
+    #[test]
+    fn redefinitions_in_body() {
+        let source = b"/dts-v1/;\n/ {\n\
+                       \ta = <1>;\n\
+                       \ta = <2>;\n\
+                       \tb;\n\
+                       \t/delete-property/ b;\n\
+                       \tb;\n\
+                       \tn { x; };\n\
+                       \tn { x; x; };\n\
+                       };";
+        let (tree, _) = parse_base(source);
+        let at = |text: &str, nth: usize| {
+            let s = String::from_utf8_lossy(source);
+            s.match_indices(text).nth(nth).unwrap().0
+        };
+
+        let redefs = tree.root.redefinitions("/");
+        assert_eq!(redefs.len(), 3);
+        match redefs[0] {
+            Redefinition::Property(ref path, first, second) => {
+                assert_eq!(path, "/a");
+                assert_eq!((first.start, second.start), (at("a =", 0), at("a =", 1)));
+            }
+            ref x => panic!("unexpected redefinition: {:?}", x),
+        }
+        match redefs[1] {
+            Redefinition::Node(ref path, first, second) => {
+                assert_eq!(path, "/n");
+                assert_eq!((first.start, second.start), (at("n {", 0), at("n {", 1)));
+            }
+            ref x => panic!("unexpected redefinition: {:?}", x),
+        }
+        match redefs[2] {
+            Redefinition::Property(ref path, ..) => assert_eq!(path, "/n/x"),
+            ref x => panic!("unexpected redefinition: {:?}", x),
+        }
+
+        // The earlier definitions are kept, the last one wins
+        match tree.root {
+            Node::Existing { ref proplist, ref duplicates, .. } => {
+                assert_eq!(duplicates.len(), 4);
+                assert_eq!(proplist["a"].to_string(), "a = <2>;");
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn omit_unreferenced_nodes() {
+        let (mut tree, amends) = parse_base(b"/dts-v1/;\n/ {\n\
+                                               uart { pinctrl-0 = <&pa>; };\n\
+                                               pa: /omit-if-no-ref/ pins-a { };\n\
+                                               /omit-if-no-ref/ pins-b { };\n\
+                                               pc: pins-c { };\n\
+                                               pins-d { };\n\
+                                               i2c { pinctrl-0 = <&{/pins-e}>; };\n\
+                                               /omit-if-no-ref/ pins-e@1 { };\n\
+                                           };\n\
+                                           /omit-if-no-ref/ &pc;\n\
+                                           /omit-if-no-ref/ &{/pins-d};");
+        assert_eq!(tree.merge_amends(&amends), Ok(()));
+        assert_eq!(tree.omit_unreferenced(),
+                   vec!["/pins-b".to_owned(), "/pins-c".to_owned(), "/pins-d".to_owned()]);
+        assert!(tree.get_node_by_path("/pins-a").is_ok());
+        assert!(tree.get_node_by_path("/pins-e@1").is_ok());
+    }
+
+    #[test]
+    fn boot_cpuid_after_amends() {
+        let (mut tree, amends) = parse_base(b"/dts-v1/;\n/ {\n\
+                                               cpus { c0: cpu@0 { reg = <0>; }; };\n\
+                                           };\n\
+                                           &c0 { reg = <2>; };");
+        assert_eq!(tree.boot_cpuid, 0);
+
+        let mut fixed = tree.clone();
+        fixed.set_boot_cpuid(7);
+        assert_eq!(fixed.merge_amends(&amends), Ok(()));
+        assert_eq!(fixed.boot_cpuid, 7);
+
+        assert_eq!(tree.merge_amends(&amends), Ok(()));
+        assert_eq!(tree.boot_cpuid, 2);
+    }
+
+    #[test]
+    fn reserve_overlaps() {
+        let (tree, _) = parse_base(b"/dts-v1/;\n\
+                                     /memreserve/ 0x80000000 0x1000;\n\
+                                     /memreserve/ 0x90000000 0x1000;\n\
+                                     / {\n\
+                                         reserved-memory {\n\
+                                             #address-cells = <2>;\n\
+                                             #size-cells = <1>;\n\
+                                             fw@80000800 { reg = <0x0 0x80000800 0x1000>; };\n\
+                                             pool { size = <0x100000>; };\n\
+                                             ram@90001000 { reg = <0x0 0x90001000 0x1000>; };\n\
+                                         };\n\
+                                     };");
+        assert_eq!(tree.reserve_overlaps(),
+                   vec![ReserveOverlap {
+                       reserve: 0,
+                       path: "/reserved-memory/fw@80000800".to_owned(),
+                       address: 0x80000800,
+                       size: 0x1000,
+                   }]);
+    }
+
+    #[test]
+    fn node_name_parts() {
+        let name = NodeName::Full("uart@fe001000".to_owned());
+        assert_eq!(name.base_name(), "uart");
+        assert_eq!(name.unit_address(), Some("fe001000"));
+        let name = NodeName::Full("cpus".to_owned());
+        assert_eq!((name.base_name(), name.unit_address()), ("cpus", None));
+        let name = NodeName::Ref("uart0".to_owned());
+        assert_eq!((name.base_name(), name.unit_address()), ("uart0", None));
+    }
+
+    #[test]
+    fn node_by_path_without_unit_address() {
+        let (tree, _) = parse_base(b"/dts-v1/;\n/ {\n\
+                                     soc@0 {\n\
+                                         uart@1000 { };\n\
+                                         i2c@2000 { };\n\
+                                         i2c@3000 { };\n\
+                                         spi { };\n\
+                                         spi@4000 { };\n\
+                                     };\n\
+                                 };");
+        assert_eq!(tree.full_path("/soc/uart"), Ok("/soc@0/uart@1000".to_owned()));
+        assert!(tree.get_node_by_path("/soc/uart").is_ok());
+        assert_eq!(tree.get_node_by_path("/soc@0/uart").unwrap().name().as_str(), "uart@1000");
+        // An exact match wins, otherwise the name must be unambiguous
+        assert_eq!(tree.full_path("/soc/spi"), Ok("/soc@0/spi".to_owned()));
+        assert!(tree.get_node_by_path("/soc/i2c").is_err());
+        assert!(tree.get_node_by_path("/soc/uart@2000").is_err());
+    }
+
+    #[test]
+    fn check_unit_addresses() {
+        let source = b"/dts-v1/;\n/ {\n\
+                       \t#address-cells = <2>;\n\
+                       \t#size-cells = <1>;\n\
+                       \tmemory { reg = <0x0 0x80000000 0x1000>; };\n\
+                       \tsoc@100000000 {\n\
+                       \t\treg = <0x1 0x0 0x1000>;\n\
+                       \t\t#address-cells = <1>;\n\
+                       \t\tranges;\n\
+                       \t\tuart@1000 { reg = <0x2000 0x100>; };\n\
+                       \t\ti2c@03000 { reg = <0x3000 0x100>; };\n\
+                       \t\tbus@4000 { ranges; };\n\
+                       \t\tgpio@5000 { };\n\
+                       \t};\n\
+                       };";
+        let (tree, _) = parse_base(source);
+        let at = |text: &str| String::from_utf8_lossy(source).find(text).unwrap();
+
+        assert_eq!(tree.check_unit_addresses(), vec![
+            UnitAddressError::Missing("/memory".to_owned(),
+                                      "80000000".to_owned(),
+                                      Span::new(at("memory"), at("memory") + 6)),
+            UnitAddressError::Mismatch("/soc@100000000/uart@1000".to_owned(),
+                                       "2000".to_owned(),
+                                       Span::new(at("uart@"), at("uart@") + 9)),
+            UnitAddressError::NoReg("/soc@100000000/gpio@5000".to_owned(),
+                                    Span::new(at("gpio@"), at("gpio@") + 9)),
+        ]);
+    }
+
+    #[test]
+    fn resolve_references() {
+        let source = b"/dts-v1/;\n/ {\n\
+                       \taliases { serial0 = &uart; intc = &{/intc}; };\n\
+                       \tintc: intc { phandle = <1>; };\n\
+                       \tuart: uart@1000 { interrupt-parent = <&intc>; clocks = <&clk &clk>; };\n\
+                       \tclk: clk { };\n\
+                       \tme: node { phandle = <&me>; };\n\
+                       \tx { foo = <&missing>; };\n\
+                       };";
+        let (mut tree, _) = parse_base(source);
+        let errors = tree.resolve_references();
+        let at = String::from_utf8_lossy(source).find("&missing").unwrap();
+        assert_eq!(errors,
+                   Err(vec![ReferenceError::Dangling("missing".to_owned(),
+                                                     "/x/foo".to_owned(),
+                                                     Span::new(at, at + 8))]));
+
+        let prop = |tree: &DTInfo, node: &str, name: &str| match tree.get_node_by_path(node) {
+            Ok(&Node::Existing { ref proplist, .. }) => proplist.get(name).cloned(),
+            _ => None,
+        };
+        match prop(&tree, "/aliases", "serial0") {
+            Some(Property::Existing { val: Some(ref data), .. }) => match data[0] {
+                Data::Reference(_, ref path, _) => assert_eq!(path, &Some("/uart@1000".to_owned())),
+                ref x => panic!("unexpected data: {:?}", x),
+            },
+            x => panic!("unexpected property: {:?}", x),
+        }
+        let cells = |tree: &DTInfo, node: &str, name: &str| {
+            prop(tree, node, name).and_then(|p| p.cells())
+        };
+        assert_eq!(cells(&tree, "/uart", "interrupt-parent"), Some(vec![1]));
+        assert_eq!(cells(&tree, "/uart", "clocks"), Some(vec![2, 2]));
+        assert_eq!(cells(&tree, "/clk", "phandle"), Some(vec![2]));
+        assert_eq!(cells(&tree, "/node", "phandle"), Some(vec![3]));
+        assert_eq!(prop(&tree, "/uart", "phandle"), None);
+        assert_eq!(cells(&tree, "/x", "foo"), None);
+
+        // Phandles given out before are kept
+        let before = tree.clone();
+        assert!(tree.resolve_references().is_err());
+        assert_eq!(tree.root, before.root);
+    }
+
+    #[test]
+    fn duplicate_phandles() {
+        let (mut tree, _) = parse_base(b"/dts-v1/;\n/ {\n\
+                                         a { phandle = <1>; };\n\
+                                         b { linux,phandle = <1>; };\n\
+                                         c { ref = <&{/b}>; };\n\
+                                     };");
+        match tree.resolve_references() {
+            Err(ref errors) => match errors.as_slice() {
+                [ReferenceError::DuplicatePhandle(1, ref path, _)] => assert_eq!(path, "/b"),
+                x => panic!("unexpected errors: {:?}", x),
+            },
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn bad_phandles() {
+        let source = b"/dts-v1/;\n/ {\n\
+                       a { phandle = <0>; };\n\
+                       b { linux,phandle = <0xffffffff>; };\n\
+                       c { ref = <&{/a} &{/b}>; };\n\
+                       };";
+        let (mut tree, _) = parse_base(source);
+        let at = |s: &str| String::from_utf8_lossy(source).find(s).unwrap();
+        assert_eq!(tree.resolve_references(),
+                   Err(vec![ReferenceError::BadPhandle("/a".to_owned(),
+                                                       Span::new(at("phandle"),
+                                                                 at("<0>") + 4)),
+                            ReferenceError::BadPhandle("/b".to_owned(),
+                                                       Span::new(at("linux,phandle"),
+                                                                 at("<0xffffffff>") + 13))]));
+
+        // Neither node is given another phandle
+        match tree.get_node_by_path("/c") {
+            Ok(&Node::Existing { ref proplist, .. }) => assert_eq!(proplist["ref"].cells(), None),
+            x => panic!("unexpected node: {:?}", x),
+        }
+        match tree.get_node_by_path("/a") {
+            Ok(&Node::Existing { ref proplist, .. }) => {
+                assert_eq!(proplist["phandle"].cells(), Some(vec![0]))
+            }
+            x => panic!("unexpected node: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn arena_round_trip() {
+        let (tree, _) = parse_base(b"/dts-v1/;\n/memreserve/ 0x0 0x10;\n/ {\n\
+                                     a: soc { x = <1>; uart@1000 { };\n\
+                                         i2c { /delete-node/ y; }; };\n\
+                                     cpus { };\n\
+                                 };");
+        let arena = ArenaTree::from(tree.clone());
+        assert_eq!(arena.children(arena.root()).len(), 2);
+        let back = DTInfo::from(arena);
+        assert_eq!(back.root, tree.root);
+        assert_eq!(back.reserve_info.len(), 1);
+    }
+
+    #[test]
+    fn arena_links() {
+        let (mut tree, _) = parse_base(b"/dts-v1/;\n/ {\n\
+                                         intc: intc { #interrupt-cells = <1>; };\n\
+                                         soc {\n\
+                                             #address-cells = <1>;\n\
+                                             #size-cells = <0>;\n\
+                                             interrupt-parent = <&intc>;\n\
+                                             uart@1000 { bus { dev { }; }; };\n\
+                                         };\n\
+                                     };");
+        assert!(tree.resolve_references().is_ok());
+        let arena = ArenaTree::from(tree);
+
+        let soc = arena.find("/soc").unwrap();
+        let dev = arena.find("/soc/uart/bus/dev").unwrap();
+        assert_eq!(arena.path(dev), "/soc/uart@1000/bus/dev");
+        assert_eq!(arena.path(arena.root()), "/");
+        assert_eq!(arena.ancestors(dev).map(|id| arena.path(id)).collect::<Vec<_>>(),
+                   vec!["/soc/uart@1000/bus", "/soc/uart@1000", "/soc", "/"]);
+
+        let uart = arena.child(soc, "uart").unwrap();
+        assert_eq!((arena.address_cells(uart), arena.size_cells(uart)), (1, 0));
+        assert_eq!((arena.address_cells(soc), arena.size_cells(soc)), (2, 1));
+        assert_eq!(arena.interrupt_parent(soc), arena.find("/intc"));
+        assert_eq!(arena.interrupt_parent(uart), arena.find("/intc"));
+        assert_eq!(arena.interrupt_parent(dev), arena.find("/intc"));
+        assert_eq!(arena.interrupt_parent(arena.find("/intc").unwrap()), None);
+    }
+
+    #[test]
+    fn arena_ids_are_stable() {
+        let (tree, _) = parse_base(b"/dts-v1/;\n/ { a { b { }; }; c { }; };");
+        let mut arena = ArenaTree::from(tree);
+        let a = arena.find("/a").unwrap();
+        let b = arena.find("/a/b").unwrap();
+        let c = arena.find("/c").unwrap();
+
+        arena.remove(a);
+        assert!(!arena.contains(a) && !arena.contains(b));
+        assert_eq!(arena.find("/c"), Some(c));
+
+        let (other, _) = parse_base(b"/dts-v1/;\n/ { d { e { }; }; };");
+        let d = match other.root {
+            Node::Existing { ref children, .. } => children["d"].clone(),
+            _ => unreachable!(),
+        };
+        let d = arena.add_child(c, d);
+        assert!(d != a && d != b);
+        assert_eq!(arena.path(arena.children(d)[0]), "/c/d/e");
+        assert!(DTInfo::from(arena).get_node_by_path("/c/d/e").is_ok());
+    }
 }
@@ -0,0 +1,404 @@
+//! Walking every node and property of a tree without writing the recursion by
+//! hand.
+//!
+//! The iterators yield each node or property along with the full path of the
+//! node, either depth first, where every node comes before its children and
+//! after its earlier siblings' children, or breadth first, where every node
+//! of one depth comes before any node of the next. Children and properties
+//! are yielded in source order. `Node::Deleted` entries, such as those found
+//! in amendments, are yielded too but have no properties or children.
+//!
+//! The `Visitor` and `VisitorMut` traits are for walks that need to know when
+//! a node's children have all been seen, or that change the tree as they go.
+//!
+//! ```
+//! use device_tree_source::parser::{parse_dt, ParseResult};
+//! use device_tree_source::visit::{self, Visitor};
+//! use device_tree_source::tree::{Node, Property};
+//!
+//! let source = b"/dts-v1/;\n/ { soc { uart@1000 { status = \"okay\"; }; }; };";
+//! let tree = match parse_dt(source) {
+//!     Ok(ParseResult::Complete(tree, _)) => tree,
+//!     _ => unreachable!(),
+//! };
+//!
+//! let paths: Vec<_> = visit::depth_first(&tree.root).map(|(path, _)| path).collect();
+//! assert_eq!(paths, vec!["/", "/soc", "/soc/uart@1000"]);
+//!
+//! // Finds the deepest node
+//! struct Depth {
+//!     current: usize,
+//!     max: usize,
+//! }
+//!
+//! impl<'a> Visitor<'a> for Depth {
+//!     fn enter_node(&mut self, _: &str, _: &'a Node) -> bool {
+//!         self.current += 1;
+//!         self.max = self.max.max(self.current);
+//!         true
+//!     }
+//!
+//!     fn leave_node(&mut self, _: &str, _: &'a Node) {
+//!         self.current -= 1;
+//!     }
+//! }
+//!
+//! let mut depth = Depth { current: 0, max: 0 };
+//! visit::walk(&tree.root, &mut depth);
+//! assert_eq!(depth.max, 3);
+//! ```
+
+use std::collections::VecDeque;
+
+use tree::{Node, Property, Values, child_path};
+
+/// Returns an iterator over `root` and every node below it, depth first. The
+/// root is taken to be at `/`, use `DepthFirst::new` for any other path.
+pub fn depth_first(root: &Node) -> DepthFirst<'_> {
+    DepthFirst::new(root, "/")
+}
+
+/// Returns an iterator over `root` and every node below it, breadth first.
+/// The root is taken to be at `/`, use `BreadthFirst::new` for any other path.
+pub fn breadth_first(root: &Node) -> BreadthFirst<'_> {
+    BreadthFirst::new(root, "/")
+}
+
+/// Walks `root` and every node below it depth first, calling the hooks of
+/// `visitor` along the way. The root is taken to be at `/`.
+pub fn walk<'a, V: Visitor<'a>>(root: &'a Node, visitor: &mut V) {
+    walk_at(root, "/", visitor)
+}
+
+/// Walks `node` and every node below it depth first, calling the hooks of
+/// `visitor` along the way. `path` is the full path of `node`.
+pub fn walk_at<'a, V: Visitor<'a>>(node: &'a Node, path: &str, visitor: &mut V) {
+    if !visitor.enter_node(path, node) {
+        return;
+    }
+    if let Node::Existing { ref proplist, ref children, .. } = *node {
+        for prop in proplist.values() {
+            visitor.visit_property(path, prop);
+        }
+        for (name, child) in children {
+            walk_at(child, &child_path(path, name), visitor);
+        }
+    }
+    visitor.leave_node(path, node);
+}
+
+/// Walks `root` and every node below it depth first, calling the hooks of
+/// `visitor` along the way. The root is taken to be at `/`.
+pub fn walk_mut<V: VisitorMut>(root: &mut Node, visitor: &mut V) {
+    walk_mut_at(root, "/", visitor)
+}
+
+/// Walks `node` and every node below it depth first, calling the hooks of
+/// `visitor` along the way. `path` is the full path of `node`.
+///
+/// Properties and children are walked as they are once `enter_node` returns,
+/// so any added there are walked as well.
+pub fn walk_mut_at<V: VisitorMut>(node: &mut Node, path: &str, visitor: &mut V) {
+    if !visitor.enter_node(path, node) {
+        return;
+    }
+    if let Node::Existing { ref mut proplist, ref mut children, .. } = *node {
+        for prop in proplist.values_mut() {
+            visitor.visit_property(path, prop);
+        }
+        for (name, child) in children.iter_mut() {
+            walk_mut_at(child, &child_path(path, name), visitor);
+        }
+    }
+    visitor.leave_node(path, node);
+}
+
+/// Hooks called by `walk` for each node and property. Every hook does nothing
+/// by default.
+///
+/// For each node `enter_node` is called first, then `visit_property` for each
+/// of its properties, then all of the hooks for each of its children, and
+/// finally `leave_node`. The path given to each hook is the full path of the
+/// node, or of the node holding the property.
+pub trait Visitor<'a> {
+    /// Called before the properties and children of the node are visited.
+    /// Returning false skips them, along with `leave_node` for this node.
+    fn enter_node(&mut self, path: &str, node: &'a Node) -> bool {
+        let _ = (path, node);
+        true
+    }
+
+    /// Called once the properties and children of the node are visited.
+    fn leave_node(&mut self, path: &str, node: &'a Node) {
+        let _ = (path, node);
+    }
+
+    /// Called for each property of the node at `path`.
+    fn visit_property(&mut self, path: &str, prop: &'a Property) {
+        let _ = (path, prop);
+    }
+}
+
+/// Hooks called by `walk_mut` for each node and property, which may change
+/// them. Every hook does nothing by default. The hooks are called in the same
+/// order as those of `Visitor`.
+pub trait VisitorMut {
+    /// Called before the properties and children of the node are visited.
+    /// Returning false skips them, along with `leave_node` for this node.
+    fn enter_node(&mut self, path: &str, node: &mut Node) -> bool {
+        let _ = (path, node);
+        true
+    }
+
+    /// Called once the properties and children of the node are visited.
+    fn leave_node(&mut self, path: &str, node: &mut Node) {
+        let _ = (path, node);
+    }
+
+    /// Called for each property of the node at `path`.
+    fn visit_property(&mut self, path: &str, prop: &mut Property) {
+        let _ = (path, prop);
+    }
+}
+
+// The children of a node, or nothing for a deleted node
+fn children_of(node: &Node) -> Vec<(&str, &Node)> {
+    match *node {
+        Node::Existing { ref children, .. } => {
+            children.iter().map(|(name, child)| (name.as_str(), child)).collect()
+        }
+        Node::Deleted { .. } => Vec::new(),
+    }
+}
+
+/// A depth first iterator over a node and every node below it, yielding the
+/// full path of each along with the node. See the module documentation.
+#[derive(Debug)]
+pub struct DepthFirst<'a> {
+    stack: Vec<(String, &'a Node)>,
+}
+
+impl<'a> DepthFirst<'a> {
+    /// Starts the walk at `node`, where `path` is the full path of `node`.
+    pub fn new(node: &'a Node, path: &str) -> Self {
+        DepthFirst { stack: vec![(path.to_owned(), node)] }
+    }
+
+    /// Turns this into an iterator over the properties of each node.
+    pub fn properties(self) -> Properties<'a, Self> {
+        Properties::new(self)
+    }
+}
+
+impl<'a> Iterator for DepthFirst<'a> {
+    type Item = (String, &'a Node);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, node) = self.stack.pop()?;
+        // Pushed in reverse so the first child is the next one popped
+        for (name, child) in children_of(node).into_iter().rev() {
+            self.stack.push((child_path(&path, name), child));
+        }
+        Some((path, node))
+    }
+}
+
+/// A breadth first iterator over a node and every node below it, yielding the
+/// full path of each along with the node. See the module documentation.
+#[derive(Debug)]
+pub struct BreadthFirst<'a> {
+    queue: VecDeque<(String, &'a Node)>,
+}
+
+impl<'a> BreadthFirst<'a> {
+    /// Starts the walk at `node`, where `path` is the full path of `node`.
+    pub fn new(node: &'a Node, path: &str) -> Self {
+        let mut queue = VecDeque::new();
+        queue.push_back((path.to_owned(), node));
+        BreadthFirst { queue: queue }
+    }
+
+    /// Turns this into an iterator over the properties of each node.
+    pub fn properties(self) -> Properties<'a, Self> {
+        Properties::new(self)
+    }
+}
+
+impl<'a> Iterator for BreadthFirst<'a> {
+    type Item = (String, &'a Node);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (path, node) = self.queue.pop_front()?;
+        for (name, child) in children_of(node) {
+            self.queue.push_back((child_path(&path, name), child));
+        }
+        Some((path, node))
+    }
+}
+
+/// An iterator over the properties of the nodes from a `DepthFirst` or
+/// `BreadthFirst` iterator, yielding the full path of the node holding each
+/// along with the property. The properties of a node are yielded in source
+/// order.
+#[derive(Debug)]
+pub struct Properties<'a, I> {
+    nodes: I,
+    current: Option<(String, Values<'a, Property>)>,
+}
+
+impl<'a, I> Properties<'a, I>
+    where I: Iterator<Item = (String, &'a Node)>
+{
+    fn new(nodes: I) -> Self {
+        Properties {
+            nodes: nodes,
+            current: None,
+        }
+    }
+}
+
+impl<'a, I> Iterator for Properties<'a, I>
+    where I: Iterator<Item = (String, &'a Node)>
+{
+    type Item = (String, &'a Property);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((ref path, ref mut props)) = self.current {
+                if let Some(prop) = props.next() {
+                    return Some((path.clone(), prop));
+                }
+            }
+
+            self.current = match self.nodes.next()? {
+                (path, &Node::Existing { ref proplist, .. }) => Some((path, proplist.values())),
+                (_, &Node::Deleted { .. }) => None,
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parser::parse_base;
+    use tree::{Cell, Data};
+
+    const SOURCE: &'static [u8] = b"/dts-v1/;\n/ {\n\
+                                    model = \"test\";\n\
+                                    cpus { cpu@0 { reg = <0>; }; };\n\
+                                    soc {\n\
+                                        a = <1>;\n\
+                                        uart@1000 { b = <2>; c = <3>; };\n\
+                                    };\n\
+                                };";
+
+    #[test]
+    fn node_order() {
+        let (tree, _) = parse_base(SOURCE);
+
+        let paths: Vec<_> = depth_first(&tree.root).map(|(path, _)| path).collect();
+        assert_eq!(paths, vec!["/", "/cpus", "/cpus/cpu@0", "/soc", "/soc/uart@1000"]);
+
+        let paths: Vec<_> = breadth_first(&tree.root).map(|(path, _)| path).collect();
+        assert_eq!(paths, vec!["/", "/cpus", "/soc", "/cpus/cpu@0", "/soc/uart@1000"]);
+
+        let soc = tree.get_node_by_path("/soc").unwrap();
+        let paths: Vec<_> = DepthFirst::new(soc, "/soc").map(|(path, _)| path).collect();
+        assert_eq!(paths, vec!["/soc", "/soc/uart@1000"]);
+    }
+
+    #[test]
+    fn property_order() {
+        let (tree, _) = parse_base(SOURCE);
+
+        let props: Vec<_> = depth_first(&tree.root)
+            .properties()
+            .map(|(path, prop)| format!("{} {}", path, prop.name()))
+            .collect();
+        assert_eq!(props,
+                   vec!["/ model", "/cpus/cpu@0 reg", "/soc a", "/soc/uart@1000 b",
+                        "/soc/uart@1000 c"]);
+
+        let props: Vec<_> = breadth_first(&tree.root)
+            .properties()
+            .map(|(path, prop)| format!("{} {}", path, prop.name()))
+            .collect();
+        assert_eq!(props,
+                   vec!["/ model", "/soc a", "/cpus/cpu@0 reg", "/soc/uart@1000 b",
+                        "/soc/uart@1000 c"]);
+    }
+
+    #[test]
+    fn visitor_hooks() {
+        struct Log(Vec<String>);
+
+        impl<'a> Visitor<'a> for Log {
+            fn enter_node(&mut self, path: &str, _: &'a Node) -> bool {
+                self.0.push(format!("enter {}", path));
+                path != "/cpus"
+            }
+
+            fn leave_node(&mut self, path: &str, _: &'a Node) {
+                self.0.push(format!("leave {}", path));
+            }
+
+            fn visit_property(&mut self, path: &str, prop: &'a Property) {
+                self.0.push(format!("prop {} {}", path, prop.name()));
+            }
+        }
+
+        let (tree, _) = parse_base(SOURCE);
+        let mut log = Log(Vec::new());
+        walk(&tree.root, &mut log);
+        assert_eq!(log.0,
+                   vec!["enter /",
+                        "prop / model",
+                        "enter /cpus",
+                        "enter /soc",
+                        "prop /soc a",
+                        "enter /soc/uart@1000",
+                        "prop /soc/uart@1000 b",
+                        "prop /soc/uart@1000 c",
+                        "leave /soc/uart@1000",
+                        "leave /soc",
+                        "leave /"]);
+    }
+
+    #[test]
+    fn visitor_mut_changes_tree() {
+        // Doubles every number in a list of cells below /soc
+        struct Double;
+
+        impl VisitorMut for Double {
+            fn enter_node(&mut self, path: &str, _: &mut Node) -> bool {
+                path == "/" || path.starts_with("/soc")
+            }
+
+            fn visit_property(&mut self, path: &str, prop: &mut Property) {
+                if path == "/" {
+                    return;
+                }
+                if let Property::Existing { val: Some(ref mut data), .. } = *prop {
+                    for d in data {
+                        if let Data::Cells(_, ref mut cells, _) = *d {
+                            for cell in cells {
+                                if let Some(value) = cell.value() {
+                                    *cell = Cell::Num(value * 2);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        let (mut tree, _) = parse_base(SOURCE);
+        walk_mut(&mut tree.root, &mut Double);
+        let cells: Vec<_> = depth_first(&tree.root)
+            .properties()
+            .filter_map(|(_, prop)| prop.cells())
+            .collect();
+        assert_eq!(cells, vec![vec![0], vec![2], vec![4], vec![6]]);
+    }
+}
@@ -2,36 +2,63 @@ use std::collections::HashMap;
 use std::path::{Path, PathBuf};
 use std::fmt;
 
-use device_tree_source::tree::{DTInfo, Node, NodeName, Property, Offset};
+use device_tree_source::tree::{DTInfo, Overlay, Fragment, Node, NodeName, Property, Data, Cell,
+                               Duplicate, Offset, Spanned, Span};
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Element<'a> {
     Node(&'a Node),
     Prop(&'a Property),
+    /// Dropped from the final tree as the node was marked `/omit-if-no-ref/`
+    /// and never referenced. Holds the node that was marked.
+    Omitted(&'a Node),
 }
 
 impl<'a> Offset for Element<'a> {
     fn get_offset(&self) -> usize {
         match *self {
-            Element::Node(n) => n.get_offset(),
+            Element::Node(n) | Element::Omitted(n) => n.get_offset(),
             Element::Prop(p) => p.get_offset(),
         }
     }
 }
 
+impl<'a> Spanned for Element<'a> {
+    fn get_span(&self) -> Span {
+        match *self {
+            Element::Node(n) | Element::Omitted(n) => n.get_span(),
+            Element::Prop(p) => p.get_span(),
+        }
+    }
+}
+
 impl<'a> fmt::Display for Element<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
             Element::Node(node) => write!(f, "{}", node),
             Element::Prop(prop) => write!(f, "{}", prop),
+            Element::Omitted(node) => {
+                write!(f, "// Node {} omitted, /omit-if-no-ref/ and never referenced",
+                       node.name())
+            }
         }
     }
 }
 
+/// A change made to a path along with the source it was made in. Source 0 is
+/// the base tree, and each overlay after that is numbered in the order it was
+/// applied.
+#[derive(Debug)]
+pub struct Change<'a> {
+    pub source: usize,
+    pub element: Element<'a>,
+}
+
 #[derive(Debug)]
 pub struct LabelStore<'a> {
-    paths: HashMap<PathBuf, Vec<Element<'a>>>,
+    paths: HashMap<PathBuf, Vec<Change<'a>>>,
     labels: HashMap<&'a str, PathBuf>,
+    source: usize,
 }
 
 impl<'a> LabelStore<'a> {
@@ -39,6 +66,7 @@ impl<'a> LabelStore<'a> {
         LabelStore {
             paths: HashMap::new(),
             labels: HashMap::new(),
+            source: 0,
         }
     }
 
@@ -49,107 +77,184 @@ impl<'a> LabelStore<'a> {
         self.fill_internal(Path::new("/"), &dt_info.root);
         for node in amends {
             match *node {
-                Node::Existing { ref name, .. } => {
-                    if name.as_str() == "/" || self.labels.contains_key(name.as_str()) {
+                Node::Existing { name: NodeName::Full(_), .. } => {
+                    self.fill_internal(Path::new(""), node);
+                }
+                Node::Existing { ref name, .. } | Node::Deleted { ref name, .. } => {
+                    // Amendments to unknown labels are skipped. The labeled node
+                    // may have been lost to a syntax error.
+                    if self.resolve(name.as_str()).is_some() {
                         self.fill_internal(Path::new(""), node);
-                    } else {
-                        unimplemented!();
                     }
                 }
-                Node::Deleted { ref name, .. } => {
-                    if self.labels.contains_key(name.as_str()) {
+            }
+        }
+    }
+
+    /// Records the changes made by an overlay. Must be called after `fill`, and
+    /// for each overlay in the order they are applied. Amendments and
+    /// fragments whose targets are unknown are skipped.
+    pub fn fill_overlay(&mut self, overlay: &'a Overlay, source: usize) {
+        self.source = source;
+        for node in &overlay.amends {
+            match *node {
+                Node::Existing { name: NodeName::Full(_), ref children, .. } => {
+                    for child in children.values() {
+                        match child.fragment() {
+                            Some(Fragment { target: Some(target), overlay }) => {
+                                if let Some(path) = self.resolve(target) {
+                                    self.fill_body(path, overlay);
+                                }
+                            }
+                            Some(Fragment { target: None, .. }) => {}
+                            None => self.fill_internal(Path::new("/"), child),
+                        }
+                    }
+                }
+                _ => {
+                    if self.resolve(node.name().as_str()).is_some() {
                         self.fill_internal(Path::new(""), node);
-                    } else {
-                        unimplemented!();
                     }
                 }
             }
         }
     }
 
+    /// Records that the node at the path was dropped from the final tree, see
+    /// `DTInfo::omit_unreferenced`. The omission is attributed to the last
+    /// source that marked the node with `/omit-if-no-ref/`.
+    pub fn omit(&mut self, path: &Path) {
+        let marked = self.paths.get(path).and_then(|changes| {
+            changes.iter()
+                   .rev()
+                   .filter_map(|c| match c.element {
+                       Element::Node(node @ &Node::Existing { omit_if_no_ref: true, .. }) => {
+                           Some((c.source, node))
+                       }
+                       _ => None,
+                   })
+                   .next()
+        });
+
+        if let Some((source, node)) = marked {
+            self.source = source;
+            self.remove_path(path.to_owned(), Element::Omitted(node));
+        }
+    }
+
+    // Resolves a reference, either a label or a path, to a path.
+    fn resolve(&self, refr: &str) -> Option<PathBuf> {
+        if refr.starts_with('/') {
+            Some(PathBuf::from(refr))
+        } else {
+            self.path_from_label(refr).map(|p| p.to_owned())
+        }
+    }
+
     fn fill_internal(&mut self, path: &Path, node: &'a Node) {
         match *node {
             Node::Deleted { ref name, .. } => {
                 let node_path = match *name {
                     NodeName::Full(ref name) => path.join(name),
-                    NodeName::Ref(ref label) => {
-                        self.path_from_label(label).unwrap().to_owned()
-                    }
+                    NodeName::Ref(ref refr) => self.resolve(refr).unwrap(),
                 };
 
-                self.delete_labels(&node_path);
-
-
-                self.paths
-                    .entry(node_path.clone())
-                    .or_insert_with(Vec::new)
-                    .push(Element::Node(node));
-
-                let paths: Vec<PathBuf> = self.paths
-                    .iter()
-                    .filter_map(|(key, val)| if key.starts_with(&node_path) {
-                        match val.last() {
-                            Some(&Element::Node(&Node::Existing { .. })) |
-                            Some(&Element::Prop(&Property::Existing { .. })) => {
-                                Some(key.to_owned())
-                            }
-                            _ => None,
-                        }
-                    } else {
-                        None
-                    })
-                    .collect();
-
-                for path in &paths {
-                    self.delete_labels(path);
-                    self.paths.get_mut(path).unwrap().push(Element::Node(node));
-                }
+                self.remove_path(node_path, Element::Node(node));
             }
-            Node::Existing { ref name, ref proplist, ref children, ref labels, .. } => {
+            Node::Existing { ref name, .. } => {
                 let node_path = match *name {
                     NodeName::Full(ref name) => path.join(name),
-                    NodeName::Ref(ref label) => {
-                        self.path_from_label(label).unwrap().to_owned()
-                    }
+                    NodeName::Ref(ref refr) => self.resolve(refr).unwrap(),
                 };
 
-                self.insert_labels(&node_path, labels);
+                self.fill_body(node_path, node);
+            }
+        }
+    }
 
-                for (name, prop) in proplist {
-                    let label_path = node_path.join(name);
+    // Records the removal of the node at `node_path` and everything below it
+    // that still exists.
+    fn remove_path(&mut self, node_path: PathBuf, element: Element<'a>) {
+        self.delete_labels(&node_path);
 
-                    match *prop {
-                        Property::Deleted { .. } => {
-                            self.delete_labels(&label_path);
+        self.push_change(node_path.clone(), element);
 
-                            self.paths
-                                .entry(label_path)
-                                .or_insert_with(Vec::new)
-                                .push(Element::Prop(prop));
-                        }
-                        Property::Existing { ref labels, .. } => {
-                            self.insert_labels(&label_path, labels);
-
-                            self.paths
-                                .entry(label_path)
-                                .or_insert_with(Vec::new)
-                                .push(Element::Prop(prop));
-                        }
+        let paths: Vec<PathBuf> = self.paths
+            .iter()
+            .filter_map(|(key, val)| if key.starts_with(&node_path) {
+                match val.last().map(|c| &c.element) {
+                    Some(&Element::Node(&Node::Existing { .. })) |
+                    Some(&Element::Prop(&Property::Existing { .. })) => {
+                        Some(key.to_owned())
                     }
+                    _ => None,
                 }
+            } else {
+                None
+            })
+            .collect();
+
+        for path in &paths {
+            self.delete_labels(path);
+            self.push_change(path.to_owned(), element);
+        }
+    }
+
+    // Records the labels, properties, and children of the node as changes to
+    // `node_path`, followed by the node itself. Definitions that were replaced
+    // later in the same body are recorded first so that both show up.
+    fn fill_body(&mut self, node_path: PathBuf, node: &'a Node) {
+        if let Node::Existing { ref proplist, ref children, ref labels, ref duplicates, .. } =
+               *node {
+            self.insert_labels(&node_path, labels);
+
+            for dup in duplicates {
+                match *dup {
+                    Duplicate::Property(ref prop) => self.fill_prop(&node_path, prop),
+                    Duplicate::Node(ref child) => self.fill_internal(&node_path, child),
+                }
+            }
+
+            for prop in proplist.values() {
+                self.fill_prop(&node_path, prop);
+            }
+
+            for node in children.values() {
+                self.fill_internal(&node_path, node);
+            }
+
+            self.push_change(node_path, Element::Node(node));
+        }
+    }
+
+    fn fill_prop(&mut self, node_path: &Path, prop: &'a Property) {
+        let label_path = node_path.join(prop.name());
 
-                for node in children.values() {
-                    self.fill_internal(&node_path, node);
+        match *prop {
+            Property::Deleted { .. } => {
+                self.delete_labels(&label_path);
+                self.push_change(label_path, Element::Prop(prop));
+            }
+            Property::Existing { ref labels, ref val, .. } => {
+                self.insert_labels(&label_path, labels);
+                // Labels within the data point to the property
+                if let Some(ref data) = *val {
+                    self.insert_data_labels(&label_path, data);
                 }
 
-                self.paths
-                    .entry(node_path)
-                    .or_insert_with(Vec::new)
-                    .push(Element::Node(node));
+                self.push_change(label_path, Element::Prop(prop));
             }
         }
     }
 
+    fn push_change(&mut self, path: PathBuf, element: Element<'a>) {
+        let change = Change { source: self.source, element: element };
+        self.paths
+            .entry(path)
+            .or_insert_with(Vec::new)
+            .push(change);
+    }
+
     fn delete_labels(&mut self, path: &Path) {
         let mut labels: Vec<&str> = Vec::new();
         for (label, p) in &self.labels {
@@ -164,16 +269,36 @@ impl<'a> LabelStore<'a> {
 
     fn insert_labels(&mut self, path: &Path, labels: &'a [String]) {
         for label in labels {
-            if !self.labels.contains_key(label.as_str()) {
-                self.labels.insert(label, path.to_owned());
-            } else if self.labels[label.as_str()] != path {
-                // TODO: maybe just print error message and allow user to determine if they should exit
-                panic!("Duplicate label \"{}\" at different paths", label);
+            self.insert_label(path, label);
+        }
+    }
+
+    fn insert_data_labels(&mut self, path: &Path, data: &'a [Data]) {
+        for d in data {
+            match *d {
+                Data::Label(ref label, _) => self.insert_label(path, label),
+                Data::Cells(_, ref cells, _) => {
+                    for cell in cells {
+                        if let Cell::Label(ref label, _) = *cell {
+                            self.insert_label(path, label);
+                        }
+                    }
+                }
+                _ => {}
             }
         }
     }
 
-    pub fn changes_from_path(&self, path: &Path) -> Option<&[Element<'a>]> {
+    fn insert_label(&mut self, path: &Path, label: &'a str) {
+        if !self.labels.contains_key(label) {
+            self.labels.insert(label, path.to_owned());
+        } else if self.labels[label] != path {
+            // TODO: maybe just print error message and allow user to determine if they should exit
+            panic!("Duplicate label \"{}\" at different paths", label);
+        }
+    }
+
+    pub fn changes_from_path(&self, path: &Path) -> Option<&[Change<'a>]> {
         self.paths.get(path).map(|v| v.as_slice())
     }
 
@@ -0,0 +1,187 @@
+//! Per-project settings, read from a `.dts_viewer.conf` file in the directory
+//! of the DTS file or any directory above it. The nearest file is used.
+//!
+//! Each line is a `key = value` setting, and lines starting with `#` are
+//! comments. Relative paths are relative to the directory holding the file.
+//!
+//! ```text
+//! # Preprocess with gcc rather than the built-in preprocessor
+//! cpp = gcc
+//! include = include
+//! include = scripts/dtc/include-prefixes
+//! define = CONFIG_ARM64=1
+//! undefine = CONFIG_FOO
+//! force_include = include/generated/autoconf.h
+//! no_defaults = true
+//! kbuild = ../build
+//! ```
+//!
+//! Settings from the command line are applied after those from the file.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use parse_define;
+
+pub const FILE_NAME: &'static str = ".dts_viewer.conf";
+
+/// Defines errors from reading a configuration file.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// Some IO error from reading the file.
+    IOError(io::Error, PathBuf),
+    /// A line that could not be understood. Holds the path of the file, the
+    /// line and a description.
+    Syntax(PathBuf, usize, String),
+}
+
+/// The settings from a configuration file.
+#[derive(Debug, Clone, Default)]
+pub struct Config {
+    /// The external preprocessor command and its arguments.
+    pub cpp: Option<Vec<String>>,
+    /// Directories to search for included files, in order.
+    pub include_dirs: Vec<PathBuf>,
+    /// Macros to define, or to remove if there is no value, in order.
+    pub macros: Vec<(String, Option<String>)>,
+    /// Files to include before the first line of the DTS file.
+    pub forced_includes: Vec<PathBuf>,
+    /// Whether to leave out the default include directories.
+    pub no_defaults: bool,
+    /// A Linux kernel build output directory to take preprocessor flags from.
+    pub kbuild: Option<PathBuf>,
+}
+
+/// Finds the nearest configuration file for a DTS file.
+pub fn find(dts: &Path) -> Option<PathBuf> {
+    let dts = dts.canonicalize().ok()?;
+    dts.ancestors()
+        .skip(1)
+        .map(|dir| dir.join(FILE_NAME))
+        .find(|path| path.is_file())
+}
+
+/// Reads a configuration file.
+pub fn load(path: &Path) -> Result<Config, ConfigError> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| ConfigError::IOError(e, path.to_owned()))?;
+
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let mut config = Config::default();
+    for (i, line) in contents.lines().enumerate() {
+        let error = |msg: String| ConfigError::Syntax(path.to_owned(), i + 1, msg);
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let mut parts = line.splitn(2, '=');
+        let key = parts.next().unwrap_or("").trim();
+        let value = match parts.next() {
+            Some(value) if !value.trim().is_empty() => value.trim(),
+            _ => return Err(error(format!("expected a value for '{}'", key))),
+        };
+
+        match key {
+            "cpp" => config.cpp = Some(value.split_whitespace().map(|w| w.to_owned()).collect()),
+            "include" => config.include_dirs.push(dir.join(value)),
+            "define" => config.macros.push(parse_define(value)),
+            "undefine" => config.macros.push((value.to_owned(), None)),
+            "force_include" => config.forced_includes.push(dir.join(value)),
+            "no_defaults" => {
+                config.no_defaults = match value {
+                    "true" => true,
+                    "false" => false,
+                    _ => return Err(error(format!("expected true or false, found '{}'", value))),
+                }
+            }
+            "kbuild" => config.kbuild = Some(dir.join(value)),
+            _ => return Err(error(format!("unknown setting '{}'", key))),
+        }
+    }
+
+    Ok(config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mktemp::Temp;
+    use write_file;
+
+    fn syntax_error(contents: &str) -> (usize, String) {
+        let dir = Temp::new_dir().unwrap();
+        let path = write_file(dir.as_ref(), FILE_NAME, contents);
+        match load(&path) {
+            Err(ConfigError::Syntax(p, line, msg)) => {
+                assert_eq!(p, path);
+                (line, msg)
+            }
+            x => panic!("load did not return syntax error: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn settings() {
+        let dir = Temp::new_dir().unwrap();
+        let dir = dir.as_ref();
+        let path = write_file(dir,
+                              FILE_NAME,
+                              "# comment\n\
+                               cpp = gcc -E  -P\n\
+                               \n\
+                               include = include\n\
+                               include=/usr/include\n\
+                               define = CONFIG_ARM64=1\n\
+                               define = DEBUG\n\
+                               undefine = CONFIG_FOO\n\
+                               force_include = include/generated/autoconf.h\n\
+                               no_defaults = true\n\
+                               kbuild = ../build\n");
+        let config = load(&path).unwrap();
+
+        assert_eq!(config.cpp, Some(vec!["gcc".to_owned(), "-E".to_owned(), "-P".to_owned()]));
+        assert_eq!(config.include_dirs, vec![dir.join("include"), PathBuf::from("/usr/include")]);
+        assert_eq!(config.macros,
+                   vec![("CONFIG_ARM64".to_owned(), Some("1".to_owned())),
+                        ("DEBUG".to_owned(), Some("1".to_owned())),
+                        ("CONFIG_FOO".to_owned(), None)]);
+        assert_eq!(config.forced_includes, vec![dir.join("include/generated/autoconf.h")]);
+        assert!(config.no_defaults);
+        assert_eq!(config.kbuild, Some(dir.join("../build")));
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(syntax_error("include = a\nfoo = bar\n"),
+                   (2, "unknown setting 'foo'".to_owned()));
+        assert_eq!(syntax_error("\n\ninclude =  \n"),
+                   (3, "expected a value for 'include'".to_owned()));
+        assert_eq!(syntax_error("kbuild\n"), (1, "expected a value for 'kbuild'".to_owned()));
+        assert_eq!(syntax_error("no_defaults = yes\n"),
+                   (1, "expected true or false, found 'yes'".to_owned()));
+
+        let dir = Temp::new_dir().unwrap();
+        match load(&dir.as_ref().join(FILE_NAME)) {
+            Err(ConfigError::IOError(_, path)) => assert_eq!(path, dir.as_ref().join(FILE_NAME)),
+            x => panic!("load did not return IO error: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn nearest_file() {
+        let dir = Temp::new_dir().unwrap();
+        let dir = dir.as_ref().canonicalize().unwrap();
+        let outer = write_file(&dir, FILE_NAME, "");
+        let inner = write_file(&dir, &format!("a/{}", FILE_NAME), "");
+        let dts = write_file(&dir, "a/b/board.dts", "");
+        let top = write_file(&dir, "top.dts", "");
+
+        assert_eq!(find(&dts), Some(inner));
+        assert_eq!(find(&top), Some(outer));
+        assert_eq!(find(&dir.join("a/missing.dts")), None);
+    }
+}
@@ -0,0 +1,273 @@
+//! Recovers the exact preprocessor flags used for a Device Tree Source file
+//! from the `.<name>.dtb.cmd` file kbuild leaves next to each built dtb in a
+//! Linux kernel build output directory.
+
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
+
+use {CppFlags, parse_define};
+
+/// Defines errors from reading kbuild command files.
+#[derive(Debug)]
+pub enum KbuildError {
+    /// Some IO error from reading the build directory or a command file.
+    IOError(io::Error, PathBuf),
+    /// No command file for the DTS file was found in the build directory.
+    NoCmdFile(PathBuf),
+    /// The command file does not contain a preprocessor command.
+    NoCppCommand(PathBuf),
+}
+
+/// Finds the command file for `dts` in `build_dir` and reads the preprocessor
+/// flags from it. Relative paths in the flags are resolved against
+/// `build_dir`, which is where kbuild ran the command from.
+pub fn cpp_flags(build_dir: &Path, dts: &Path) -> Result<CppFlags, KbuildError> {
+    let cmd_file = match find_cmd_file(build_dir, dts) {
+        Some(cmd_file) => cmd_file,
+        None => return Err(KbuildError::NoCmdFile(dts.to_owned())),
+    };
+
+    let mut contents = String::new();
+    File::open(&cmd_file)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .map_err(|e| KbuildError::IOError(e, cmd_file.clone()))?;
+
+    // Newer kernels save the command as `savedcmd_<target> := ...`, older ones
+    // as `cmd_<target> := ...`
+    let command = contents.lines()
+        .filter(|line| line.starts_with("savedcmd_") || line.starts_with("cmd_"))
+        .filter_map(|line| line.splitn(2, " := ").nth(1))
+        .next();
+
+    // The dtb rule runs several commands, only one of which preprocesses
+    let cpp = command.map(shell_commands)
+        .and_then(|commands| commands.into_iter().find(|words| words.iter().any(|w| w == "-E")));
+    match cpp {
+        Some(words) => Ok(parse_flags(&words[1..], build_dir)),
+        None => Err(KbuildError::NoCppCommand(cmd_file)),
+    }
+}
+
+// kbuild mirrors the source tree in the build directory, so the end of the
+// DTS file's path usually leads straight to its command file. The whole build
+// directory is searched otherwise.
+fn find_cmd_file(build_dir: &Path, dts: &Path) -> Option<PathBuf> {
+    let stem = dts.file_stem()?.to_string_lossy();
+    let names = [format!(".{}.dtb.cmd", stem), format!(".{}.dtbo.cmd", stem)];
+
+    let parts: Vec<_> = dts.parent()
+        .map(|parent| {
+            parent.components()
+                .filter_map(|c| match c {
+                    Component::Normal(part) => Some(part),
+                    _ => None,
+                })
+                .collect()
+        })
+        .unwrap_or_else(Vec::new);
+    for skip in 0..parts.len() + 1 {
+        let dir = parts[skip..].iter().fold(build_dir.to_owned(), |dir, part| dir.join(part));
+        if let Some(found) = names.iter().map(|name| dir.join(name)).find(|p| p.is_file()) {
+            return Some(found);
+        }
+    }
+
+    search(build_dir, &names)
+}
+
+fn search(dir: &Path, names: &[String]) -> Option<PathBuf> {
+    let mut subdirs = Vec::new();
+    for entry in fs::read_dir(dir).ok()?.filter_map(|e| e.ok()) {
+        // Symlinks are not followed, as O= builds link back to the source tree
+        let file_type = match entry.file_type() {
+            Ok(file_type) => file_type,
+            Err(_) => continue,
+        };
+        if file_type.is_dir() {
+            subdirs.push(entry.path());
+        } else if file_type.is_file() &&
+                  names.iter().any(|name| entry.file_name().to_string_lossy() == **name) {
+            return Some(entry.path());
+        }
+    }
+
+    subdirs.iter().filter_map(|subdir| search(subdir, names)).next()
+}
+
+// Splits a shell command line into commands and each command into words,
+// following quotes and backslashes. Make's `$$` escape is undone first.
+fn shell_commands(line: &str) -> Vec<Vec<String>> {
+    let line = line.replace("$$", "$");
+    let mut commands = Vec::new();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut in_word = false;
+
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\'' => {
+                in_word = true;
+                word.extend(chars.by_ref().take_while(|&c| c != '\''));
+            }
+            '"' => {
+                in_word = true;
+                while let Some(c) = chars.next() {
+                    match c {
+                        '"' => break,
+                        '\\' => word.extend(chars.next()),
+                        c => word.push(c),
+                    }
+                }
+            }
+            '\\' => {
+                in_word = true;
+                word.extend(chars.next());
+            }
+            c if c.is_whitespace() || c == ';' => {
+                if in_word {
+                    words.push(word.clone());
+                    word.clear();
+                    in_word = false;
+                }
+                if c == ';' && !words.is_empty() {
+                    commands.push(words.clone());
+                    words.clear();
+                }
+            }
+            c => {
+                in_word = true;
+                word.push(c);
+            }
+        }
+    }
+    if in_word {
+        words.push(word);
+    }
+    if !words.is_empty() {
+        commands.push(words);
+    }
+
+    commands
+}
+
+fn parse_flags(words: &[String], build_dir: &Path) -> CppFlags {
+    let mut flags = CppFlags::default();
+    let value_flags = ["-include", "-isystem", "-idirafter", "-iquote", "-I", "-D", "-U"];
+    let mut words = words.iter();
+    while let Some(word) = words.next() {
+        // Flags take their value either joined or as the next word
+        let (flag, value) = match value_flags.iter().find(|flag| word.starts_with(**flag)) {
+            Some(flag) if word.len() == flag.len() => (*flag, words.next().cloned()),
+            Some(flag) => (*flag, Some(word[flag.len()..].to_owned())),
+            None => {
+                // Skip the values of other flags that take one
+                if word == "-o" || word == "-x" {
+                    words.next();
+                }
+                continue;
+            }
+        };
+        let value = match value {
+            Some(value) => value,
+            None => break,
+        };
+
+        match flag {
+            "-D" => flags.macros.push(parse_define(&value)),
+            "-U" => flags.macros.push((value, None)),
+            "-include" => {
+                let path = resolve(build_dir, &value);
+                let path = if path.is_file() { path } else { PathBuf::from(value) };
+                flags.forced_includes.push(path);
+            }
+            _ => flags.include_dirs.push(resolve(build_dir, &value)),
+        }
+    }
+
+    flags
+}
+
+// Joins a path from the command to the build directory, dropping any `.`
+fn resolve(build_dir: &Path, path: &str) -> PathBuf {
+    build_dir.join(Path::new(path)
+                       .components()
+                       .filter(|c| *c != Component::CurDir)
+                       .collect::<PathBuf>())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mktemp::Temp;
+    use write_file;
+
+    // The dtb rule as kbuild saves it, with the preprocessor command first
+    const CMD: &'static str = "savedcmd_arch/arm64/boot/dts/acme/board.dtb := \
+        gcc -E -Wp,-MMD,arch/arm64/boot/dts/acme/.board.dtb.d.pre.tmp -nostdinc \
+        -I./scripts/dtc/include-prefixes -I ./include -undef -D__DTS__ \
+        -DFOO=$$(bar) -D 'STR=\"a b\"' -U BAR -x assembler-with-cpp \
+        -o arch/arm64/boot/dts/acme/.board.dtb.dts.tmp arch/arm64/boot/dts/acme/board.dts \
+        -include ./include/generated/autoconf.h -includemissing.h ; \
+        ./scripts/dtc/dtc -o arch/arm64/boot/dts/acme/board.dtb -b 0 \
+        -iarch/arm64/boot/dts/acme/ -i./scripts/dtc/include-prefixes \
+        -d arch/arm64/boot/dts/acme/.board.dtb.d.dtc.tmp \
+        arch/arm64/boot/dts/acme/.board.dtb.dts.tmp ; \
+        cat arch/arm64/boot/dts/acme/.board.dtb.d.pre.tmp \
+        > arch/arm64/boot/dts/acme/.board.dtb.d\n";
+
+    #[test]
+    fn flags_from_cmd_file() {
+        let dir = Temp::new_dir().unwrap();
+        let build = dir.as_ref();
+        write_file(build, "arch/arm64/boot/dts/acme/.board.dtb.cmd", CMD);
+        let autoconf = write_file(build, "include/generated/autoconf.h", "");
+
+        let dts = Path::new("/src/linux/arch/arm64/boot/dts/acme/board.dts");
+        let flags = cpp_flags(build, dts).unwrap();
+        assert_eq!(flags.include_dirs,
+                   vec![build.join("scripts/dtc/include-prefixes"), build.join("include")]);
+        assert_eq!(flags.macros,
+                   vec![("__DTS__".to_owned(), Some("1".to_owned())),
+                        ("FOO".to_owned(), Some("$(bar)".to_owned())),
+                        ("STR".to_owned(), Some("\"a b\"".to_owned())),
+                        ("BAR".to_owned(), None)]);
+        assert_eq!(flags.forced_includes, vec![autoconf, PathBuf::from("missing.h")]);
+        assert_eq!(flags.command, None);
+    }
+
+    #[test]
+    fn cmd_file_search() {
+        let dir = Temp::new_dir().unwrap();
+        let build = dir.as_ref();
+        // Older kernels use the `cmd_` prefix
+        write_file(build,
+                   "arch/arm/boot/dts/.other.dtb.cmd",
+                   &CMD.replacen("savedcmd_", "cmd_", 1));
+        write_file(build, "arch/arm/boot/dts/.no-cpp.dtb.cmd", "cmd_no-cpp.dtb := dtc -o x y\n");
+
+        // Not mirrored in the build directory, so it is searched for
+        let flags = cpp_flags(build, Path::new("other.dts")).unwrap();
+        assert_eq!(flags.include_dirs.len(), 2);
+
+        match cpp_flags(build, Path::new("no-cpp.dts")) {
+            Err(KbuildError::NoCppCommand(path)) => {
+                assert_eq!(path, build.join("arch/arm/boot/dts/.no-cpp.dtb.cmd"))
+            }
+            x => panic!("unexpected result: {:?}", x),
+        }
+        match cpp_flags(build, Path::new("missing.dts")) {
+            Err(KbuildError::NoCmdFile(path)) => assert_eq!(path, Path::new("missing.dts")),
+            x => panic!("unexpected result: {:?}", x),
+        }
+    }
+
+    #[test]
+    fn shell_words() {
+        let words = |words: &[&str]| words.iter().map(|w| w.to_string()).collect::<Vec<_>>();
+        assert_eq!(shell_commands("a 'b c'\"d\\\"e\" f\\ g;h $$x ; ;i"),
+                   vec![words(&["a", "b cd\"e", "f g"]), words(&["h", "$x"]), words(&["i"])]);
+        assert!(shell_commands("  ").is_empty());
+    }
+}
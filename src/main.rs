@@ -4,29 +4,39 @@ extern crate clap;
 extern crate mktemp;
 
 mod change_tracker;
+mod config;
+mod kbuild;
 
+use std::env;
 use std::process::Command;
 use std::path::{Path, PathBuf};
 use std::io::{self, BufRead, Write};
-use std::iter::Iterator;
+use std::iter::{self, Iterator};
 use std::fmt::{self, Display, Formatter};
 
 use mktemp::Temp;
 
-use device_tree_source::parser::{ParseResult, parse_dt};
-use device_tree_source::tree::Offset;
+use clap::ArgMatches;
+
+use device_tree_source::parser::{SyntaxError, parse_dt_recover, parse_overlay_recover, is_overlay};
+use device_tree_source::tree::{Spanned, Span, DTInfo, AmendError, Redefinition, UnitAddressError,
+                               ReferenceError, Node, NodeName, Property, Data, Cell};
+use device_tree_source::cpp::{Preprocessor, CppError, ExcludedRegion};
+use device_tree_source::query::Query;
 use device_tree_source::include::{IncludeBounds, IncludeMethod, IncludeError, BoundsError,
-                                  include_files, get_bounds_containing_offset};
+                                  include_files, get_bounds_containing_offset,
+                                  find_include_file, read_incbin, MacroLocator};
 
-use change_tracker::LabelStore;
+use change_tracker::{LabelStore, Element};
+use config::{Config, ConfigError};
+use kbuild::KbuildError;
 
 // General idea:
-//  Run CPP
+//  Run CPP, either built in or external
 //  Parse file for DTS includes and replace with include contents
 //  Find byte starts/ends for each file
 //  Parse file to create device tree
-//  create mapping of objects to byte offset starting points
-//  - not perfect with offsets stored in object, but it will do
+//  create mapping of objects to byte spans
 //  create mapping of full paths to objects
 //  create mapping of labels to full paths
 //  Parse device tree to create changes
@@ -42,82 +52,60 @@ fn main() {
             (@arg file: +required "DTS file to parse")
             (@arg no_defaults: -n --no_defaults "Disable default includes. \
                 An 'include' directory, if it exists, is automatically included")
-            (@arg include: -I ... +takes_value "Additional files to pass to CPP as an include")
+            (@arg include: -I ... +takes_value number_of_values(1) "Additional directory to \
+                search for included files")
+            (@arg define: -D ... +takes_value number_of_values(1) "Macro to define, as NAME or \
+                NAME=VALUE")
+            (@arg undefine: -U ... +takes_value number_of_values(1) "Macro to remove. Applied \
+                after every -D")
+            (@arg forced_include: --include ... +takes_value number_of_values(1) "File to \
+                include before the DTS file, as with cpp's -include")
+            (@arg cpp: --cpp +takes_value "External preprocessor to run, such as 'gcc' or \
+                'clang', or 'builtin'. Defaults to $CPP if it is set and the built-in \
+                preprocessor otherwise")
+            (@arg no_config: --no_config "Ignore .dts_viewer.conf files. Otherwise the nearest \
+                one in or above the directory of the DTS file is used")
+            (@arg kbuild: -k --kbuild +takes_value "Linux kernel build output directory. The \
+                preprocessor flags kbuild used for the DTS file are read from its .cmd file \
+                there in place of the defaults")
+            (@arg macros: -m --macros "List every macro visible to the DTS file, where it was \
+                defined and what it redefined, then exit")
+            (@arg overlay: -o --overlay ... +takes_value "Overlay to apply on top of the DTS \
+                file. Overlays are applied in the order given")
+            (@arg boot_cpuid: -b --boot_cpuid +takes_value "Boot CPU ID, in decimal or 0x hex. \
+                Defaults to the reg of the first CPU under /cpus")
+            (@arg query: -q --query ... +takes_value number_of_values(1) "Query to run on the \
+                final tree, such as '/soc/**[compatible=ns16550a][status=okay]' or \
+                '&uart0:reg'. Prints what was found for each and exits")
         )
         .get_matches();
 
     let file_name = matches.value_of("file").unwrap();
-    let mut cpp_temp_out = Temp::new_file().expect("Could not create temp file");
-    let mut include_dirs = Vec::new();
-
-    let mut cpp_command = Command::new("gcc");
-    cpp_command.args(&["-E", "-nostdinc"])
-        .args(&["-undef", "-D__DTS__", "-x", "assembler-with-cpp"])
-        .args(&["-o", cpp_temp_out.as_ref().to_str().unwrap()])
-        .arg(&file_name);
-
-    if !matches.is_present("no_defaults") {
-        cpp_command.args(&["-I", "."]);
-        include_dirs.push(PathBuf::from("."));
-        if Path::new("include").is_dir() {
-            cpp_command.args(&["-I", "include/"]);
-            include_dirs.push(PathBuf::from("include/"));
-        }
-
-        if let Some(parent) = Path::new(file_name).parent() {
-            cpp_command.args(&["-I", &parent.to_string_lossy()]);
-            include_dirs.push(parent.to_owned());
-
-            let include = parent.join("include");
-            if include.is_dir() {
-                cpp_command.args(&["-I", &include.to_string_lossy()]);
-                include_dirs.push(include);
-            }
-        } else {
-            println!("Could not get parent directory of file");
-        }
-    }
-
-    if let Some(includes) = matches.values_of("include") {
-        for include in includes {
-            if Path::new(include).is_dir() {
-                cpp_command.args(&["-I", include]);
-                include_dirs.push(PathBuf::from(include));
+    let boot_cpuid = match matches.value_of("boot_cpuid") {
+        Some(id) => {
+            let parsed = if id.starts_with("0x") || id.starts_with("0X") {
+                u32::from_str_radix(&id[2..], 16)
+            } else {
+                id.parse()
+            };
+            match parsed {
+                Ok(id) => Some(id),
+                Err(_) => {
+                    println!("Invalid boot CPU ID: {}", id);
+                    return;
+                }
             }
         }
-    }
-
-    // println!("{:?}", cpp_command);
-
-    let include_output = cpp_command.output().expect("Failed to start CPP");
-    if !include_output.status.success() {
-        // Done to prevent a panic as the file will not have been written to
-        cpp_temp_out.release();
-        println!("Failed to execute CPP. Error message is below.");
-        print!("{}", String::from_utf8_lossy(&include_output.stderr));
+        None => None,
+    };
+    if matches.is_present("macros") {
+        list_macros(file_name, &matches);
         return;
     }
 
-    let (buffer, bounds) = match include_files(&cpp_temp_out, &include_dirs) {
-        Ok(x) => x,
-        Err(e) => {
-            match e {
-                IncludeError::IOError(err, path) => {
-                    print!("IO error: {}", err);
-                    if let Some(path) = path {
-                        println!(" {}", path.display());
-                    }
-                }
-                IncludeError::LinemarkerInDtsi(path) =>
-                    println!("Extraneous linemarker found in DT include: {}",
-                             path.to_string_lossy()),
-                IncludeError::ParseError(_) =>
-                    println!("Failed to convert line to byte offset for bounds tracking."),
-                IncludeError::NoBoundReturned(path) =>
-                    println!("No bounds returned after parsing file: {}", path.to_string_lossy()),
-            }
-            return;
-        }
+    let (buffer, bounds, include_dirs, excluded) = match preprocess(file_name, &matches) {
+        Some(x) => x,
+        None => return,
     };
 
     // println!("{:#?}", bounds);
@@ -127,32 +115,169 @@ fn main() {
     //     total_dts_dump.write_all(&buffer).unwrap()
     // }
 
+    if is_overlay(&buffer) {
+        let (overlay, errors) = parse_overlay_recover(&buffer);
+        for err in &errors {
+            print_syntax_error(&bounds, &buffer, err);
+        }
+        for node in &overlay.amends {
+            print_redefinitions(&bounds, &buffer, node);
+        }
+        println!("-- {} is an overlay and must be applied to a base tree. It amends:",
+                 file_name);
+        for node in &overlay.amends {
+            match *node.name() {
+                NodeName::Ref(ref label) => println!("    &{}", label),
+                NodeName::Full(ref name) => println!("    {}", name),
+            }
+        }
+        return;
+    }
+
+    let (mut dt_info, mut amends, errors) = parse_dt_recover(&buffer);
+
+    {
+        let mut locator = MacroLocator::new(&bounds, &buffer);
+        fill_macro_names(&mut dt_info.root, &mut locator);
+        for node in &mut amends {
+            fill_macro_names(node, &mut locator);
+        }
+    }
+
     let include_tree = IncludeTree::bounds_to_tree(&bounds);
-    if let Some(tree) = include_tree {
+    if let Some(mut tree) = include_tree {
+        let mut incbins = Vec::new();
+        collect_incbins(&dt_info.root, &mut incbins);
+        for node in &amends {
+            collect_incbins(node, &mut incbins);
+        }
+
+        for (offset, file) in incbins {
+            let binary = find_include_file(file, &include_dirs)
+                            .unwrap_or_else(|_| PathBuf::from(file));
+            let added = match get_bounds_containing_offset(&bounds, offset) {
+                Ok(bound) => tree.add_binary(bound.child_path(), binary.clone()),
+                Err(_) => false,
+            };
+            if !added {
+                tree.binaries.push(binary);
+            }
+        }
+
+        for region in &excluded {
+            if let Some(file) = tree.find_mut(region.path()) {
+                file.excluded.push(region.clone());
+            }
+        }
+
         println!("{}", tree);
     } else {
         println!("-- Could not constuct include tree from bounds!");
     }
 
-    let (dt_info, amends) = match parse_dt(&buffer) {
-        Ok(ParseResult::Complete(dt_info, amends)) => (dt_info, amends),
-        Ok(ParseResult::RemainingInput(dt_info, amends, rem)) => {
-            println!("Input remaining after parsing:\n\"{}\"", String::from_utf8_lossy(rem));
-            (dt_info, amends)
+    for err in &errors {
+        print_syntax_error(&bounds, &buffer, err);
+    }
+    if !errors.is_empty() {
+        println!("-- {} syntax error(s) found, showing what could be parsed\n", errors.len());
+    }
+    print_redefinitions(&bounds, &buffer, &dt_info.root);
+    for node in &amends {
+        print_redefinitions(&bounds, &buffer, node);
+    }
+
+    // TODO: perform secondary checks and jazz (only smooth) - issue 9
+
+    let mut overlay_files = Vec::new();
+    if let Some(files) = matches.values_of("overlay") {
+        for file in files {
+            match preprocess(file, &matches) {
+                Some((buffer, bounds, include_dirs, _)) =>
+                    overlay_files.push((file, buffer, bounds, include_dirs)),
+                None => return,
+            }
         }
-        Err(err) => {
-            println!("{:?}", err);
-            return;
+    }
+
+    let mut overlays = Vec::new();
+    for &(_, ref buffer, ref bounds, _) in &overlay_files {
+        let (overlay, errors) = parse_overlay_recover(buffer);
+        for err in &errors {
+            print_syntax_error(bounds, buffer, err);
         }
-    };
+        for node in &overlay.amends {
+            print_redefinitions(bounds, buffer, node);
+        }
+        overlays.push(overlay);
+    }
 
-    // TODO: perform secondary checks and jazz (only smooth) - issue 9
+    // Source 0 is the base tree, followed by each overlay in order
+    let mut sources = vec![(file_name, &buffer[..], &bounds[..], &include_dirs[..])];
+    for &(file, ref buffer, ref bounds, ref include_dirs) in &overlay_files {
+        sources.push((file, buffer, bounds, include_dirs));
+    }
+
+    let mut final_tree = dt_info.clone();
+    if let Some(id) = boot_cpuid {
+        final_tree.set_boot_cpuid(id);
+    }
+    if let Err(errs) = final_tree.merge_amends(&amends) {
+        for err in &errs {
+            print_amend_error(&bounds, &buffer, err);
+        }
+    }
+    // Checked before overlays are applied so every node is from the base tree
+    for err in &final_tree.check_unit_addresses() {
+        print_unit_address_error(&bounds, &buffer, err);
+    }
+    for (overlay, &(_, buffer, bounds, _)) in overlays.iter().zip(&sources[1..]) {
+        if let Err(errs) = final_tree.apply_overlay(overlay) {
+            for err in &errs {
+                print_amend_error(bounds, buffer, err);
+            }
+        }
+    }
+
+    let omitted = final_tree.omit_unreferenced();
+
+    // Reserved memory from the base tree comes first, followed by each overlay's
+    let reserve_sources: Vec<usize> = iter::repeat(0)
+        .take(dt_info.reserve_info.len())
+        .chain(overlays.iter()
+                       .enumerate()
+                       .flat_map(|(i, overlay)| {
+                           iter::repeat(i + 1).take(overlay.reserve_info.len())
+                       }))
+        .collect();
+    print_reserve_overlaps(&final_tree, &sources, &reserve_sources);
+    println!("-- Boot CPU ID: {:#x}\n", final_tree.boot_cpuid);
 
     let mut store = LabelStore::new();
     store.fill(&dt_info, &amends);
+    for (i, overlay) in overlays.iter().enumerate() {
+        store.fill_overlay(overlay, i + 1);
+    }
+    for path in &omitted {
+        store.omit(Path::new(path));
+    }
+
+    if let Err(errs) = final_tree.resolve_references() {
+        for err in &errs {
+            print_reference_error(&sources, &store, err);
+        }
+    }
+
+    if let Some(queries) = matches.values_of("query") {
+        for query in queries {
+            print_query(&final_tree, query);
+        }
+        return;
+    }
+
+    let excluded_defs = excluded_paths(&excluded, &dt_info, &amends, &store);
 
     loop {
-        print!("Enter alias or path: ");
+        print!("Enter alias, path or ?query: ");
         io::stdout().flush().expect("Error flushing stdout");
 
         let mut line = String::new();
@@ -163,9 +288,13 @@ fn main() {
         if line.is_empty() {
             break;
         }
+        if line.starts_with('?') {
+            print_query(&final_tree, &line[1..]);
+            continue;
+        }
 
         let path = if line.starts_with('/') {
-            Some(PathBuf::from(line))
+            Some(full_path(&final_tree, Path::new(line)))
         } else {
             let line = if line.starts_with('&') {
                 &line[1..]
@@ -192,38 +321,683 @@ fn main() {
             match store.changes_from_path(&path) {
                 Some(changes) => {
                     for change in changes {
-                        let offset = change.get_offset();
-                        // println!("Start offset: {}", offset);
-                        match get_bounds_containing_offset(&bounds, offset) {
+                        let (source, buffer, bounds, include_dirs) = sources[change.source];
+                        if change.source != 0 {
+                            println!("Overlay: {}", source);
+                        }
+                        let span = change.element.get_span();
+                        match get_bounds_containing_offset(bounds, span.start) {
                             Ok(bound) => {
                                 print!("File: {}", bound.child_path().to_string_lossy());
-
-                                match bound.file_line_from_global(&buffer, offset) {
-                                    Ok((line, col)) => {
-                                        println!(", Line: {}, Column: {}", line, col)
-                                    }
-                                    Err(err) => match err {
-                                        BoundsError::ParseError(_) =>
-                                            println!("Offset ({}) could not be converted to line.",
-                                                     offset),
-                                        BoundsError::IOError(..) =>
-                                            println!("Failed to open file: {}",
-                                                     bound.child_path().to_string_lossy()),
-                                        BoundsError::NotWithinBounds =>
-                                            println!("File offset ({}) was supposed to be in\
-                                                         bound. {:?}",
-                                                     offset,
-                                                     bound),
-                                    }
-                                }
+                                print_span_lines(bounds, bound, buffer, span);
                             }
-                            Err(_) => println!("-- Could not find file for offset {}", offset),
+                            Err(_) => println!("-- Could not find file for offset {}", span.start),
                         }
-                        println!("{}\n", change);
+                        print_element(&change.element, buffer);
+                        print_incbins(&change.element, include_dirs);
+                        println!();
                     }
+                    print_final(&final_tree, &path);
                 }
                 None => println!("Nothing at path"),
             }
+
+            for &(ref excluded_path, line, region) in &excluded_defs {
+                if *excluded_path == path {
+                    println!("-- Also in {} line {}, but excluded by {}\n",
+                             region.path().to_string_lossy(),
+                             line,
+                             region.condition());
+                }
+            }
+        }
+    }
+}
+
+/// How to run the preprocessor on a file.
+#[derive(Debug, Clone, Default)]
+pub struct CppFlags {
+    /// The external preprocessor command and its arguments, or `None` for the
+    /// built-in preprocessor.
+    pub command: Option<Vec<String>>,
+    /// The directories searched for included files, in order.
+    pub include_dirs: Vec<PathBuf>,
+    /// Macros to define, or to remove if there is no value, in order.
+    pub macros: Vec<(String, Option<String>)>,
+    /// Files included before the first line, as with `-include`.
+    pub forced_includes: Vec<PathBuf>,
+}
+
+impl CppFlags {
+    fn preprocessor(&self) -> Preprocessor {
+        let mut cpp = Preprocessor::new(&self.include_dirs);
+        for &(ref name, ref value) in &self.macros {
+            match *value {
+                Some(ref value) => cpp.define(name, value),
+                None => cpp.undefine(name),
+            }
+        }
+        for file in &self.forced_includes {
+            cpp.force_include(file);
+        }
+        cpp
+    }
+}
+
+/// Splits a macro definition given as `NAME` or `NAME=VALUE` into its name
+/// and value, which defaults to 1 as it does for `-D`.
+pub fn parse_define(define: &str) -> (String, Option<String>) {
+    let mut parts = define.splitn(2, '=');
+    let name = parts.next().unwrap_or("").trim().to_owned();
+    (name, Some(parts.next().map_or("1", |v| v.trim()).to_owned()))
+}
+
+// The flags for the file. The base is either from kbuild or the defaults, then
+// the settings from any configuration file and the command line are added.
+fn cpp_flags(file_name: &str, matches: &ArgMatches) -> Option<CppFlags> {
+    let config = match config::find(Path::new(file_name)) {
+        Some(ref path) if !matches.is_present("no_config") => {
+            match config::load(path) {
+                Ok(config) => config,
+                Err(ConfigError::IOError(err, path)) => {
+                    println!("IO error: {} {}", err, path.display());
+                    return None;
+                }
+                Err(ConfigError::Syntax(path, line, msg)) => {
+                    println!("{}:{}: error: {}", path.display(), line, msg);
+                    return None;
+                }
+            }
+        }
+        _ => Config::default(),
+    };
+
+    let build_dir = matches.value_of("kbuild").map(PathBuf::from).or(config.kbuild);
+    let mut flags = match build_dir {
+        Some(build_dir) => {
+            match kbuild::cpp_flags(&build_dir, Path::new(file_name)) {
+                Ok(flags) => flags,
+                Err(KbuildError::IOError(err, path)) => {
+                    println!("IO error: {} {}", err, path.display());
+                    return None;
+                }
+                Err(KbuildError::NoCmdFile(path)) => {
+                    println!("No kbuild .cmd file found for {} in {}",
+                             path.display(),
+                             build_dir.display());
+                    return None;
+                }
+                Err(KbuildError::NoCppCommand(path)) => {
+                    println!("No preprocessor command found in {}", path.display());
+                    return None;
+                }
+            }
+        }
+        None => default_flags(file_name, matches.is_present("no_defaults") || config.no_defaults),
+    };
+
+    flags.include_dirs.extend(config.include_dirs);
+    flags.macros.extend(config.macros);
+    flags.forced_includes.extend(config.forced_includes);
+
+    if let Some(includes) = matches.values_of("include") {
+        for include in includes {
+            if Path::new(include).is_dir() {
+                flags.include_dirs.push(PathBuf::from(include));
+            } else {
+                println!("Ignoring include directory {} as it is not a directory", include);
+            }
+        }
+    }
+    if let Some(defines) = matches.values_of("define") {
+        flags.macros.extend(defines.map(parse_define));
+    }
+    if let Some(undefines) = matches.values_of("undefine") {
+        flags.macros.extend(undefines.map(|name| (name.to_owned(), None)));
+    }
+    if let Some(files) = matches.values_of("forced_include") {
+        flags.forced_includes.extend(files.map(PathBuf::from));
+    }
+
+    // The command line comes first, then the configuration file, then $CPP
+    let command = matches.value_of("cpp")
+        .map(|cpp| cpp.split_whitespace().map(|w| w.to_owned()).collect())
+        .or(config.cpp)
+        .or_else(|| {
+            env::var("CPP").ok().map(|cpp| cpp.split_whitespace().map(|w| w.to_owned()).collect())
+        });
+    flags.command = match command {
+        Some(ref command) if command.is_empty() || *command == ["builtin"] => None,
+        command => command,
+    };
+
+    Some(flags)
+}
+
+fn default_flags(file_name: &str, no_defaults: bool) -> CppFlags {
+    let mut include_dirs = Vec::new();
+
+    if !no_defaults {
+        include_dirs.push(PathBuf::from("."));
+        if Path::new("include").is_dir() {
+            include_dirs.push(PathBuf::from("include/"));
+        }
+
+        if let Some(parent) = Path::new(file_name).parent() {
+            include_dirs.push(parent.to_owned());
+
+            let include = parent.join("include");
+            if include.is_dir() {
+                include_dirs.push(include);
+            }
+        } else {
+            println!("Could not get parent directory of file");
+        }
+    }
+
+    CppFlags {
+        command: None,
+        include_dirs: include_dirs,
+        macros: vec![("__DTS__".to_owned(), Some("1".to_owned()))],
+        forced_includes: Vec::new(),
+    }
+}
+
+// Runs CPP on the file and resolves DTS includes, printing any errors. Returns
+// the resulting buffer, the bounds of each file within it, the include
+// directories used, and the regions left out by conditionals. Only the
+// built-in preprocessor can report left out regions.
+fn preprocess(file_name: &str, matches: &ArgMatches)
+              -> Option<(Vec<u8>, Vec<IncludeBounds>, Vec<PathBuf>, Vec<ExcludedRegion>)> {
+    let flags = cpp_flags(file_name, matches)?;
+    let result = if let Some(ref command) = flags.command {
+        run_cpp(command, file_name, &flags).map(|(buffer, bounds)| (buffer, bounds, Vec::new()))
+    } else {
+        let mut cpp = flags.preprocessor();
+        match cpp.preprocess(file_name) {
+            Ok((buffer, bounds)) => Some((buffer, bounds, cpp.excluded().to_vec())),
+            Err(CppError::IOError(err, path)) => {
+                print!("IO error: {}", err);
+                if let Some(path) = path {
+                    println!(" {}", path.display());
+                }
+                None
+            }
+            Err(CppError::Syntax(path, line, msg)) => {
+                println!("{}:{}: error: {}", path.display(), line, msg);
+                None
+            }
+        }
+    };
+
+    result.map(|(buffer, bounds, excluded)| (buffer, bounds, flags.include_dirs, excluded))
+}
+
+// Lists every macro the file can see, where each was defined and any earlier
+// definitions it replaced
+fn list_macros(file_name: &str, matches: &ArgMatches) {
+    let mut cpp = match cpp_flags(file_name, matches) {
+        Some(flags) => flags.preprocessor(),
+        None => return,
+    };
+    match cpp.preprocess(file_name) {
+        Ok(_) => {}
+        Err(CppError::IOError(err, path)) => {
+            print!("IO error: {}", err);
+            if let Some(path) = path {
+                println!(" {}", path.display());
+            }
+            return;
+        }
+        Err(CppError::Syntax(path, line, msg)) => {
+            println!("{}:{}: error: {}", path.display(), line, msg);
+            return;
+        }
+    }
+
+    let location = |path: &Path, line: usize| if line == 0 {
+        path.display().to_string()
+    } else {
+        format!("{}:{}", path.display(), line)
+    };
+
+    for def in cpp.definitions() {
+        println!("{}: {}", location(def.path(), def.line()), def);
+        if def.params().is_none() {
+            let expanded = cpp.expand_text(def.name());
+            if expanded != def.value() {
+                println!("    expands to: {}", expanded);
+            }
+        }
+        let history = cpp.history(def.name());
+        // A definition removed with #undef was not redefined by the next one
+        for old in history[..history.len() - 1].iter().rev() {
+            match old.undefined() {
+                Some((path, line)) => {
+                    println!("    was {}, removed at {}: {}",
+                             location(old.path(), old.line()),
+                             location(path, line),
+                             old)
+                }
+                None => println!("    redefines {}: {}", location(old.path(), old.line()), old),
+            }
+        }
+    }
+}
+
+fn run_cpp(command: &[String], file_name: &str, flags: &CppFlags)
+           -> Option<(Vec<u8>, Vec<IncludeBounds>)> {
+    let mut cpp_temp_out = Temp::new_file().expect("Could not create temp file");
+
+    let mut cpp_command = Command::new(&command[0]);
+    cpp_command.args(&command[1..])
+        .args(&["-E", "-nostdinc"])
+        .args(&["-undef", "-x", "assembler-with-cpp"])
+        .args(&["-o", cpp_temp_out.as_ref().to_str().unwrap()])
+        .arg(&file_name);
+    for &(ref name, ref value) in &flags.macros {
+        match *value {
+            Some(ref value) => cpp_command.arg(format!("-D{}={}", name, value)),
+            None => cpp_command.arg(format!("-U{}", name)),
+        };
+    }
+    for file in &flags.forced_includes {
+        cpp_command.arg("-include").arg(file);
+    }
+    for dir in &flags.include_dirs {
+        cpp_command.arg("-I").arg(dir);
+    }
+
+    // println!("{:?}", cpp_command);
+
+    let include_output = match cpp_command.output() {
+        Ok(output) => output,
+        Err(err) => {
+            // Done to prevent a panic as the file will not have been written to
+            cpp_temp_out.release();
+            println!("Failed to start CPP ({}): {}", command.join(" "), err);
+            return None;
+        }
+    };
+    if !include_output.status.success() {
+        // Done to prevent a panic as the file will not have been written to
+        cpp_temp_out.release();
+        println!("Failed to execute CPP. Error message is below.");
+        print!("{}", String::from_utf8_lossy(&include_output.stderr));
+        return None;
+    }
+
+    match include_files(&cpp_temp_out, &flags.include_dirs) {
+        Ok(x) => Some(x),
+        Err(e) => {
+            match e {
+                IncludeError::IOError(err, path) => {
+                    print!("IO error: {}", err);
+                    if let Some(path) = path {
+                        println!(" {}", path.display());
+                    }
+                }
+                IncludeError::LinemarkerInDtsi(path) =>
+                    println!("Extraneous linemarker found in DT include: {}",
+                             path.to_string_lossy()),
+                IncludeError::ParseError(_) =>
+                    println!("Failed to convert line to byte offset for bounds tracking."),
+                IncludeError::NoBoundReturned(path) =>
+                    println!("No bounds returned after parsing file: {}", path.to_string_lossy()),
+            }
+            None
+        }
+    }
+}
+
+fn print_span_lines(bounds: &[IncludeBounds], bound: &IncludeBounds, buffer: &[u8], span: Span) {
+    let start = bound.file_line_from_global(buffer, span.start);
+    // The end is exclusive, so look up the last byte the span covers. It may
+    // be in a different bound of the same file, such as after a macro.
+    let last = span.end.saturating_sub(1).max(span.start);
+    let end = match get_bounds_containing_offset(bounds, last) {
+        Ok(end_bound) if end_bound.child_path() == bound.child_path() => {
+            end_bound.file_line_from_global(buffer, last)
+        }
+        _ => Err(BoundsError::NotWithinBounds),
+    };
+    match (start, end) {
+        (Ok((line, col)), Ok((end_line, end_col))) => {
+            println!(", Line: {}, Column: {} to Line: {}, Column: {}",
+                     line,
+                     col,
+                     end_line,
+                     end_col)
+        }
+        (Ok((line, col)), Err(_)) => println!(", Line: {}, Column: {}", line, col),
+        (Err(err), _) => match err {
+            BoundsError::ParseError(_) =>
+                println!("Offset ({}) could not be converted to line.", span.start),
+            BoundsError::IOError(..) =>
+                println!("Failed to open file: {}", bound.child_path().to_string_lossy()),
+            BoundsError::NotWithinBounds =>
+                println!("File offset ({}) was supposed to be in bound. {:?}", span.start, bound),
+        },
+    }
+}
+
+// Properties are shown exactly as they were written, nodes are too large for
+// that so they are summarized instead
+fn print_element(element: &Element, buffer: &[u8]) {
+    let span = element.get_span();
+    match *element {
+        Element::Prop(_) if !span.is_empty() && span.end <= buffer.len() => {
+            println!("{}", String::from_utf8_lossy(&buffer[span.start..span.end]))
+        }
+        _ => println!("{}", element),
+    }
+}
+
+fn print_location(bounds: &[IncludeBounds], buffer: &[u8], offset: usize) {
+    match get_bounds_containing_offset(bounds, offset) {
+        Ok(bound) => {
+            match bound.file_line_from_global(buffer, offset) {
+                Ok((line, col)) => print!("{}:{}:{}: ",
+                                          bound.child_path().to_string_lossy(),
+                                          line,
+                                          col),
+                Err(_) => print!("{}: offset {}: ",
+                                 bound.child_path().to_string_lossy(),
+                                 offset),
+            }
+        }
+        Err(_) => print!("Offset {}: ", offset),
+    }
+}
+
+fn print_syntax_error(bounds: &[IncludeBounds], buffer: &[u8], err: &SyntaxError) {
+    print_location(bounds, buffer, err.offset);
+    println!("error: {}", err.message);
+    println!("    {}", err.snippet);
+}
+
+// Redefining a name within the same node body is allowed, with the last
+// definition winning, but is usually a copy and paste mistake
+fn print_redefinitions(bounds: &[IncludeBounds], buffer: &[u8], node: &Node) {
+    let path = match *node.name() {
+        NodeName::Ref(ref label) if !label.starts_with('/') => format!("&{}", label),
+        NodeName::Ref(ref name) | NodeName::Full(ref name) => name.to_owned(),
+    };
+    for redef in node.redefinitions(&path) {
+        let (what, path, first, second) = match redef {
+            Redefinition::Property(path, first, second) => ("property", path, first, second),
+            Redefinition::Node(path, first, second) => ("node", path, first, second),
+        };
+        print_location(bounds, buffer, second.start);
+        println!("warning: {} {} defined again in the same node", what, path);
+        print_location(bounds, buffer, first.start);
+        println!("note: previous definition of {} was here", path);
+    }
+}
+
+fn print_amend_error(bounds: &[IncludeBounds], buffer: &[u8], err: &AmendError) {
+    match *err {
+        AmendError::UnresolvedTarget(ref target, offset) => {
+            print_location(bounds, buffer, offset);
+            println!("error: target {} does not exist", target);
+        }
+        AmendError::MissingTarget(offset) => {
+            print_location(bounds, buffer, offset);
+            println!("error: fragment has no target or target-path property");
+        }
+        AmendError::NoSuchNode(ref name, offset) => {
+            print_location(bounds, buffer, offset);
+            println!("error: deleted node {} does not exist", name);
+        }
+    }
+}
+
+// Reserving the same memory with both `/memreserve/` and `/reserved-memory` is
+// usually left over from moving from one to the other
+fn print_reserve_overlaps(tree: &DTInfo,
+                          sources: &[(&str, &[u8], &[IncludeBounds], &[PathBuf])],
+                          reserve_sources: &[usize]) {
+    for overlap in tree.reserve_overlaps() {
+        let reserve = &tree.reserve_info[overlap.reserve];
+        let (_, buffer, bounds, _) = sources[reserve_sources[overlap.reserve]];
+        print_location(bounds, buffer, reserve.span.start);
+        println!("warning: /memreserve/ {:#x} {:#x} overlaps {} at {:#x} {:#x}",
+                 reserve.address,
+                 reserve.size,
+                 overlap.path,
+                 overlap.address,
+                 overlap.size);
+    }
+}
+
+fn print_unit_address_error(bounds: &[IncludeBounds], buffer: &[u8], err: &UnitAddressError) {
+    match *err {
+        UnitAddressError::NoReg(ref path, span) => {
+            print_location(bounds, buffer, span.start);
+            println!("warning: {} has a unit address but no reg or ranges property", path);
+        }
+        UnitAddressError::Missing(ref path, ref expected, span) => {
+            print_location(bounds, buffer, span.start);
+            println!("warning: {} has a reg property but no unit address, expected @{}",
+                     path,
+                     expected);
+        }
+        UnitAddressError::Mismatch(ref path, ref expected, span) => {
+            print_location(bounds, buffer, span.start);
+            println!("warning: unit address of {} does not match its reg property, expected @{}",
+                     path,
+                     expected);
+        }
+    }
+}
+
+// The final tree mixes nodes from the base tree and every overlay, so the
+// source of an error is found from the changes made to the path
+fn print_reference_error(sources: &[(&str, &[u8], &[IncludeBounds], &[PathBuf])],
+                         store: &LabelStore,
+                         err: &ReferenceError) {
+    let (path, span) = match *err {
+        ReferenceError::Dangling(_, ref path, span) |
+        ReferenceError::DuplicatePhandle(_, ref path, span) |
+        ReferenceError::BadPhandle(ref path, span) => (path, span),
+    };
+    let source = store.changes_from_path(Path::new(path))
+        .and_then(|changes| {
+            changes.iter().find(|change| {
+                let outer = change.element.get_span();
+                outer.start <= span.start && span.end <= outer.end
+            })
+        })
+        .map(|change| sources[change.source]);
+    if let Some((_, buffer, bounds, _)) = source {
+        print_location(bounds, buffer, span.start);
+    }
+
+    match *err {
+        ReferenceError::Dangling(ref refr, ref path, _) if refr.starts_with('/') => {
+            println!("error: {} refers to &{{{}}}, which is not a node", path, refr)
+        }
+        ReferenceError::Dangling(ref refr, ref path, _) => {
+            println!("error: {} refers to &{}, which is not a node", path, refr)
+        }
+        ReferenceError::DuplicatePhandle(phandle, ref path, _) => {
+            println!("error: phandle {:#x} of {} is already used by another node", phandle, path)
+        }
+        ReferenceError::BadPhandle(ref path, _) => {
+            println!("error: bad value in phandle property of {}", path)
+        }
+    }
+}
+
+// Fills in the unit addresses left out of a path, such as `/soc/uart` for
+// `/soc/uart@fe001000`. The last part may also name a property.
+fn full_path(tree: &DTInfo, path: &Path) -> PathBuf {
+    if let Ok(full) = tree.full_path(&path.to_string_lossy()) {
+        return PathBuf::from(full);
+    }
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(name)) => match tree.full_path(&parent.to_string_lossy()) {
+            Ok(full) => PathBuf::from(full).join(name),
+            Err(_) => path.to_owned(),
+        },
+        _ => path.to_owned(),
+    }
+}
+
+// Works out the path each node and property in the left out regions would have
+// had, from the node each region is within
+fn excluded_paths<'a>(regions: &'a [ExcludedRegion],
+                      dt_info: &DTInfo,
+                      amends: &[Node],
+                      store: &LabelStore)
+                      -> Vec<(PathBuf, usize, &'a ExcludedRegion)> {
+    fn path_at(node: &Node, path: PathBuf, offset: usize) -> Option<PathBuf> {
+        match *node {
+            Node::Existing { ref children, span, .. }
+                if span.start < offset && offset < span.end => {
+                children.iter()
+                    .filter_map(|(name, child)| path_at(child, path.join(name), offset))
+                    .next()
+                    .or(Some(path))
+            }
+            _ => None,
+        }
+    }
+
+    let label_path = |label: &str| store.path_from_label(label).map(|p| p.to_owned());
+
+    let mut paths = Vec::new();
+    for region in regions {
+        let within = path_at(&dt_info.root, PathBuf::from("/"), region.offset())
+            .or_else(|| {
+                amends.iter()
+                    .filter_map(|node| {
+                        let path = match *node.name() {
+                            NodeName::Ref(ref label) => label_path(label)?,
+                            NodeName::Full(ref name) => PathBuf::from(name),
+                        };
+                        path_at(node, path, region.offset())
+                    })
+                    .next()
+            });
+
+        for (relative, line) in region.definitions() {
+            let path = if relative.starts_with('&') {
+                let mut parts = relative[1..].splitn(2, '/');
+                let label = parts.next().unwrap_or("");
+                label_path(label).map(|p| p.join(parts.next().unwrap_or("")))
+            } else if relative.starts_with('/') {
+                Some(PathBuf::from(&relative))
+            } else {
+                within.as_ref().map(|p| p.join(&relative))
+            };
+            if let Some(path) = path {
+                paths.push((path, line, region));
+            }
+        }
+    }
+
+    paths
+}
+
+// Lists the nodes or properties of the final tree found by a query
+fn print_query(tree: &DTInfo, query: &str) {
+    let query = match Query::parse(query) {
+        Ok(query) => query,
+        Err(err) => {
+            println!("-- Invalid query: {}\n{}\n{}^", err.message, query, " ".repeat(err.offset));
+            return;
+        }
+    };
+
+    let found = if query.selects_properties() {
+        let props = query.properties(tree);
+        for &(ref path, prop) in &props {
+            println!("{}: {}", path, prop);
+        }
+        props.len()
+    } else {
+        let nodes = query.nodes(tree);
+        for &(ref path, _) in &nodes {
+            println!("{}", path);
+        }
+        nodes.len()
+    };
+    println!("-- {} found\n", found);
+}
+
+// Shows what is at the path once all amendments and overlays are applied
+fn print_final(tree: &DTInfo, path: &Path) {
+    let path_str = path.to_string_lossy();
+    if let Ok(node) = tree.get_node_by_path(&path_str) {
+        println!("-- Final result:\n{}\n", node);
+        return;
+    }
+
+    let prop = path.parent()
+                   .and_then(|parent| tree.get_node_by_path(&parent.to_string_lossy()).ok())
+                   .and_then(|node| match *node {
+                       Node::Existing { ref proplist, .. } => path.file_name().and_then(|name| {
+                           proplist.get(&*name.to_string_lossy())
+                       }),
+                       Node::Deleted { .. } => None,
+                   });
+    match prop {
+        Some(prop) => println!("-- Final result:\n{}\n", prop),
+        None => println!("-- Not in final result\n"),
+    }
+}
+
+fn fill_macro_names(node: &mut Node, locator: &mut MacroLocator) {
+    if let Node::Existing { ref mut proplist, ref mut children, .. } = *node {
+        for prop in proplist.values_mut() {
+            if let Property::Existing { val: Some(ref mut data), .. } = *prop {
+                for d in data {
+                    if let Data::Cells(_, ref mut cells, _) = *d {
+                        for cell in cells {
+                            if let Cell::Expr { span, ref mut macro_name, .. } = *cell {
+                                *macro_name = locator.macro_at_offset(span.start);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        for child in children.values_mut() {
+            fill_macro_names(child, locator);
+        }
+    }
+}
+
+fn collect_incbins<'a>(node: &'a Node, incbins: &mut Vec<(usize, &'a str)>) {
+    if let Node::Existing { ref proplist, ref children, .. } = *node {
+        for prop in proplist.values() {
+            if let Property::Existing { val: Some(ref data), .. } = *prop {
+                for d in data {
+                    if let Data::Incbin(ref file, .., span) = *d {
+                        incbins.push((span.start, file));
+                    }
+                }
+            }
+        }
+        for child in children.values() {
+            collect_incbins(child, incbins);
+        }
+    }
+}
+
+// Binary includes are only read once the property is looked at
+fn print_incbins(change: &Element, include_dirs: &[PathBuf]) {
+    if let Element::Prop(&Property::Existing { val: Some(ref data), .. }) = *change {
+        for d in data {
+            if let Data::Incbin(ref file, offset, len, _) = *d {
+                match read_incbin(file, offset, len, include_dirs) {
+                    Ok(bytes) => println!("-- /incbin/ \"{}\": {} bytes", file, bytes.len()),
+                    Err(IncludeError::IOError(err, _)) =>
+                        println!("-- /incbin/ \"{}\" could not be read: {}", file, err),
+                    Err(_) => println!("-- /incbin/ \"{}\" could not be read", file),
+                }
+            }
         }
     }
 }
@@ -233,6 +1007,8 @@ struct IncludeTree {
     path: PathBuf,
     method: IncludeMethod,
     includes: Vec<IncludeTree>,
+    binaries: Vec<PathBuf>,
+    excluded: Vec<ExcludedRegion>,
 }
 
 impl IncludeTree {
@@ -242,6 +1018,8 @@ impl IncludeTree {
                 path: first.child_path().to_owned(),
                 includes: Vec::new(),
                 method: first.include_method().clone(),
+                binaries: Vec::new(),
+                excluded: Vec::new(),
             };
 
             //TODO: we don't really need the filter, benchmark speed w/wo
@@ -258,6 +1036,28 @@ impl IncludeTree {
         }
     }
 
+    /// Adds a file included with `/incbin/` to the first tree node for the
+    /// file containing the statement. Returns false if that file is not in the
+    /// tree.
+    fn add_binary(&mut self, including: &Path, binary: PathBuf) -> bool {
+        match self.find_mut(including) {
+            Some(tree) => {
+                tree.binaries.push(binary);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Finds the first tree node for a file.
+    fn find_mut(&mut self, path: &Path) -> Option<&mut IncludeTree> {
+        if self.path == path {
+            return Some(self);
+        }
+
+        self.includes.iter_mut().filter_map(|t| t.find_mut(path)).next()
+    }
+
     fn write(&self, f: &mut Formatter, prefix: &str) -> fmt::Result {
         let mut next_prefix = prefix.to_owned();
         next_prefix.push_str(" |-");
@@ -270,6 +1070,13 @@ impl IncludeTree {
         for t in &self.includes {
             t.write(f, &next_prefix)?;
         }
+        for b in &self.binaries {
+            writeln!(f, "{} {}: BIN", next_prefix, b.to_string_lossy())?;
+        }
+        for e in &self.excluded {
+            let (first, last) = e.lines();
+            writeln!(f, "{} lines {}-{}: excluded by {}", next_prefix, first, last, e.condition())?;
+        }
 
         Ok(())
     }
@@ -280,3 +1087,27 @@ impl Display for IncludeTree {
         self.write(f, "")
     }
 }
+
+// Writes a file for the tests, creating the directories it is in
+#[cfg(test)]
+fn write_file(dir: &Path, name: &str, contents: &str) -> PathBuf {
+    let path = dir.join(name);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent).unwrap();
+    }
+    std::fs::File::create(&path).unwrap().write_all(contents.as_bytes()).unwrap();
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defines() {
+        assert_eq!(parse_define("FOO"), ("FOO".to_owned(), Some("1".to_owned())));
+        assert_eq!(parse_define("FOO=2"), ("FOO".to_owned(), Some("2".to_owned())));
+        assert_eq!(parse_define("FOO="), ("FOO".to_owned(), Some("".to_owned())));
+        assert_eq!(parse_define(" FOO = a=b "), ("FOO".to_owned(), Some("a=b".to_owned())));
+    }
+}